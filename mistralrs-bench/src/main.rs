@@ -61,11 +61,29 @@ fn run_bench(
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repetition_context_size: None,
         max_len: Some(n_gen),
+        max_chars: None,
         stop_toks: None,
         logits_bias: None,
+        logits_bias_strs: None,
+        logits_bias_patterns: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        seed: None,
+        mirostat: None,
+        penalty_alpha: None,
+        include_stop_str_in_output: false,
+        prompt_logprobs: None,
+        return_raw_tokens: false,
+        continuous_usage_stats: false,
+        stream_granularity: None,
+        sampler_order: None,
+        typical_p: None,
+        eta_cutoff: None,
+        epsilon_cutoff: None,
+        penalize_prompt: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -83,6 +101,9 @@ fn run_bench(
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        chat_template: None,
+        user: None,
+        priority: None,
     });
 
     let mut usages = Vec::new();
@@ -115,6 +136,7 @@ fn run_bench(
                     }
                     Response::CompletionChunk(_) => unreachable!(),
                     Response::ImageGeneration(_) => unreachable!(),
+                    Response::Embeddings(_) => unreachable!(),
                 },
                 None => unreachable!("Expected a Done response, got None",),
             }
@@ -226,11 +248,29 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repetition_context_size: None,
         max_len: Some(5),
+        max_chars: None,
         stop_toks: None,
         logits_bias: None,
+        logits_bias_strs: None,
+        logits_bias_patterns: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        seed: None,
+        mirostat: None,
+        penalty_alpha: None,
+        include_stop_str_in_output: false,
+        prompt_logprobs: None,
+        return_raw_tokens: false,
+        continuous_usage_stats: false,
+        stream_granularity: None,
+        sampler_order: None,
+        typical_p: None,
+        eta_cutoff: None,
+        epsilon_cutoff: None,
+        penalize_prompt: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -238,9 +278,10 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
     let req = Request::Normal(NormalRequest {
         id: mistralrs.next_request_id(),
         messages: RequestMessage::Completion {
-            text: "Hello!".to_string(),
+            text: vec!["Hello!".to_string()],
             echo_prompt: false,
             best_of: 1,
+            beam_search: None,
         },
         sampling_params: sampling_params.clone(),
         response: tx,
@@ -252,6 +293,9 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        chat_template: None,
+        user: None,
+        priority: None,
     });
 
     sender
@@ -523,9 +567,10 @@ fn main() -> anyhow::Result<()> {
             let r = run_bench(
                 mistralrs.clone(),
                 RequestMessage::Completion {
-                    text: "Rust".to_string(),
+                    text: vec!["Rust".to_string()],
                     echo_prompt: false,
                     best_of: 1,
+                    beam_search: None,
                 },
                 args.n_gen - 1,
                 *concurrency,
@@ -0,0 +1,215 @@
+//! Beam search, which keeps the most likely continuations of a prompt at each step rather than
+//! sampling one, to find the output the model finds most likely overall.
+//!
+//! The beams of a prompt are sequences forked from its prompt step, like the choices of a
+//! request with `n > 1`. At each step they are continued together: the best continuations over
+//! all of them are kept, where a beam continued more than once is copied into the beams which
+//! were not continued at all. A beam which finishes leaves the search, which goes on with the
+//! others, and the best finished beams by length normalized score are returned.
+
+/// How a completion request is decoded with beam search.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BeamSearchParams {
+    /// The hypotheses kept for each prompt.
+    pub num_beams: usize,
+    /// Hypotheses are scored by their total logprob divided by their length to this power, so
+    /// above 0 favors longer outputs and below 0 shorter ones.
+    pub length_penalty: f32,
+    /// Stop as soon as `n` hypotheses have finished, instead of once none of the running ones
+    /// would score better than them if they finished now.
+    pub early_stopping: bool,
+}
+
+impl BeamSearchParams {
+    /// The score of a hypothesis of `len` tokens with a total logprob of `cumulative_logprob`.
+    pub(crate) fn score(&self, cumulative_logprob: f32, len: usize) -> f32 {
+        normalized_logprob(cumulative_logprob, len, self.length_penalty)
+    }
+
+    /// Whether the running hypotheses of a prompt, the best of which scores `best_running` as it
+    /// is, should stop, now that hypotheses with scores `finished` have finished and the best `n`
+    /// are returned.
+    pub(crate) fn should_stop(&self, finished: &[f32], n: usize, best_running: f32) -> bool {
+        if finished.len() < n {
+            return false;
+        }
+        if self.early_stopping {
+            return true;
+        }
+        let mut finished = finished.to_vec();
+        finished.sort_by(|a, b| b.total_cmp(a));
+        best_running <= finished[n - 1]
+    }
+}
+
+/// The total logprob `cumulative_logprob` of `len` tokens divided by `len` to the power of
+/// `length_penalty`. With a `length_penalty` of 1 this is the mean token logprob.
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn normalized_logprob(cumulative_logprob: f32, len: usize, length_penalty: f32) -> f32 {
+    cumulative_logprob / (len.max(1) as f32).powf(length_penalty)
+}
+
+/// Continuing beam `parent` with `token`, whose logprob is `logprob`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct BeamSelection {
+    pub parent: usize,
+    pub token: u32,
+    pub logprob: f32,
+}
+
+/// The `n` best continuations of beams with total logprobs `cumulative_logprobs` and next token
+/// logprobs `logprobs`, best first. Ties go to the earlier beam and then to the lower token, so the
+/// search is deterministic.
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn select_beams(
+    cumulative_logprobs: &[f32],
+    logprobs: &[Vec<f32>],
+    n: usize,
+) -> Vec<BeamSelection> {
+    let mut candidates = Vec::new();
+    for (parent, logprobs) in logprobs.iter().enumerate() {
+        // No more than `n` continuations of one beam can be among the best `n`.
+        let mut tokens = (0..logprobs.len()).collect::<Vec<_>>();
+        let by_logprob =
+            |a: &usize, b: &usize| logprobs[*b].total_cmp(&logprobs[*a]).then(a.cmp(b));
+        if n < tokens.len() {
+            tokens.select_nth_unstable_by(n, by_logprob);
+            tokens.truncate(n);
+        }
+        candidates.extend(tokens.into_iter().map(|token| BeamSelection {
+            parent,
+            token: token as u32,
+            logprob: logprobs[token],
+        }));
+    }
+    let total =
+        |selection: &BeamSelection| cumulative_logprobs[selection.parent] + selection.logprob;
+    candidates.sort_by(|a, b| {
+        total(b)
+            .total_cmp(&total(a))
+            .then(a.parent.cmp(&b.parent))
+            .then(a.token.cmp(&b.token))
+    });
+    candidates.truncate(n);
+    candidates
+}
+
+/// The index in `selections` which each of `n_beams` beams continues with. A beam takes the first
+/// selection continuing it, so that only beams continued more than once are copied, into the
+/// beams which are not continued. Beams beyond the selections are `None`.
+pub(crate) fn assign_beams(selections: &[BeamSelection], n_beams: usize) -> Vec<Option<usize>> {
+    let mut assigned = vec![None; n_beams];
+    let mut copies = Vec::new();
+    for (i, selection) in selections.iter().enumerate() {
+        match assigned.get(selection.parent) {
+            Some(None) => assigned[selection.parent] = Some(i),
+            _ => copies.push(i),
+        }
+    }
+    let mut copies = copies.into_iter();
+    for beam in assigned.iter_mut().filter(|beam| beam.is_none()) {
+        *beam = copies.next();
+    }
+    assigned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assign_beams, select_beams, BeamSearchParams};
+
+    /// A toy model over the vocabulary `a`, `b`, `x`, `y` and end of sequence, given as the
+    /// logprobs of the next token after each context. `a` is likelier than `b` at first, but only
+    /// continues with tokens which are each unlikely, so `b x` is the most likely output overall.
+    fn next_logprobs(context: &[u32]) -> Vec<f32> {
+        let p = |probs: [f32; 5]| probs.iter().map(|p| p.ln()).collect::<Vec<_>>();
+        match context {
+            [] => p([0.55, 0.41, 0.02, 0.01, 0.01]),
+            [0] => p([0.01, 0.01, 0.33, 0.33, 0.32]),
+            [0, _] => p([0.01, 0.01, 0.01, 0.01, 0.96]),
+            [1] => p([0.01, 0.01, 0.9, 0.04, 0.04]),
+            [1, _] => p([0.01, 0.01, 0.01, 0.01, 0.96]),
+            _ => p([0.01, 0.01, 0.01, 0.01, 0.96]),
+        }
+    }
+
+    const EOS: u32 = 4;
+
+    /// Decode with `num_beams`, returning the best finished hypothesis, without its end of
+    /// sequence, and its score.
+    fn beam_search(params: BeamSearchParams) -> (Vec<u32>, f32) {
+        // Beams are (tokens, total logprob). The first step only continues the prompt once.
+        let mut beams = vec![(Vec::new(), 0.0f32)];
+        let mut finished = Vec::new();
+        let mut n_running = params.num_beams;
+        while n_running > 0 {
+            let cumulative = beams.iter().map(|(_, total)| *total).collect::<Vec<_>>();
+            let logprobs = beams
+                .iter()
+                .map(|(tokens, _)| next_logprobs(tokens))
+                .collect::<Vec<_>>();
+            let selections = select_beams(&cumulative, &logprobs, n_running);
+            let assigned = assign_beams(&selections, n_running);
+            let mut running = Vec::new();
+            for selection in assigned.into_iter().flatten().map(|i| selections[i]) {
+                let (mut tokens, total) = beams[selection.parent].clone();
+                let total = total + selection.logprob;
+                if selection.token == EOS {
+                    let score = params.score(total, tokens.len() + 1);
+                    finished.push((tokens, score));
+                } else {
+                    tokens.push(selection.token);
+                    running.push((tokens, total));
+                }
+            }
+            n_running = running.len();
+            let scores = finished.iter().map(|(_, score)| *score).collect::<Vec<_>>();
+            let best_running = running
+                .iter()
+                .map(|(tokens, total)| params.score(*total, tokens.len()))
+                .fold(f32::NEG_INFINITY, f32::max);
+            if params.should_stop(&scores, 1, best_running) {
+                break;
+            }
+            beams = running;
+        }
+        finished
+            .into_iter()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap()
+    }
+
+    #[test]
+    fn two_beams_find_the_likeliest_output_which_greedy_decoding_misses() {
+        let params = |num_beams| BeamSearchParams {
+            num_beams,
+            length_penalty: 1.0,
+            early_stopping: false,
+        };
+        let (greedy, greedy_score) = beam_search(params(1));
+        assert_eq!(greedy[0], 0);
+
+        let (best, score) = beam_search(params(2));
+        assert_eq!(best, [1, 2]);
+        assert!(score > greedy_score);
+        // The search is deterministic.
+        assert_eq!(beam_search(params(2)), (best, score));
+    }
+
+    #[test]
+    fn beams_continued_twice_are_copied_into_the_others() {
+        // Beam 0 has the two best continuations, beam 1 none.
+        let selections = select_beams(
+            &[-0.1, -5.0],
+            &[vec![-0.2, -0.3, -9.0], vec![-1.0, -1.0, -1.0]],
+            2,
+        );
+        assert_eq!(
+            selections
+                .iter()
+                .map(|s| (s.parent, s.token))
+                .collect::<Vec<_>>(),
+            [(0, 0), (0, 1)]
+        );
+        assert_eq!(assign_beams(&selections, 2), [Some(0), Some(1)]);
+    }
+}
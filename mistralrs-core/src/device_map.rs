@@ -17,6 +17,7 @@ pub struct DeviceLayerMapMetadata {
 pub struct DeviceMapMetadata {
     device_layers: Option<Vec<DeviceLayerMapMetadata>>,
     host_layers: Option<usize>,
+    even_split: Option<Vec<usize>>,
 }
 
 impl DeviceMapMetadata {
@@ -24,6 +25,15 @@ impl DeviceMapMetadata {
         Self {
             device_layers: Some(device_layers),
             host_layers: None,
+            even_split: None,
+        }
+    }
+    /// Split the layers evenly over the GPUs with `ordinals`, the first ones taking any remainder.
+    pub fn from_even_split(ordinals: Vec<usize>) -> Self {
+        Self {
+            device_layers: None,
+            host_layers: None,
+            even_split: Some(ordinals),
         }
     }
     /// A device mapper to not map device.
@@ -31,10 +41,11 @@ impl DeviceMapMetadata {
         Self {
             device_layers: None,
             host_layers: None,
+            even_split: None,
         }
     }
     pub fn is_dummy(&self) -> bool {
-        self.device_layers.is_none()
+        self.device_layers.is_none() && self.even_split.is_none()
     }
     pub fn into_mapper(
         &self,
@@ -71,6 +82,27 @@ impl DeviceMapMetadata {
             }
         }
 
+        if let Some(ordinals) = self
+            .even_split
+            .as_ref()
+            .filter(|ordinals| !ordinals.is_empty())
+        {
+            let n = ordinals.len();
+            let device_layers = ordinals
+                .iter()
+                .enumerate()
+                .map(|(i, ordinal)| DeviceLayerMapMetadata {
+                    ordinal: *ordinal,
+                    layers: model_layers / n + usize::from(i < model_layers % n),
+                })
+                .collect();
+            return Self::from_num_device_layers(device_layers).into_mapper(
+                model_layers,
+                device,
+                None,
+            );
+        }
+
         // How many device layers
         // Clamp to max of model layers
         let n_device_layers = if let Some(layers) = &self.device_layers {
@@ -25,6 +25,7 @@ impl Processor for DiffusionProcessor {
         _messages: Vec<IndexMap<String, MessageContent>>,
         _add_generation_prompt: bool,
         _tools: Vec<crate::Tool>,
+        _template_override: Option<&str>,
     ) -> Result<(Vec<u32>, String)> {
         anyhow::bail!(
             "DiffusionProcessor::process should not be used. It does not expect chat messages."
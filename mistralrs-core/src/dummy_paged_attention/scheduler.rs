@@ -19,7 +19,7 @@ use tracing::warn;
 use crate::{
     get_mut_arcmutex,
     paged_attention::BlockEngine,
-    scheduler::{Scheduler, SchedulerOutput},
+    scheduler::{waiting_order, Scheduler, SchedulerOutput},
     sequence::{Sequence, SequenceState, StopReason},
     TERMINATE_ALL_NEXT_STEP,
 };
@@ -67,6 +67,10 @@ impl PagedAttentionScheduler {
         // If there are no swapped seqs (they have higher priority), add seqs that are in the
         // waiting queue to the running queue.
         if self.swapped_out.is_empty() {
+            let order = waiting_order();
+            self.waiting
+                .make_contiguous()
+                .sort_by_key(|seq| order(&*get_mut_arcmutex!(seq)));
             let mut scheduled = VecDeque::new();
             let mut did_ignore = false;
             while !self.waiting.is_empty() {
@@ -339,6 +343,49 @@ impl Scheduler for PagedAttentionScheduler {
     fn add_seq(&mut self, seq: Sequence) {
         self.waiting.push_back(Arc::new(Mutex::new(seq)));
     }
+    fn cancel_request(&mut self, request_id: usize) -> bool {
+        // Sequences finish as canceled at their next step, which sends their final response.
+        // Waiting and swapped out sequences whose client went away are dropped, as no one would
+        // receive it. Waiting sequences have no blocks allocated.
+        let mut canceled = false;
+        self.waiting.retain(|seq| {
+            let mut seq = get_mut_arcmutex!(seq);
+            if seq.request_id() != request_id {
+                return true;
+            }
+            canceled = true;
+            seq.request_cancel();
+            !seq.responder().is_closed()
+        });
+        // Swapped out sequences are not in the running set, so free them here.
+        let mut to_free_ids = Vec::new();
+        self.swapped_out.retain(|seq| {
+            let mut seq = get_mut_arcmutex!(seq);
+            if seq.request_id() != request_id {
+                return true;
+            }
+            canceled = true;
+            seq.request_cancel();
+            if seq.responder().is_closed() {
+                to_free_ids.push(seq.get_id());
+                false
+            } else {
+                true
+            }
+        });
+        for id in to_free_ids {
+            self._free(id);
+        }
+        // Running sequences are freed in `free_finished_sequence_groups`.
+        for seq in &self.running {
+            let mut seq = get_mut_arcmutex!(seq);
+            if seq.request_id() == request_id && !seq.is_finished_paged_attn() {
+                seq.request_cancel();
+                canceled = true;
+            }
+        }
+        canceled
+    }
     fn schedule(&mut self) -> SchedulerOutput<'_> {
         SchedulerOutput::PagedAttention {
             output: self.schedule(),
@@ -362,4 +409,7 @@ impl Scheduler for PagedAttentionScheduler {
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         Some(&mut self.block_engine)
     }
+    fn kv_cache_usage(&self) -> Option<(usize, usize)> {
+        Some(self.block_engine.gpu_block_usage())
+    }
 }
@@ -1,6 +1,10 @@
+mod oom;
+
 use once_cell::sync::Lazy;
+use oom::{OomBackoff, OomRecovery};
 use std::{
     collections::HashMap,
+    num::NonZeroUsize,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -10,29 +14,36 @@ use std::{
 use tokio::sync::{mpsc::Receiver, Mutex};
 
 use crate::{
-    aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
+    aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx, toktree::TokTrie},
     pipeline::{
         text_models_inputs_processor::PagedAttentionMeta, AdapterInstruction, CacheBackendMetadata,
-        CacheInstruction,
+        CacheInstruction, FimTemplate, ModelCategory, ModelKind,
     },
     request::NormalRequest,
     response::CompletionChoice,
-    scheduler::{Scheduler, SchedulerOutput},
+    scheduler::{
+        forced_batch_size, BatchComposition, EngineStats, Scheduler, SchedulerOutput,
+        SchedulerStats,
+    },
     sequence::{SeqStepType, StopReason},
-    tools::{ToolCallingMatcher, ToolChoice},
-    CompletionResponse, RequestMessage, Response, SchedulerConfig, DEBUG,
+    tools::{forced_tool_call_regex, ToolCallingMatcher, ToolChoice},
+    CompletionResponse, RequestMessage, Response, SchedulerConfig, DEBUG, SPECULATIVE_STATS,
 };
 use rand::SeedableRng;
 use rand_isaac::Isaac64Rng;
 use tracing::{info, warn};
 
 use crate::{
+    gbnf::gbnf_to_yacc,
     get_mut_arcmutex, handle_pipeline_forward_error, handle_seq_error,
+    json_schema::json_schema_to_yacc,
+    output_filter::OutputFilter,
     pipeline::Pipeline,
     prefix_cacher::PrefixCacheManager,
+    reasoning::ReasoningDelimiters,
     request::Request,
     response::{ChatCompletionResponse, Choice, ResponseMessage},
-    sampler::Sampler,
+    sampler::{tokens_matching, Sampler},
     sequence::{Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
     Constraint, StopTokens,
 };
@@ -42,6 +53,8 @@ pub enum EngineInstruction {
 }
 
 const SEED: u64 = 0;
+/// How many logit bias patterns to keep the matched tokens of.
+const MAX_CACHED_BIAS_PATTERNS: usize = 64;
 /// Terminate all sequences on the next scheduling step. Be sure to reset this.
 pub static TERMINATE_ALL_NEXT_STEP: AtomicBool = AtomicBool::new(false);
 
@@ -53,6 +66,8 @@ pub struct Engine {
     rx: Receiver<Request>,
     pipeline: Arc<Mutex<dyn Pipeline>>,
     scheduler: Box<dyn Scheduler>,
+    scheduler_stats: Arc<SchedulerStats>,
+    system_fingerprint: Arc<std::sync::RwLock<String>>,
     id: usize,
     truncate_sequence: bool,
     no_kv_cache: bool,
@@ -60,6 +75,13 @@ pub struct Engine {
     is_debug: bool,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
+    reasoning_delimiters: Option<ReasoningDelimiters>,
+    /// The most prompt tokens a sequence runs in one prompt step.
+    prefill_chunk_size: Option<usize>,
+    output_filter: Arc<dyn OutputFilter>,
+    last_batch: BatchComposition,
+    /// The tokens matched by recently used logit bias patterns.
+    bias_pattern_tokens: HashMap<String, Arc<Vec<u32>>>,
 }
 
 impl Engine {
@@ -68,15 +90,19 @@ impl Engine {
         rx: Receiver<Request>,
         pipeline: Arc<Mutex<dyn Pipeline>>,
         config: SchedulerConfig,
+        scheduler_stats: Arc<SchedulerStats>,
+        system_fingerprint: Arc<std::sync::RwLock<String>>,
         truncate_sequence: bool,
         no_kv_cache: bool,
         no_prefix_cache: bool,
         prefix_cache_n: usize,
         disable_eos_stop: bool,
         throughput_logging_enabled: bool,
+        reasoning_delimiters: Option<ReasoningDelimiters>,
+        prefill_chunk_size: Option<NonZeroUsize>,
+        output_filter: Arc<dyn OutputFilter>,
     ) -> Self {
         let device = get_mut_arcmutex!(pipeline).device().clone();
-        let is_xlora = get_mut_arcmutex!(pipeline).get_metadata().is_xlora;
         let has_no_kv_cache = get_mut_arcmutex!(pipeline).get_metadata().has_no_kv_cache;
         if no_kv_cache {
             // Diffusion models...
@@ -87,22 +113,35 @@ impl Engine {
         let no_prefix_cache = matches!(config, SchedulerConfig::PagedAttentionMeta { .. })
             || no_prefix_cache
             || has_no_kv_cache;
+        // Chunks after the first resume from the KV cache of the sequence, like a prefix cache.
+        let prefill_chunk_size = if prefill_chunk_size.is_some()
+            && (matches!(config, SchedulerConfig::PagedAttentionMeta { .. })
+                || no_kv_cache
+                || has_no_kv_cache)
+        {
+            warn!("Chunked prefill requires a KV cache without PagedAttention, running prompts whole.");
+            None
+        } else {
+            prefill_chunk_size.map(usize::from)
+        };
         Self {
             rx,
             pipeline,
             scheduler: config.into_scheduler(),
+            scheduler_stats,
+            system_fingerprint,
             id: 0,
             truncate_sequence,
             no_kv_cache: no_kv_cache & !has_no_kv_cache,
-            prefix_cacher: PrefixCacheManager::new(
-                device,
-                prefix_cache_n,
-                is_xlora,
-                no_prefix_cache,
-            ),
+            prefix_cacher: PrefixCacheManager::new(device, prefix_cache_n, no_prefix_cache),
             is_debug: DEBUG.load(Ordering::Relaxed),
             disable_eos_stop,
             throughput_logging_enabled,
+            reasoning_delimiters,
+            prefill_chunk_size,
+            output_filter,
+            last_batch: BatchComposition::default(),
+            bias_pattern_tokens: HashMap::new(),
         }
     }
 
@@ -126,13 +165,48 @@ impl Engine {
                 }
                 self.handle_request(request).await;
             }
+            self.scheduler_stats.update(&*self.scheduler);
             let run_start = Instant::now();
             let scheduled = self.scheduler.schedule();
+            self.last_batch = match &scheduled {
+                SchedulerOutput::DefaultScheduler { output } => BatchComposition {
+                    prompt: output.prompt.iter().map(|seq| seq.request_id()).collect(),
+                    completion: output
+                        .completion
+                        .iter()
+                        .map(|seq| seq.request_id())
+                        .collect(),
+                },
+                SchedulerOutput::PagedAttention { output } => {
+                    // A step runs either only prompts or only completions.
+                    let ids = output
+                        .scheduled
+                        .iter()
+                        .map(|seq| get_mut_arcmutex!(seq).request_id())
+                        .collect();
+                    if output
+                        .scheduled
+                        .first()
+                        .is_some_and(|seq| get_mut_arcmutex!(seq).is_prompt())
+                    {
+                        BatchComposition {
+                            prompt: ids,
+                            completion: Vec::new(),
+                        }
+                    } else {
+                        BatchComposition {
+                            prompt: Vec::new(),
+                            completion: ids,
+                        }
+                    }
+                }
+            };
 
             match scheduled {
                 SchedulerOutput::DefaultScheduler {
                     output: mut scheduled,
                 } => {
+                    let mut forks = Vec::new();
                     let mut prompt_ts = None;
                     let mut completion_ts = None;
                     if scheduled.completion.len() > 0 {
@@ -141,8 +215,13 @@ impl Engine {
                             scheduled.completion.iter().map(|seq| *seq.id()).collect();
                         let res = {
                             let mut pipeline = get_mut_arcmutex!(self.pipeline);
+                            // Beams swap their KV caches between steps.
                             let pre_op = if !self.no_kv_cache
-                                && last_completion_ids != current_completion_ids
+                                && (last_completion_ids != current_completion_ids
+                                    || scheduled
+                                        .completion
+                                        .iter()
+                                        .any(|seq| seq.beam_search().is_some()))
                             {
                                 CacheInstruction::In(
                                     scheduled.completion[0]
@@ -204,51 +283,107 @@ impl Engine {
 
                     if scheduled.prompt.len() > 0 {
                         let throughput_start = Instant::now();
-                        let logits = {
-                            let mut pipeline = get_mut_arcmutex!(self.pipeline);
 
-                            // Run the prompt seqs
-                            let post_op = if !self.no_kv_cache {
-                                CacheInstruction::Out
-                            } else {
-                                CacheInstruction::Reset {
-                                    reset_non_granular: false,
-                                    adapter_inst: AdapterInstruction::None,
-                                }
-                            };
-                            let adapter_inst = scheduled.prompt[0]
-                                .get_adapters()
-                                .map(AdapterInstruction::Activate)
-                                .unwrap_or(AdapterInstruction::None);
+                        // Long prompts run a chunk per step, so that other sequences keep
+                        // generating in the steps between.
+                        if let Some(chunk_size) = self.prefill_chunk_size {
+                            for seq in scheduled.prompt.iter_mut() {
+                                seq.set_prefill_chunk(chunk_size);
+                            }
+                        }
 
-                            // Reset non granular state because the old sequence must be dead.
-                            // Technically we don't need to do this but it is better to be safe.
-                            pipeline
-                                .step(
-                                    &mut scheduled.prompt,
-                                    true,
-                                    &mut self.prefix_cacher,
-                                    self.disable_eos_stop,
-                                    rng.clone(),
-                                    CacheBackendMetadata::DefaultInstructions {
-                                        pre_op: CacheInstruction::Reset {
-                                            reset_non_granular: false,
-                                            adapter_inst,
+                        // Sequences resuming from a prefix cache start from their own KV cache,
+                        // which cannot be batched with others of a different length.
+                        let (prefilled, fresh): (Vec<&mut Sequence>, Vec<&mut Sequence>) =
+                            scheduled
+                                .prompt
+                                .iter_mut()
+                                .map(|seq| &mut **seq)
+                                .partition(|seq| seq.prefix_cache_len() > 0);
+                        let mut batches = Vec::new();
+                        if !fresh.is_empty() {
+                            batches.push(fresh);
+                        }
+                        // Run as a stack, so that the halves of a batch which ran out of memory
+                        // run next and in order.
+                        batches.extend(prefilled.into_iter().rev().map(|seq| vec![seq]));
+                        let mut oom_backoff = OomBackoff::default();
+
+                        'batches: while let Some(mut batch) = batches.pop() {
+                            let logits = {
+                                let mut pipeline = get_mut_arcmutex!(self.pipeline);
+
+                                // Run the prompt seqs
+                                let post_op = if !self.no_kv_cache {
+                                    CacheInstruction::Out
+                                } else {
+                                    CacheInstruction::Reset {
+                                        reset_non_granular: false,
+                                        adapter_inst: AdapterInstruction::None,
+                                    }
+                                };
+                                let adapter_inst = batch[0]
+                                    .get_adapters()
+                                    .map(AdapterInstruction::Activate)
+                                    .unwrap_or(AdapterInstruction::None);
+
+                                // Reset non granular state because the old sequence must be dead.
+                                // Technically we don't need to do this but it is better to be safe.
+                                let pre_op = if batch[0].prefix_cache_len() > 0 {
+                                    CacheInstruction::In(adapter_inst)
+                                } else {
+                                    CacheInstruction::Reset {
+                                        reset_non_granular: false,
+                                        adapter_inst,
+                                    }
+                                };
+
+                                pipeline
+                                    .step(
+                                        &mut batch,
+                                        true,
+                                        &mut self.prefix_cacher,
+                                        self.disable_eos_stop,
+                                        rng.clone(),
+                                        CacheBackendMetadata::DefaultInstructions {
+                                            pre_op,
+                                            post_op,
                                         },
-                                        post_op,
-                                    },
-                                )
-                                .await
-                        };
+                                    )
+                                    .await
+                            };
 
-                        handle_pipeline_forward_error!(
-                            "prompt step",
-                            logits,
-                            &mut scheduled.prompt,
-                            self.pipeline,
-                            'lp,
-                            self.prefix_cacher
-                        );
+                            let logits = match logits {
+                                Err(e) if oom::is_oom_error(&e) => {
+                                    match oom_backoff.recover(batch) {
+                                        OomRecovery::Retry(halves, delay) => {
+                                            let sizes =
+                                                halves.iter().map(Vec::len).collect::<Vec<_>>();
+                                            warn!("Prompt step ran out of memory, retrying in {delay:?} with batches of {sizes:?} sequences.");
+                                            tokio::time::sleep(delay).await;
+                                            batches.extend(halves.into_iter().rev());
+                                            continue 'batches;
+                                        }
+                                        OomRecovery::GiveUp(give_up) => {
+                                            batch = give_up;
+                                            let retries = oom::MAX_OOM_RETRIES;
+                                            Err(candle_core::Error::Msg(format!("The prompt does not fit in memory, even when run on its own after {retries} retries: {e}")))
+                                        }
+                                    }
+                                }
+                                logits => logits,
+                            };
+
+                            // Failing one batch does not fail the others of this step.
+                            handle_pipeline_forward_error!(
+                                "prompt step",
+                                logits,
+                                &mut batch,
+                                self.pipeline,
+                                'batches,
+                                self.prefix_cacher
+                            );
+                        }
 
                         let throughput_end = Instant::now();
                         #[allow(clippy::cast_precision_loss)]
@@ -266,10 +401,21 @@ impl Engine {
                         }
 
                         for seq in scheduled.prompt.iter_mut() {
+                            if matches!(seq.getstate(), SequenceState::Error) {
+                                continue;
+                            }
+                            if seq.is_partial_prefill() {
+                                seq.finish_prefill_chunk();
+                                continue;
+                            }
                             match seq.sequence_stepping_type() {
                                 SeqStepType::OneShot => {
                                     seq.set_state(SequenceState::Done(StopReason::GeneratedImage))
                                 }
+                                SeqStepType::Embedding => seq
+                                    .set_state(SequenceState::Done(StopReason::GeneratedEmbedding)),
+                                // It may have finished with its first token.
+                                SeqStepType::PromptAndDecode if seq.is_finished_paged_attn() => (),
                                 SeqStepType::PromptAndDecode => {
                                     seq.set_state(SequenceState::RunningCompletion)
                                 }
@@ -283,6 +429,7 @@ impl Engine {
                                 seq.len() as f32 / (now - seq.timestamp()) as f32;
                             seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
                             seq.prompt_timestamp = Some(now);
+                            forks.extend(seq.take_forks());
                         }
                         last_completion_ids = vec![];
                     }
@@ -341,6 +488,13 @@ impl Engine {
                             self.handle_request(request).await;
                         }
                     }
+
+                    // Forks may finish with their first token too.
+                    for seq in forks {
+                        if !seq.is_finished_paged_attn() {
+                            self.scheduler.add_seq(seq);
+                        }
+                    }
                 }
                 SchedulerOutput::PagedAttention { mut output } => {
                     if !output.scheduled.is_empty() {
@@ -461,11 +615,52 @@ impl Engine {
                 SequenceRecognizer::Regex(StackRecognizer::from(RecRx::from_rx(rx, None)?).into())
             }
             Constraint::Yacc(cfg) => SequenceRecognizer::Cfg(CfgParser::from_yacc(cfg)?.into()),
+            Constraint::Gbnf(gbnf) => {
+                SequenceRecognizer::Cfg(CfgParser::from_yacc(&gbnf_to_yacc(gbnf)?)?.into())
+            }
+            Constraint::JsonSchema(schema) => {
+                SequenceRecognizer::Cfg(CfgParser::from_yacc(&json_schema_to_yacc(schema)?)?.into())
+            }
+            Constraint::Json => SequenceRecognizer::Cfg(
+                CfgParser::from_yacc(&json_schema_to_yacc(&serde_json::Value::Bool(true))?)?.into(),
+            ),
+            Constraint::Choice(choices) => {
+                if choices.is_empty() {
+                    anyhow::bail!("A choice constraint needs at least one choice.");
+                }
+                SequenceRecognizer::Regex(
+                    StackRecognizer::from(RecRx::from_rx(&choice_regex(choices), None)?).into(),
+                )
+            }
             Constraint::None => SequenceRecognizer::None,
         };
         Ok(recognizer)
     }
 
+    /// The tokens whose text matches the regex `pattern`. Scanning the vocabulary takes a while, so
+    /// the matches of recently used patterns are cached.
+    fn bias_pattern_tokens(
+        &mut self,
+        pattern: &str,
+        tok_trie: &TokTrie,
+    ) -> Result<Arc<Vec<u32>>, regex::Error> {
+        if let Some(toks) = self.bias_pattern_tokens.get(pattern) {
+            return Ok(toks.clone());
+        }
+        let regex = regex::bytes::Regex::new(pattern)?;
+        let vocab_size = u32::try_from(tok_trie.vocab_size()).unwrap_or(u32::MAX);
+        let toks = Arc::new(tokens_matching(
+            &regex,
+            (0..vocab_size).map(|tok| tok_trie.token(tok)),
+        ));
+        if self.bias_pattern_tokens.len() >= MAX_CACHED_BIAS_PATTERNS {
+            self.bias_pattern_tokens.clear();
+        }
+        self.bias_pattern_tokens
+            .insert(pattern.to_string(), toks.clone());
+        Ok(toks)
+    }
+
     async fn handle_request(&mut self, request: Request) {
         match request {
             Request::ActivateAdapters(adapters) => {
@@ -474,7 +669,76 @@ impl Engine {
                     Err(e) => warn!("Adapter activation failed: {e:?}"),
                 }
             }
+            Request::LoadAdapter {
+                name,
+                source,
+                response,
+            } => {
+                let result = get_mut_arcmutex!(self.pipeline).load_adapter(name.clone(), source);
+                match result {
+                    Ok(n) => info!("Loaded adapter `{name}` into {n} LoRA layers."),
+                    Err(ref e) => warn!("Loading adapter `{name}` failed: {e:?}"),
+                }
+                // The client may have disconnected while the adapter was loading.
+                let _ = response.send(result.map(|_| ())).await;
+            }
             Request::Normal(request) => self.add_request(request).await,
+            Request::TerminateSeq { id } => {
+                info!("Terminating sequences of request {id}.");
+                self.scheduler.cancel_request(id);
+            }
+            Request::CancelRequest { id, response } => {
+                // Requests are handled between steps, so a sequence cannot finish while this runs.
+                let canceled = self.scheduler.cancel_request(id);
+                if canceled {
+                    info!("Canceled request {id}.");
+                }
+                let _ = response.send(canceled).await;
+            }
+            Request::GetStats { response } => {
+                #[allow(clippy::cast_precision_loss)]
+                let kv_cache_utilization = self
+                    .scheduler
+                    .kv_cache_usage()
+                    .filter(|(_, total)| *total > 0)
+                    .map(|(used, total)| used as f64 / total as f64 * 100.);
+                let stats = EngineStats {
+                    running_sequences: self.scheduler.running_len(),
+                    waiting_sequences: self.scheduler.waiting_len(),
+                    batch_size: self.last_batch.len(),
+                    last_batch: self.last_batch.clone(),
+                    forced_batch_size: forced_batch_size(),
+                    kv_cache_utilization,
+                    speculative_acceptance_rate: SPECULATIVE_STATS.acceptance_rate(),
+                };
+                let _ = response.send(stats).await;
+            }
+            Request::RenderPrompt {
+                messages,
+                tools,
+                chat_template,
+                response,
+            } => {
+                let pipeline = &*get_mut_arcmutex!(self.pipeline);
+                let prompt = if pipeline
+                    .get_chat_template()
+                    .as_ref()
+                    .is_some_and(|ch_t| ch_t.has_chat_template())
+                {
+                    pipeline.get_processor().process(
+                        pipeline,
+                        messages,
+                        true,
+                        tools,
+                        chat_template.as_deref(),
+                    )
+                } else {
+                    Err(anyhow::Error::msg(
+                        "This model does not have a chat template to render the prompt with.",
+                    ))
+                };
+                let _ = response.send(prompt).await;
+            }
             Request::ReIsq(level) => {
                 if let Err(e) = get_mut_arcmutex!(self.pipeline).re_isq_model(level) {
                     warn!("ISQ requantization failed: {e:?}");
@@ -497,12 +761,17 @@ impl Engine {
             }
         );
 
-        let best_of = match request.messages {
-            RequestMessage::Completion { best_of, .. } => best_of,
+        let (best_of, beam_search) = match request.messages {
+            RequestMessage::Completion {
+                best_of,
+                beam_search,
+                ..
+            } => (best_of, beam_search),
             RequestMessage::Chat(_)
             | RequestMessage::CompletionTokens(_)
             | RequestMessage::VisionChat { .. }
-            | RequestMessage::ImageGeneration { .. } => 1,
+            | RequestMessage::ImageGeneration { .. }
+            | RequestMessage::Embedding { .. } => (1, None),
         };
         if is_chat
             && !get_mut_arcmutex!(self.pipeline)
@@ -525,6 +794,30 @@ impl Engine {
             } => Some(images.clone()),
             _ => None,
         };
+        if images.is_some()
+            && !matches!(
+                get_mut_arcmutex!(self.pipeline).category(),
+                ModelCategory::Vision { .. }
+            )
+        {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "Received images for a model which does not accept image inputs. Use a vision model or send text only.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+
+        // Forcing a specific tool constrains the output to a call of that tool, unless the
+        // request brings its own grammar.
+        let constraint = match (&request.constraint, &request.tool_choice) {
+            (Constraint::None, Some(ToolChoice::Tool(tool))) if request.tools.is_some() => {
+                Constraint::Regex(forced_tool_call_regex(tool))
+            }
+            (constraint, _) => constraint.clone(),
+        };
 
         let matcher = if request.tools.is_some() {
             Some(Arc::new(handle_seq_error!(
@@ -542,6 +835,7 @@ impl Engine {
 
         let seq_step_type = match &request.messages {
             RequestMessage::ImageGeneration { .. } => SeqStepType::OneShot,
+            RequestMessage::Embedding { .. } => SeqStepType::Embedding,
             _ => SeqStepType::PromptAndDecode,
         };
 
@@ -552,7 +846,7 @@ impl Engine {
             _ => None,
         };
 
-        let (mut prompt_tokens, prompt_text) = match request.messages {
+        let mut prompts = match request.messages {
             RequestMessage::Chat(messages)
             | RequestMessage::VisionChat {
                 images: _,
@@ -564,8 +858,9 @@ impl Engine {
                     messages,
                     true,
                     request.tools.unwrap_or_default(),
+                    request.chat_template.as_deref(),
                 );
-                handle_seq_error!(template, request.response)
+                vec![handle_seq_error!(template, request.response)]
             }
             RequestMessage::Completion { text, .. } => {
                 let Some(tokenizer) = &get_mut_arcmutex!(self.pipeline).tokenizer() else {
@@ -578,17 +873,88 @@ impl Engine {
                         .expect("Expected receiver.");
                     return;
                 };
-                let prompt = tokenizer
-                    .encode(text.clone(), true)
-                    .map_err(anyhow::Error::msg);
-                (
-                    handle_seq_error!(prompt, request.response)
-                        .get_ids()
-                        .to_vec(),
-                    text,
-                )
+                if text.is_empty() {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            "Completion requests require at least one prompt.".into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+                let fim_template = match request.suffix {
+                    Some(ref suffix) => match FimTemplate::from_tokenizer(tokenizer) {
+                        Some(template) => Some((template, suffix)),
+                        None => {
+                            request
+                                .response
+                                .send(Response::ValidationError(
+                                    "`suffix` requires a model with fill-in-the-middle tokens, which this model does not have."
+                                        .into(),
+                                ))
+                                .await
+                                .expect("Expected receiver.");
+                            return;
+                        }
+                    },
+                    None => None,
+                };
+                let mut prompts = Vec::with_capacity(text.len());
+                for text in text {
+                    // The echoed prompt stays the prefix, only the model sees the FIM layout.
+                    let prompt_text = match fim_template {
+                        Some((ref template, suffix)) => template.apply(&text, suffix),
+                        None => text.clone(),
+                    };
+                    let prompt = tokenizer
+                        .encode(prompt_text, true)
+                        .map_err(anyhow::Error::msg);
+                    prompts.push((
+                        handle_seq_error!(prompt, request.response)
+                            .get_ids()
+                            .to_vec(),
+                        text,
+                    ));
+                }
+                prompts
+            }
+            RequestMessage::ImageGeneration { prompt, .. } => vec![(vec![u32::MAX], prompt)],
+            RequestMessage::Embedding { inputs } => {
+                let Some(tokenizer) = &get_mut_arcmutex!(self.pipeline).tokenizer() else {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            "Embedding requests require the pipeline to have a tokenizer".into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                };
+                if inputs.is_empty() {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            "Embedding requests require at least one input.".into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+                let mut prompts = Vec::with_capacity(inputs.len());
+                for input in inputs {
+                    let prompt = tokenizer
+                        .encode(input.clone(), true)
+                        .map_err(anyhow::Error::msg);
+                    prompts.push((
+                        handle_seq_error!(prompt, request.response)
+                            .get_ids()
+                            .to_vec(),
+                        input,
+                    ));
+                }
+                prompts
             }
-            RequestMessage::ImageGeneration { prompt, .. } => (vec![u32::MAX], prompt),
             RequestMessage::CompletionTokens(it) => {
                 let Some(tokenizer) = &get_mut_arcmutex!(self.pipeline).tokenizer() else {
                     request
@@ -603,10 +969,13 @@ impl Engine {
                 let prompt = tokenizer
                     .decode(&it, false)
                     .map_err(|e| anyhow::Error::msg(e.to_string()));
-                (it, handle_seq_error!(prompt, request.response))
+                vec![(it, handle_seq_error!(prompt, request.response))]
             }
         };
-        if prompt_tokens.is_empty() {
+        if prompts
+            .iter()
+            .any(|(prompt_tokens, _)| prompt_tokens.is_empty())
+        {
             request
                 .response
                 .send(Response::ValidationError(
@@ -617,35 +986,33 @@ impl Engine {
             return;
         }
 
-        if prompt_tokens.len() > get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len {
+        let max_seq_len = get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len;
+        for (prompt_tokens, _) in prompts.iter_mut() {
+            if prompt_tokens.len() <= max_seq_len {
+                continue;
+            }
             if !self.truncate_sequence {
                 request
                     .response
                     .send(Response::ValidationError(
-                        format!("Prompt sequence length is greater than {}, perhaps consider using `truncate_sequence`?", get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len).into(),
+                        format!("Prompt sequence length is greater than {max_seq_len}, perhaps consider using `truncate_sequence`?").into(),
                     )).await.expect("Expected receiver.");
                 return;
-            } else {
-                let prompt_len = prompt_tokens.len();
-                let max_len = get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len;
-                let currently_over = prompt_len - max_len;
-                let sampling_max = if let Some(sampling_max) = request.sampling_params.max_len {
-                    if currently_over + sampling_max >= prompt_len {
-                        10
-                    } else {
-                        sampling_max
-                    }
-                } else {
-                    10
-                };
-                prompt_tokens = prompt_tokens[(currently_over + sampling_max)..].to_vec();
-                warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt_tokens.len());
             }
+            let prompt_len = prompt_tokens.len();
+            let currently_over = prompt_len - max_seq_len;
+            let sampling_max = if let Some(sampling_max) = request.sampling_params.max_len {
+                if currently_over + sampling_max >= prompt_len {
+                    10
+                } else {
+                    sampling_max
+                }
+            } else {
+                10
+            };
+            *prompt_tokens = prompt_tokens[(currently_over + sampling_max)..].to_vec();
+            warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt_tokens.len());
         }
-        let prefill_cache = handle_seq_error!(
-            self.prefix_cacher.search_for_matching_cache(&prompt_tokens),
-            request.response
-        );
 
         let topk = request
             .sampling_params
@@ -654,6 +1021,7 @@ impl Engine {
             .unwrap_or(-1);
         let topp = request.sampling_params.top_p.unwrap_or(1.0);
         let minp = request.sampling_params.min_p.unwrap_or(0.0);
+        let typicalp = request.sampling_params.typical_p.unwrap_or(1.0);
         let num_hidden_layers = get_mut_arcmutex!(self.pipeline)
             .get_metadata()
             .num_hidden_layers;
@@ -668,6 +1036,16 @@ impl Engine {
                 for id in i {
                     // We can't use ` ` (space) as a stop token because other tokens like ` moon` start with a space.
                     if let Some(tok_trie) = tok_trie.as_ref() {
+                        let vocab_size = tok_trie.vocab_size();
+                        if *id as usize >= vocab_size {
+                            request
+                                .response
+                                .send(Response::ValidationError(
+                                    format!("Stop token id {id} is out of range for a vocabulary of {vocab_size} tokens.").into(),
+                                ))
+                                .await .expect("Expected receiver.");
+                            return;
+                        }
                         if tok_trie.has_extensions(tok_trie.token(*id)) {
                             request
                                 .response
@@ -727,18 +1105,188 @@ impl Engine {
             }
         };
 
-        let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
-            request.sampling_params.n_choices,
+        // Every prompt gets its own `best_of` candidates, or beams, of which the best `n_choices`
+        // are returned, all collected into one response.
+        let candidates_per_prompt = match beam_search {
+            Some(params) => params.num_beams,
+            None => best_of.max(request.sampling_params.n_choices),
+        };
+        let mut group = SequenceGroup::new(
+            candidates_per_prompt * prompts.len(),
+            prompts.len(),
             request.is_streaming,
             is_chat,
-            best_of,
-        )));
+            request.sampling_params.n_choices,
+        );
+        group.continuous_usage_stats = request.sampling_params.continuous_usage_stats;
+        group.system_fingerprint = self
+            .system_fingerprint
+            .read()
+            .expect("`system_fingerprint` was poisoned")
+            .clone();
+        // A partial choice means nothing, so a streamed classification sends its choice in one
+        // chunk, which also has the finish reason.
+        group.stream_granularity = match constraint {
+            Constraint::Choice(_) => request
+                .sampling_params
+                .stream_granularity
+                .or(Some(usize::MAX)),
+            _ => request.sampling_params.stream_granularity,
+        };
+        let group = Arc::new(tokio::sync::Mutex::new(group));
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time travel has occurred!");
 
+        if request.sampling_params.mirostat.is_some()
+            && (request.sampling_params.top_k.is_some()
+                || request.sampling_params.top_p.is_some()
+                || request.sampling_params.typical_p.is_some())
+        {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "Mirostat replaces top-k, top-p and typical-p sampling, so they cannot be set together."
+                        .into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+
+        if request
+            .sampling_params
+            .repetition_penalty
+            .is_some_and(|penalty| penalty <= 0.)
+        {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "Repetition penalty must be positive.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
+
+        let token_embeddings = match request.sampling_params.penalty_alpha {
+            Some(penalty_alpha) => {
+                let message = if !(0. ..=1.).contains(&penalty_alpha) {
+                    Some("`penalty_alpha` must be between 0 and 1.")
+                } else if !request.sampling_params.top_k.is_some_and(|k| k > 0) {
+                    Some("Contrastive search chooses between the `top_k` most likely tokens, so `penalty_alpha` requires a positive `top_k`.")
+                } else {
+                    None
+                };
+                let token_embeddings = get_mut_arcmutex!(self.pipeline).token_embeddings();
+                let message = message.or(token_embeddings
+                    .is_none()
+                    .then_some("Contrastive search is not supported for this model."));
+                if let Some(message) = message {
+                    request
+                        .response
+                        .send(Response::ValidationError(message.into()))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+                token_embeddings.map(|token_embeddings| (penalty_alpha, token_embeddings))
+            }
+            None => None,
+        };
+
         let tokenizer = get_mut_arcmutex!(self.pipeline).tokenizer();
 
+        let mut logits_bias = request.sampling_params.logits_bias.clone();
+        if let Some(ref biases_strs) = request.sampling_params.logits_bias_strs {
+            let Some(tokenizer) = &tokenizer else {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "Logit biases keyed by token strings require the pipeline to have a tokenizer."
+                            .into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            };
+            for (tok_str, bias) in biases_strs {
+                let encoded = tokenizer.encode(tok_str.to_string(), false);
+                let toks = handle_seq_error!(encoded, request.response)
+                    .get_ids()
+                    .to_vec();
+                if toks.len() != 1 {
+                    request
+                        .response
+                        .send(Response::ValidationError(
+                            format!(
+                                "Logit bias key `{tok_str}` must encode to exactly one token, got {}.",
+                                toks.len()
+                            )
+                            .into(),
+                        ))
+                        .await
+                        .expect("Expected receiver.");
+                    return;
+                }
+                logits_bias
+                    .get_or_insert_with(HashMap::new)
+                    .insert(toks[0], *bias);
+            }
+        }
+        if let Some(ref patterns) = request.sampling_params.logits_bias_patterns {
+            let tok_trie = get_mut_arcmutex!(self.pipeline)
+                .get_metadata()
+                .tok_trie
+                .clone();
+            let Some(tok_trie) = tok_trie else {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        "Logit bias patterns require the pipeline to have a tokenizer.".into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            };
+            for (pattern, bias) in patterns {
+                let toks = match self.bias_pattern_tokens(pattern, &tok_trie) {
+                    Ok(toks) => toks,
+                    Err(e) => {
+                        request
+                            .response
+                            .send(Response::ValidationError(
+                                format!("Logit bias pattern `{pattern}` is not a valid regex: {e}")
+                                    .into(),
+                            ))
+                            .await
+                            .expect("Expected receiver.");
+                        return;
+                    }
+                };
+                let biases = logits_bias.get_or_insert_with(HashMap::new);
+                for tok in toks.iter() {
+                    *biases.entry(*tok).or_default() += bias;
+                }
+            }
+        }
+        if let (Some(logits_bias), Some(tokenizer)) = (&logits_bias, &tokenizer) {
+            let vocab_size = tokenizer.get_vocab_size(true);
+            if let Some(tok) = logits_bias.keys().find(|tok| **tok as usize >= vocab_size) {
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        format!(
+                            "Logit bias token id {tok} is out of range for a vocabulary of {vocab_size} tokens."
+                        )
+                        .into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+
         let sampler = Sampler::new(
             Some(request.sampling_params.temperature.unwrap_or(1.0)),
             request.sampling_params.top_n_logprobs,
@@ -749,9 +1297,25 @@ impl Engine {
             topk,
             topp,
             minp,
+            typicalp,
+            request.sampling_params.mirostat,
+            logits_bias,
+            request.sampling_params.repetition_penalty,
+            request.sampling_params.repetition_context_size,
             request.logits_processors.unwrap_or_default(),
         );
-        let sampler = handle_seq_error!(sampler, request.response);
+        let sampler = handle_seq_error!(sampler, request.response)
+            .with_eta_epsilon_cutoffs(
+                request.sampling_params.eta_cutoff,
+                request.sampling_params.epsilon_cutoff,
+            )
+            .with_sampler_order(request.sampling_params.sampler_order.as_deref());
+        let sampler = match token_embeddings {
+            Some((penalty_alpha, token_embeddings)) => {
+                sampler.with_contrastive_search(penalty_alpha, token_embeddings)
+            }
+            None => sampler,
+        };
 
         if request.sampling_params.n_choices == 0 {
             request
@@ -763,76 +1327,273 @@ impl Engine {
                 .expect("Expected receiver.");
             return;
         }
+        if best_of > request.sampling_params.n_choices && request.is_streaming {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "`best_of` candidates are only compared once all have finished, so they cannot be streamed.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
 
-        // Add sequences
-        for response_index in 0..request.sampling_params.n_choices {
-            let recognizer = match Self::build_sequence_recognizer(&request.constraint) {
-                Ok(recognizer) => recognizer,
-                Err(err) => {
-                    request
-                        .response
-                        .send(Response::ValidationError(
-                            format!("Invalid grammar. {}", err).into(),
-                        ))
-                        .await
-                        .expect("Expected receiver.");
-                    return;
-                }
-            };
-
-            let block_size = get_mut_arcmutex!(self.pipeline)
+        // Only the first choice for each prompt processes it, the others are forked from its KV
+        // cache. PagedAttention manages the KV cache itself, so there every choice runs the prompt.
+        let fork_choices = !self.no_kv_cache
+            && matches!(seq_step_type, SeqStepType::PromptAndDecode)
+            && get_mut_arcmutex!(self.pipeline)
                 .get_metadata()
                 .cache_config
-                .clone()
-                .map(|conf| conf.block_size);
-            let trie = get_mut_arcmutex!(self.pipeline)
-                .get_metadata()
-                .tok_trie
-                .as_ref()
-                .map(|x| (**x).clone());
-            let seq = Sequence::new_waiting(
-                prompt_tokens.clone(),
-                prompt_text.clone(),
-                self.id,
-                now.as_millis(),
-                num_hidden_layers,
-                request.response.clone(),
-                sampler.clone(),
-                stop_toks.clone(),
-                stop_strings.clone(),
-                request.sampling_params.max_len,
-                request.return_logprobs,
-                get_mut_arcmutex!(self.pipeline).get_metadata().is_xlora,
-                group.clone(),
-                response_index,
-                now.as_secs(),
-                recognizer,
-                request.suffix.clone(),
-                if echo_prompt {
-                    Some(prompt_text.clone())
-                } else {
-                    None
-                },
-                request.adapters.clone(),
-                images.clone(),
-                block_size,
-                trie,
-                matcher.clone(),
-                image_generation_format,
-                seq_step_type,
-                diffusion_params.clone(),
+                .is_none();
+
+        if let Some(params) = beam_search {
+            let is_speculative = matches!(
+                get_mut_arcmutex!(self.pipeline).get_metadata().kind,
+                ModelKind::Speculative { .. } | ModelKind::NGramSpeculative { .. }
+            );
+            let message = if params.num_beams == 0 {
+                Some("`num_beams` must be at least 1.")
+            } else if request.sampling_params.n_choices > params.num_beams {
+                Some("Beam search returns its best `n` beams, so `n` must be at most `num_beams`.")
+            } else if best_of > request.sampling_params.n_choices {
+                Some("`best_of` cannot be combined with beam search.")
+            } else if request.is_streaming {
+                Some("The best beams are only known once all have finished, so beam search cannot be streamed.")
+            } else if !matches!(constraint, Constraint::None) {
+                Some("Beam search cannot be combined with a grammar.")
+            } else if !fork_choices || is_speculative {
+                Some("Beam search requires the KV cache, and is not supported with PagedAttention or speculative decoding.")
+            } else {
+                None
+            };
+            if let Some(message) = message {
+                request
+                    .response
+                    .send(Response::ValidationError(message.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+
+        // Add sequences
+        for (prompt_index, (prompt_tokens, prompt_text)) in prompts.into_iter().enumerate() {
+            let prompt_sampler = sampler.clone().with_penalized_prompt(
+                request.sampling_params.penalize_prompt.unwrap_or(true),
+                prompt_tokens.len(),
             );
-            let seq = if let Some(prefill_cache) = prefill_cache.clone() {
-                seq.prefill(
-                    prefill_cache.normal,
-                    prefill_cache.xlora,
-                    prefill_cache.toks,
+            // Caches are keyed by tokens only, so they cannot be shared when adapters or images
+            // also shape the prompt. Prompt logprobs need the logits of every prompt position.
+            let prefill_cache = if request.adapters.is_none()
+                && images.is_none()
+                && request.sampling_params.prompt_logprobs.is_none()
+            {
+                handle_seq_error!(
+                    self.prefix_cacher.search_for_matching_cache(&prompt_tokens),
+                    request.response
                 )
             } else {
-                seq
+                None
             };
-            self.id += 1;
-            self.scheduler.add_seq(seq);
+            let mut choices = Vec::with_capacity(candidates_per_prompt);
+            for choice_index in 0..candidates_per_prompt {
+                let response_index = prompt_index * candidates_per_prompt + choice_index;
+                let recognizer = match Self::build_sequence_recognizer(&constraint) {
+                    Ok(recognizer) => recognizer,
+                    Err(err) => {
+                        request
+                            .response
+                            .send(Response::ValidationError(
+                                format!("Invalid grammar. {}", err).into(),
+                            ))
+                            .await
+                            .expect("Expected receiver.");
+                        return;
+                    }
+                };
+
+                let block_size = get_mut_arcmutex!(self.pipeline)
+                    .get_metadata()
+                    .cache_config
+                    .clone()
+                    .map(|conf| conf.block_size);
+                let trie = get_mut_arcmutex!(self.pipeline)
+                    .get_metadata()
+                    .tok_trie
+                    .as_ref()
+                    .map(|x| (**x).clone());
+                let seq = Sequence::new_waiting(
+                    prompt_tokens.clone(),
+                    prompt_text.clone(),
+                    self.id,
+                    request.id,
+                    now.as_millis(),
+                    num_hidden_layers,
+                    request.response.clone(),
+                    prompt_sampler.clone(),
+                    stop_toks.clone(),
+                    stop_strings.clone(),
+                    request.sampling_params.max_len,
+                    request.return_logprobs,
+                    get_mut_arcmutex!(self.pipeline).get_metadata().is_xlora,
+                    group.clone(),
+                    response_index,
+                    now.as_secs(),
+                    recognizer,
+                    // Only an echoed completion holds the whole text around the middle.
+                    request.suffix.clone().filter(|_| echo_prompt),
+                    if echo_prompt {
+                        Some(prompt_text.clone())
+                    } else {
+                        None
+                    },
+                    request.adapters.clone(),
+                    images.clone(),
+                    block_size,
+                    trie,
+                    matcher.clone(),
+                    image_generation_format,
+                    seq_step_type,
+                    diffusion_params.clone(),
+                );
+                let seq = seq
+                    .with_seed(request.sampling_params.seed)
+                    .with_include_stop_str_in_output(
+                        request.sampling_params.include_stop_str_in_output,
+                    )
+                    .with_prompt_logprobs(request.sampling_params.prompt_logprobs)
+                    .with_return_raw_tokens(request.sampling_params.return_raw_tokens)
+                    .with_reasoning_delimiters(
+                        self.reasoning_delimiters.as_ref().filter(|_| is_chat),
+                    )
+                    .with_priority(request.priority)
+                    .with_max_chars(request.sampling_params.max_chars)
+                    .with_output_filter(self.output_filter.clone())
+                    .with_beam_search(beam_search);
+                let seq = if let Some(prefill_cache) = prefill_cache.clone() {
+                    seq.prefill(
+                        prefill_cache.normal,
+                        prefill_cache.xlora,
+                        prefill_cache.toks,
+                    )
+                } else {
+                    seq
+                };
+                self.id += 1;
+                choices.push(seq);
+            }
+            if fork_choices {
+                let mut choices = choices.into_iter();
+                let mut leader = choices.next().expect("At least one choice.");
+                leader.set_forks(choices.collect());
+                self.scheduler.add_seq(leader);
+            } else {
+                for seq in choices {
+                    self.scheduler.add_seq(seq);
+                }
+            }
         }
     }
 }
+
+/// A regex matching exactly one of `choices`, and nothing else.
+fn choice_regex(choices: &[String]) -> String {
+    let alternatives = choices
+        .iter()
+        .map(|choice| regex::escape(choice))
+        .collect::<Vec<_>>();
+    format!("(?:{})", alternatives.join("|"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        aici::{
+            recognizer::StackRecognizer,
+            rx::RecRx,
+            toktree::{Recognizer, SpecialToken},
+        },
+        pipeline::testing::{complete, send_completion},
+        Response, SamplingParams, TestPipeline,
+    };
+
+    use super::choice_regex;
+
+    #[test]
+    fn test_choice_regex() {
+        let choices = ["positive", "negative", "neutral"].map(String::from);
+        let rx = choice_regex(&choices);
+        let accepts = |text: &str| {
+            let mut recognizer = StackRecognizer::from(RecRx::from_rx(&rx, None).unwrap());
+            text.bytes().all(|b| recognizer.try_push_byte(b))
+                && recognizer.special_allowed(SpecialToken::EndOfSentence)
+        };
+        for choice in &choices {
+            assert!(accepts(choice));
+        }
+        assert!(!accepts("neg"));
+        assert!(!accepts("positive!"));
+        assert!(!accepts("Positive"));
+
+        // Choices are literal, not patterns.
+        let rx = choice_regex(&["a.b".to_string()]);
+        let mut recognizer = StackRecognizer::from(RecRx::from_rx(&rx, None).unwrap());
+        assert!(!"axb".bytes().all(|b| recognizer.try_push_byte(b)));
+    }
+
+    #[tokio::test]
+    async fn forks_finishing_with_their_first_token_are_done() {
+        let mistralrs = TestPipeline::new("test").build(true);
+        let params = SamplingParams {
+            temperature: Some(0.),
+            max_len: Some(1),
+            n_choices: 3,
+            return_raw_tokens: true,
+            ..SamplingParams::deterministic()
+        };
+        let mut rx = send_completion(&mistralrs, &["a b c"], params).await;
+        let Some(Response::CompletionDone(response)) = rx.recv().await else {
+            panic!("Expected a completion.");
+        };
+        let mut indices = response
+            .choices
+            .iter()
+            .map(|choice| choice.index)
+            .collect::<Vec<_>>();
+        indices.sort();
+        assert_eq!(indices, [0, 1, 2]);
+        let expected = TestPipeline::greedy_completion(&[0, 1, 2], 1);
+        for choice in &response.choices {
+            assert_eq!(choice.finish_reason, "length");
+            assert_eq!(choice.raw_tokens.as_ref(), Some(&expected));
+        }
+
+        // Neither the leader nor the forks are scheduled again.
+        let next = tokio::time::timeout(Duration::from_millis(500), rx.recv()).await;
+        assert!(!matches!(next, Ok(Some(_))));
+    }
+
+    #[tokio::test]
+    async fn seeded_choices_differ_and_are_reproducible() {
+        let mistralrs = TestPipeline::new("test").build(true);
+        let params = SamplingParams {
+            temperature: Some(1.),
+            max_len: Some(8),
+            n_choices: 3,
+            seed: Some(42),
+            ..SamplingParams::deterministic()
+        };
+        let choices = complete(&mistralrs, &["a b c"], params.clone()).await;
+        assert_eq!(choices.len(), 3);
+        assert!(choices.iter().all(|toks| toks.len() == 8));
+        // Each choice has its own seed, and so its own tokens.
+        assert_ne!(choices[0], choices[1]);
+        assert_ne!(choices[0], choices[2]);
+        assert_ne!(choices[1], choices[2]);
+
+        assert_eq!(complete(&mistralrs, &["a b c"], params).await, choices);
+    }
+}
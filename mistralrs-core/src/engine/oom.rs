@@ -0,0 +1,111 @@
+//! Recovery from out of memory errors in the prompt step. A batch which runs out of memory is split
+//! in half and retried after a delay which doubles with every failure, so that a transient spike
+//! does not fail every request in the batch. Only a single sequence which still does not fit
+//! after [`MAX_OOM_RETRIES`] attempts is failed.
+
+use std::time::Duration;
+
+/// Attempts at a single sequence, after the first, before its error is returned to the client.
+pub(super) const MAX_OOM_RETRIES: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Whether `e` is an allocation failure, as reported by CUDA, Metal or the CPU allocator.
+pub(super) fn is_oom_error(e: &candle_core::Error) -> bool {
+    let msg = e.to_string().to_lowercase();
+    msg.contains("out of memory") || msg.contains("out_of_memory") || msg.contains("outofmemory")
+}
+
+pub(super) enum OomRecovery<T> {
+    /// Run these batches instead, after waiting this long.
+    Retry(Vec<Vec<T>>, Duration),
+    /// The batch cannot fit, so its error must be returned.
+    GiveUp(Vec<T>),
+}
+
+/// Tracks the out of memory failures during one prompt step.
+#[derive(Default)]
+pub(super) struct OomBackoff {
+    failures: u32,
+    single_retries: u32,
+}
+
+impl OomBackoff {
+    /// What to do with `batch`, whose prompt step ran out of memory.
+    pub(super) fn recover<T>(&mut self, mut batch: Vec<T>) -> OomRecovery<T> {
+        if batch.len() == 1 {
+            if self.single_retries == MAX_OOM_RETRIES {
+                return OomRecovery::GiveUp(batch);
+            }
+            self.single_retries += 1;
+        }
+        let delay = INITIAL_BACKOFF
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(MAX_BACKOFF);
+        self.failures += 1;
+        if batch.len() == 1 {
+            return OomRecovery::Retry(vec![batch], delay);
+        }
+        let second = batch.split_off(batch.len() / 2);
+        OomRecovery::Retry(vec![batch, second], delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `batch` like the engine does, with a device that can only fit `capacity` sequences at
+    /// once. Returns the batches which ran and those which were given up on.
+    fn run(batch: Vec<usize>, capacity: usize) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+        let mut backoff = OomBackoff::default();
+        let mut pending = vec![batch];
+        let (mut ran, mut failed) = (Vec::new(), Vec::new());
+        while let Some(batch) = pending.pop() {
+            if batch.len() <= capacity {
+                ran.push(batch);
+                continue;
+            }
+            let e = candle_core::Error::Msg("CUDA_ERROR_OUT_OF_MEMORY: out of memory".into());
+            assert!(is_oom_error(&e));
+            match backoff.recover(batch) {
+                OomRecovery::Retry(batches, _) => pending.extend(batches.into_iter().rev()),
+                OomRecovery::GiveUp(batch) => failed.push(batch),
+            }
+        }
+        (ran, failed)
+    }
+
+    #[test]
+    fn test_oom_splits_the_batch() {
+        let (ran, failed) = run(vec![0, 1, 2, 3, 4], 2);
+        assert_eq!(ran, vec![vec![0, 1], vec![2], vec![3, 4]]);
+        assert!(failed.is_empty());
+    }
+
+    #[test]
+    fn test_oom_gives_up_on_a_sequence_which_cannot_fit() {
+        let (ran, failed) = run(vec![0, 1], 0);
+        assert!(ran.is_empty());
+        assert_eq!(failed, vec![vec![0], vec![1]]);
+
+        let mut backoff = OomBackoff::default();
+        let delays = (0..MAX_OOM_RETRIES)
+            .map(|_| match backoff.recover(vec![0]) {
+                OomRecovery::Retry(_, delay) => delay,
+                OomRecovery::GiveUp(_) => panic!("Gave up before retrying."),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_millis(50),
+                Duration::from_millis(100),
+                Duration::from_millis(200)
+            ]
+        );
+        assert!(!is_oom_error(&candle_core::Error::Msg(
+            "shape mismatch".into()
+        )));
+    }
+}
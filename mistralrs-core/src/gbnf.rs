@@ -0,0 +1,742 @@
+//! Compile a GBNF grammar, the format of llama.cpp, into a yacc grammar that can drive constrained
+//! generation.
+//!
+//! GBNF matches characters, while the yacc parser first splits its input into lexemes. To keep the
+//! lexer out of the way, the bytes used by the grammar's terminals are partitioned into disjoint
+//! classes and every byte is lexed on its own as the class it belongs to. Characters outside ASCII
+//! become sequences of those byte classes, and the rules of the grammar are then translated one to
+//! one. Grammars are parsed as LR(1), so ambiguous ones may reject some of the strings they match.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+};
+
+use anyhow::{bail, Context, Result};
+
+/// The largest count accepted in a `{m,n}` repetition.
+const MAX_REPETITIONS: usize = 1000;
+
+type Alternatives = Vec<Vec<Expr>>;
+
+#[derive(Debug)]
+enum Expr {
+    Literal(String),
+    /// Characters in any of the inclusive ranges, or in none of them if negated.
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+    Rule(String),
+    Group(Alternatives),
+    Repeat {
+        expr: Box<Expr>,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+/// Build a yacc grammar accepting exactly the strings matched by the `root` rule of `gbnf`.
+pub fn gbnf_to_yacc(gbnf: &str) -> Result<String> {
+    let definitions = Parser::new(gbnf).parse_grammar()?;
+    let mut rules = HashMap::new();
+    for (name, alternatives) in &definitions {
+        if rules.insert(name.as_str(), alternatives).is_some() {
+            bail!("Rule `{name}` is defined more than once.");
+        }
+    }
+    if !rules.contains_key("root") {
+        bail!("The grammar has no `root` rule.");
+    }
+
+    let mut byte_ranges = BTreeSet::new();
+    for (_, alternatives) in &definitions {
+        collect_byte_ranges(alternatives, &mut byte_ranges);
+    }
+    if byte_ranges.is_empty() {
+        bail!("The grammar must match at least one character.");
+    }
+
+    let mut compiler = Compiler {
+        rules,
+        names: HashMap::new(),
+        todo: Vec::new(),
+        atoms: ByteAtoms::new(&byte_ranges),
+        byte_ranges: HashMap::new(),
+        out: String::new(),
+        n_helpers: 0,
+    };
+    let start = compiler.rule_name("root")?;
+    while let Some(name) = compiler.todo.pop() {
+        let alternatives = compiler.rules[name.as_str()];
+        let rule = compiler.names[&name].clone();
+        let alternatives = alternatives
+            .iter()
+            .map(|seq| compiler.compile_sequence(seq))
+            .collect::<Result<Vec<_>>>()?;
+        compiler.add_rule(&rule, &alternatives)?;
+    }
+
+    let mut grammar = format!("%start {start}\n%%\n\n");
+    grammar.push_str(&compiler.out);
+    Ok(grammar)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+/// Characters allowed in a rule name.
+fn is_word_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '-'
+}
+
+impl Parser {
+    fn new(src: &str) -> Self {
+        Self {
+            chars: src.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Result<char> {
+        let ch = self.peek().context("Unexpected end of the grammar.")?;
+        self.pos += 1;
+        Ok(ch)
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, ch)| self.chars.get(self.pos + i) == Some(&ch))
+    }
+
+    fn line(&self) -> usize {
+        1 + self.chars[..self.pos.min(self.chars.len())]
+            .iter()
+            .filter(|ch| **ch == '\n')
+            .count()
+    }
+
+    /// Skip whitespace, including newlines, and `#` comments.
+    fn skip_space(&mut self) {
+        while let Some(ch) = self.peek() {
+            if ch == '#' {
+                while self.peek().is_some_and(|ch| ch != '\n') {
+                    self.pos += 1;
+                }
+            } else if ch.is_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_word_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            bail!("Expected a rule name on line {}.", self.line());
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    /// Whether the next tokens are `name ::=`, which starts the next rule.
+    fn at_rule_start(&mut self) -> bool {
+        let start = self.pos;
+        let is_start = self.parse_name().is_ok() && {
+            self.skip_space();
+            self.starts_with("::=")
+        };
+        self.pos = start;
+        is_start
+    }
+
+    fn parse_grammar(&mut self) -> Result<Vec<(String, Alternatives)>> {
+        let mut definitions = Vec::new();
+        loop {
+            self.skip_space();
+            if self.peek().is_none() {
+                return Ok(definitions);
+            }
+            let name = self.parse_name()?;
+            self.skip_space();
+            if !self.starts_with("::=") {
+                bail!("Expected `::=` after `{name}` on line {}.", self.line());
+            }
+            self.pos += 3;
+            let alternatives = self.parse_alternatives(false)?;
+            definitions.push((name, alternatives));
+        }
+    }
+
+    fn parse_alternatives(&mut self, nested: bool) -> Result<Alternatives> {
+        let mut alternatives = vec![self.parse_sequence(nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.parse_sequence(nested)?);
+        }
+        Ok(alternatives)
+    }
+
+    fn parse_sequence(&mut self, nested: bool) -> Result<Vec<Expr>> {
+        let mut seq = Vec::new();
+        loop {
+            self.skip_space();
+            match self.peek() {
+                None | Some('|') => break,
+                Some(')') if nested => break,
+                Some(')') => bail!("Unmatched `)` on line {}.", self.line()),
+                Some(_) if !nested && self.at_rule_start() => break,
+                Some(_) => {}
+            }
+            let mut expr = self.parse_atom()?;
+            loop {
+                self.skip_space();
+                let (min, max) = match self.peek() {
+                    Some('{') => self.parse_count()?,
+                    Some('*') => {
+                        self.pos += 1;
+                        (0, None)
+                    }
+                    Some('+') => {
+                        self.pos += 1;
+                        (1, None)
+                    }
+                    Some('?') => {
+                        self.pos += 1;
+                        (0, Some(1))
+                    }
+                    _ => break,
+                };
+                expr = Expr::Repeat {
+                    expr: Box::new(expr),
+                    min,
+                    max,
+                };
+            }
+            seq.push(expr);
+        }
+        Ok(seq)
+    }
+
+    fn parse_atom(&mut self) -> Result<Expr> {
+        let line = self.line();
+        match self.peek() {
+            Some('"') => {
+                self.pos += 1;
+                let mut literal = String::new();
+                loop {
+                    match self.peek() {
+                        None => bail!("Unterminated literal on line {line}."),
+                        Some('"') => {
+                            self.pos += 1;
+                            return Ok(Expr::Literal(literal));
+                        }
+                        Some(_) => literal.push(self.parse_char()?),
+                    }
+                }
+            }
+            Some('[') => {
+                self.pos += 1;
+                let negated = self.peek() == Some('^');
+                if negated {
+                    self.pos += 1;
+                }
+                let mut ranges = Vec::new();
+                loop {
+                    match self.peek() {
+                        None => bail!("Unterminated character class on line {line}."),
+                        Some(']') => {
+                            self.pos += 1;
+                            return Ok(Expr::Class { negated, ranges });
+                        }
+                        Some(_) => {
+                            let start = self.parse_char()?;
+                            let end = if self.peek() == Some('-')
+                                && self.chars.get(self.pos + 1).is_some_and(|ch| *ch != ']')
+                            {
+                                self.pos += 1;
+                                self.parse_char()?
+                            } else {
+                                start
+                            };
+                            if end < start {
+                                bail!("Invalid character range `{start}-{end}` on line {line}.");
+                            }
+                            ranges.push((start, end));
+                        }
+                    }
+                }
+            }
+            Some('.') => {
+                self.pos += 1;
+                Ok(Expr::Class {
+                    negated: true,
+                    ranges: Vec::new(),
+                })
+            }
+            Some('(') => {
+                self.pos += 1;
+                let alternatives = self.parse_alternatives(true)?;
+                if self.bump()? != ')' {
+                    bail!("Expected `)` to close the group on line {line}.");
+                }
+                Ok(Expr::Group(alternatives))
+            }
+            Some(ch) if is_word_char(ch) => Ok(Expr::Rule(self.parse_name()?)),
+            Some(ch) => bail!("Unexpected character `{ch}` on line {line}."),
+            None => bail!("Unexpected end of the grammar."),
+        }
+    }
+
+    /// One character of a literal or a character class, with llama.cpp's escapes.
+    fn parse_char(&mut self) -> Result<char> {
+        let ch = self.bump()?;
+        if ch != '\\' {
+            return Ok(ch);
+        }
+        let n_digits = match self.bump()? {
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            't' => return Ok('\t'),
+            'r' => return Ok('\r'),
+            'n' => return Ok('\n'),
+            ch @ ('\\' | '"' | '[' | ']') => return Ok(ch),
+            ch => bail!("Unknown escape `\\{ch}` on line {}.", self.line()),
+        };
+        let mut value = 0;
+        for _ in 0..n_digits {
+            let digit = self
+                .bump()?
+                .to_digit(16)
+                .with_context(|| format!("Invalid hex escape on line {}.", self.line()))?;
+            value = value * 16 + digit;
+        }
+        char::from_u32(value)
+            .with_context(|| format!("Invalid character U+{value:X} on line {}.", self.line()))
+    }
+
+    /// A `{m}`, `{m,}` or `{m,n}` repetition count.
+    fn parse_count(&mut self) -> Result<(usize, Option<usize>)> {
+        let line = self.line();
+        self.pos += 1;
+        let min = self
+            .parse_count_bound()?
+            .with_context(|| format!("Expected a repetition count on line {line}."))?;
+        self.skip_space();
+        let max = if self.peek() == Some(',') {
+            self.pos += 1;
+            self.parse_count_bound()?
+        } else {
+            Some(min)
+        };
+        self.skip_space();
+        if self.bump()? != '}' {
+            bail!("Expected `}}` to close the repetition count on line {line}.");
+        }
+        if max.is_some_and(|max| max < min) {
+            bail!("Invalid repetition count on line {line}: the maximum is below the minimum.");
+        }
+        Ok((min, max))
+    }
+
+    fn parse_count_bound(&mut self) -> Result<Option<usize>> {
+        self.skip_space();
+        let start = self.pos;
+        while self.peek().is_some_and(|ch| ch.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Ok(None);
+        }
+        let n = self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse::<usize>()?;
+        if n > MAX_REPETITIONS {
+            bail!(
+                "Repetition counts may be at most {MAX_REPETITIONS}, got {n} on line {}.",
+                self.line()
+            );
+        }
+        Ok(Some(n))
+    }
+}
+
+/// The largest code point encoded with 1, 2 and 3 UTF-8 bytes.
+const UTF8_MAX_SCALARS: [u32; 3] = [0x7F, 0x7FF, 0xFFFF];
+
+/// Split the code points `start..=end` into sequences of byte ranges, such that the UTF-8 encodings
+/// of those code points are exactly the byte strings matching one of the sequences. Surrogates are
+/// skipped.
+fn utf8_sequences(start: u32, end: u32, out: &mut Vec<Vec<(u8, u8)>>) {
+    if start > end {
+        return;
+    }
+    if start <= 0xDFFF && end >= 0xD800 {
+        if start < 0xD800 {
+            utf8_sequences(start, 0xD7FF, out);
+        }
+        if end > 0xDFFF {
+            utf8_sequences(0xE000, end, out);
+        }
+        return;
+    }
+    for max in UTF8_MAX_SCALARS {
+        if start <= max && max < end {
+            utf8_sequences(start, max, out);
+            utf8_sequences(max + 1, end, out);
+            return;
+        }
+    }
+    // Within a length, split until every continuation byte spans its whole range or is fixed.
+    for i in (1..4).filter(|_| end > 0x7F) {
+        let m = (1 << (6 * i)) - 1;
+        if start & !m != end & !m {
+            if start & m != 0 {
+                utf8_sequences(start, start | m, out);
+                utf8_sequences((start | m) + 1, end, out);
+                return;
+            }
+            if end & m != m {
+                utf8_sequences(start, (end & !m) - 1, out);
+                utf8_sequences(end & !m, end, out);
+                return;
+            }
+        }
+    }
+    let (mut start_buf, mut end_buf) = ([0; 4], [0; 4]);
+    let start = char::from_u32(start)
+        .expect("Surrogates were split off.")
+        .encode_utf8(&mut start_buf)
+        .as_bytes();
+    let end = char::from_u32(end)
+        .expect("Surrogates were split off.")
+        .encode_utf8(&mut end_buf)
+        .as_bytes();
+    out.push(start.iter().copied().zip(end.iter().copied()).collect());
+}
+
+/// The byte range sequences of the characters a class matches.
+fn class_sequences(negated: bool, ranges: &[(char, char)]) -> Vec<Vec<(u8, u8)>> {
+    let mut ranges = ranges
+        .iter()
+        .map(|(start, end)| (*start as u32, *end as u32))
+        .collect::<Vec<_>>();
+    ranges.sort_unstable();
+    if negated {
+        let mut complement = Vec::new();
+        let mut next = 0;
+        for (start, end) in ranges {
+            if start > next {
+                complement.push((next, start - 1));
+            }
+            next = next.max(end + 1);
+        }
+        if next <= char::MAX as u32 {
+            complement.push((next, char::MAX as u32));
+        }
+        ranges = complement;
+    }
+    let mut out = Vec::new();
+    for (start, end) in ranges {
+        utf8_sequences(start, end, &mut out);
+    }
+    out
+}
+
+fn collect_byte_ranges(alternatives: &Alternatives, out: &mut BTreeSet<(u8, u8)>) {
+    for expr in alternatives.iter().flatten() {
+        collect_expr_byte_ranges(expr, out);
+    }
+}
+
+fn collect_expr_byte_ranges(expr: &Expr, out: &mut BTreeSet<(u8, u8)>) {
+    match expr {
+        Expr::Literal(literal) => out.extend(literal.bytes().map(|b| (b, b))),
+        Expr::Class { negated, ranges } => {
+            out.extend(class_sequences(*negated, ranges).into_iter().flatten())
+        }
+        Expr::Rule(_) => {}
+        Expr::Group(alternatives) => collect_byte_ranges(alternatives, out),
+        Expr::Repeat { expr, .. } => collect_expr_byte_ranges(expr, out),
+    }
+}
+
+/// A partition of the bytes used by a grammar into the coarsest classes that every byte range of the
+/// grammar is a union of. Each class becomes one lexer token.
+struct ByteAtoms {
+    /// The class of each byte, if any range contains it.
+    atom_of: [Option<usize>; 256],
+    /// The token matching each class.
+    tokens: Vec<String>,
+}
+
+impl ByteAtoms {
+    fn new(ranges: &BTreeSet<(u8, u8)>) -> Self {
+        let mut atom_of = [None; 256];
+        let mut members: Vec<Vec<u8>> = Vec::new();
+        let mut by_signature = HashMap::new();
+        for byte in 0..=u8::MAX {
+            let signature = ranges
+                .iter()
+                .enumerate()
+                .filter(|(_, (lo, hi))| (*lo..=*hi).contains(&byte))
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+            if signature.is_empty() {
+                continue;
+            }
+            let atom = *by_signature.entry(signature).or_insert_with(|| {
+                members.push(Vec::new());
+                members.len() - 1
+            });
+            members[atom].push(byte);
+            atom_of[usize::from(byte)] = Some(atom);
+        }
+
+        let tokens = members
+            .iter()
+            .map(|bytes| {
+                let mut class = String::new();
+                let mut i = 0;
+                while i < bytes.len() {
+                    let mut j = i;
+                    while j + 1 < bytes.len() && bytes[j + 1] == bytes[j] + 1 {
+                        j += 1;
+                    }
+                    if i == j {
+                        class.push_str(&format!("\\x{:02X}", bytes[i]));
+                    } else {
+                        class.push_str(&format!("\\x{:02X}-\\x{:02X}", bytes[i], bytes[j]));
+                    }
+                    i = j + 1;
+                }
+                format!("\"/[{class}]/\"")
+            })
+            .collect();
+        Self { atom_of, tokens }
+    }
+}
+
+struct Compiler<'a> {
+    rules: HashMap<&'a str, &'a Alternatives>,
+    /// Yacc rule of each GBNF rule referenced so far.
+    names: HashMap<String, String>,
+    /// Referenced GBNF rules which have not been emitted yet.
+    todo: Vec<String>,
+    atoms: ByteAtoms,
+    /// Symbol matching each byte range used so far.
+    byte_ranges: HashMap<(u8, u8), String>,
+    out: String,
+    n_helpers: usize,
+}
+
+impl Compiler<'_> {
+    fn rule_name(&mut self, name: &str) -> Result<String> {
+        if let Some(rule) = self.names.get(name) {
+            return Ok(rule.clone());
+        }
+        if !self.rules.contains_key(name) {
+            bail!("Rule `{name}` is not defined.");
+        }
+        // GBNF names have no underscores, so this cannot collide.
+        let rule = format!("r_{}", name.replace('-', "_"));
+        self.names.insert(name.to_string(), rule.clone());
+        self.todo.push(name.to_string());
+        Ok(rule)
+    }
+
+    fn new_helper(&mut self) -> String {
+        self.n_helpers += 1;
+        format!("h_{}", self.n_helpers)
+    }
+
+    fn add_rule(&mut self, name: &str, alternatives: &[String]) -> Result<()> {
+        writeln!(self.out, "{name}: {} ;", alternatives.join(" | "))?;
+        Ok(())
+    }
+
+    /// A helper rule matching any of `alternatives`, or the alternative itself if there is one.
+    fn alternatives(&mut self, mut alternatives: Vec<String>) -> Result<String> {
+        if alternatives.len() == 1 {
+            return Ok(alternatives.remove(0));
+        }
+        let name = self.new_helper();
+        self.add_rule(&name, &alternatives)?;
+        Ok(name)
+    }
+
+    fn byte_range(&mut self, lo: u8, hi: u8) -> Result<String> {
+        if let Some(symbol) = self.byte_ranges.get(&(lo, hi)) {
+            return Ok(symbol.clone());
+        }
+        let atoms = (lo..=hi)
+            .filter_map(|b| self.atoms.atom_of[usize::from(b)])
+            .collect::<BTreeSet<_>>();
+        let tokens = atoms
+            .into_iter()
+            .map(|atom| self.atoms.tokens[atom].clone())
+            .collect();
+        let symbol = self.alternatives(tokens)?;
+        self.byte_ranges.insert((lo, hi), symbol.clone());
+        Ok(symbol)
+    }
+
+    /// The symbols matching `seq`, separated by spaces. Empty if it only matches the empty string.
+    fn compile_sequence(&mut self, seq: &[Expr]) -> Result<String> {
+        let symbols = seq
+            .iter()
+            .map(|expr| self.compile(expr))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(symbols
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join(" "))
+    }
+
+    fn compile(&mut self, expr: &Expr) -> Result<String> {
+        match expr {
+            Expr::Literal(literal) => {
+                let symbols = literal
+                    .bytes()
+                    .map(|b| self.byte_range(b, b))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(symbols.join(" "))
+            }
+            Expr::Class { negated, ranges } => {
+                let sequences = class_sequences(*negated, ranges);
+                if sequences.is_empty() {
+                    bail!("A character class must match at least one character.");
+                }
+                let alternatives = sequences
+                    .into_iter()
+                    .map(|seq| {
+                        let symbols = seq
+                            .into_iter()
+                            .map(|(lo, hi)| self.byte_range(lo, hi))
+                            .collect::<Result<Vec<_>>>()?;
+                        Ok(symbols.join(" "))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                self.alternatives(alternatives)
+            }
+            Expr::Rule(name) => self.rule_name(name),
+            Expr::Group(alternatives) if alternatives.len() == 1 => {
+                self.compile_sequence(&alternatives[0])
+            }
+            Expr::Group(alternatives) => {
+                let alternatives = alternatives
+                    .iter()
+                    .map(|seq| self.compile_sequence(seq))
+                    .collect::<Result<Vec<_>>>()?;
+                let name = self.new_helper();
+                self.add_rule(&name, &alternatives)?;
+                Ok(name)
+            }
+            Expr::Repeat { expr, min, max } => {
+                let item = self.compile(expr)?;
+                if item.is_empty() {
+                    return Ok(item);
+                }
+                let mut symbols = vec![item.clone(); *min];
+                match max {
+                    None => {
+                        let star = self.new_helper();
+                        self.add_rule(&star, &[String::new(), format!("{star} {item}")])?;
+                        symbols.push(star);
+                    }
+                    Some(max) if max > min => {
+                        // Nest the optional items, `(x (x (x)?)?)?`, so that the grammar stays
+                        // unambiguous.
+                        let mut optional = String::new();
+                        for _ in *min..*max {
+                            let name = self.new_helper();
+                            self.add_rule(&name, &[String::new(), format!("{item} {optional}")])?;
+                            optional = name;
+                        }
+                        symbols.push(optional);
+                    }
+                    Some(_) => {}
+                }
+                Ok(symbols.join(" "))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::gbnf_to_yacc;
+    use crate::aici::{
+        cfg::CfgParser,
+        toktree::{Recognizer, SpecialToken},
+    };
+
+    fn accepts(yacc: &str, text: &str) -> bool {
+        let mut parser = CfgParser::from_yacc(yacc).unwrap();
+        text.bytes().all(|b| parser.try_push_byte(b))
+            && parser.special_allowed(SpecialToken::EndOfSentence)
+    }
+
+    #[test]
+    fn test_digits_only() {
+        let yacc = gbnf_to_yacc("root ::= [0-9]+").unwrap();
+        assert!(accepts(&yacc, "7"));
+        assert!(accepts(&yacc, "2024"));
+        assert!(!accepts(&yacc, ""));
+        assert!(!accepts(&yacc, "12a"));
+        assert!(!accepts(&yacc, "-1"));
+    }
+
+    #[test]
+    fn test_llama_cpp_syntax() {
+        let gbnf = r#"
+            # Names separated by commas.
+            root ::= item ("," ws item)*
+            item ::= [A-Z] [a-zé]* | "\"" [^"\n]+ "\""
+            ws ::= " "?
+            year ::= [0-9]{4}
+        "#;
+        let yacc = gbnf_to_yacc(gbnf).unwrap();
+        assert!(accepts(&yacc, "Ann"));
+        assert!(accepts(&yacc, "Ann,Bé, \"x y\""));
+        assert!(!accepts(&yacc, "ann"));
+        assert!(!accepts(&yacc, "Ann,"));
+        assert!(!accepts(&yacc, "\"\""));
+
+        let yacc = gbnf_to_yacc("root ::= \"v\" [0-9]{1,3} (\".\" [0-9]{2,})?").unwrap();
+        assert!(accepts(&yacc, "v1"));
+        assert!(accepts(&yacc, "v123.45"));
+        assert!(!accepts(&yacc, "v1234"));
+        assert!(!accepts(&yacc, "v1.2"));
+    }
+
+    #[test]
+    fn test_rejects_invalid_grammars() {
+        for gbnf in [
+            "item ::= \"a\"",
+            "root ::= item",
+            "root ::= \"a",
+            "root ::= [a-",
+            "root ::= \"\\q\"",
+            "root ::= (\"a\"",
+            "root ::= \"a\"{3,1}",
+            "root ::= \"a\"\nroot ::= \"b\"",
+            "root ::= \"\"",
+        ] {
+            assert!(gbnf_to_yacc(gbnf).is_err(), "{gbnf}");
+        }
+    }
+}
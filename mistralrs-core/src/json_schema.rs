@@ -0,0 +1,505 @@
+//! Compile a JSON schema into a yacc grammar that can drive constrained generation, and check
+//! generated JSON against the schema.
+//!
+//! Only the structural subset of JSON schema is supported: `type` (including unions), `enum`, `const`,
+//! `properties`/`required`, `items`, `anyOf`/`oneOf` and local `$ref`s into `$defs`/`definitions`.
+//! Object properties are emitted in the order `serde_json` iterates them; other keywords are ignored.
+//! The grammar cannot express bounds, so [`validate_json_schema`] additionally checks `allOf`,
+//! `additionalProperties`, `minimum`/`maximum` and their exclusive forms, `minLength`/`maxLength`,
+//! `pattern` and `minItems`/`maxItems`.
+
+use std::{collections::HashMap, fmt::Write};
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+const JSON_TOKENS: &str = r#"SKIP: "/[ \t\n\r]+/" ;
+STRING: "/\x22(\\[\x22\\/bfnrt]|\\u[0-9a-fA-F]{4}|[^\x22\\\x00-\x1F])*\x22/" ;
+NUMBER: "/-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?/" ;
+INTEGER: "/-?(0|[1-9][0-9]*)/" ;
+"#;
+
+const JSON_RULES: &str = r#"json_value: json_object | json_array | STRING | NUMBER | "true" | "false" | "null" ;
+json_object: "{" "}" | "{" json_members "}" ;
+json_members: json_pair | json_members "," json_pair ;
+json_pair: STRING ":" json_value ;
+json_array: "[" "]" | "[" json_elements "]" ;
+json_elements: json_value | json_elements "," json_value ;
+"#;
+
+/// Build a yacc grammar accepting exactly the JSON documents described by `schema`.
+/// Passing `true` (or `{}`) accepts any JSON value.
+pub fn json_schema_to_yacc(schema: &Value) -> Result<String> {
+    let mut compiler = SchemaCompiler {
+        root: schema,
+        rules: String::new(),
+        n_rules: 0,
+        refs: HashMap::new(),
+    };
+    let start = compiler.compile(schema)?;
+
+    let mut grammar = String::from("%start root\n%%\n\n");
+    grammar.push_str(JSON_TOKENS);
+    grammar.push_str(JSON_RULES);
+    writeln!(grammar, "root: {start} ;")?;
+    grammar.push_str(&compiler.rules);
+    Ok(grammar)
+}
+
+/// A token matching `text` literally, written as a regex token so that quotes need no escaping.
+fn literal_token(text: &str) -> String {
+    let escaped = text
+        .chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() || !ch.is_ascii() {
+                ch.to_string()
+            } else {
+                format!("\\x{:02X}", ch as u32)
+            }
+        })
+        .collect::<String>();
+    format!("\"/{escaped}/\"")
+}
+
+struct SchemaCompiler<'a> {
+    root: &'a Value,
+    rules: String,
+    n_rules: usize,
+    /// `$ref` pointer to the rule it was compiled into, which also makes recursive schemas terminate.
+    refs: HashMap<String, String>,
+}
+
+impl<'a> SchemaCompiler<'a> {
+    fn new_rule(&mut self) -> String {
+        self.n_rules += 1;
+        format!("schema_{}", self.n_rules)
+    }
+
+    fn add_rule(&mut self, name: &str, alternatives: &[String]) -> Result<()> {
+        writeln!(self.rules, "{name}: {} ;", alternatives.join(" | "))?;
+        Ok(())
+    }
+
+    fn alternatives(&mut self, alternatives: Vec<String>) -> Result<String> {
+        let name = self.new_rule();
+        self.add_rule(&name, &alternatives)?;
+        Ok(name)
+    }
+
+    /// Compile `schema` and return the name of the rule (or token) that matches it.
+    fn compile(&mut self, schema: &'a Value) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("json_value".to_string()),
+            Value::Bool(false) => bail!("A `false` schema can never be satisfied."),
+            Value::Object(schema) => schema,
+            other => bail!("A schema must be an object or a boolean, got `{other}`."),
+        };
+
+        if let Some(pointer) = schema.get("$ref") {
+            let pointer = pointer
+                .as_str()
+                .context("`$ref` must be a string.")?
+                .to_string();
+            return self.compile_ref(pointer);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal_token(&serde_json::to_string(value)?));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values.as_array().context("`enum` must be an array.")?;
+            if values.is_empty() {
+                bail!("`enum` must have at least one value.");
+            }
+            let alternatives = values
+                .iter()
+                .map(|v| Ok(literal_token(&serde_json::to_string(v)?)))
+                .collect::<Result<Vec<_>>>()?;
+            return self.alternatives(alternatives);
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(key) {
+                let schemas = schemas
+                    .as_array()
+                    .with_context(|| format!("`{key}` must be an array."))?;
+                if schemas.is_empty() {
+                    bail!("`{key}` must have at least one schema.");
+                }
+                let alternatives = schemas
+                    .iter()
+                    .map(|s| self.compile(s))
+                    .collect::<Result<Vec<_>>>()?;
+                return self.alternatives(alternatives);
+            }
+        }
+
+        match schema.get("type") {
+            None => Ok("json_value".to_string()),
+            Some(Value::String(tp)) => self.compile_type(tp, schema),
+            Some(Value::Array(tps)) => {
+                if tps.is_empty() {
+                    bail!("`type` must have at least one type.");
+                }
+                let alternatives = tps
+                    .iter()
+                    .map(|tp| {
+                        let tp = tp.as_str().context("`type` entries must be strings.")?;
+                        self.compile_type(tp, schema)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                self.alternatives(alternatives)
+            }
+            Some(other) => bail!("`type` must be a string or an array, got `{other}`."),
+        }
+    }
+
+    fn compile_type(
+        &mut self,
+        tp: &str,
+        schema: &'a serde_json::Map<String, Value>,
+    ) -> Result<String> {
+        match tp {
+            "string" => Ok("STRING".to_string()),
+            "number" => Ok("NUMBER".to_string()),
+            "integer" => Ok("INTEGER".to_string()),
+            "boolean" => self.alternatives(vec!["\"true\"".to_string(), "\"false\"".to_string()]),
+            "null" => Ok("\"null\"".to_string()),
+            "array" => self.compile_array(schema),
+            "object" => self.compile_object(schema),
+            other => bail!("Unknown type `{other}`."),
+        }
+    }
+
+    fn compile_array(&mut self, schema: &'a serde_json::Map<String, Value>) -> Result<String> {
+        let item = match schema.get("items") {
+            Some(items) => self.compile(items)?,
+            None => return Ok("json_array".to_string()),
+        };
+        let elements = self.new_rule();
+        self.add_rule(
+            &elements,
+            &[item.clone(), format!("{elements} \",\" {item}")],
+        )?;
+        self.alternatives(vec![
+            "\"[\" \"]\"".to_string(),
+            format!("\"[\" {elements} \"]\""),
+        ])
+    }
+
+    fn compile_object(&mut self, schema: &'a serde_json::Map<String, Value>) -> Result<String> {
+        let properties = match schema.get("properties") {
+            Some(Value::Object(properties)) => properties,
+            Some(_) => bail!("`properties` must be an object."),
+            None => return Ok("json_object".to_string()),
+        };
+        let required = match schema.get("required") {
+            Some(Value::Array(required)) => required
+                .iter()
+                .map(|r| r.as_str().context("`required` entries must be strings."))
+                .collect::<Result<Vec<_>>>()?,
+            Some(_) => bail!("`required` must be an array."),
+            None => Vec::new(),
+        };
+        if let Some(missing) = required.iter().find(|r| !properties.contains_key(**r)) {
+            bail!("Required property `{missing}` is not in `properties`.");
+        }
+
+        let pairs = properties
+            .iter()
+            .map(|(name, property)| {
+                let key = literal_token(&serde_json::to_string(name)?);
+                let value = self.compile(property)?;
+                Ok((
+                    format!("{key} \":\" {value}"),
+                    required.contains(&name.as_str()),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // `first[k]` matches properties `k..` when none has been emitted yet, `rest[k]` when the next
+        // one needs a leading comma. Optional properties may be skipped, required ones may not.
+        let first = (0..=pairs.len())
+            .map(|_| self.new_rule())
+            .collect::<Vec<_>>();
+        let rest = (0..=pairs.len())
+            .map(|_| self.new_rule())
+            .collect::<Vec<_>>();
+        self.add_rule(&first[pairs.len()], &[String::new()])?;
+        self.add_rule(&rest[pairs.len()], &[String::new()])?;
+        for (k, (pair, is_required)) in pairs.iter().enumerate().rev() {
+            let mut first_alts = vec![format!("{pair} {}", rest[k + 1])];
+            let mut rest_alts = vec![format!("\",\" {pair} {}", rest[k + 1])];
+            if !is_required {
+                first_alts.push(first[k + 1].clone());
+                rest_alts.push(rest[k + 1].clone());
+            }
+            self.add_rule(&first[k], &first_alts)?;
+            self.add_rule(&rest[k], &rest_alts)?;
+        }
+        self.alternatives(vec![format!("\"{{\" {} \"}}\"", first[0])])
+    }
+
+    fn compile_ref(&mut self, pointer: String) -> Result<String> {
+        if let Some(rule) = self.refs.get(&pointer) {
+            return Ok(rule.clone());
+        }
+        let target = pointer
+            .strip_prefix('#')
+            .and_then(|p| self.root.pointer(p))
+            .with_context(|| format!("Cannot resolve `$ref` `{pointer}`."))?;
+        let rule = self.new_rule();
+        self.refs.insert(pointer, rule.clone());
+        let inner = self.compile(target)?;
+        self.add_rule(&rule, &[inner])?;
+        Ok(rule)
+    }
+}
+
+/// Check that `value` matches `schema`, naming the JSON pointer of the first mismatch otherwise.
+pub fn validate_json_schema(schema: &Value, value: &Value) -> Result<()> {
+    Validator { root: schema }.validate(schema, value, "")
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+}
+
+impl Validator<'_> {
+    fn validate(&self, schema: &Value, value: &Value, path: &str) -> Result<()> {
+        let at = if path.is_empty() { "/" } else { path };
+        let schema = match schema {
+            Value::Bool(true) => return Ok(()),
+            Value::Bool(false) => bail!("`{at}` is not allowed."),
+            Value::Object(schema) => schema,
+            other => bail!("A schema must be an object or a boolean, got `{other}`."),
+        };
+
+        if let Some(pointer) = schema.get("$ref") {
+            let target = pointer
+                .as_str()
+                .and_then(|p| p.strip_prefix('#'))
+                .and_then(|p| self.root.pointer(p))
+                .with_context(|| format!("Cannot resolve `$ref` `{pointer}`."))?;
+            return self.validate(target, value, path);
+        }
+        if let Some(expected) = schema.get("const") {
+            if value != expected {
+                bail!("`{at}` must be `{expected}`, got `{value}`.");
+            }
+        }
+        if let Some(Value::Array(values)) = schema.get("enum") {
+            if !values.contains(value) {
+                bail!("`{at}` must be one of the `enum` values, got `{value}`.");
+            }
+        }
+        if let Some(Value::Array(schemas)) = schema.get("allOf") {
+            for schema in schemas {
+                self.validate(schema, value, path)?;
+            }
+        }
+        if let Some(Value::Array(schemas)) = schema.get("anyOf") {
+            if !schemas
+                .iter()
+                .any(|s| self.validate(s, value, path).is_ok())
+            {
+                bail!("`{at}` matches none of the `anyOf` schemas.");
+            }
+        }
+        if let Some(Value::Array(schemas)) = schema.get("oneOf") {
+            let n_matching = schemas
+                .iter()
+                .filter(|s| self.validate(s, value, path).is_ok())
+                .count();
+            if n_matching != 1 {
+                bail!("`{at}` matches {n_matching} of the `oneOf` schemas instead of one.");
+            }
+        }
+
+        match schema.get("type") {
+            None => (),
+            Some(Value::String(tp)) if !has_type(value, tp) => {
+                bail!("`{at}` must be of type `{tp}`, got `{value}`.")
+            }
+            Some(Value::Array(tps))
+                if !tps
+                    .iter()
+                    .any(|tp| tp.as_str().is_some_and(|tp| has_type(value, tp))) =>
+            {
+                bail!(
+                    "`{at}` must be of one of the types `{}`, got `{value}`.",
+                    Value::Array(tps.clone())
+                )
+            }
+            Some(_) => (),
+        }
+
+        match value {
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or(f64::NAN);
+                let bound = |key: &str| schema.get(key).and_then(Value::as_f64);
+                if bound("minimum").is_some_and(|min| number < min)
+                    || bound("exclusiveMinimum").is_some_and(|min| number <= min)
+                    || bound("maximum").is_some_and(|max| number > max)
+                    || bound("exclusiveMaximum").is_some_and(|max| number >= max)
+                {
+                    bail!("`{at}` is out of the bounds of the schema, got `{number}`.");
+                }
+            }
+            Value::String(string) => {
+                let len = string.chars().count();
+                let bound = |key: &str| schema.get(key).and_then(Value::as_u64);
+                if bound("minLength").is_some_and(|min| (len as u64) < min)
+                    || bound("maxLength").is_some_and(|max| (len as u64) > max)
+                {
+                    bail!(
+                        "`{at}` has a length of {len}, which is out of the bounds of the schema."
+                    );
+                }
+                if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                    let regex = regex::Regex::new(pattern)
+                        .with_context(|| format!("Invalid `pattern` `{pattern}`."))?;
+                    if !regex.is_match(string) {
+                        bail!("`{at}` does not match the pattern `{pattern}`.");
+                    }
+                }
+            }
+            Value::Array(items) => {
+                let bound = |key: &str| schema.get(key).and_then(Value::as_u64);
+                if bound("minItems").is_some_and(|min| (items.len() as u64) < min)
+                    || bound("maxItems").is_some_and(|max| (items.len() as u64) > max)
+                {
+                    bail!(
+                        "`{at}` has {} items, which is out of the bounds of the schema.",
+                        items.len()
+                    );
+                }
+                if let Some(item_schema) = schema.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        self.validate(item_schema, item, &format!("{path}/{i}"))?;
+                    }
+                }
+            }
+            Value::Object(object) => {
+                let properties = schema.get("properties").and_then(Value::as_object);
+                if let Some(Value::Array(required)) = schema.get("required") {
+                    if let Some(missing) = required
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .find(|name| !object.contains_key(*name))
+                    {
+                        bail!("`{at}` is missing the required property `{missing}`.");
+                    }
+                }
+                for (name, property) in object {
+                    let property_path = format!("{path}/{name}");
+                    match properties.and_then(|properties| properties.get(name)) {
+                        Some(property_schema) => {
+                            self.validate(property_schema, property, &property_path)?
+                        }
+                        None => {
+                            if let Some(additional) = schema.get("additionalProperties") {
+                                self.validate(additional, property, &property_path)?;
+                            }
+                        }
+                    }
+                }
+            }
+            Value::Null | Value::Bool(_) => (),
+        }
+        Ok(())
+    }
+}
+
+fn has_type(value: &Value, tp: &str) -> bool {
+    match tp {
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{json_schema_to_yacc, validate_json_schema};
+    use crate::aici::cfg::CfgParser;
+
+    #[test]
+    fn compiles_to_valid_grammar() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer"},
+                "tags": {"type": "array", "items": {"enum": ["a", "b"]}},
+                "parent": {"$ref": "#"}
+            },
+            "required": ["name"]
+        });
+        for schema in [json!(true), schema] {
+            let yacc = json_schema_to_yacc(&schema).unwrap();
+            CfgParser::from_yacc(&yacc).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_schemas() {
+        for schema in [
+            json!(1),
+            json!({"type": "strin"}),
+            json!({"enum": []}),
+            json!({"$ref": "#/$defs/missing"}),
+            json!({"type": "object", "properties": {}, "required": ["a"]}),
+        ] {
+            assert!(json_schema_to_yacc(&schema).is_err());
+        }
+    }
+
+    #[test]
+    fn validates_bounds_the_grammar_cannot_enforce() {
+        // Constrained decoding guarantees the structure, but not that `age` is in range.
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string", "minLength": 1},
+                "age": {"type": "integer", "minimum": 0, "maximum": 150},
+                "tags": {"type": "array", "items": {"enum": ["a", "b"]}, "maxItems": 2}
+            },
+            "required": ["name", "age"],
+            "additionalProperties": false
+        });
+        validate_json_schema(&schema, &json!({"name": "Ada", "age": 36, "tags": ["a"]})).unwrap();
+
+        for (value, error) in [
+            (
+                json!({"name": "Ada", "age": 200}),
+                "`/age` is out of the bounds",
+            ),
+            (
+                json!({"name": "Ada"}),
+                "missing the required property `age`",
+            ),
+            (
+                json!({"name": "Ada", "age": 1.5}),
+                "`/age` must be of type `integer`",
+            ),
+            (
+                json!({"name": "Ada", "age": 1, "tags": ["c"]}),
+                "`/tags/0` must be one of",
+            ),
+            (
+                json!({"name": "Ada", "age": 1, "x": 1}),
+                "`/x` is not allowed",
+            ),
+            (json!({"name": "", "age": 1}), "`/name` has a length of 0"),
+        ] {
+            let e = validate_json_schema(&schema, &value).unwrap_err();
+            assert!(e.to_string().contains(error), "{e}");
+        }
+    }
+}
@@ -5,6 +5,7 @@ use cublaslt::setup_cublas_lt_wrapper;
 use engine::Engine;
 pub use engine::{EngineInstruction, ENGINE_INSTRUCTIONS, TERMINATE_ALL_NEXT_STEP};
 pub use lora::Ordering;
+use pipeline::set_kv_cache_dtype;
 pub use pipeline::ModelCategory;
 pub use pipeline::Pipeline;
 #[cfg(feature = "pyo3_macros")]
@@ -14,6 +15,7 @@ use std::{
     error::Error,
     fs::OpenOptions,
     io::Write,
+    num::NonZeroUsize,
     sync::{
         atomic::{self, AtomicBool, AtomicUsize},
         Arc, Mutex, RwLock,
@@ -21,9 +23,11 @@ use std::{
     thread::{self, JoinHandle},
     time::{SystemTime, UNIX_EPOCH},
 };
+use tokenizers::Tokenizer;
 use tokio::sync::mpsc::{channel, Sender};
 
 mod aici;
+mod beam_search;
 mod cuda;
 mod device_map;
 mod engine;
@@ -40,7 +44,9 @@ mod amoe;
 mod cublaslt;
 #[cfg(not(all(feature = "cuda", target_family = "unix")))]
 mod dummy_paged_attention;
+mod gbnf;
 mod gguf;
+mod json_schema;
 pub mod layers;
 mod layers_masker;
 mod layers_utils;
@@ -51,8 +57,10 @@ mod paged_attention;
 use dummy_paged_attention as paged_attention;
 mod attention;
 mod diffusion_models;
+mod output_filter;
 mod pipeline;
 mod prefix_cacher;
+mod reasoning;
 mod request;
 mod response;
 mod sampler;
@@ -66,30 +74,43 @@ mod vision_models;
 mod xlora_models;
 
 pub use amoe::{AnyMoeConfig, AnyMoeExpertType};
+pub use beam_search::BeamSearchParams;
 pub use device_map::{DeviceLayerMapMetadata, DeviceMapMetadata, LayerDeviceMapper};
+pub use gbnf::gbnf_to_yacc;
 pub use gguf::{GGUFArchitecture, GGUF_MULTI_FILE_DELIMITER};
+use indexmap::IndexMap;
+pub use json_schema::{json_schema_to_yacc, validate_json_schema};
 pub use mistralrs_quant::IsqType;
+pub use output_filter::{NoOpOutputFilter, OutputFilter, RegexOutputFilter};
 pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig};
+#[cfg(any(test, feature = "testing"))]
+pub use pipeline::TestPipeline;
 pub use pipeline::{
-    chat_template::ChatTemplate, parse_isq_value, AnyMoeLoader, AnyMoePipeline,
-    DiffusionGenerationParams, DiffusionLoader, DiffusionLoaderBuilder, DiffusionLoaderType,
-    DiffusionSpecificConfig, GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoader,
-    GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader, Idefics2Loader, IsqOrganization,
-    LLaVALoader, LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths, MistralLoader,
-    MixtralLoader, ModelKind, ModelPaths, NormalLoader, NormalLoaderBuilder, NormalLoaderType,
-    NormalSpecificConfig, Phi2Loader, Phi3Loader, Phi3VLoader, Qwen2Loader, SpeculativeConfig,
-    SpeculativeLoader, SpeculativePipeline, Starcoder2Loader, TokenSource, VisionLoader,
-    VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
+    chat_template::{validate_chat_template, ChatTemplate},
+    parse_isq_value, AnyMoeLoader, AnyMoePipeline, DiffusionGenerationParams, DiffusionLoader,
+    DiffusionLoaderBuilder, DiffusionLoaderType, DiffusionSpecificConfig, FimTemplate, GGMLLoader,
+    GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig,
+    GemmaLoader, Idefics2Loader, IsqOrganization, KvCacheDtype, LLaVALoader, LLaVANextLoader,
+    LlamaLoader, Loader, LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths,
+    NGramSpeculativeConfig, NGramSpeculativeLoader, NGramSpeculativePipeline, NormalLoader,
+    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Phi2Loader, Phi3Loader,
+    Phi3VLoader, Qwen2Loader, SpeculativeConfig, SpeculativeLoader, SpeculativePipeline,
+    SpeculativeStats, Starcoder2Loader, TokenSource, VisionLoader, VisionLoaderBuilder,
+    VisionLoaderType, VisionSpecificConfig, SPECULATIVE_STATS,
 };
+pub use reasoning::ReasoningDelimiters;
 pub use request::{
     Constraint, ImageGenerationResponseFormat, MessageContent, NormalRequest, Request,
     RequestMessage,
 };
 pub use response::*;
 pub use sampler::{
-    CustomLogitsProcessor, DrySamplingParams, SamplingParams, StopTokens, TopLogprob,
+    CustomLogitsProcessor, DrySamplingParams, MirostatParams, SamplerStep, SamplingParams,
+    StopTokens, TopLogprob, DEFAULT_SAMPLER_ORDER,
+};
+pub use scheduler::{
+    BatchComposition, DefaultSchedulerMethod, EngineStats, SchedulerConfig, SchedulerStats,
 };
-pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig};
 use serde::Serialize;
 use tokio::runtime::Runtime;
 use toml_selector::{TomlLoaderArgs, TomlSelector};
@@ -119,6 +140,7 @@ pub struct MistralRs {
     sender: RwLock<Sender<Request>>,
     log: Option<String>,
     id: String,
+    adapter_names: RwLock<Vec<String>>,
     creation_time: u64,
     next_request_id: Mutex<RefCell<usize>>,
     reboot_state: RebootState,
@@ -126,18 +148,29 @@ pub struct MistralRs {
     engine_id: usize,
     category: ModelCategory,
     config: MistralRsConfig,
+    scheduler_stats: Arc<SchedulerStats>,
+    tokenizer: Option<Arc<Tokenizer>>,
+    max_seq_len: usize,
+    /// Models served alongside this one, added with [`MistralRs::add_model`].
+    other_models: RwLock<Vec<Arc<MistralRs>>>,
+    system_fingerprint: Arc<RwLock<String>>,
 }
 
 #[derive(Clone)]
 struct RebootState {
     pipeline: Arc<tokio::sync::Mutex<dyn Pipeline>>,
     method: SchedulerConfig,
+    scheduler_stats: Arc<SchedulerStats>,
+    system_fingerprint: Arc<RwLock<String>>,
     truncate_sequence: bool,
     no_kv_cache: bool,
     no_prefix_cache: bool,
     prefix_cache_n: usize,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
+    reasoning_delimiters: Option<ReasoningDelimiters>,
+    prefill_chunk_size: Option<NonZeroUsize>,
+    output_filter: Arc<dyn OutputFilter>,
 }
 
 #[derive(Debug)]
@@ -174,7 +207,11 @@ pub struct MistralRsBuilder {
     prefix_cache_n: Option<usize>,
     disable_eos_stop: Option<bool>,
     gemm_full_precision_f16: Option<bool>,
+    kv_cache_dtype: Option<KvCacheDtype>,
     throughput_logging_enabled: Option<()>,
+    reasoning_delimiters: Option<ReasoningDelimiters>,
+    prefill_chunk_size: Option<NonZeroUsize>,
+    output_filter: Arc<dyn OutputFilter>,
 }
 
 impl MistralRsBuilder {
@@ -189,7 +226,11 @@ impl MistralRsBuilder {
             prefix_cache_n: None,
             disable_eos_stop: None,
             gemm_full_precision_f16: None,
+            kv_cache_dtype: None,
             throughput_logging_enabled: None,
+            reasoning_delimiters: None,
+            prefill_chunk_size: None,
+            output_filter: Arc::new(NoOpOutputFilter),
         }
     }
     pub fn with_log(mut self, log: String) -> Self {
@@ -224,10 +265,32 @@ impl MistralRsBuilder {
         self.gemm_full_precision_f16 = Some(gemm_full_precision);
         self
     }
+    /// Store the KV cache quantized. This does not apply to the PagedAttention KV cache.
+    pub fn with_kv_cache_dtype(mut self, kv_cache_dtype: KvCacheDtype) -> Self {
+        self.kv_cache_dtype = Some(kv_cache_dtype);
+        self
+    }
     pub fn with_throughput_logging(mut self) -> Self {
         self.throughput_logging_enabled = Some(());
         self
     }
+    /// Stream the text of chat completions inside `delimiters` as `reasoning_content`.
+    pub fn with_reasoning_delimiters(mut self, delimiters: Option<ReasoningDelimiters>) -> Self {
+        self.reasoning_delimiters = delimiters;
+        self
+    }
+    /// Run at most `prefill_chunk_size` tokens of a prompt per step, so that a long prompt does
+    /// not hold up the other sequences. Not supported with PagedAttention or without a KV cache,
+    /// nor by speculative decoding with a draft model.
+    pub fn with_prefill_chunk_size(mut self, prefill_chunk_size: Option<NonZeroUsize>) -> Self {
+        self.prefill_chunk_size = prefill_chunk_size;
+        self
+    }
+    /// Stop sequences with the `content_filter` finish reason once their output trips `filter`.
+    pub fn with_output_filter(mut self, filter: Arc<dyn OutputFilter>) -> Self {
+        self.output_filter = filter;
+        self
+    }
 
     pub fn build(self) -> Arc<MistralRs> {
         MistralRs::new(self)
@@ -292,7 +355,11 @@ impl MistralRs {
             prefix_cache_n,
             disable_eos_stop,
             gemm_full_precision_f16,
+            kv_cache_dtype,
             throughput_logging_enabled,
+            reasoning_delimiters,
+            prefill_chunk_size,
+            output_filter,
         } = config;
 
         let category = pipeline.try_lock().unwrap().category();
@@ -305,6 +372,7 @@ impl MistralRs {
             set_gemm_reduced_precision_f16();
         }
         setup_cublas_lt_wrapper();
+        set_kv_cache_dtype(kv_cache_dtype.unwrap_or_default());
 
         let truncate_sequence = truncate_sequence.unwrap_or(false);
         let no_kv_cache = no_kv_cache.unwrap_or(false);
@@ -313,26 +381,50 @@ impl MistralRs {
         let disable_eos_stop = disable_eos_stop.unwrap_or(false);
         let throughput_logging_enabled = throughput_logging_enabled.is_some();
 
+        let scheduler_stats = Arc::new(SchedulerStats::default());
+
+        let id = pipeline.try_lock().unwrap().name();
+        let adapter_names = pipeline
+            .try_lock()
+            .unwrap()
+            .get_metadata()
+            .adapter_names
+            .clone();
+        let system_fingerprint = pipeline
+            .try_lock()
+            .unwrap()
+            .get_metadata()
+            .system_fingerprint(&id, &adapter_names);
+        let system_fingerprint = Arc::new(RwLock::new(system_fingerprint));
+
         let reboot_state = RebootState {
             pipeline: pipeline.clone(),
             method: method.clone(),
+            scheduler_stats: scheduler_stats.clone(),
+            system_fingerprint: system_fingerprint.clone(),
             truncate_sequence,
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
             disable_eos_stop,
             throughput_logging_enabled,
+            reasoning_delimiters: reasoning_delimiters.clone(),
+            prefill_chunk_size,
+            output_filter: output_filter.clone(),
         };
 
         let (tx, rx) = channel(10_000);
 
         let sender = RwLock::new(tx);
-        let id = pipeline.try_lock().unwrap().name();
 
         let kind = pipeline.try_lock().unwrap().get_metadata().kind.clone();
         let device = pipeline.try_lock().unwrap().device();
         let config = MistralRsConfig { kind, device };
+        let tokenizer = pipeline.try_lock().unwrap().tokenizer();
+        let max_seq_len = pipeline.try_lock().unwrap().get_metadata().max_seq_len;
 
+        let engine_scheduler_stats = scheduler_stats.clone();
+        let engine_system_fingerprint = system_fingerprint.clone();
         let engine_handler = thread::spawn(move || {
             let rt = Runtime::new().unwrap();
             rt.block_on(async move {
@@ -340,12 +432,17 @@ impl MistralRs {
                     rx,
                     pipeline,
                     method,
+                    engine_scheduler_stats,
+                    engine_system_fingerprint,
                     truncate_sequence,
                     no_kv_cache,
                     no_prefix_cache,
                     prefix_cache_n,
                     disable_eos_stop,
                     throughput_logging_enabled,
+                    reasoning_delimiters,
+                    prefill_chunk_size,
+                    output_filter,
                 );
                 engine.run().await;
             });
@@ -358,6 +455,7 @@ impl MistralRs {
             sender,
             log,
             id,
+            adapter_names: RwLock::new(adapter_names),
             creation_time: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("Time travel has occurred!")
@@ -367,6 +465,11 @@ impl MistralRs {
             engine_handler: RwLock::new(engine_handler),
             category,
             config,
+            scheduler_stats,
+            tokenizer,
+            max_seq_len,
+            other_models: RwLock::new(Vec::new()),
+            system_fingerprint,
         })
     }
 
@@ -396,12 +499,17 @@ impl MistralRs {
                         rx,
                         reboot_state.pipeline.clone(),
                         reboot_state.method,
+                        reboot_state.scheduler_stats,
+                        reboot_state.system_fingerprint,
                         reboot_state.truncate_sequence,
                         reboot_state.no_kv_cache,
                         reboot_state.no_prefix_cache,
                         reboot_state.prefix_cache_n,
                         reboot_state.disable_eos_stop,
                         reboot_state.throughput_logging_enabled,
+                        reboot_state.reasoning_delimiters,
+                        reboot_state.prefill_chunk_size,
+                        reboot_state.output_filter,
                     );
                     engine.run().await;
                 });
@@ -423,6 +531,13 @@ impl MistralRs {
         }
     }
 
+    /// Whether the engine is running and accepting requests. This does not send anything to the
+    /// engine, so it is cheap and never waits behind inference, and it does not reboot a dead engine.
+    pub fn is_ready(&self) -> bool {
+        let sender_open = self.sender.read().is_ok_and(|sender| !sender.is_closed());
+        sender_open && matches!(self.engine_dead(), Ok(false))
+    }
+
     pub fn get_sender(&self) -> Result<Sender<Request>, MistralRsError> {
         if self.engine_dead()? {
             tracing::warn!("Engine is dead, rebooting");
@@ -438,10 +553,247 @@ impl MistralRs {
         self.id.clone()
     }
 
+    /// Identifies the loaded model, its quantization, dtype and adapters, as the
+    /// `system_fingerprint` of its responses.
+    pub fn get_system_fingerprint(&self) -> String {
+        self.system_fingerprint
+            .read()
+            .expect("`system_fingerprint` was poisoned")
+            .clone()
+    }
+
+    /// Names of the adapters loaded alongside the model, or since with [`MistralRs::load_adapter`].
+    pub fn get_adapter_names(&self) -> Vec<String> {
+        self.adapter_names
+            .read()
+            .expect("`adapter_names` was poisoned")
+            .clone()
+    }
+
+    /// Load the LoRA adapter at `source`, a local directory or a Hugging Face model ID, into the
+    /// running model so that requests may activate it as `name`. The engine loads it between steps,
+    /// so it never races with inference. Returns the names of all available adapters.
+    pub async fn load_adapter(&self, name: String, source: String) -> anyhow::Result<Vec<String>> {
+        let (tx, mut rx) = channel(1);
+        self.get_sender()?
+            .send(Request::LoadAdapter {
+                name: name.clone(),
+                source,
+                response: tx,
+            })
+            .await
+            .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        rx.recv()
+            .await
+            .ok_or_else(|| anyhow::Error::msg("No response received from the engine."))??;
+
+        // Responses with the new adapter are told apart by their fingerprint.
+        let metadata = self.reboot_state.pipeline.lock().await.get_metadata();
+        let mut adapter_names = self
+            .adapter_names
+            .write()
+            .expect("`adapter_names` was poisoned");
+        if !adapter_names.contains(&name) {
+            adapter_names.push(name);
+        }
+        *self
+            .system_fingerprint
+            .write()
+            .expect("`system_fingerprint` was poisoned") =
+            metadata.system_fingerprint(&self.id, &adapter_names);
+        Ok(adapter_names.clone())
+    }
+
+    /// Stop generating for the request with this ID. Returns whether it was still running; if it
+    /// already finished, or never existed, nothing happens.
+    pub async fn cancel_request(&self, id: usize) -> anyhow::Result<bool> {
+        let (tx, mut rx) = channel(1);
+        self.get_sender()?
+            .send(Request::CancelRequest { id, response: tx })
+            .await
+            .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        rx.recv()
+            .await
+            .ok_or_else(|| anyhow::Error::msg("No response received from the engine."))
+    }
+
+    /// A snapshot of the scheduler, taken by the engine between steps.
+    pub async fn get_engine_stats(&self) -> anyhow::Result<EngineStats> {
+        let (tx, mut rx) = channel(1);
+        self.get_sender()?
+            .send(Request::GetStats { response: tx })
+            .await
+            .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        rx.recv()
+            .await
+            .ok_or_else(|| anyhow::Error::msg("No response received from the engine."))
+    }
+
+    /// The prompt a chat request with `messages` and `tools` would run on: its token IDs, and its
+    /// text after applying the chat template, or `chat_template` instead if given. Nothing is
+    /// generated.
+    pub async fn render_chat_prompt(
+        &self,
+        messages: Vec<IndexMap<String, MessageContent>>,
+        tools: Vec<Tool>,
+        chat_template: Option<String>,
+    ) -> anyhow::Result<(Vec<u32>, String)> {
+        let (tx, mut rx) = channel(1);
+        self.get_sender()?
+            .send(Request::RenderPrompt {
+                messages,
+                tools,
+                chat_template,
+                response: tx,
+            })
+            .await
+            .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        rx.recv()
+            .await
+            .ok_or_else(|| anyhow::Error::msg("No response received from the engine."))?
+    }
+
+    /// The token IDs of `text` under the tokenizer the engine uses, optionally with the special
+    /// tokens the tokenizer adds, such as BOS.
+    pub fn tokenize(&self, text: &str, add_special_tokens: bool) -> anyhow::Result<Vec<u32>> {
+        let encoding = self
+            .model_tokenizer()?
+            .encode(text, add_special_tokens)
+            .map_err(anyhow::Error::msg)?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    /// The text of `tokens` under the tokenizer the engine uses, optionally without special tokens.
+    pub fn detokenize(&self, tokens: &[u32], skip_special_tokens: bool) -> anyhow::Result<String> {
+        self.model_tokenizer()?
+            .decode(tokens, skip_special_tokens)
+            .map_err(anyhow::Error::msg)
+    }
+
+    fn model_tokenizer(&self) -> anyhow::Result<&Tokenizer> {
+        self.tokenizer
+            .as_deref()
+            .ok_or_else(|| anyhow::Error::msg("This model has no tokenizer."))
+    }
+
+    /// The context length of the model, which the prompt and the generated tokens share.
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// Whether prompts longer than [`MistralRs::max_seq_len`] are truncated rather than rejected.
+    pub fn truncates_sequences(&self) -> bool {
+        self.reboot_state.truncate_sequence
+    }
+
     pub fn get_creation_time(&self) -> u64 {
         self.creation_time
     }
 
+    /// Sizes of the scheduler queues, as of the last engine step.
+    pub fn get_scheduler_stats(&self) -> &SchedulerStats {
+        &self.scheduler_stats
+    }
+
+    /// Whether the engine, and those of the models added with [`MistralRs::add_model`], have no
+    /// queued requests and no waiting or running sequences, so that they can be shut down without
+    /// cutting off any generation.
+    pub fn is_idle(&self) -> bool {
+        let no_queued_requests = self
+            .sender
+            .read()
+            .is_ok_and(|sender| sender.capacity() == sender.max_capacity());
+        no_queued_requests
+            && self.scheduler_stats.waiting() == 0
+            && self.scheduler_stats.running() == 0
+            && self
+                .other_models
+                .read()
+                .expect("`other_models` was poisoned")
+                .iter()
+                .all(|model| model.is_idle())
+    }
+
+    /// Serve `model`, with its own engine, alongside this one. Requests are routed to it by its ID
+    /// with [`MistralRs::get_model`].
+    pub fn add_model(&self, model: Arc<MistralRs>) -> anyhow::Result<()> {
+        let mut other_models = self
+            .other_models
+            .write()
+            .expect("`other_models` was poisoned");
+        let id = model.get_id();
+        if id == self.id || other_models.iter().any(|other| other.id == id) {
+            anyhow::bail!("A model with ID `{id}` is already being served.");
+        }
+        other_models.push(model);
+        Ok(())
+    }
+
+    /// Stop serving the model with this ID, which was added with [`MistralRs::add_model`], and
+    /// return it. Its engine keeps running until the returned model is dropped. This model itself
+    /// cannot be removed, as it holds the added models and serves requests naming none of them.
+    pub fn remove_model(&self, id: &str) -> anyhow::Result<Arc<MistralRs>> {
+        let mut other_models = self
+            .other_models
+            .write()
+            .expect("`other_models` was poisoned");
+        if id == self.id {
+            anyhow::bail!("The model `{id}` was loaded at startup, so it cannot be removed.");
+        }
+        let Some(index) = other_models.iter().position(|model| model.id == id) else {
+            anyhow::bail!("No model with ID `{id}` is being served.");
+        };
+        Ok(other_models.remove(index))
+    }
+
+    /// Serve `model` in place of the model with ID `id`, which was added with
+    /// [`MistralRs::add_model`], and return the latter. Requests are routed to one or the other,
+    /// never to neither. As with [`MistralRs::remove_model`], this model itself cannot be replaced.
+    pub fn replace_model(&self, id: &str, model: Arc<MistralRs>) -> anyhow::Result<Arc<MistralRs>> {
+        let mut other_models = self
+            .other_models
+            .write()
+            .expect("`other_models` was poisoned");
+        if id == self.id {
+            anyhow::bail!("The model `{id}` was loaded at startup, so it cannot be replaced.");
+        }
+        let Some(index) = other_models.iter().position(|other| other.id == id) else {
+            anyhow::bail!("No model with ID `{id}` is being served.");
+        };
+        let new_id = model.get_id();
+        if new_id != id
+            && (new_id == self.id || other_models.iter().any(|other| other.id == new_id))
+        {
+            anyhow::bail!("A model with ID `{new_id}` is already being served.");
+        }
+        Ok(std::mem::replace(&mut other_models[index], model))
+    }
+
+    /// The served model with this ID, or `None` if there is none. `default` refers to this model,
+    /// and if no models were added with [`MistralRs::add_model`] so does every other ID, so that
+    /// clients may name it freely.
+    pub fn get_model(self: &Arc<Self>, id: &str) -> Option<Arc<MistralRs>> {
+        let other_models = self
+            .other_models
+            .read()
+            .expect("`other_models` was poisoned");
+        if other_models.is_empty() || id == self.id || id == "default" {
+            return Some(self.clone());
+        }
+        other_models.iter().find(|model| model.id == id).cloned()
+    }
+
+    /// This model, followed by the models added with [`MistralRs::add_model`].
+    pub fn get_models(self: &Arc<Self>) -> Vec<Arc<MistralRs>> {
+        let other_models = self
+            .other_models
+            .read()
+            .expect("`other_models` was poisoned");
+        std::iter::once(self.clone())
+            .chain(other_models.iter().cloned())
+            .collect()
+    }
+
     pub fn get_model_category(&self) -> ModelCategory {
         self.category
     }
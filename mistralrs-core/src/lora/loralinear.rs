@@ -18,6 +18,9 @@ pub struct LoraLinear {
     layer_n: usize,
     merged: bool,
     adapters: HashMap<String, Adapter>,
+    linear_config: LoraLinearConfig,
+    /// The prefix of this layer's adapter weights, used to find them in adapters loaded later.
+    prefix: String,
 }
 
 impl LoraLinear {
@@ -109,6 +112,8 @@ impl LoraLinear {
                 layer_n,
                 merged: false,
                 adapters,
+                linear_config: linear_config.clone(),
+                prefix: vb.prefix(),
             })
         } else {
             Ok(LoraLinear {
@@ -121,6 +126,8 @@ impl LoraLinear {
                 layer_n,
                 merged: false,
                 adapters,
+                linear_config: linear_config.clone(),
+                prefix: vb.prefix(),
             })
         }
     }
@@ -155,6 +162,27 @@ impl AdapterSwapper for LoraLinear {
         }
         Ok(())
     }
+    fn _load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<bool> {
+        if self.merged {
+            bail!("Cannot load adapter `{name}` into a layer whose adapters have been merged.");
+        }
+        let layer_vb = vb.set_prefix(&self.prefix);
+        let (a_vb, b_vb) = (layer_vb.pp("lora_A"), layer_vb.pp("lora_B"));
+        if !a_vb.contains_tensor("weight") || !b_vb.contains_tensor("weight") {
+            return Ok(false);
+        }
+        let adapter = make_adapter(a_vb, b_vb, cfg, &self.linear_config)?;
+
+        // Stacked adapters cannot be swapped, so apply them one by one from now on.
+        if let Either::Right((_, a)) = &self.a_adapters {
+            self.a_adapters = Either::Left(a.clone());
+        }
+        if let Either::Right((_, b)) = &self.b_adapters {
+            self.b_adapters = Either::Left(b.clone());
+        }
+        self.adapters.insert(name.to_string(), adapter);
+        Ok(true)
+    }
     fn can_load(&self) -> bool {
         true
     }
@@ -30,6 +30,17 @@ pub struct Ordering {
     pub preload_adapters: Option<Vec<PreloadAdapter>>,
 }
 
+impl Ordering {
+    /// Names of all adapters in the ordering, including the preloaded ones.
+    pub fn adapter_names(&self) -> Vec<String> {
+        let mut names = self.adapters.clone().unwrap_or_default();
+        if let Some(preload_adapters) = &self.preload_adapters {
+            names.extend(preload_adapters.iter().map(|a| a.name.clone()));
+        }
+        names
+    }
+}
+
 #[derive(Clone, Debug)]
 /// Configuration for LoraLinear
 pub struct LoraLinearConfig {
@@ -126,7 +137,18 @@ pub trait AdapterSwapper {
             Ok(0)
         }
     }
+    /// Load a new adapter from `vb`, returning the number of layers it was loaded into. Layers for which
+    /// the adapter has no weights are left as they are.
+    fn load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.can_load() && self._load_adapter(name, vb, cfg)? {
+            Ok(1)
+        } else {
+            Ok(0)
+        }
+    }
     fn _activate_adapters(&mut self, adapters: &[String]) -> Result<()>;
+    /// Returns whether the adapter has weights for this layer.
+    fn _load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<bool>;
     fn can_load(&self) -> bool;
 }
 
@@ -143,6 +165,9 @@ impl AdapterSwapper for Linear {
     fn _activate_adapters(&mut self, _adapter: &[String]) -> Result<()> {
         unreachable!()
     }
+    fn _load_adapter(&mut self, _name: &str, _vb: &VarBuilder, _cfg: &LoraConfig) -> Result<bool> {
+        unreachable!()
+    }
     fn can_load(&self) -> bool {
         false
     }
@@ -202,6 +202,9 @@ impl AdapterSwapper for QLoraLinear {
         }
         Ok(())
     }
+    fn _load_adapter(&mut self, _name: &str, _vb: &VarBuilder, _cfg: &LoraConfig) -> Result<bool> {
+        bail!("Loading adapters at runtime is not supported for quantized LoRA layers.")
+    }
     fn can_load(&self) -> bool {
         self.linear_config.is_some()
     }
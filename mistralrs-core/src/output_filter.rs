@@ -0,0 +1,133 @@
+//! Filters run over the generated text, such as safety filters, which stop a sequence with the
+//! `content_filter` finish reason when they trip.
+
+use std::{fmt::Debug, ops::Range};
+
+use regex::Regex;
+
+use crate::sequence::StopReason;
+
+/// A filter over the text generated for a sequence, checked after each decoded token.
+pub trait OutputFilter: Debug + Send + Sync {
+    /// The byte range of the first offending span in `completion`, the text generated so far,
+    /// if the filter trips. Spans may cross tokens, so all of the text is given each time.
+    fn check(&self, completion: &str) -> Option<Range<usize>>;
+
+    /// Whether the output is cut before the offending span, rather than after it. Text which was
+    /// already streamed stays streamed.
+    fn redact(&self) -> bool {
+        false
+    }
+}
+
+/// The default filter, which never trips.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoOpOutputFilter;
+
+impl OutputFilter for NoOpOutputFilter {
+    fn check(&self, _completion: &str) -> Option<Range<usize>> {
+        None
+    }
+}
+
+/// A filter tripped by any match of its regexes, such as `(?i)\bbanned\b`.
+#[derive(Clone, Debug)]
+pub struct RegexOutputFilter {
+    patterns: Vec<Regex>,
+    redact: bool,
+}
+
+impl RegexOutputFilter {
+    pub fn new<I, S>(patterns: I, redact: bool) -> Result<Self, regex::Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Ok(Self {
+            patterns: patterns
+                .into_iter()
+                .map(|pattern| Regex::new(pattern.as_ref()))
+                .collect::<Result<_, _>>()?,
+            redact,
+        })
+    }
+}
+
+impl OutputFilter for RegexOutputFilter {
+    fn check(&self, completion: &str) -> Option<Range<usize>> {
+        self.patterns
+            .iter()
+            .filter_map(|pattern| pattern.find(completion))
+            .map(|m| m.range())
+            .min_by_key(|span| span.start)
+    }
+
+    fn redact(&self) -> bool {
+        self.redact
+    }
+}
+
+/// The stop reason for a completion of `completion_bytes` which trips `filter`, cut before or
+/// after the offending span. A trailing partial UTF-8 character is not checked yet.
+pub(crate) fn content_filter_reason(
+    completion_bytes: &[u8],
+    filter: &dyn OutputFilter,
+) -> Option<StopReason> {
+    let text = match std::str::from_utf8(completion_bytes) {
+        Ok(text) => text,
+        Err(e) => std::str::from_utf8(&completion_bytes[..e.valid_up_to()]).ok()?,
+    };
+    let span = filter.check(text)?;
+    Some(StopReason::ContentFilter {
+        completion_bytes_pos: if filter.redact() {
+            span.start
+        } else {
+            span.end
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sequence::StopReason;
+
+    use super::{content_filter_reason, NoOpOutputFilter, RegexOutputFilter};
+
+    #[test]
+    fn filter_trips_on_a_banned_word_split_across_tokens() {
+        let tokens: [&[u8]; 4] = [b" That is", b" a forb", b"idden", b" word"];
+        let run = |redact| {
+            let filter = RegexOutputFilter::new([r"(?i)\bforbidden\b"], redact).unwrap();
+            let mut completion_bytes = Vec::new();
+            for tok_bytes in tokens {
+                completion_bytes.extend_from_slice(tok_bytes);
+                if let Some(reason) = content_filter_reason(&completion_bytes, &filter) {
+                    return (completion_bytes, reason);
+                }
+            }
+            panic!("Expected the filter to trip.");
+        };
+
+        let (completion_bytes, reason) = run(true);
+        let StopReason::ContentFilter {
+            completion_bytes_pos,
+        } = reason
+        else {
+            panic!("Expected a content filter stop.");
+        };
+        // It trips once the word is complete, and the word is redacted.
+        assert_eq!(completion_bytes, b" That is a forbidden");
+        assert_eq!(&completion_bytes[..completion_bytes_pos], b" That is a ");
+        assert_eq!(reason.to_string(), "content_filter");
+
+        let (completion_bytes, reason) = run(false);
+        assert_eq!(
+            reason,
+            StopReason::ContentFilter {
+                completion_bytes_pos: completion_bytes.len()
+            }
+        );
+
+        assert_eq!(content_filter_reason(b"forbidden", &NoOpOutputFilter), None);
+    }
+}
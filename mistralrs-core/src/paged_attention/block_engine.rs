@@ -203,6 +203,12 @@ impl BlockEngine {
         }
     }
 
+    /// The number of GPU blocks in use and the total number of GPU blocks.
+    pub fn gpu_block_usage(&self) -> (usize, usize) {
+        let free = *self.gpu_allocator.get_num_free_blocks();
+        (self.num_gpu_blocks - free, self.num_gpu_blocks)
+    }
+
     pub fn can_allocate(&self, seq: &impl BlockEngineSequence) -> AllocStatus {
         let num_required_blocks = seq.get_logical_token_blocks();
         let num_free_gpu_blocks = self.gpu_allocator.get_num_free_blocks();
@@ -364,12 +364,17 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
             -1,
             0.0,
             0.0,
+            1.0,
+            None,
+            None,
+            None,
+            None,
             vec![],
         )
         .map_err(candle_core::Error::msg)?;
 
         let dummy_group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
-            1, false, false, 0,
+            1, 1, false, false, 0,
         )));
 
         // Clear KV cache in prep for training
@@ -400,6 +405,7 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
                             ])],
                             true,
                             Vec::new(),
+                            None,
                         )
                         .map_err(candle_core::Error::msg)?;
                     let images = image_urls.as_ref().map(|urls| {
@@ -549,6 +555,7 @@ fn new_dummy_seq(
         prompt,
         0,
         0,
+        0,
         1,
         dummy_sender,
         dummy_sampler,
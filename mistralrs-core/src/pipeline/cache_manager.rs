@@ -1,6 +1,14 @@
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::{
+    f64::consts::LN_2,
+    fmt::Display,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
 
-use candle_core::{Tensor, D};
+use candle_core::{DType, Tensor, D};
 
 use crate::{get_mut_arcmutex, sequence::Sequence};
 
@@ -19,6 +27,163 @@ pub trait CacheManager<T: CacheManagerMixin + MetadataMixin + ?Sized> {
 
 pub type LayerCaches = Vec<Option<(Tensor, Tensor)>>;
 
+/// How the KV cache stores keys and values.
+///
+/// The quantized formats store each token of each head as 8 or 4 bit integers sharing a
+/// power-of-two scale, whose exponent takes one extra byte, so a head dimension of 128 takes 129
+/// bytes with `Q8` and 65 with `Q4` instead of 256 with `F16`. The cache is dequantized
+/// to the model's dtype before attention.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KvCacheDtype {
+    /// Store keys and values in the model's dtype, unquantized.
+    #[default]
+    F16,
+    Q8,
+    Q4,
+}
+
+impl FromStr for KvCacheDtype {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f16" => Ok(Self::F16),
+            "q8" => Ok(Self::Q8),
+            "q4" => Ok(Self::Q4),
+            other => Err(format!(
+                "Unknown KV cache dtype `{other}`, expected one of `f16`, `q8`, `q4`."
+            )),
+        }
+    }
+}
+
+impl Display for KvCacheDtype {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::F16 => write!(f, "f16"),
+            Self::Q8 => write!(f, "q8"),
+            Self::Q4 => write!(f, "q4"),
+        }
+    }
+}
+
+static KV_CACHE_DTYPE: AtomicU8 = AtomicU8::new(KvCacheDtype::F16 as u8);
+
+/// Set how all KV caches store keys and values from now on. This should be set before any request
+/// is run, as caches already holding tokens are not converted.
+pub fn set_kv_cache_dtype(dtype: KvCacheDtype) {
+    KV_CACHE_DTYPE.store(dtype as u8, Ordering::Relaxed);
+}
+
+pub(crate) fn kv_cache_dtype() -> KvCacheDtype {
+    match KV_CACHE_DTYPE.load(Ordering::Relaxed) {
+        x if x == KvCacheDtype::Q8 as u8 => KvCacheDtype::Q8,
+        x if x == KvCacheDtype::Q4 as u8 => KvCacheDtype::Q4,
+        _ => KvCacheDtype::F16,
+    }
+}
+
+impl KvCacheDtype {
+    /// The largest magnitude of a quantized value.
+    fn max_q(&self) -> f64 {
+        match self {
+            Self::F16 => unreachable!("`F16` is not quantized."),
+            Self::Q8 => 127.,
+            Self::Q4 => 7.,
+        }
+    }
+
+    /// Quantize `x`, with the head dimension last, to a `U8` tensor whose last dimension holds the
+    /// quantized values followed by the exponent of their scale.
+    fn quantize(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        let max_q = self.max_q();
+        let x = x.to_dtype(DType::F32)?;
+        // The smallest power of two scaling the largest value of the token into range. Exact powers
+        // of two are nudged down so that rounding errors cannot bump them to the next exponent.
+        let exponent = (x.abs()?.max_keepdim(D::Minus1)?.clamp(1e-30, f64::MAX)? / max_q)?
+            .log()?
+            .affine(1. / LN_2, -1e-3)?
+            .ceil()?
+            .clamp(-127., 127.)?;
+        let scale = exponent.affine(LN_2, 0.)?.exp()?;
+        // Offset so that the values are unsigned.
+        let q = (x.broadcast_div(&scale)?.round()?.clamp(-max_q, max_q)? + (max_q + 1.))?;
+        let q = match self {
+            Self::Q4 => {
+                // Pack pairs of values into one byte.
+                let mut dims = q.dims().to_vec();
+                let head_dim = dims.pop().expect("KV cache tensors have a head dimension.");
+                dims.extend([head_dim / 2, 2]);
+                let q = q.reshape(dims)?;
+                ((q.narrow(D::Minus1, 0, 1)? * 16.)? + q.narrow(D::Minus1, 1, 1)?)?
+                    .squeeze(D::Minus1)?
+            }
+            _ => q,
+        };
+        Tensor::cat(&[q, (exponent + 128.)?], D::Minus1)?.to_dtype(DType::U8)
+    }
+
+    /// Invert [`Self::quantize`], returning a tensor of `dtype`.
+    fn dequantize(&self, q: &Tensor, dtype: DType) -> candle_core::Result<Tensor> {
+        let max_q = self.max_q();
+        let q = q.to_dtype(DType::F32)?;
+        let n_packed = q.dim(D::Minus1)? - 1;
+        let scale = (q.narrow(D::Minus1, n_packed, 1)? - 128.)?
+            .affine(LN_2, 0.)?
+            .exp()?;
+        let q = q.narrow(D::Minus1, 0, n_packed)?;
+        let q = match self {
+            Self::Q4 => {
+                let hi = (&q / 16.)?.floor()?;
+                let lo = (&q - (&hi * 16.)?)?;
+                Tensor::stack(&[hi, lo], D::Minus1)?.flatten_from(D::Minus2)?
+            }
+            _ => q,
+        };
+        (q - (max_q + 1.))?.broadcast_mul(&scale)?.to_dtype(dtype)
+    }
+
+    /// Append `k` and `v` to the quantized cache `prev`, returning the full keys and values. The
+    /// new tokens are returned as they are, only the cached ones are dequantized.
+    fn update_cache(
+        &self,
+        cache: &mut Option<(Tensor, Tensor)>,
+        prev: Option<(Tensor, Tensor)>,
+        k: Tensor,
+        v: Tensor,
+    ) -> candle_core::Result<(Tensor, Tensor)> {
+        let (k_q, v_q) = (self.quantize(&k)?, self.quantize(&v)?);
+        let (out, stored) = match prev {
+            None => ((k, v), (k_q, v_q)),
+            Some((prev_k, prev_v)) => (
+                (
+                    Tensor::cat(&[self.dequantize(&prev_k, k.dtype())?, k], 2)?,
+                    Tensor::cat(&[self.dequantize(&prev_v, v.dtype())?, v], 2)?,
+                ),
+                (
+                    Tensor::cat(&[prev_k, k_q], 2)?,
+                    Tensor::cat(&[prev_v, v_q], 2)?,
+                ),
+            ),
+        };
+        *cache = Some(stored);
+        Ok(out)
+    }
+
+    /// The keys and values of `cache` as a tensor of `dtype`.
+    fn read_cache(
+        &self,
+        cache: &(Tensor, Tensor),
+        dtype: DType,
+    ) -> candle_core::Result<(Tensor, Tensor)> {
+        let (k, v) = cache;
+        match self {
+            Self::F16 => Ok((k.clone(), v.clone())),
+            _ => Ok((self.dequantize(k, dtype)?, self.dequantize(v, dtype)?)),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Cache {
     cache: Arc<Mutex<LayerCaches>>,
@@ -72,6 +237,18 @@ impl Cache {
         self.xlora_cache.is_some()
     }
 
+    /// The keys and values held by the KV cache, as a tensor of `dtype` if it is quantized, for
+    /// models which attend over their cache without updating it.
+    pub(crate) fn read_kv_cache(
+        cache: &Option<(Tensor, Tensor)>,
+        dtype: DType,
+    ) -> Result<Option<(Tensor, Tensor)>, candle_core::Error> {
+        cache
+            .as_ref()
+            .map(|cache| kv_cache_dtype().read_cache(cache, dtype))
+            .transpose()
+    }
+
     /// Update the KV cache and return (k,v)
     pub(crate) fn update_kv_cache(
         cache: &mut Option<(Tensor, Tensor)>,
@@ -79,6 +256,11 @@ impl Cache {
         v: Tensor,
         slow_cat: bool,
     ) -> Result<(Tensor, Tensor), candle_core::Error> {
+        let kv_cache_dtype = kv_cache_dtype();
+        if kv_cache_dtype != KvCacheDtype::F16 {
+            let prev = cache.clone();
+            return kv_cache_dtype.update_cache(cache, prev, k, v);
+        }
         let (k, v) = match &*cache {
             None => (k, v),
             Some((k_cache, v_cache)) => {
@@ -106,6 +288,11 @@ impl Cache {
         sliding_window: Option<usize>,
         slow_cat: bool,
     ) -> Result<(Tensor, Tensor, Option<Tensor>), candle_core::Error> {
+        let kv_cache_dtype = kv_cache_dtype();
+        if kv_cache_dtype != KvCacheDtype::F16 && cache.is_none() {
+            let (k, v) = kv_cache_dtype.update_cache(cache, None, k, v)?;
+            return Ok((k, v, attention_mask.cloned()));
+        }
         let (k, v, attention_mask) = match cache.clone() {
             None => (k, v, attention_mask.cloned()),
             Some((mut prev_k, mut prev_v)) => {
@@ -137,6 +324,11 @@ impl Cache {
                         }
                     }
                 }
+                if kv_cache_dtype != KvCacheDtype::F16 {
+                    let (k, v) =
+                        kv_cache_dtype.update_cache(cache, Some((prev_k, prev_v)), k, v)?;
+                    return Ok((k, v, mask));
+                }
                 let (k, v) = if !slow_cat {
                     let k = candle_nn::ops::kvconcat(&prev_k, &k, 2)?;
                     let v = candle_nn::ops::kvconcat(&prev_v, &v, 2)?;
@@ -329,3 +521,172 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::KvCacheDtype;
+
+    /// A fixed tensor of values spread over [-1, 1], so that the test does not depend on a seed.
+    fn fixed(dims: &[usize], stride: usize, offset: usize) -> Tensor {
+        let n = dims.iter().product::<usize>();
+        let values = (0..n)
+            .map(|i| ((i * stride + offset) % 1009) as f32 / 504.5 - 1.)
+            .collect::<Vec<_>>();
+        Tensor::from_vec(values, dims, &Device::Cpu).unwrap()
+    }
+
+    /// Decode a fixed prompt through one attention layer whose cache is stored as `dtype`, as a
+    /// model does token by token, and return the logits of every position.
+    fn decode_logits(dtype: KvCacheDtype) -> Vec<Vec<f32>> {
+        let (heads, seq_len, head_dim, vocab) = (2, 16, 64, 32);
+        let q = fixed(&[1, heads, seq_len, head_dim], 7919, 1);
+        let k = fixed(&[1, heads, seq_len, head_dim], 6007, 2);
+        let v = fixed(&[1, heads, seq_len, head_dim], 4001, 3);
+        let lm_head = fixed(&[heads * head_dim, vocab], 3011, 4);
+
+        let mut cache = None;
+        (0..seq_len)
+            .map(|t| {
+                let token = |x: &Tensor| x.narrow(2, t, 1).unwrap().contiguous().unwrap();
+                let (k_t, v_t) = (token(&k), token(&v));
+                let (k, v) = match dtype {
+                    KvCacheDtype::F16 => {
+                        let kv = match cache.take() {
+                            None => (k_t, v_t),
+                            Some((k, v)) => (
+                                Tensor::cat(&[k, k_t], 2).unwrap(),
+                                Tensor::cat(&[v, v_t], 2).unwrap(),
+                            ),
+                        };
+                        cache = Some(kv.clone());
+                        kv
+                    }
+                    _ => {
+                        let prev = cache.clone();
+                        dtype.update_cache(&mut cache, prev, k_t, v_t).unwrap()
+                    }
+                };
+                let q_t = token(&q);
+                let scores =
+                    (q_t.matmul(&k.t().unwrap()).unwrap() / (head_dim as f64).sqrt()).unwrap();
+                let probs = candle_nn::ops::softmax_last_dim(&scores).unwrap();
+                probs
+                    .matmul(&v)
+                    .unwrap()
+                    .reshape((1, heads * head_dim))
+                    .unwrap()
+                    .matmul(&lm_head)
+                    .unwrap()
+                    .squeeze(0)
+                    .unwrap()
+                    .to_vec1::<f32>()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn softmax(logits: &[f32]) -> Vec<f32> {
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let exp = logits.iter().map(|x| (x - max).exp()).collect::<Vec<_>>();
+        let sum = exp.iter().sum::<f32>();
+        exp.iter().map(|x| x / sum).collect()
+    }
+
+    #[test]
+    fn test_quantized_kv_cache_logit_drift() {
+        let reference = decode_logits(KvCacheDtype::F16);
+        // Bounds on the KL divergence of the next token distribution from the one with an
+        // unquantized cache, and on the logit drift relative to the largest logit.
+        for (dtype, max_kl, max_drift) in
+            [(KvCacheDtype::Q8, 1e-3, 0.05), (KvCacheDtype::Q4, 0.1, 0.5)]
+        {
+            for (position, (expected, actual)) in
+                reference.iter().zip(decode_logits(dtype)).enumerate()
+            {
+                let (p, q) = (softmax(expected), softmax(&actual));
+                let kl = p.iter().zip(&q).map(|(p, q)| p * (p / q).ln()).sum::<f32>();
+                assert!(kl < max_kl, "{dtype}: KL divergence {kl} at {position}");
+
+                let largest = expected.iter().fold(0f32, |max, x| max.max(x.abs()));
+                let drift = expected
+                    .iter()
+                    .zip(&actual)
+                    .fold(0f32, |max, (e, a)| max.max((e - a).abs()))
+                    / largest;
+                assert!(
+                    drift < max_drift,
+                    "{dtype}: logit drift {drift} at {position}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantized_kv_cache_is_read_dequantized() {
+        let k = fixed(&[1, 2, 5, 64], 7919, 1);
+        let v = fixed(&[1, 2, 5, 64], 6007, 2);
+        for dtype in [KvCacheDtype::F16, KvCacheDtype::Q8, KvCacheDtype::Q4] {
+            let mut cache = None;
+            let (k_out, v_out) = match dtype {
+                KvCacheDtype::F16 => {
+                    cache = Some((k.clone(), v.clone()));
+                    (k.clone(), v.clone())
+                }
+                _ => dtype
+                    .update_cache(&mut cache, None, k.clone(), v.clone())
+                    .unwrap(),
+            };
+            let (k_read, v_read) = dtype
+                .read_cache(cache.as_ref().unwrap(), DType::F32)
+                .unwrap();
+            assert_eq!(k_read.dtype(), DType::F32);
+            assert_eq!(k_read.dims(), k.dims());
+            // What a later step reads agrees with what the first step attended over.
+            for (read, out) in [(k_read, k_out), (v_read, v_out)] {
+                let err = (read - out)
+                    .unwrap()
+                    .abs()
+                    .unwrap()
+                    .max_all()
+                    .unwrap()
+                    .to_scalar::<f32>()
+                    .unwrap();
+                assert!(err < 0.15, "{dtype}: error {err}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantized_kv_cache_round_trip() {
+        let x = Tensor::randn(0f32, 1., (1, 2, 5, 64), &Device::Cpu).unwrap();
+        // Bounds on the error relative to the largest value of each token.
+        for (dtype, tolerance) in [(KvCacheDtype::Q8, 0.01), (KvCacheDtype::Q4, 0.15)] {
+            let q = dtype.quantize(&x).unwrap();
+            assert_eq!(q.dtype(), DType::U8);
+            let packed_dim = if dtype == KvCacheDtype::Q8 { 64 } else { 32 };
+            assert_eq!(q.dims(), &[1, 2, 5, packed_dim + 1]);
+
+            let deq = dtype.dequantize(&q, DType::F32).unwrap();
+            let err = (&deq - &x)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .broadcast_div(&x.abs().unwrap().max_keepdim(3).unwrap())
+                .unwrap()
+                .max_all()
+                .unwrap()
+                .to_scalar::<f32>()
+                .unwrap();
+            assert!(err < tolerance, "{dtype}: relative error {err}");
+
+            // Quantizing what was dequantized is lossless, so the cache does not drift.
+            let requantized = dtype.quantize(&deq).unwrap();
+            assert_eq!(
+                requantized.flatten_all().unwrap().to_vec1::<u8>().unwrap(),
+                q.flatten_all().unwrap().to_vec1::<u8>().unwrap()
+            );
+        }
+    }
+}
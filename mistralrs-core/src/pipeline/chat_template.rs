@@ -214,6 +214,52 @@ fn tojson(value: Value, kwargs: Kwargs) -> Result<Value, Error> {
     })
 }
 
+/// Tool calls are stored flattened as `id`, `name` and `arguments` in a [`MessageContent`].
+/// Chat templates expect the OpenAI layout (`tool_call.function.name`), so restore it here.
+fn nest_tool_calls(calls: Vec<IndexMap<String, String>>) -> Vec<serde_json::Value> {
+    calls
+        .into_iter()
+        .map(|call| {
+            let arguments = call
+                .get("arguments")
+                .map(|args| {
+                    serde_json::from_str(args)
+                        .unwrap_or_else(|_| serde_json::Value::String(args.clone()))
+                })
+                .unwrap_or(serde_json::Value::Null);
+            serde_json::json!({
+                "id": call.get("id"),
+                "type": "function",
+                "function": {
+                    "name": call.get("name"),
+                    "arguments": arguments,
+                },
+            })
+        })
+        .collect()
+}
+
+fn chat_template_environment<'source>() -> Environment<'source> {
+    let mut env = Environment::new();
+
+    // enable python methods such as .strip()
+    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+
+    // https://github.com/huggingface/transformers/blob/76a33a10923ccc1074917f6b6a1e719e626b7dc9/src/transformers/tokenization_utils_base.py#L1842
+    env.set_lstrip_blocks(true);
+    env.set_trim_blocks(true);
+
+    env.add_function("raise_exception", raise_exception);
+    env.add_filter("tojson", tojson);
+    env
+}
+
+/// Check that a Jinja chat template compiles, so that a bad override fails before any request uses it.
+pub fn validate_chat_template(template: &str) -> Result<()> {
+    chat_template_environment().add_template("chat_template", template)?;
+    Ok(())
+}
+
 pub fn apply_chat_template_to(
     messages: Vec<IndexMap<String, MessageContent>>,
     add_generation_prompt: bool,
@@ -223,14 +269,7 @@ pub fn apply_chat_template_to(
     unk_tok: Option<String>,
     tools: Vec<Tool>,
 ) -> Result<String> {
-    let mut env = Environment::new();
-
-    // enable python methods such as .strip()
-    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
-
-    // https://github.com/huggingface/transformers/blob/76a33a10923ccc1074917f6b6a1e719e626b7dc9/src/transformers/tokenization_utils_base.py#L1842
-    env.set_lstrip_blocks(true);
-    env.set_trim_blocks(true);
+    let mut env = chat_template_environment();
 
     #[derive(Serialize, Deserialize)]
     struct UntaggedContent(#[serde(with = "either::serde_untagged")] MessageContent);
@@ -238,7 +277,13 @@ pub fn apply_chat_template_to(
     for message in messages {
         let mut new_message = IndexMap::new();
         for (k, v) in message {
-            new_message.insert(k, UntaggedContent(v));
+            let v = match (k.as_str(), v) {
+                ("tool_calls", Either::Right(calls)) => {
+                    Value::from_serialize(nest_tool_calls(calls))
+                }
+                (_, v) => Value::from_serialize(UntaggedContent(v)),
+            };
+            new_message.insert(k, v);
         }
         new_messages.push(new_message);
     }
@@ -264,8 +309,6 @@ pub fn apply_chat_template_to(
     };
 
     env.add_template("chat_template", &template)?;
-    env.add_function("raise_exception", raise_exception);
-    env.add_filter("tojson", tojson);
     let tmpl = env.get_template("chat_template").unwrap();
 
     let date = chrono::Utc::now();
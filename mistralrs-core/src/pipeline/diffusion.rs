@@ -227,6 +227,8 @@ impl Loader for DiffusionLoader {
                 cache_config: None,
                 cache_engine: None,
                 prompt_batchsize: None,
+                adapter_names: Vec::new(),
+                isq: None,
             }),
             dummy_cache: Cache::new(0, false),
         })))
@@ -0,0 +1,54 @@
+use candle_core::{DType, Tensor};
+
+use crate::{
+    sequence::{Sequence, SequenceState, StopReason},
+    EmbeddingChoice, EmbeddingResponse, EmbeddingUsage,
+};
+
+pub async fn send_embedding_responses(
+    input_seqs: &mut [&mut Sequence],
+    embeddings: Vec<Tensor>,
+    model: String,
+) -> candle_core::Result<()> {
+    if input_seqs.len() != embeddings.len() {
+        candle_core::bail!(
+            "Input seqs len ({}) does not match embeddings len ({})",
+            input_seqs.len(),
+            embeddings.len()
+        );
+    }
+
+    for (seq, embedding) in input_seqs.iter_mut().zip(embeddings) {
+        let embedding = embedding.flatten_all()?.to_dtype(DType::F32)?;
+        let norm = embedding.sqr()?.sum_all()?.sqrt()?.to_scalar::<f32>()?;
+        let embedding = if norm > 0. {
+            (embedding / norm as f64)?
+        } else {
+            embedding
+        };
+        seq.add_embedding_choice_to_group(EmbeddingChoice {
+            index: seq.get_response_index(),
+            embedding: embedding.to_vec1::<f32>()?,
+        });
+
+        let group = seq.get_mut_group();
+        group
+            .maybe_send_embedding_response(
+                EmbeddingResponse {
+                    data: group.get_embedding_choices(),
+                    model: model.clone(),
+                    usage: EmbeddingUsage {
+                        prompt_tokens: group.total_prompt_toks,
+                        total_tokens: group.total_toks,
+                    },
+                },
+                seq.responder(),
+            )
+            .await
+            .map_err(candle_core::Error::msg)?;
+
+        seq.set_state(SequenceState::Done(StopReason::GeneratedEmbedding));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use tokenizers::Tokenizer;
+
+/// Special tokens of the code models we know, as (prefix, suffix, middle).
+const KNOWN_FIM_TOKENS: &[(&str, &str, &str)] = &[
+    // StarCoder, StarCoder2
+    ("<fim_prefix>", "<fim_suffix>", "<fim_middle>"),
+    // Qwen2.5-Coder
+    ("<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"),
+    // DeepSeek-Coder
+    ("<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>"),
+    // CodeLlama
+    ("▁<PRE>", "▁<SUF>", "▁<MID>"),
+];
+
+/// The special tokens a model uses for fill-in-the-middle. A prompt is laid out as
+/// `<prefix token>prefix<suffix token>suffix<middle token>`, and the model generates the middle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FimTemplate {
+    pub prefix: String,
+    pub suffix: String,
+    pub middle: String,
+}
+
+impl FimTemplate {
+    /// The template of the model using `tokenizer`, if its vocabulary has the FIM tokens of a
+    /// known code model.
+    pub fn from_tokenizer(tokenizer: &Tokenizer) -> Option<Self> {
+        KNOWN_FIM_TOKENS
+            .iter()
+            .find(|(prefix, suffix, middle)| {
+                [prefix, suffix, middle]
+                    .iter()
+                    .all(|tok| tokenizer.token_to_id(tok).is_some())
+            })
+            .map(|(prefix, suffix, middle)| Self {
+                prefix: prefix.to_string(),
+                suffix: suffix.to_string(),
+                middle: middle.to_string(),
+            })
+    }
+
+    /// The prompt for generating the text between `prefix` and `suffix`.
+    pub fn apply(&self, prefix: &str, suffix: &str) -> String {
+        format!(
+            "{}{prefix}{}{suffix}{}",
+            self.prefix, self.suffix, self.middle
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokenizers::{models::wordlevel::WordLevel, AddedToken, Tokenizer};
+
+    use super::FimTemplate;
+
+    fn tokenizer(tokens: &[&str]) -> Tokenizer {
+        let vocab = tokens
+            .iter()
+            .chain(&["<unk>"])
+            .zip(0u32..)
+            .map(|(tok, i)| (tok.to_string(), i))
+            .collect::<HashMap<_, _>>();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.add_special_tokens(
+            &tokens
+                .iter()
+                .map(|tok| AddedToken::from(tok.to_string(), true))
+                .collect::<Vec<_>>(),
+        );
+        tokenizer
+    }
+
+    #[test]
+    fn test_fim_template() {
+        let template = FimTemplate::from_tokenizer(&tokenizer(&[
+            "<fim_prefix>",
+            "<fim_suffix>",
+            "<fim_middle>",
+        ]))
+        .unwrap();
+        assert_eq!(
+            template.apply("def add(a, b):\n    ", "\n    return c"),
+            "<fim_prefix>def add(a, b):\n    <fim_suffix>\n    return c<fim_middle>"
+        );
+
+        // All three tokens are required.
+        assert!(
+            FimTemplate::from_tokenizer(&tokenizer(&["<fim_prefix>", "<fim_suffix>"])).is_none()
+        );
+    }
+}
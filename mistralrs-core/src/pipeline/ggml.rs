@@ -387,6 +387,12 @@ impl Loader for GGMLLoader {
                 cache_config: None,
                 cache_engine: None,
                 prompt_batchsize: self.config.prompt_batchsize,
+                adapter_names: self
+                    .xlora_order
+                    .as_ref()
+                    .map(Ordering::adapter_names)
+                    .unwrap_or_default(),
+                isq: None,
             }),
         })))
     }
@@ -534,6 +534,12 @@ impl Loader for GGUFLoader {
                 cache_config,
                 cache_engine,
                 prompt_batchsize: self.config.prompt_batchsize,
+                adapter_names: self
+                    .xlora_order
+                    .as_ref()
+                    .map(Ordering::adapter_names)
+                    .unwrap_or_default(),
+                isq: None,
             }),
         })))
     }
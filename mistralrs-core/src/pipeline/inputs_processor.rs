@@ -144,6 +144,8 @@ pub mod text_models_inputs_processor {
         let mut seqlens_q = vec![0];
         let mut seqlens_k = vec![0];
         for (seq, mut ctxt) in input_seqs.iter().zip(toks) {
+            // Tokens restored from the prefix cache are already in the KV cache.
+            let chunk_offset_toks = chunk_offset_toks + seq.prefix_cache_len();
             let prompt_len = ctxt.len();
             let offset = last_n_context_len.unwrap_or_default();
             seqlen_offsets.push(offset.1 + chunk_offset_toks);
@@ -214,24 +216,15 @@ pub mod text_models_inputs_processor {
         }
 
         let mut tmp = Vec::new();
-        if last_n_context_len.is_some() {
-            for pos in (0..seqs_tensors.len())
-                .map(|i| {
-                    (*seqlen_offsets.get(i).unwrap() as i64
-                        ..*seqlen_offsets.get(i).unwrap() as i64 + max_len as i64)
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>()
-            {
-                tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
-            }
-        } else {
-            for pos in (0..seqs_tensors.len())
-                .map(|_| (0..max_len).map(|x| x as i64).collect::<Vec<_>>())
-                .collect::<Vec<_>>()
-            {
-                tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
-            }
+        for pos in (0..seqs_tensors.len())
+            .map(|i| {
+                (*seqlen_offsets.get(i).unwrap() as i64
+                    ..*seqlen_offsets.get(i).unwrap() as i64 + max_len as i64)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+        {
+            tmp.push(Tensor::from_slice(&pos, pos.len(), device)?.unsqueeze(0)?);
         }
         let max_q = *seqlens_q.iter().max().unwrap();
         let max_k = *seqlens_k.iter().max().unwrap();
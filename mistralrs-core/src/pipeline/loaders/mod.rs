@@ -257,6 +257,9 @@ pub enum ModelKind {
         draft: Box<ModelKind>,
     },
 
+    #[strum(to_string = "n-gram speculative: target: `{target}`")]
+    NGramSpeculative { target: Box<ModelKind> },
+
     #[strum(to_string = "anymoe: target: `{target}`")]
     AnyMoe { target: Box<ModelKind> },
 }
@@ -318,7 +321,7 @@ impl ModelKind {
 
                 [t.quantized_kind(), d.quantized_kind()].concat()
             }
-            AnyMoe { target } => target.quantized_kind(),
+            NGramSpeculative { target } | AnyMoe { target } => target.quantized_kind(),
         }
     }
 
@@ -343,7 +346,7 @@ impl ModelKind {
 
                 [t.adapted_kind(), d.adapted_kind()].concat()
             }
-            AnyMoe { target } => target.adapted_kind(),
+            NGramSpeculative { target } | AnyMoe { target } => target.adapted_kind(),
         }
     }
 }
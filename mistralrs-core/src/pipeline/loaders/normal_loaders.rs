@@ -63,6 +63,36 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
         flash_params: &FlashParams,
         flash_params_full: &FlashParams,
     ) -> candle_core::Result<Tensor>;
+    /// The final hidden state of the last token of each sequence, used as its embedding.
+    #[allow(clippy::too_many_arguments)]
+    fn forward_embeddings(
+        &self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _flash_params: &FlashParams,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Embeddings are not supported for this model.")
+    }
+    /// The logits at every position of each sequence, `(batch_size, seq_len, vocab_size)`.
+    fn forward_prompt_logits(
+        &self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _flash_params: &FlashParams,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Prompt logprobs are not supported for this model.")
+    }
+    /// The input embeddings, `(vocab_size, hidden_size)`, if the model exposes them.
+    fn token_embeddings(&self) -> Option<Tensor> {
+        None
+    }
     fn is_xlora(&self) -> bool;
     fn device(&self) -> &Device;
     fn cache(&self) -> &Cache;
@@ -73,6 +103,15 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
             "Activating adapters is only supported for models fine-tuned with LoRA."
         );
     }
+    /// Load a new adapter into the LoRA layers, returning the number of layers it was loaded into.
+    fn load_adapter(
+        &mut self,
+        _name: &str,
+        _vb: &VarBuilder,
+        _cfg: &LoraConfig,
+    ) -> candle_core::Result<usize> {
+        candle_core::bail!("Loading adapters is only supported for models fine-tuned with LoRA.");
+    }
     fn config(&self) -> &ModelConfigMetadata;
 }
 
@@ -148,7 +148,12 @@ macro_rules! get_paths {
         } else {
             None
         };
-        let template_filename = if let Some(ref p) = $this.chat_template {
+        // A chat template which is not a .json file is the template itself.
+        let template_filename = if let Some(p) = $this
+            .chat_template
+            .as_ref()
+            .filter(|p| p.ends_with(".json"))
+        {
             info!("Using chat template file at `{p}`");
             Some(PathBuf::from_str(p)?)
         } else {
@@ -235,13 +240,14 @@ macro_rules! get_paths_gguf {
         ));
         let model_id = std::path::Path::new(&this_model_id);
 
-        let chat_template = if let Some(ref p) = $this.chat_template {
-            if p.ends_with(".json") {
-                info!("Using chat template file at `{p}`");
-                Some(PathBuf::from_str(p)?)
-            } else {
-                panic!("Specified chat template file must end with .json");
-            }
+        // A chat template which is not a .json file is the template itself.
+        let chat_template = if let Some(p) = $this
+            .chat_template
+            .as_ref()
+            .filter(|p| p.ends_with(".json"))
+        {
+            info!("Using chat template file at `{p}`");
+            Some(PathBuf::from_str(p)?)
         } else {
             if $this.model_id.is_none() {
                 None
@@ -2,17 +2,22 @@ mod amoe;
 mod cache_manager;
 pub mod chat_template;
 mod diffusion;
+mod embedding;
+mod fim;
 mod ggml;
 mod gguf;
 mod inputs_processor;
 mod isq;
 mod loaders;
 mod macros;
+mod ngram_speculative;
 mod normal;
 mod paths;
 mod processing;
 mod sampling;
 mod speculative;
+#[cfg(any(test, feature = "testing"))]
+pub(crate) mod testing;
 mod vision;
 
 pub use super::diffusion_models::DiffusionGenerationParams;
@@ -24,6 +29,8 @@ use crate::prefix_cacher::PrefixCacheManager;
 pub use amoe::{AnyMoeLoader, AnyMoePipeline};
 use chat_template::ChatTemplate;
 pub use diffusion::{DiffusionLoader, DiffusionLoaderBuilder, DiffusionSpecificConfig};
+use embedding::send_embedding_responses;
+pub use fim::FimTemplate;
 pub use ggml::{GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig};
 pub use gguf::{GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig};
 use image::DynamicImage;
@@ -38,26 +45,38 @@ pub use loaders::{
     VLlamaLoader, VisionLoaderType, VisionModel, VisionModelLoader,
 };
 use mistralrs_quant::IsqType;
+pub use ngram_speculative::{
+    NGramSpeculativeConfig, NGramSpeculativeLoader, NGramSpeculativePipeline,
+};
 pub use normal::{NormalLoader, NormalLoaderBuilder, NormalSpecificConfig};
-pub(crate) use paths::{get_chat_template, get_model_paths, get_xlora_paths, XLoraPaths};
+pub(crate) use paths::{
+    get_chat_template, get_lora_adapter_paths, get_model_paths, get_xlora_paths, XLoraPaths,
+};
 pub(crate) use processing::{
     apply_chat_template, BasicProcessor, MessagesAction, Processor, ProcessorCreator,
 };
 use rand_isaac::Isaac64Rng;
-pub use speculative::{SpeculativeConfig, SpeculativeLoader, SpeculativePipeline};
+use rustc_hash::FxHasher;
+use sampling::record_prompt_logprobs;
+pub use speculative::{
+    SpeculativeConfig, SpeculativeLoader, SpeculativePipeline, SpeculativeStats, SPECULATIVE_STATS,
+};
 use std::any::Any;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::num::NonZeroUsize;
 use std::sync::Arc;
+#[cfg(any(test, feature = "testing"))]
+pub use testing::TestPipeline;
 use tokenizers::Tokenizer;
 pub use vision::{VisionLoader, VisionLoaderBuilder, VisionSpecificConfig};
 
 use anyhow::Result;
 use candle_core::{DType, Device, IndexOp, Tensor, Var};
 
-use crate::sequence::Sequence;
+use crate::sequence::{SeqStepType, Sequence};
 
-pub use self::cache_manager::{Cache, CacheManager, LayerCaches};
+pub use self::cache_manager::{set_kv_cache_dtype, Cache, CacheManager, KvCacheDtype, LayerCaches};
 pub use self::inputs_processor::{
     text_models_inputs_processor, InputsProcessor, InputsProcessorType,
 };
@@ -79,6 +98,28 @@ pub struct GeneralMetadata {
     pub cache_config: Option<CacheConfig>,
     pub cache_engine: Option<CacheEngine>,
     pub prompt_batchsize: Option<NonZeroUsize>,
+    /// Names of the loaded adapters, empty if there are none.
+    pub adapter_names: Vec<String>,
+    /// The ISQ type the weights were quantized to when loading, if any.
+    pub isq: Option<IsqType>,
+}
+
+impl GeneralMetadata {
+    /// The `system_fingerprint` of responses from the model `model_id` with `adapter_names`
+    /// loaded: a hash of these, of the model kind, which includes GGUF quantization, of the ISQ
+    /// type and of the dtype. It is the same whenever the same model is loaded the same way, so
+    /// clients can tell when the model behind the server changed.
+    pub fn system_fingerprint(&self, model_id: &str, adapter_names: &[String]) -> String {
+        let mut adapter_names = adapter_names.to_vec();
+        adapter_names.sort();
+        let mut hasher = FxHasher::default();
+        model_id.hash(&mut hasher);
+        self.kind.to_string().hash(&mut hasher);
+        format!("{:?}", self.isq).hash(&mut hasher);
+        format!("{:?}", self.activation_dtype).hash(&mut hasher);
+        adapter_names.hash(&mut hasher);
+        format!("fp_{:016x}", hasher.finish())
+    }
 }
 
 pub enum AdapterInstruction {
@@ -126,6 +167,11 @@ pub trait CacheManagerMixin {
 pub trait AdapterActivationMixin {
     /// Returns the number of activated adapters.
     fn activate_adapters(&mut self, adapters: Vec<String>) -> Result<usize>;
+    /// Load the LoRA adapter at `source`, a local directory or a Hugging Face model ID, so that it
+    /// may be activated as `name`. Returns the number of layers it was loaded into.
+    fn load_adapter(&mut self, _name: String, _source: String) -> Result<usize> {
+        anyhow::bail!("Loading adapters at runtime is not supported for this model.")
+    }
 }
 
 pub trait MetadataMixin {
@@ -216,6 +262,7 @@ pub enum CacheBackendMetadata<'a> {
 pub enum ForwardInputsResult {
     CausalGeneration { logits: Tensor },
     Image { images: Vec<DynamicImage> },
+    Embeddings { embeddings: Tensor },
 }
 
 impl ForwardInputsResult {
@@ -227,6 +274,9 @@ impl ForwardInputsResult {
             Self::Image { images } => Ok(Self::Image {
                 images: vec![images[bs_idx].clone()],
             }),
+            Self::Embeddings { embeddings } => Ok(Self::Embeddings {
+                embeddings: embeddings.i(bs_idx)?,
+            }),
         }
     }
 
@@ -236,6 +286,9 @@ impl ForwardInputsResult {
                 logits: logits.to_device(device)?,
             }),
             Self::Image { .. } => Ok(self.clone()),
+            Self::Embeddings { embeddings } => Ok(Self::Embeddings {
+                embeddings: embeddings.to_device(device)?,
+            }),
         }
     }
 }
@@ -256,6 +309,44 @@ pub trait Pipeline:
         inputs: Box<dyn Any>,
     ) -> Result<ForwardInputsResult, candle_core::Error>;
 
+    /// Run the model without the LM head, returning the final hidden state of the last token of
+    /// each sequence.
+    fn forward_embeddings(&mut self, _inputs: Box<dyn Any>) -> Result<Tensor, candle_core::Error> {
+        candle_core::bail!("Embeddings are not supported for this pipeline.")
+    }
+
+    /// Run the model, returning the logits at every position of each sequence,
+    /// `(batch_size, seq_len, vocab_size)`, for prompt logprobs.
+    fn forward_prompt_logits(
+        &mut self,
+        _inputs: Box<dyn Any>,
+    ) -> Result<Tensor, candle_core::Error> {
+        candle_core::bail!("Prompt logprobs are not supported for this pipeline.")
+    }
+
+    /// Run the model and record the prompt logprobs of the sequences at `seq_indices` of
+    /// `input_seqs`, returning the logits of the last position like `forward_inputs`.
+    fn forward_inputs_with_prompt_logprobs(
+        &mut self,
+        inputs: Box<dyn Any>,
+        input_seqs: &mut [&mut Sequence],
+        seq_indices: &[usize],
+    ) -> Result<ForwardInputsResult, candle_core::Error> {
+        let logits = self.forward_prompt_logits(inputs)?;
+        for (logit_idx, seq_idx) in seq_indices.iter().enumerate() {
+            record_prompt_logprobs(input_seqs[*seq_idx], &logits.i(logit_idx)?)?;
+        }
+        let last = logits.dim(1)? - 1;
+        Ok(ForwardInputsResult::CausalGeneration {
+            logits: logits.narrow(1, last, 1)?,
+        })
+    }
+
+    /// The input embeddings of the model, `(vocab_size, hidden_size)`, for contrastive search.
+    fn token_embeddings(&self) -> Option<Tensor> {
+        None
+    }
+
     #[allow(clippy::too_many_arguments)]
     async fn step(
         &mut self,
@@ -282,6 +373,10 @@ pub trait Pipeline:
                 );
 
                 let mut logits = vec![None; input_seqs.len()];
+                let is_embedding = matches!(
+                    input_seqs[0].sequence_stepping_type(),
+                    SeqStepType::Embedding
+                );
 
                 for (i, inputs) in inputs_iter.enumerate() {
                     let InputProcessorOutput {
@@ -341,7 +436,19 @@ pub trait Pipeline:
                         }
                     }
 
-                    let raw_logits = self.forward_inputs(inputs)?;
+                    let wants_prompt_logprobs = is_prompt
+                        && seq_indices
+                            .iter()
+                            .any(|i| input_seqs[*i].prompt_top_logprobs().is_some());
+                    let raw_logits = if is_embedding {
+                        ForwardInputsResult::Embeddings {
+                            embeddings: self.forward_embeddings(inputs)?,
+                        }
+                    } else if wants_prompt_logprobs {
+                        self.forward_inputs_with_prompt_logprobs(inputs, input_seqs, &seq_indices)?
+                    } else {
+                        self.forward_inputs(inputs)?
+                    };
 
                     for (logit_idx, seq_idx) in seq_indices.into_iter().enumerate() {
                         logits[seq_idx] = Some(raw_logits.index_bs(logit_idx)?);
@@ -368,46 +475,73 @@ pub trait Pipeline:
 
                 match &logits[0] {
                     ForwardInputsResult::CausalGeneration { .. } => {
-                        self.sample_causal_gen(
+                        // A chunk before the end of its prompt has nothing to sample.
+                        let (mut sampled_seqs, logits): (Vec<_>, Vec<_>) = input_seqs
+                            .iter_mut()
+                            .zip(logits)
+                            .filter(|(seq, _)| !seq.is_partial_prefill())
+                            .map(|(seq, r)| {
+                                #[allow(irrefutable_let_patterns)]
+                                let ForwardInputsResult::CausalGeneration { logits } = r
+                                else {
+                                    unreachable!(
+                                        "All results must have same type, `CausalGeneration`"
+                                    )
+                                };
+                                // Sample with the whole prompt as the context, also when only
+                                // its end was run.
+                                seq.reset_prefill_toks();
+                                (&mut **seq, logits)
+                            })
+                            .unzip();
+                        if !sampled_seqs.is_empty() {
+                            self.sample_causal_gen(
+                                &mut sampled_seqs,
+                                logits,
+                                prefix_cacher,
+                                disable_eos_stop,
+                                rng,
+                            )
+                            .await?;
+                        }
+                    }
+                    ForwardInputsResult::Image { .. } => {
+                        send_responses(
                             input_seqs,
                             logits
                                 .into_iter()
                                 .map(|r| {
                                     #[allow(irrefutable_let_patterns)]
-                                    let ForwardInputsResult::CausalGeneration { logits } = r
+                                    let ForwardInputsResult::Image { images } = r
                                     else {
                                         unreachable!(
                                             "All results must have same type, `CausalGeneration`"
                                         )
                                     };
-                                    logits
+                                    images
+                                        .into_iter()
+                                        .next()
+                                        .expect("Must have at least 1 element.")
                                 })
                                 .collect::<Vec<_>>(),
-                            prefix_cacher,
-                            disable_eos_stop,
-                            rng,
                         )
                         .await?;
                     }
-                    ForwardInputsResult::Image { .. } => {
-                        send_responses(
+                    ForwardInputsResult::Embeddings { .. } => {
+                        send_embedding_responses(
                             input_seqs,
                             logits
                                 .into_iter()
                                 .map(|r| {
-                                    #[allow(irrefutable_let_patterns)]
-                                    let ForwardInputsResult::Image { images } = r
-                                    else {
+                                    let ForwardInputsResult::Embeddings { embeddings } = r else {
                                         unreachable!(
-                                            "All results must have same type, `CausalGeneration`"
+                                            "All results must have same type, `Embeddings`"
                                         )
                                     };
-                                    images
-                                        .into_iter()
-                                        .next()
-                                        .expect("Must have at least 1 element.")
+                                    embeddings
                                 })
                                 .collect::<Vec<_>>(),
+                            self.name(),
                         )
                         .await?;
                     }
@@ -440,6 +574,10 @@ pub trait Pipeline:
                 );
 
                 let mut logits = vec![None; input_seqs.len()];
+                let is_embedding = matches!(
+                    input_seqs[0].sequence_stepping_type(),
+                    SeqStepType::Embedding
+                );
 
                 for inputs in inputs_iter {
                     let InputProcessorOutput {
@@ -447,7 +585,19 @@ pub trait Pipeline:
                         seq_indices,
                     } = inputs.map_err(candle_core::Error::msg)?;
 
-                    let raw_logits = self.forward_inputs(inputs)?;
+                    let wants_prompt_logprobs = is_prompt
+                        && seq_indices
+                            .iter()
+                            .any(|i| input_seqs[*i].prompt_top_logprobs().is_some());
+                    let raw_logits = if is_embedding {
+                        ForwardInputsResult::Embeddings {
+                            embeddings: self.forward_embeddings(inputs)?,
+                        }
+                    } else if wants_prompt_logprobs {
+                        self.forward_inputs_with_prompt_logprobs(inputs, input_seqs, &seq_indices)?
+                    } else {
+                        self.forward_inputs(inputs)?
+                    };
 
                     for (logit_idx, seq_idx) in seq_indices.into_iter().enumerate() {
                         logits[seq_idx] = Some(raw_logits.index_bs(logit_idx)?);
@@ -505,6 +655,24 @@ pub trait Pipeline:
                         )
                         .await?;
                     }
+                    ForwardInputsResult::Embeddings { .. } => {
+                        send_embedding_responses(
+                            input_seqs,
+                            logits
+                                .into_iter()
+                                .map(|r| {
+                                    let ForwardInputsResult::Embeddings { embeddings } = r else {
+                                        unreachable!(
+                                            "All results must have same type, `Embeddings`"
+                                        )
+                                    };
+                                    embeddings
+                                })
+                                .collect::<Vec<_>>(),
+                            self.name(),
+                        )
+                        .await?;
+                    }
                 }
                 Ok(())
             }
@@ -778,4 +946,151 @@ mod tests {
 
         test_with_inputs(&templates, &expected_outputs, inputs);
     }
+
+    #[test]
+    /// The prompt `dry_run` returns for a system message and a user message, as the model sees it.
+    fn test_dry_run_prompt() {
+        let templates = [
+            (true, "<s>", "</s>", "<unk>", "{{ bos_token }}{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}"),
+        ];
+        let expected_outputs = [
+            "<s><|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHello<|im_end|>\n<|im_start|>assistant\n",
+        ];
+        let inputs = vec![
+            hashmap! {
+                "role".to_string() => Either::Left("system".to_string()),
+                "content".to_string() => Either::Left("Be brief.".to_string())
+            },
+            hashmap! {
+                "role".to_string() => Either::Left("user".to_string()),
+                "content".to_string() => Either::Left("Hello".to_string())
+            },
+        ];
+        test_with_inputs(&templates, &expected_outputs, inputs);
+    }
+
+    #[test]
+    fn test_chat_template_override() {
+        use super::chat_template::{
+            apply_chat_template_to, validate_chat_template, ChatTemplateValue,
+        };
+
+        let template = "{% for message in messages %}<{{ message['role'] }}>{{ message['content'] }}</{{ message['role'] }}>{% endfor %}{% if add_generation_prompt %}<assistant>{% endif %}";
+        validate_chat_template(template).unwrap();
+
+        let messages = vec![
+            hashmap! {
+                "role".to_string() => Either::Left("system".to_string()),
+                "content".to_string() => Either::Left("Be brief.".to_string())
+            },
+            hashmap! {
+                "role".to_string() => Either::Left("user".to_string()),
+                "content".to_string() => Either::Left("Hello".to_string())
+            },
+        ];
+        let output = apply_chat_template_to(
+            messages,
+            true,
+            &ChatTemplateValue(Either::Left(template.to_string())),
+            None,
+            None,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            "<system>Be brief.</system><user>Hello</user><assistant>"
+        );
+
+        assert!(validate_chat_template("{% for message in messages %}").is_err());
+    }
+
+    #[test]
+    fn test_chat_template_message_names() {
+        use super::chat_template::{apply_chat_template_to, ChatTemplateValue};
+
+        // ChatML with names, as used for multi-agent conversations.
+        let template = "{% for message in messages %}{{ '<|im_start|>' + message['role'] }}{% if message['name'] %}{{ ' name=' + message['name'] }}{% endif %}{{ '\n' + message['content'] + '<|im_end|>\n' }}{% endfor %}";
+        let messages = vec![
+            hashmap! {
+                "role".to_string() => Either::Left("user".to_string()),
+                "name".to_string() => Either::Left("alice".to_string()),
+                "content".to_string() => Either::Left("Hi!".to_string())
+            },
+            hashmap! {
+                "role".to_string() => Either::Left("assistant".to_string()),
+                "content".to_string() => Either::Left("Hello.".to_string())
+            },
+        ];
+        let output = apply_chat_template_to(
+            messages,
+            false,
+            &ChatTemplateValue(Either::Left(template.to_string())),
+            None,
+            None,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            "<|im_start|>user name=alice\nHi!<|im_end|>\n<|im_start|>assistant\nHello.<|im_end|>\n"
+        );
+    }
+
+    #[test]
+    fn test_system_fingerprint_is_stable() {
+        use super::{GeneralMetadata, ModelKind};
+        use candle_core::DType;
+        use mistralrs_quant::IsqType;
+
+        let metadata = |isq, activation_dtype| GeneralMetadata {
+            max_seq_len: 4096,
+            tok_trie: None,
+            has_no_kv_cache: false,
+            num_hidden_layers: 32,
+            eos_tok: vec![2],
+            kind: ModelKind::Normal,
+            is_xlora: false,
+            activation_dtype,
+            sliding_window: None,
+            cache_config: None,
+            cache_engine: None,
+            prompt_batchsize: None,
+            adapter_names: Vec::new(),
+            isq,
+        };
+        let loaded = metadata(Some(IsqType::Q4K), DType::BF16);
+        let adapters = ["math".to_string(), "code".to_string()];
+        let fingerprint = loaded.system_fingerprint("mistral", &adapters);
+        assert!(fingerprint.starts_with("fp_"));
+
+        // Every response from the same model has the same fingerprint, whatever the adapter order.
+        assert_eq!(loaded.system_fingerprint("mistral", &adapters), fingerprint);
+        let reordered = [adapters[1].clone(), adapters[0].clone()];
+        assert_eq!(
+            loaded.system_fingerprint("mistral", &reordered),
+            fingerprint
+        );
+        let reloaded = metadata(Some(IsqType::Q4K), DType::BF16);
+        assert_eq!(
+            reloaded.system_fingerprint("mistral", &adapters),
+            fingerprint
+        );
+
+        // Another model, adapter set, quantization or dtype changes it.
+        assert_ne!(loaded.system_fingerprint("llama", &adapters), fingerprint);
+        assert_ne!(
+            loaded.system_fingerprint("mistral", &adapters[..1]),
+            fingerprint
+        );
+        let unquantized = metadata(None, DType::BF16);
+        assert_ne!(
+            unquantized.system_fingerprint("mistral", &adapters),
+            fingerprint
+        );
+        let f16 = metadata(Some(IsqType::Q4K), DType::F16);
+        assert_ne!(f16.system_fingerprint("mistral", &adapters), fingerprint);
+    }
 }
@@ -0,0 +1,502 @@
+use std::{
+    any::Any,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result as anyhowResult;
+use candle_core::{DType, Device, IndexOp, Result, Tensor};
+use mistralrs_quant::IsqType;
+use rand_isaac::Isaac64Rng;
+use tokenizers::Tokenizer;
+use tracing::warn;
+
+use crate::{
+    get_mut_arcmutex,
+    pipeline::{sampling::finish_or_add_toks_to_seq, AdapterInstruction, Cache},
+    prefix_cacher::PrefixCacheManager,
+    sampler::Logprobs,
+    sequence::{Sequence, SequenceRecognizer},
+    DeviceMapMetadata, Loader, ModelKind, PagedAttentionConfig, Pipeline, TokenSource,
+    TryIntoDType, SPECULATIVE_STATS,
+};
+
+use super::{
+    cache_manager::DefaultCacheManager, chat_template::ChatTemplate, AdapterActivationMixin,
+    AnyMoePipelineMixin, CacheBackendMetadata, CacheInstruction, CacheManager, CacheManagerMixin,
+    ForwardInputsResult, GeneralMetadata, IsqPipelineMixin, MetadataMixin, ModelCategory,
+    ModelPaths, PreProcessingMixin, Processor,
+};
+
+/// A loader for an n-gram speculative pipeline around the model of a [`Loader`].
+pub struct NGramSpeculativeLoader {
+    pub target: Box<dyn Loader>,
+    pub config: NGramSpeculativeConfig,
+}
+
+impl Loader for NGramSpeculativeLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        if paged_attn_config.is_some() {
+            warn!(
+                "Speculative decoding does not currently support PagedAttention, running without"
+            );
+        }
+        let target = self.target.load_model_from_hf(
+            revision,
+            token_source,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            None,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            NGramSpeculativePipeline::new(target, self.config),
+        )))
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        if paged_attn_config.is_some() {
+            warn!(
+                "Speculative decoding does not currently support PagedAttention, running without"
+            );
+        }
+        let target = self.target.load_model_from_path(
+            paths,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            None,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            NGramSpeculativePipeline::new(target, self.config),
+        )))
+    }
+    fn get_id(&self) -> String {
+        format!(
+            "N-gram speculative: tgt = `{}`, gamma = `{}`",
+            self.target.get_id(),
+            self.config.gamma,
+        )
+    }
+    fn get_kind(&self) -> ModelKind {
+        ModelKind::NGramSpeculative {
+            target: Box::new(self.target.get_kind()),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+/// Metadata for an n-gram speculative pipeline
+pub struct NGramSpeculativeConfig {
+    /// At most γ tokens to draft per step
+    pub gamma: usize,
+    /// Longest n-gram at the end of the sequence to look up earlier in it
+    pub max_ngram: usize,
+}
+
+impl NGramSpeculativeConfig {
+    pub const DEFAULT_MAX_NGRAM: usize = 3;
+}
+
+/// Draft up to `gamma` tokens by prompt lookup: the tokens which followed the latest earlier
+/// occurrence of the n-gram ending `toks`, for the longest n up to `max_ngram` which has one.
+fn propose_ngram_draft(toks: &[u32], max_ngram: usize, gamma: usize) -> Vec<u32> {
+    for n in (1..=max_ngram.min(toks.len().saturating_sub(1))).rev() {
+        let ngram = &toks[toks.len() - n..];
+        if let Some(start) = (0..toks.len() - n)
+            .rev()
+            .find(|start| &toks[*start..*start + n] == ngram)
+        {
+            let continuation = &toks[start + n..];
+            return continuation[..continuation.len().min(gamma)].to_vec();
+        }
+    }
+    Vec::new()
+}
+
+/// The tokens to add to the sequence for `draft`, sampling the target model at each drafted
+/// position with `sample(position, accepted_draft)`. Draft tokens are accepted for as long as the
+/// target samples them too, and the target's own sample is kept at the first disagreement, or
+/// after the last draft token if all were accepted. Sampling stops there, so every kept token is
+/// sampled exactly as decoding without a draft would have.
+fn verify_draft(
+    draft: &[u32],
+    mut sample: impl FnMut(usize, &[u32]) -> Result<Logprobs>,
+) -> Result<Vec<Logprobs>> {
+    let mut accepted = Vec::new();
+    for position in 0..=draft.len() {
+        let sampled = sample(position, &draft[..position])?;
+        let agrees = draft.get(position) == Some(&sampled.token);
+        accepted.push(sampled);
+        if !agrees {
+            break;
+        }
+    }
+    Ok(accepted)
+}
+
+/// Speculative decoding without a draft model, by prompt lookup: tokens are drafted from the
+/// sequence itself, by continuing its last n-gram like its latest earlier occurrence did, and
+/// verified by running the target model once over all of them. This speeds up outputs which
+/// repeat their context, such as code edits or extraction, at no cost beyond a longer forward pass.
+///
+/// Prompts, constrained sequences and steps which find no n-gram are run by the target model
+/// as usual.
+pub struct NGramSpeculativePipeline {
+    target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    config: NGramSpeculativeConfig,
+    metadata: Arc<GeneralMetadata>,
+    category: ModelCategory,
+}
+
+impl NGramSpeculativePipeline {
+    pub fn new(
+        target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        config: NGramSpeculativeConfig,
+    ) -> Self {
+        let metadata = get_mut_arcmutex!(target).get_metadata().clone();
+        let category = get_mut_arcmutex!(target).category();
+        Self {
+            target,
+            config,
+            metadata,
+            category,
+        }
+    }
+
+    /// Drop the last `n` positions of the target model's KV cache.
+    fn narrow_cache(&self, n: usize) -> Result<()> {
+        let target = get_mut_arcmutex!(self.target);
+        for (k, v) in target.cache().lock().iter_mut().flatten() {
+            *k = k.i((.., .., ..k.dims()[2] - n, ..))?;
+            *v = v.i((.., .., ..v.dims()[2] - n, ..))?;
+        }
+        if target.get_metadata().is_xlora {
+            for (k, v) in target.cache().xlora_lock().iter_mut().flatten() {
+                *k = k.i((.., .., ..k.dims()[2] - n, ..))?;
+                *v = v.i((.., .., ..v.dims()[2] - n, ..))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PreProcessingMixin for NGramSpeculativePipeline {
+    fn get_processor(&self) -> Arc<dyn Processor> {
+        get_mut_arcmutex!(self.target).get_processor()
+    }
+    fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+        get_mut_arcmutex!(self.target).get_chat_template()
+    }
+    fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+        get_mut_arcmutex!(self.target).get_input_processor_config()
+    }
+}
+
+impl IsqPipelineMixin for NGramSpeculativePipeline {
+    fn re_isq_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
+        get_mut_arcmutex!(self.target).re_isq_model(dtype)
+    }
+}
+
+impl CacheManagerMixin for NGramSpeculativePipeline {
+    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
+        DefaultCacheManager.clone_in_cache(
+            &*get_mut_arcmutex!(self.target),
+            seqs,
+            modify_draft_cache,
+        );
+    }
+    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
+        DefaultCacheManager.clone_out_cache(
+            &*get_mut_arcmutex!(self.target),
+            seqs,
+            modify_draft_cache,
+        );
+    }
+    fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool) {
+        DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(self.target), modify_draft_cache);
+        if reset_non_granular {
+            self.reset_non_granular_state()
+        }
+    }
+    fn cache(&self) -> &Cache {
+        unreachable!()
+    }
+}
+
+impl AdapterActivationMixin for NGramSpeculativePipeline {
+    /// Returns the number of activated adapters.
+    fn activate_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
+        get_mut_arcmutex!(self.target).activate_adapters(adapters)
+    }
+}
+
+impl MetadataMixin for NGramSpeculativePipeline {
+    fn device(&self) -> Device {
+        get_mut_arcmutex!(self.target).device()
+    }
+    fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+        get_mut_arcmutex!(self.target).tokenizer()
+    }
+    fn name(&self) -> String {
+        format!(
+            "N-gram speculative: tgt = `{}`, gamma = `{}`",
+            get_mut_arcmutex!(self.target).name(),
+            self.config.gamma,
+        )
+    }
+    fn reset_non_granular_state(&self) {
+        get_mut_arcmutex!(self.target).reset_non_granular_state();
+    }
+    fn get_metadata(&self) -> Arc<GeneralMetadata> {
+        self.metadata.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl Pipeline for NGramSpeculativePipeline {
+    fn forward_inputs(&mut self, _inputs: Box<dyn Any>) -> Result<ForwardInputsResult> {
+        unreachable!()
+    }
+    async fn sample_causal_gen(
+        &self,
+        _seqs: &mut [&mut Sequence],
+        _logits: Vec<Tensor>,
+        _prefix_cacher: &mut PrefixCacheManager,
+        _disable_eos_stop: bool,
+        _rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    ) -> Result<()> {
+        unreachable!()
+    }
+    async fn step(
+        &mut self,
+        input_seqs: &mut [&mut Sequence],
+        is_prompt: bool,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+        backend_metadata: CacheBackendMetadata<'_>,
+    ) -> Result<()> {
+        // Tokens of a constrained sequence would have to be checked against its grammar as well.
+        let draft = match input_seqs {
+            [seq] if !is_prompt && matches!(seq.recognizer, SequenceRecognizer::None) => {
+                propose_ngram_draft(seq.get_toks(), self.config.max_ngram, self.config.gamma)
+            }
+            _ => Vec::new(),
+        };
+        if draft.is_empty() {
+            return self
+                .target
+                .lock()
+                .await
+                .step(
+                    input_seqs,
+                    is_prompt,
+                    prefix_cacher,
+                    disable_eos_stop,
+                    rng,
+                    backend_metadata,
+                )
+                .await;
+        }
+        let CacheBackendMetadata::DefaultInstructions { pre_op, post_op } = backend_metadata else {
+            unreachable!("Speculative decoding runs without PagedAttention.")
+        };
+
+        let (CacheInstruction::In(adapter_inst)
+        | CacheInstruction::Nothing(adapter_inst)
+        | CacheInstruction::Reset { adapter_inst, .. }) = &pre_op
+        else {
+            unreachable!("Unreachable PRE cache op.")
+        };
+        if let AdapterInstruction::Activate(adapters) = adapter_inst {
+            self.activate_adapters(adapters.clone())
+                .map_err(|e| candle_core::Error::msg(e.to_string()))?;
+        }
+        match pre_op {
+            CacheInstruction::In(_) => self.clone_in_cache(input_seqs, false),
+            CacheInstruction::Reset {
+                reset_non_granular, ..
+            } => self.set_none_cache(reset_non_granular, false),
+            _ => (),
+        }
+
+        let seq = &mut input_seqs[0];
+
+        // ======================= Run the model with the last token and the draft. ============================
+        let initial_cache_len = get_mut_arcmutex!(self.target).cache().lock()[0]
+            .as_ref()
+            .map(|(k, _)| k.dims()[2])
+            .unwrap_or(0);
+        let mut prefill_tokens = vec![*seq.get_toks().last().unwrap()];
+        prefill_tokens.extend(&draft);
+        seq.set_prefill_toks(prefill_tokens);
+        let inputs = self
+            .get_processor()
+            .inputs_processor()
+            .process_inputs(
+                self.tokenizer(),
+                &mut [seq],
+                true, // use the "prefill" tokens
+                self.metadata.is_xlora,
+                &self.device(),
+                self.metadata.has_no_kv_cache,
+                Some((draft.len() + 1, initial_cache_len)),
+                self.get_input_processor_config(),
+                None,
+                None,
+            )
+            .nth(0)
+            .unwrap()
+            .map_err(candle_core::Error::msg)?
+            .inputs;
+        let logits = get_mut_arcmutex!(self.target).forward_inputs(inputs)?;
+        #[allow(irrefutable_let_patterns)]
+        let ForwardInputsResult::CausalGeneration { logits } = logits
+        else {
+            candle_core::bail!("Speculative decoding requires `CausalGeneration` forward results");
+        };
+        seq.reset_prefill_toks();
+
+        // ======================= Verify the draft. ============================
+        let logits = logits.squeeze(0)?.to_dtype(DType::F32)?;
+        let sampler = seq.sampler();
+        let rng = seq.rng().unwrap_or(rng);
+        let return_logprobs = seq.return_logprobs();
+        let mut context = seq.get_toks().to_vec();
+        let context_len = context.len();
+        let accepted = verify_draft(&draft, |position, accepted_draft| {
+            context.truncate(context_len);
+            context.extend(accepted_draft);
+            sampler.sample(
+                logits.get(position)?,
+                &context,
+                return_logprobs,
+                rng.clone(),
+                false,
+            )
+        })?;
+        SPECULATIVE_STATS.record(draft.len(), accepted.len() - 1);
+
+        // The cache holds the last token and every draft token, but the last kept token is run next.
+        self.narrow_cache(draft.len() + 1 - accepted.len())?;
+
+        let eos_tok = if disable_eos_stop {
+            None
+        } else {
+            Some(&self.metadata.eos_tok[..])
+        };
+        for token in accepted {
+            finish_or_add_toks_to_seq(self, prefix_cacher, seq, token, eos_tok, false).await?;
+            if seq.is_finished_paged_attn() {
+                break;
+            }
+        }
+
+        match post_op {
+            CacheInstruction::Out => self.clone_out_cache(input_seqs, false),
+            CacheInstruction::Nothing(_) => (),
+            CacheInstruction::Reset {
+                reset_non_granular,
+                adapter_inst: _,
+            } => self.set_none_cache(reset_non_granular, false),
+            _ => unreachable!("Unreachable post cache op."),
+        }
+        Ok(())
+    }
+    fn category(&self) -> ModelCategory {
+        self.category
+    }
+}
+
+// AnyMoE is not supported: the defaults report `amoe_supported` as false, so the AnyMoE
+// pipeline rejects this one as a target before calling any of the other methods.
+impl AnyMoePipelineMixin for NGramSpeculativePipeline {}
+
+#[cfg(test)]
+mod tests {
+    use super::{propose_ngram_draft, verify_draft};
+    use crate::sampler::Logprobs;
+
+    fn logprobs(token: u32) -> Logprobs {
+        Logprobs {
+            token,
+            logprob: 0.,
+            bytes: None,
+            top_logprobs: None,
+        }
+    }
+
+    #[test]
+    fn drafts_the_continuation_of_the_latest_ngram() {
+        let toks = [1, 2, 3, 4, 9, 2, 3, 5, 6, 2, 3];
+        // `2 3` last continued with `5 6`, while `1 2 3` does not occur again.
+        assert_eq!(propose_ngram_draft(&toks, 3, 2), [5, 6]);
+        assert_eq!(propose_ngram_draft(&toks, 3, 8), [5, 6, 2, 3]);
+        assert!(propose_ngram_draft(&[1, 2, 3], 3, 4).is_empty());
+        assert!(propose_ngram_draft(&[], 3, 4).is_empty());
+    }
+
+    #[test]
+    fn repetitive_prompt_decodes_like_greedy() {
+        // A greedy model which repeats its context every 5 tokens, except that every 7th
+        // token is a 0, so that some drafts are rejected.
+        let model = |context: &[u32]| {
+            if context.len() % 7 == 0 {
+                0
+            } else {
+                context[context.len() - 5]
+            }
+        };
+        let prompt = vec![10, 11, 12, 13, 14, 10, 11, 12, 13, 14];
+        let n_tokens = 40;
+
+        let mut greedy = prompt.clone();
+        while greedy.len() < prompt.len() + n_tokens {
+            greedy.push(model(&greedy));
+        }
+
+        let mut speculative = prompt.clone();
+        let (mut proposed, mut accepted) = (0, 0);
+        while speculative.len() < prompt.len() + n_tokens {
+            let draft = propose_ngram_draft(&speculative, 3, 4);
+            let kept = verify_draft(&draft, |_, accepted_draft| {
+                let context = [&speculative[..], accepted_draft].concat();
+                Ok(logprobs(model(&context)))
+            })
+            .unwrap();
+            proposed += draft.len();
+            accepted += kept.len() - 1;
+            speculative.extend(kept.iter().map(|logprobs| logprobs.token));
+        }
+        speculative.truncate(prompt.len() + n_tokens);
+
+        assert_eq!(speculative, greedy);
+        assert!(accepted > 0 && accepted < proposed);
+    }
+}
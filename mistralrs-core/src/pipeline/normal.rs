@@ -1,8 +1,8 @@
 use super::cache_manager::DefaultCacheManager;
 use super::{
-    get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, NormalModel, NormalModelLoader,
-    TokenSource, XLoraPaths,
+    get_lora_adapter_paths, get_model_paths, get_xlora_paths,
+    text_models_inputs_processor::ModelInputs, AdapterKind, CacheManager, GeneralMetadata, Loader,
+    ModelKind, ModelPaths, NormalModel, NormalModelLoader, TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, ForwardInputsResult,
@@ -26,7 +26,10 @@ use crate::prefix_cacher::PrefixCacheManager;
 use crate::sequence::Sequence;
 use crate::utils::debug::DeviceRepr;
 use crate::utils::tokenizer::get_tokenizer;
-use crate::utils::{tokens::get_token, varbuilder_utils::from_mmaped_safetensors};
+use crate::utils::{
+    tokens::get_token,
+    varbuilder_utils::{from_mmaped_safetensors, load_preload_adapters},
+};
 use crate::xlora_models::NonGranularState;
 use crate::{
     api_dir_list, api_get_file, get_mut_arcmutex, get_paths, get_uqff_paths, lora_model_loader,
@@ -40,6 +43,7 @@ use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
 use regex_automata::meta::Regex;
 use std::any::Any;
+use std::collections::HashMap;
 use std::fs;
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
@@ -455,6 +459,12 @@ impl Loader for NormalLoader {
                 cache_config,
                 cache_engine,
                 prompt_batchsize: self.config.prompt_batchsize,
+                adapter_names: self
+                    .xlora_order
+                    .as_ref()
+                    .map(Ordering::adapter_names)
+                    .unwrap_or_default(),
+                isq: in_situ_quant,
             }),
             topology: self.config.topology.clone(),
             silent,
@@ -534,6 +544,30 @@ impl AdapterActivationMixin for NormalPipeline {
             .activate_adapters(adapter_names)
             .map_err(anyhow::Error::msg)
     }
+    fn load_adapter(&mut self, name: String, source: String) -> anyhow::Result<usize> {
+        if !self.metadata.kind.is_adapted_and(|a| a.is_lora()) {
+            anyhow::bail!("Loading adapters is only supported for models fine-tuned with LoRA.")
+        }
+        let (weights, config) =
+            get_lora_adapter_paths(&source, &TokenSource::CacheToken, self.silent)?;
+        let adapters = load_preload_adapters(
+            &Some(HashMap::from([(name.clone(), (weights, config))])),
+            self.metadata.activation_dtype,
+            self.model.device(),
+            self.silent,
+        )?
+        .expect("Expected the adapter to be loaded.");
+        let (vb, config) = &adapters[&name];
+
+        let n = self
+            .model
+            .load_adapter(&name, vb, config)
+            .map_err(anyhow::Error::msg)?;
+        if n == 0 {
+            anyhow::bail!("Adapter `{name}` has no weights for any of the LoRA layers.")
+        }
+        Ok(n)
+    }
 }
 
 impl MetadataMixin for NormalPipeline {
@@ -618,6 +652,72 @@ impl Pipeline for NormalPipeline {
         };
         Ok(ForwardInputsResult::CausalGeneration { logits })
     }
+    fn forward_embeddings(&mut self, inputs: Box<dyn Any>) -> Result<Tensor, candle_core::Error> {
+        let ModelInputs {
+            input_ids,
+            seqlen_offsets,
+            seqlen_offsets_kernel,
+            context_lens,
+            position_ids,
+            mut paged_attn_meta,
+            flash_meta,
+            ..
+        } = *inputs.downcast().expect("Downcast failed.");
+        if self.model.is_xlora() {
+            candle_core::bail!("Embeddings are not supported for X-LoRA models.");
+        }
+        let paged_attn_meta = match (
+            self.get_metadata().cache_engine.as_ref(),
+            &mut paged_attn_meta,
+        ) {
+            (Some(engine), Some(meta)) => Some((engine.get_kv_cache().clone(), meta)),
+            _ => None,
+        };
+        self.model.forward_embeddings(
+            &input_ids,
+            &seqlen_offsets,
+            seqlen_offsets_kernel,
+            context_lens,
+            position_ids,
+            paged_attn_meta,
+            &flash_meta,
+        )
+    }
+    fn forward_prompt_logits(
+        &mut self,
+        inputs: Box<dyn Any>,
+    ) -> Result<Tensor, candle_core::Error> {
+        let ModelInputs {
+            input_ids,
+            seqlen_offsets,
+            seqlen_offsets_kernel,
+            position_ids,
+            mut paged_attn_meta,
+            flash_meta,
+            ..
+        } = *inputs.downcast().expect("Downcast failed.");
+        if self.model.is_xlora() {
+            candle_core::bail!("Prompt logprobs are not supported for X-LoRA models.");
+        }
+        let paged_attn_meta = match (
+            self.get_metadata().cache_engine.as_ref(),
+            &mut paged_attn_meta,
+        ) {
+            (Some(engine), Some(meta)) => Some((engine.get_kv_cache().clone(), meta)),
+            _ => None,
+        };
+        self.model.forward_prompt_logits(
+            &input_ids,
+            &seqlen_offsets,
+            seqlen_offsets_kernel,
+            position_ids,
+            paged_attn_meta,
+            &flash_meta,
+        )
+    }
+    fn token_embeddings(&self) -> Option<Tensor> {
+        self.model.token_embeddings()
+    }
     async fn sample_causal_gen(
         &self,
         seqs: &mut [&mut Sequence],
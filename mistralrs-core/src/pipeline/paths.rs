@@ -258,6 +258,42 @@ pub fn get_xlora_paths(
     })
 }
 
+/// Get the weights and config of a LoRA adapter to be loaded at runtime, given either a local
+/// directory or a Hugging Face model ID (optionally as a `https://huggingface.co/` URL).
+pub(crate) fn get_lora_adapter_paths(
+    source: &str,
+    token_source: &TokenSource,
+    silent: bool,
+) -> Result<(PathBuf, LoraConfig)> {
+    const CONFIG_FILENAME: &str = "adapter_config.json";
+    const WEIGHTS_FILENAME: &str = "adapter_model.safetensors";
+
+    let local = Path::new(source);
+    let (config, weights) = if local.is_dir() {
+        info!("Loading adapter locally at `{source}`");
+        (local.join(CONFIG_FILENAME), local.join(WEIGHTS_FILENAME))
+    } else {
+        let model_id = source
+            .trim_start_matches("https://huggingface.co/")
+            .trim_end_matches('/');
+        info!("Loading adapter from `{model_id}`");
+        let api = ApiBuilder::new()
+            .with_progress(!silent)
+            .with_token(get_token(token_source)?)
+            .build()?
+            .model(model_id.to_string());
+        (api.get(CONFIG_FILENAME)?, api.get(WEIGHTS_FILENAME)?)
+    };
+    if !config.exists() || !weights.exists() {
+        anyhow::bail!(
+            "Expected `{CONFIG_FILENAME}` and `{WEIGHTS_FILENAME}` in adapter `{source}`."
+        );
+    }
+
+    let config: LoraConfig = serde_json::from_str(&fs::read_to_string(config)?)?;
+    Ok((weights, config))
+}
+
 pub fn get_model_paths(
     revision: String,
     token_source: &TokenSource,
@@ -342,7 +378,8 @@ pub fn get_model_paths(
 /// If the provided `tokenizer_config.json` from [`ModelPaths.get_template_filename`] does not
 /// have a `chat_template`, use the provided one.
 ///
-/// - Uses `chat_template_fallback` if `paths` does not contain a chat template file. This may be a literal or .json file.
+/// - Uses `chat_template_fallback` if `paths` does not contain a chat template file. This may be a literal or .json file,
+///   and a literal is used over the chat template file.
 /// - `chat_template_ovrd` (GGUF chat template content) causes the usage of that string chat template initially.
 ///   Falls back to `chat_template_file` if it is invalid. *The user must add the bos/unk/eos tokens manually if this
 ///   is used.*
@@ -352,6 +389,12 @@ pub(crate) fn get_chat_template(
     chat_template_fallback: &Option<String>,
     chat_template_ovrd: Option<String>,
 ) -> ChatTemplate {
+    // A fallback which is not a .json file is the template itself, and overrides the model's.
+    let chat_template_ovrd = chat_template_fallback
+        .clone()
+        .filter(|f| !f.ends_with(".json"))
+        .or(chat_template_ovrd);
+
     // Get template content, this may be overridden.
     let template_content = if let Some(template_filename) = paths.get_template_filename() {
         if template_filename
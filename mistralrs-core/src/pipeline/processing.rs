@@ -9,7 +9,10 @@ use crate::{
     MessageContent, Pipeline, Tool,
 };
 
-use super::{chat_template::apply_chat_template_to, text_models_inputs_processor, InputsProcessor};
+use super::{
+    chat_template::{apply_chat_template_to, ChatTemplateValue},
+    text_models_inputs_processor, InputsProcessor,
+};
 
 /// Trait to create processors.
 pub trait ProcessorCreator {
@@ -30,13 +33,15 @@ pub enum MessagesAction {
 /// Also includes method to retrieve the input processor for processing inputs for the
 /// model.
 pub trait Processor {
-    /// Get the tokens and the untokenized prompt
+    /// Get the tokens and the untokenized prompt. `template_override` replaces the model's chat
+    /// template.
     fn process(
         &self,
         pipeline: &dyn Pipeline,
         messages: Vec<IndexMap<String, MessageContent>>,
         add_generation_prompt: bool,
         tools: Vec<Tool>,
+        template_override: Option<&str>,
     ) -> Result<(Vec<u32>, String)> {
         let prompt = apply_chat_template(
             pipeline,
@@ -44,6 +49,7 @@ pub trait Processor {
             add_generation_prompt,
             self.template_action(),
             tools,
+            template_override,
         )?;
         let encoding = pipeline
             .tokenizer()
@@ -65,6 +71,7 @@ pub(crate) fn apply_chat_template(
     add_generation_prompt: bool,
     action: MessagesAction,
     tools: Vec<Tool>,
+    template_override: Option<&str>,
 ) -> Result<String> {
     let messages = match action {
         MessagesAction::Keep => messages,
@@ -103,7 +110,11 @@ pub(crate) fn apply_chat_template(
     let chat_template = pipeline
         .get_chat_template()
         .with_context(|| "`apply_chat_template` expects the pipeline to have a chat template.")?;
-    let template = chat_template.chat_template.as_ref().unwrap();
+    let template_override =
+        template_override.map(|template| ChatTemplateValue(Either::Left(template.to_string())));
+    let template = template_override
+        .as_ref()
+        .unwrap_or_else(|| chat_template.chat_template.as_ref().unwrap());
     let bos_tok = if let Some(ref bos) = chat_template.bos_token {
         match bos.0 {
             Either::Left(ref lit) => Some(lit.to_string()),
@@ -1,17 +1,115 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
-use candle_core::{DType, Device, Result, Tensor};
+use candle_core::{DType, Device, Result, Tensor, D};
 use rand_isaac::Isaac64Rng;
 
 use crate::{
+    aici::toktree::TokTrie,
+    beam_search::{assign_beams, select_beams},
     get_bias_if_not_allowed,
     prefix_cacher::PrefixCacheManager,
-    sampler::Logprobs,
-    sequence::{Sequence, SequenceRecognizer},
+    sampler::{Logprobs, TopLogprob},
+    sequence::{text_toks, Sequence, SequenceRecognizer},
 };
 
 use super::Pipeline;
 
+/// Convert the logprobs of a sampled token into the OpenAI format. The `bytes` come from the token
+/// trie, so a token which is only part of a UTF-8 character still reports its raw bytes.
+fn response_logprob(logprob: &Logprobs, tok_trie: Option<&TokTrie>) -> crate::ResponseLogprob {
+    let token_str = |token: u32, decoded: &Option<String>| {
+        decoded
+            .clone()
+            .or_else(|| tok_trie.map(|trie| trie.token_str(token)))
+            .unwrap_or_default()
+    };
+    let token_bytes = |token: u32| tok_trie.map(|trie| trie.token(token).to_vec());
+    crate::ResponseLogprob {
+        token: token_str(logprob.token, &logprob.bytes),
+        logprob: logprob.logprob,
+        bytes: token_bytes(logprob.token),
+        top_logprobs: logprob
+            .top_logprobs
+            .iter()
+            .flatten()
+            .map(|top| crate::ResponseTopLogprob {
+                token: token_str(top.token, &top.bytes),
+                logprob: top.logprob,
+                bytes: token_bytes(top.token),
+            })
+            .collect(),
+    }
+}
+
+/// The logprobs of `targets` under `logits`, `(n_positions, vocab_size)`, where the logits at each
+/// position predict the target at the same index, with the `top_n` top logprobs at each position.
+#[allow(clippy::cast_possible_truncation)]
+fn target_logprobs(logits: &Tensor, targets: &[u32], top_n: usize) -> Result<Vec<Logprobs>> {
+    let logits = logits.narrow(0, 0, targets.len())?.to_dtype(DType::F32)?;
+    let logprobs = candle_nn::ops::log_softmax(&logits, D::Minus1)?.to_vec2::<f32>()?;
+    Ok(logprobs
+        .into_iter()
+        .zip(targets)
+        .map(|(position, &token)| {
+            let mut ranked = (0..position.len()).collect::<Vec<_>>();
+            ranked.sort_by(|a, b| position[*b].total_cmp(&position[*a]));
+            let top_logprobs = ranked
+                .into_iter()
+                .take(top_n)
+                .map(|top| TopLogprob {
+                    token: top as u32,
+                    logprob: position[top],
+                    bytes: None,
+                })
+                .collect();
+            Logprobs {
+                token,
+                logprob: position[token as usize],
+                bytes: None,
+                top_logprobs: Some(top_logprobs),
+            }
+        })
+        .collect())
+}
+
+/// Record the logprobs of the prompt tokens of `seq` predicted by `logits`, the
+/// `(seq_len, vocab_size)` logits of the prompt chunk it just ran, if it requested them. Chunks
+/// run in order, so the chunk starts at the first position without a recorded logprob, and the
+/// last position of the prompt predicts the first completion token rather than a prompt token.
+pub(crate) fn record_prompt_logprobs(seq: &mut Sequence, logits: &Tensor) -> Result<()> {
+    let Some(top_n) = seq.prompt_top_logprobs() else {
+        return Ok(());
+    };
+    let start = seq.prompt_logprobs().len();
+    let prompt = &seq.get_toks()[..seq.prompt_tokens()];
+    if start + 1 >= prompt.len() {
+        // Already recorded, and the prompt is only being recomputed.
+        return Ok(());
+    }
+    let end = (start + 1 + logits.dim(0)?).min(prompt.len());
+    let logprobs = target_logprobs(logits, &prompt[start + 1..end], top_n)?;
+    seq.add_prompt_logprobs(logprobs);
+    Ok(())
+}
+
+/// Streamed sequences send a chunk every this many tokens, unless they set a `stream_granularity`.
+const STREAMING_RATE_LIMIT: usize = 3;
+
+/// Whether a streamed sequence sends a chunk after its latest token, its `token_index`-th: always
+/// once it is done, and otherwise once `unstreamed_toks` reaches its `stream_granularity`.
+fn stream_chunk_due(
+    stream_granularity: Option<usize>,
+    token_index: usize,
+    unstreamed_toks: usize,
+    is_done: bool,
+) -> bool {
+    is_done
+        || match stream_granularity {
+            Some(granularity) => unstreamed_toks >= granularity,
+            None => token_index % STREAMING_RATE_LIMIT == 0,
+        }
+}
+
 pub(crate) async fn finish_or_add_toks_to_seq(
     this: &dyn Pipeline,
     prefix_cacher: &mut PrefixCacheManager,
@@ -20,46 +118,66 @@ pub(crate) async fn finish_or_add_toks_to_seq(
     eos_tok: Option<&[u32]>,
     use_prefix_cacher: bool,
 ) -> Result<()> {
-    let is_done = seq.is_done(logprobs.token, eos_tok, this.get_metadata().max_seq_len);
-    seq.add_token(
-        logprobs.clone(),
-        this.get_metadata()
-            .tok_trie
-            .as_ref()
-            .ok_or(candle_core::Error::Msg(
-                "`finish_or_add_toks_to_seq` requires the pipeline to have a token trie"
-                    .to_string(),
-            ))?
-            .decode(&[logprobs.token]),
-        &is_done,
-    );
+    let completion_bytes = this
+        .get_metadata()
+        .tok_trie
+        .as_ref()
+        .ok_or(candle_core::Error::Msg(
+            "`finish_or_add_toks_to_seq` requires the pipeline to have a token trie".to_string(),
+        ))?
+        .decode(&[logprobs.token]);
+    let is_done = seq
+        .is_done(
+            logprobs.token,
+            &completion_bytes,
+            eos_tok,
+            this.get_metadata().max_seq_len,
+        )
+        .or_else(|| {
+            seq.constraint_complete()
+                .then_some(crate::sequence::StopReason::ConstraintComplete)
+        });
+    seq.add_token(logprobs.clone(), completion_bytes, &is_done);
     // Handle streaming requests
     if seq.get_mut_group().is_streaming {
-        const STREAMING_RATE_LIMIT: usize = 3;
-
-        let token_index = seq.get_toks().len();
-        let rate_limit_allowed = is_done.is_some() || token_index % STREAMING_RATE_LIMIT == 0;
+        let stream_granularity = seq.get_mut_group().stream_granularity;
+        let rate_limit_allowed = stream_chunk_due(
+            stream_granularity,
+            seq.get_toks().len(),
+            seq.unstreamed_toks(),
+            is_done.is_some(),
+        );
 
         if rate_limit_allowed {
             if let Some(delta) = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
+                let tok_trie = this.get_metadata().tok_trie.clone();
+                let return_raw_tokens = seq.return_raw_tokens();
+                let new_logprobs = seq.get_delta_logprobs();
+                let delta_raw_tokens = return_raw_tokens.then(|| text_toks(new_logprobs, &is_done));
+                let delta_logprobs = new_logprobs
+                    .iter()
+                    .map(|logprob| response_logprob(logprob, tok_trie.as_deref()))
+                    .collect::<Vec<_>>();
                 if seq.get_mut_group().is_chat {
+                    let (reasoning_content, content) =
+                        seq.split_reasoning(&delta, is_done.is_some());
                     seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
                         delta: crate::Delta {
-                            content: delta.clone(),
+                            content,
                             role: "assistant".to_string(),
+                            reasoning_content,
                         },
                         index: seq.get_response_index(),
                         finish_reason: is_done.map(|x| x.to_string()),
+                        matched_stop: is_done.and_then(|x| seq.matched_stop(&x)),
                         logprobs: if seq.return_logprobs() {
-                            Some(crate::ResponseLogprob {
-                                token: delta,
-                                bytes: logprobs.bytes.clone().map(|b| b.into_bytes()),
-                                logprob: logprobs.logprob,
-                                top_logprobs: logprobs.top_logprobs.unwrap().clone(),
+                            Some(crate::Logprobs {
+                                content: Some(delta_logprobs),
                             })
                         } else {
                             None
                         },
+                        raw_tokens: delta_raw_tokens,
                     });
                 } else {
                     seq.add_streaming_completion_chunk_choice_to_group(
@@ -67,16 +185,13 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                             text: delta.clone(),
                             index: seq.get_response_index(),
                             finish_reason: is_done.map(|x| x.to_string()),
+                            matched_stop: is_done.and_then(|x| seq.matched_stop(&x)),
                             logprobs: if seq.return_logprobs() {
-                                Some(crate::ResponseLogprob {
-                                    token: delta,
-                                    bytes: logprobs.bytes.clone().map(|b| b.into_bytes()),
-                                    logprob: logprobs.logprob,
-                                    top_logprobs: logprobs.top_logprobs.unwrap().clone(),
-                                })
+                                delta_logprobs.into_iter().last()
                             } else {
                                 None
                             },
+                            raw_tokens: delta_raw_tokens,
                         },
                     );
                 }
@@ -112,41 +227,27 @@ pub(crate) async fn finish_or_add_toks_to_seq(
         */
         {
             seq.set_state(crate::sequence::SequenceState::Done(reason));
-            let (tokenizer, pipeline_name) = {
-                let pipeline_name = this.name();
-                let tokenizer = this.tokenizer();
-                (tokenizer, pipeline_name)
-            };
 
             let logprobs = if seq.return_logprobs() {
-                let mut logprobs = Vec::new();
-                for logprob in seq.logprobs() {
-                    let resp_logprob = crate::ResponseLogprob {
-                        token: crate::handle_seq_error_ok!(
-                            tokenizer
-                            .as_ref()
-                            .ok_or(candle_core::Error::Msg(
-                                "`finish_or_add_toks_to_seq` requires the pipeline to have a tokenizer"
-                                    .to_string(),
-                            ))?.decode(&[logprob.token], false),
-                            seq.responder()
-                        ),
-                        bytes: logprob.bytes.clone().map(|b| b.into_bytes()),
-                        logprob: logprob.logprob,
-                        top_logprobs: logprob.top_logprobs.clone().unwrap(),
-                    };
-                    logprobs.push(resp_logprob);
-                }
+                let tok_trie = this.get_metadata().tok_trie.clone();
+                let logprobs = seq
+                    .logprobs()
+                    .iter()
+                    .map(|logprob| response_logprob(logprob, tok_trie.as_deref()))
+                    .collect::<Vec<_>>();
                 Some(logprobs)
             } else {
                 None
             };
 
+            let raw_tokens = seq.return_raw_tokens().then(|| seq.completion_toks());
+
             let text = match reason {
                 crate::sequence::StopReason::Length(_)
                 | crate::sequence::StopReason::ModelLength(_)
                 | crate::sequence::StopReason::Eos
                 | crate::sequence::StopReason::StopTok(_)
+                | crate::sequence::StopReason::ConstraintComplete
                 | crate::sequence::StopReason::Canceled => {
                     String::from_utf8_lossy(seq.completion_bytes())
                         .trim_start()
@@ -155,6 +256,13 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                 crate::sequence::StopReason::StopString {
                     completion_bytes_pos,
                     ..
+                }
+                | crate::sequence::StopReason::CharLength {
+                    completion_bytes_pos,
+                    ..
+                }
+                | crate::sequence::StopReason::ContentFilter {
+                    completion_bytes_pos,
                 } => {
                     let txt = String::from_utf8_lossy(seq.completion_bytes());
                     txt[..completion_bytes_pos].trim_start().to_string()
@@ -162,6 +270,9 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                 crate::sequence::StopReason::GeneratedImage => {
                     candle_core::bail!("Stop reason was `GeneratedImage`.")
                 }
+                crate::sequence::StopReason::GeneratedEmbedding => {
+                    candle_core::bail!("Stop reason was `GeneratedEmbedding`.")
+                }
             };
 
             if seq.get_mut_group().is_chat {
@@ -174,8 +285,14 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                     }
                     tool_calls = calls;
                 }
+                let finish_reason = if tool_calls.is_empty() {
+                    reason.to_string()
+                } else {
+                    "tool_calls".to_string()
+                };
                 let choice = crate::Choice {
-                    finish_reason: reason.to_string(),
+                    finish_reason,
+                    matched_stop: seq.matched_stop(&reason),
                     index: seq.get_response_index(),
                     message: crate::ResponseMessage {
                         content: text_new,
@@ -183,14 +300,27 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                         tool_calls,
                     },
                     logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
+                    raw_tokens,
                 };
                 seq.add_choice_to_group(choice);
             } else {
+                let prompt_logprobs =
+                    seq.prompt_top_logprobs().map(|_| {
+                        let tok_trie = this.get_metadata().tok_trie.clone();
+                        std::iter::once(None)
+                            .chain(seq.prompt_logprobs().iter().map(|logprob| {
+                                Some(response_logprob(logprob, tok_trie.as_deref()))
+                            }))
+                            .collect()
+                    });
                 let choice = crate::CompletionChoice {
                     finish_reason: reason.to_string(),
+                    matched_stop: seq.matched_stop(&reason),
                     index: seq.get_response_index(),
                     text,
                     logprobs: None,
+                    prompt_logprobs,
+                    raw_tokens,
                 };
                 seq.add_completion_choice_to_group(choice);
             }
@@ -200,40 +330,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                 prefix_cacher.evict_to_cpu()?;
             }
 
-            let group = seq.get_mut_group();
-            if group.is_chat {
-                group
-                    .maybe_send_chat_done_response(
-                        crate::ChatCompletionResponse {
-                            id: seq.id().to_string(),
-                            choices: group.get_choices().to_vec(),
-                            created: seq.creation_time(),
-                            model: pipeline_name,
-                            system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
-                            object: "chat.completion".to_string(),
-                            usage: group.get_usage(),
-                        },
-                        seq.responder(),
-                    )
-                    .await
-                    .map_err(candle_core::Error::msg)?;
-            } else {
-                group
-                    .maybe_send_completion_done_response(
-                        crate::CompletionResponse {
-                            id: seq.id().to_string(),
-                            choices: group.get_completion_choices().to_vec(),
-                            created: seq.creation_time(),
-                            model: pipeline_name,
-                            system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
-                            object: "text_completion".to_string(),
-                            usage: group.get_usage(),
-                        },
-                        seq.responder(),
-                    )
-                    .await
-                    .map_err(candle_core::Error::msg)?;
-            }
+            maybe_send_done_response(this, seq).await?;
         }
         this.reset_non_granular_state();
     }
@@ -241,6 +338,48 @@ pub(crate) async fn finish_or_add_toks_to_seq(
     Ok(())
 }
 
+/// Send the response to the request of `seq` if all of its choices have finished.
+async fn maybe_send_done_response(this: &dyn Pipeline, seq: &Sequence) -> Result<()> {
+    let pipeline_name = this.name();
+    let group = seq.get_mut_group();
+    if group.is_chat {
+        group
+            .maybe_send_chat_done_response(
+                crate::ChatCompletionResponse {
+                    id: seq.request_id().to_string(),
+                    choices: group.get_choices().to_vec(),
+                    created: seq.creation_time(),
+                    model: pipeline_name,
+                    system_fingerprint: group.system_fingerprint.clone(),
+                    object: "chat.completion".to_string(),
+                    usage: group.get_usage(),
+                    seed: seq.seed(),
+                },
+                seq.responder(),
+            )
+            .await
+            .map_err(candle_core::Error::msg)?;
+    } else {
+        group
+            .maybe_send_completion_done_response(
+                crate::CompletionResponse {
+                    id: seq.request_id().to_string(),
+                    choices: group.get_completion_choices().to_vec(),
+                    created: seq.creation_time(),
+                    model: pipeline_name,
+                    system_fingerprint: group.system_fingerprint.clone(),
+                    object: "text_completion".to_string(),
+                    usage: group.get_usage(),
+                    seed: seq.seed(),
+                },
+                seq.responder(),
+            )
+            .await
+            .map_err(candle_core::Error::msg)?;
+    }
+    Ok(())
+}
+
 pub async fn sample_and_add_toks(
     this: &dyn Pipeline,
     seqs: &mut [&mut Sequence],
@@ -249,11 +388,24 @@ pub async fn sample_and_add_toks(
     disable_eos_stop: bool,
     rng: Arc<std::sync::Mutex<Isaac64Rng>>,
 ) -> Result<()> {
+    debug_assert_eq!(logits_seq.len(), seqs.len());
+
+    let (beams, sampled): (Vec<_>, Vec<_>) = std::iter::zip(logits_seq, seqs.iter_mut())
+        .map(|(logits, seq)| (logits, &mut **seq))
+        .partition(|(_, seq)| seq.beam_search().is_some());
+    if !beams.is_empty() {
+        step_beams(this, beams, prefix_cacher, disable_eos_stop).await?;
+    }
+    let (logits_seq, mut seqs): (Vec<_>, Vec<_>) = sampled.into_iter().unzip();
     let seqs_len = seqs.len();
-    debug_assert_eq!(logits_seq.len(), seqs_len);
 
     let use_async_pool = seqs_len > 1;
 
+    // Choices forked from a sequence sample their first token from its prompt logits.
+    let fork_logits = std::iter::zip(&logits_seq, seqs.iter())
+        .map(|(logits, seq)| seq.has_forks().then(|| logits.clone()))
+        .collect::<Vec<_>>();
+
     let sampling_futures: Vec<_> = std::iter::zip(logits_seq, seqs.iter_mut())
         .map(|(logits_per_seq, seq)| {
             let return_logprobs = seq.return_logprobs();
@@ -283,6 +435,194 @@ pub async fn sample_and_add_toks(
         finish_or_add_toks_to_seq(this, prefix_cacher, seq, next_token, eos_tok, true).await?;
     }
 
+    for (logits, seq) in std::iter::zip(fork_logits, seqs.iter_mut()) {
+        if let Some(logits) = logits {
+            sample_forks(
+                this,
+                seq,
+                logits,
+                prefix_cacher,
+                disable_eos_stop,
+                rng.clone(),
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Continue the `beams`, with their logits, together with the other beams of their prompts rather
+/// than sampling them. At the prompt step of a prompt, its first beam has the others as forks.
+async fn step_beams(
+    this: &dyn Pipeline,
+    beams: Vec<(Tensor, &mut Sequence)>,
+    prefix_cacher: &mut PrefixCacheManager,
+    disable_eos_stop: bool,
+) -> Result<()> {
+    // The beams of a prompt have the same request, and consecutive response indices.
+    let mut prompts = BTreeMap::<_, Vec<_>>::new();
+    for (logits, seq) in beams {
+        let num_beams = seq.beam_search().expect("Beams have beam search").num_beams;
+        let key = (seq.request_id(), seq.get_response_index() / num_beams);
+        prompts.entry(key).or_default().push((logits, seq));
+    }
+    for ((_, prompt_index), beams) in prompts {
+        let (logits, mut beams): (Vec<_>, Vec<_>) = beams.into_iter().unzip();
+        if beams[0].has_forks() {
+            let leader = &mut *beams[0];
+            let mut forks = leader.take_forks();
+            for fork in forks.iter_mut() {
+                fork.fork_from(leader);
+            }
+            let mut all_beams = std::iter::once(&mut *leader)
+                .chain(forks.iter_mut())
+                .collect::<Vec<_>>();
+            continue_beams(
+                this,
+                &mut all_beams,
+                logits,
+                prompt_index,
+                prefix_cacher,
+                disable_eos_stop,
+            )
+            .await?;
+            leader.set_forks(forks);
+        } else {
+            continue_beams(
+                this,
+                &mut beams,
+                logits,
+                prompt_index,
+                prefix_cacher,
+                disable_eos_stop,
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Continue the running `beams` of one prompt with the best continuations of the first
+/// `logits.len()` of them, and stop those left once none of them would be returned.
+async fn continue_beams(
+    this: &dyn Pipeline,
+    beams: &mut [&mut Sequence],
+    logits: Vec<Tensor>,
+    prompt_index: usize,
+    prefix_cacher: &mut PrefixCacheManager,
+    disable_eos_stop: bool,
+) -> Result<()> {
+    let params = beams[0].beam_search().expect("Beams have beam search");
+    let logprobs = logits
+        .into_iter()
+        .map(|logits| {
+            let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+            candle_nn::ops::log_softmax(&logits, D::Minus1)?.to_vec1::<f32>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let cumulative_logprobs = beams[..logprobs.len()]
+        .iter()
+        .map(|seq| seq.cumulative_logprob())
+        .collect::<Vec<_>>();
+    let selections = select_beams(&cumulative_logprobs, &logprobs, beams.len());
+    let assigned = assign_beams(&selections, beams.len());
+    // Copy the beams continued in the place of others before any of them is continued.
+    let states = assigned
+        .iter()
+        .enumerate()
+        .map(|(beam, selection)| {
+            let parent = selections[(*selection)?].parent;
+            (parent != beam).then(|| beams[parent].beam_state())
+        })
+        .collect::<Vec<_>>();
+
+    let eos_tok = if disable_eos_stop {
+        None
+    } else {
+        Some(&this.get_metadata().eos_tok[..])
+    };
+    for ((seq, selection), state) in beams.iter_mut().zip(assigned).zip(states) {
+        let Some(selection) = selection.map(|i| selections[i]) else {
+            // There are fewer tokens than beams.
+            prune_beam(this, seq).await?;
+            continue;
+        };
+        if let Some(state) = state {
+            seq.set_beam_state(state);
+        }
+        let logprobs = Logprobs {
+            token: selection.token,
+            logprob: selection.logprob,
+            bytes: None,
+            top_logprobs: None,
+        };
+        finish_or_add_toks_to_seq(this, prefix_cacher, seq, logprobs, eos_tok, true).await?;
+    }
+
+    let (finished, n) = {
+        let group = beams[0].get_mut_group();
+        (group.completion_scores(prompt_index), group.n_per_prompt())
+    };
+    let best_running = beams
+        .iter()
+        .filter(|seq| seq.is_running())
+        .map(|seq| seq.choice_score())
+        .fold(f32::NEG_INFINITY, f32::max);
+    if params.should_stop(&finished, n, best_running) {
+        for seq in beams.iter_mut().filter(|seq| seq.is_running()) {
+            prune_beam(this, seq).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Stop a beam which will not be returned, and send the response if it was the last one running.
+async fn prune_beam(this: &dyn Pipeline, seq: &mut Sequence) -> Result<()> {
+    seq.set_state(crate::sequence::SequenceState::Done(
+        crate::sequence::StopReason::Canceled,
+    ));
+    seq.get_mut_group().drop_choice();
+    this.reset_non_granular_state();
+    maybe_send_done_response(this, seq).await
+}
+
+/// Fork the choices attached to `seq` from its prompt step, sampling each one's first token from
+/// `logits` with its own sampler and RNG. They are returned to `seq` for the engine to schedule.
+async fn sample_forks(
+    this: &dyn Pipeline,
+    seq: &mut Sequence,
+    logits: Tensor,
+    prefix_cacher: &mut PrefixCacheManager,
+    disable_eos_stop: bool,
+    rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+) -> Result<()> {
+    let mut forks = seq.take_forks();
+    for fork in forks.iter_mut() {
+        fork.fork_from(seq);
+        let return_logprobs = fork.return_logprobs();
+        let sampled = sample_sequence(
+            logits.clone(),
+            fork,
+            return_logprobs,
+            rng.clone(),
+            false,
+            true, // Append result to trie
+            false,
+        )
+        .await;
+        let next_token = crate::handle_seq_error_stateaware_ok!(sampled, fork);
+
+        let metadata = this.get_metadata();
+        let eos_tok = if disable_eos_stop {
+            None
+        } else {
+            Some(&metadata.eos_tok[..])
+        };
+
+        finish_or_add_toks_to_seq(this, prefix_cacher, fork, next_token, eos_tok, true).await?;
+    }
+    seq.set_forks(forks);
     Ok(())
 }
 
@@ -298,6 +638,8 @@ pub async fn sample_sequence(
     sample_speculative: bool,
 ) -> Result<Logprobs> {
     let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+    // Seeded sequences use their own RNG so that other requests do not perturb them.
+    let rng = seq.rng().unwrap_or(rng);
 
     let sampler = seq.sampler();
     let ctx_clone = seq.get_toks().to_vec();
@@ -426,3 +768,61 @@ pub async fn sample_target_sequence_speculative(
     }
     Ok(sampled)
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::{stream_chunk_due, target_logprobs};
+
+    #[test]
+    fn test_prompt_logprobs_nll() {
+        // The logits after each token of a three token sentence, over a vocabulary of four.
+        let logits = Tensor::new(
+            &[
+                [2.0f32, 1.0, 0.5, -1.0],
+                [0.0, 3.0, 0.0, 0.0],
+                [1.0, 1.0, 1.0, 1.0],
+            ],
+            &Device::Cpu,
+        )
+        .unwrap();
+        let sentence = [3u32, 0, 1];
+        // The first token has no logprob, and the last logits predict the completion.
+        let logprobs = target_logprobs(&logits, &sentence[1..], 2).unwrap();
+        assert_eq!(logprobs.len(), 2);
+
+        let expected = |row: [f32; 4], token: usize| {
+            row[token] - row.iter().map(|logit| logit.exp()).sum::<f32>().ln()
+        };
+        let nll = -logprobs.iter().map(|l| l.logprob).sum::<f32>();
+        let expected_nll =
+            -(expected([2.0, 1.0, 0.5, -1.0], 0) + expected([0.0, 3.0, 0.0, 0.0], 1));
+        assert!((nll - expected_nll).abs() < 1e-5);
+
+        let top = logprobs[0].top_logprobs.as_ref().unwrap();
+        assert_eq!(top.iter().map(|t| t.token).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(logprobs[1].token, 1);
+    }
+
+    #[test]
+    fn test_stream_granularity() {
+        // Streams 30 tokens, of which the last one is done, counting the chunks sent.
+        let chunks = |granularity: Option<usize>| {
+            let mut unstreamed = 0;
+            let mut chunks = 0;
+            for token_index in 1..=30 {
+                unstreamed += 1;
+                if stream_chunk_due(granularity, token_index, unstreamed, token_index == 30) {
+                    unstreamed = 0;
+                    chunks += 1;
+                }
+            }
+            chunks
+        };
+        assert_eq!(chunks(Some(1)), 30);
+        // 7 chunks of 4 tokens, and the last chunk of the remaining 2.
+        assert_eq!(chunks(Some(4)), 8);
+        assert_eq!(chunks(None), 10);
+    }
+}
@@ -1,7 +1,10 @@
 use std::{
     any::Any,
     iter::zip,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use anyhow::Result as anyhowResult;
@@ -26,7 +29,8 @@ use crate::{
 };
 
 use super::{
-    cache_manager::DefaultCacheManager, chat_template::ChatTemplate, sampling::SpeculativeSample,
+    cache_manager::{DefaultCacheManager, LayerCaches},
+    chat_template::ChatTemplate,
     AdapterActivationMixin, AnyMoePipelineMixin, CacheBackendMetadata, CacheInstruction,
     CacheManager, CacheManagerMixin, ForwardInputsResult, GeneralMetadata, IsqPipelineMixin,
     MetadataMixin, ModelCategory, ModelPaths, PreProcessingMixin,
@@ -52,7 +56,7 @@ impl Loader for SpeculativeLoader {
         in_situ_quant: Option<IsqType>,
         paged_attn_config: Option<PagedAttentionConfig>,
     ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
-        let paged_attn_config = if paged_attn_config.is_none() {
+        let paged_attn_config = if paged_attn_config.is_some() {
             warn!(
                 "Speculative decoding does not currently support PagedAttention, running without"
             );
@@ -99,7 +103,7 @@ impl Loader for SpeculativeLoader {
         in_situ_quant: Option<IsqType>,
         paged_attn_config: Option<PagedAttentionConfig>,
     ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
-        let paged_attn_config = if paged_attn_config.is_none() {
+        let paged_attn_config = if paged_attn_config.is_some() {
             warn!(
                 "Speculative decoding does not currently support PagedAttention, running without"
             );
@@ -148,6 +152,53 @@ impl Loader for SpeculativeLoader {
     }
 }
 
+/// Draft tokens proposed and accepted across all speculative pipelines, with a draft model or
+/// n-grams.
+pub static SPECULATIVE_STATS: SpeculativeStats = SpeculativeStats::new();
+
+/// Counts of the draft tokens proposed to and accepted by the target model.
+pub struct SpeculativeStats {
+    proposed: AtomicUsize,
+    accepted: AtomicUsize,
+}
+
+impl SpeculativeStats {
+    const fn new() -> Self {
+        Self {
+            proposed: AtomicUsize::new(0),
+            accepted: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, proposed: usize, accepted: usize) {
+        self.proposed.fetch_add(proposed, Ordering::Relaxed);
+        self.accepted.fetch_add(accepted, Ordering::Relaxed);
+    }
+
+    /// Number of draft tokens proposed so far.
+    pub fn proposed(&self) -> usize {
+        self.proposed.load(Ordering::Relaxed)
+    }
+
+    /// Number of draft tokens the target model accepted so far.
+    pub fn accepted(&self) -> usize {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// The fraction of proposed draft tokens which were accepted, or `None` before any were proposed.
+    pub fn acceptance_rate(&self) -> Option<f64> {
+        let proposed = self.proposed();
+        (proposed > 0).then(|| self.accepted() as f64 / proposed as f64)
+    }
+}
+
+/// The number of leading draft tokens which the target model sampled as well. Generation continues
+/// from the target model's own sample at the first disagreement, so that the output follows the
+/// target distribution; with greedy sampling it is exactly the target model's output.
+fn n_accepted_draft_tokens(draft: &[u32], target: &[u32]) -> usize {
+    zip(draft, target).take_while(|(d, t)| d == t).count()
+}
+
 /// Speculative decoding pipeline: <https://arxiv.org/pdf/2211.17192>
 ///
 /// # Algorithm
@@ -224,6 +275,282 @@ impl SpeculativePipeline {
             category,
         })
     }
+
+    /// Run one speculative step for `seqs`: the draft model proposes `gamma` tokens for each
+    /// sequence, and a single batched run of the target model verifies all of them. Completion
+    /// sequences are batched by the scheduler with equal lengths, which the batched caches require.
+    #[allow(clippy::too_many_arguments)]
+    async fn speculate(
+        &mut self,
+        seqs: &mut [&mut Sequence],
+        is_prompt: bool,
+        pre_op: &CacheInstruction,
+        post_op: &CacheInstruction,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<()> {
+        match pre_op {
+            CacheInstruction::In(_) => self.clone_in_cache(seqs, true),
+            CacheInstruction::Nothing(_) => (),
+            CacheInstruction::Reset {
+                reset_non_granular, ..
+            } => self.set_none_cache(*reset_non_granular, false),
+            _ => unreachable!("Unreachable PRE cache op."),
+        }
+        let n_seqs = seqs.len();
+
+        // ======================= Run draft model gamma times producing tokens ============================
+        // ======================= Sample the `gamma` logits. ============================
+        let mut draft_tokens = vec![Vec::with_capacity(self.gamma); n_seqs];
+        for i in 0..self.gamma {
+            let is_xlora = get_mut_arcmutex!(self.draft).get_metadata().is_xlora;
+            let device = get_mut_arcmutex!(self.draft).device();
+            let has_no_kv_cache = get_mut_arcmutex!(self.draft).get_metadata().has_no_kv_cache;
+            let inputs = self
+                .get_processor()
+                .inputs_processor()
+                .process_inputs(
+                    self.tokenizer(),
+                    seqs,
+                    is_prompt && i == 0, // Only prompt (no kv cache) if first
+                    is_xlora,
+                    &device,
+                    has_no_kv_cache,
+                    None,
+                    None,
+                    None, // TODO: get block tables/handle it
+                    None, // TODO: do we support???
+                )
+                .nth(0)
+                .unwrap()
+                .unwrap();
+            let logits = get_mut_arcmutex!(self.draft).forward_inputs(Box::new(inputs))?;
+            #[allow(irrefutable_let_patterns)]
+            let ForwardInputsResult::CausalGeneration { logits } = logits
+            else {
+                candle_core::bail!(
+                    "Speculative decoding requires `CausalGeneration` forward results"
+                );
+            };
+
+            for ((seq, logits), tokens) in
+                zip(seqs.iter_mut(), logits.chunk(n_seqs, 0)?).zip(&mut draft_tokens)
+            {
+                let sample = sample_sequence(
+                    logits,
+                    seq,
+                    seq.return_logprobs(),
+                    rng.clone(),
+                    false, // todo tune
+                    false, // do not add to tok trie yet
+                    true,
+                )
+                .await?;
+                seq.add_tmp_tok(sample.token);
+                tokens.push(sample.token);
+            }
+        }
+
+        // ======================= Add all draft tokens but the last one. Add the last from the seq. ============================
+        for (seq, tokens) in zip(seqs.iter_mut(), &draft_tokens) {
+            seq.remove_tmp_tok(self.gamma);
+            let mut draft_prefill_tokens = if is_prompt {
+                seq.get_toks().to_vec()
+            } else {
+                vec![*seq.get_toks().last().unwrap()]
+            };
+            draft_prefill_tokens.extend(&tokens[..tokens.len() - 1]);
+            seq.set_prefill_toks(draft_prefill_tokens);
+        }
+
+        // ======================= Run the model with all draft tokens. ============================
+
+        let initial_cache_len = get_mut_arcmutex!(self.target).cache().lock()[0]
+            .as_ref()
+            .map(|(k, _)| k.dims()[2])
+            .unwrap_or(0);
+
+        // ========= Run the model ============
+        let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
+        let device = get_mut_arcmutex!(self.target).device();
+        let has_no_kv_cache = get_mut_arcmutex!(self.target)
+            .get_metadata()
+            .has_no_kv_cache;
+        let inputs = self
+            .get_processor()
+            .inputs_processor()
+            .process_inputs(
+                self.tokenizer(),
+                seqs,
+                true, // use the "prefill" tokens
+                is_xlora,
+                &device,
+                has_no_kv_cache,
+                Some((self.gamma, initial_cache_len)), // Get the last gamma, see above
+                None,
+                None, // TODO: get block tables/handle it
+                None, // TODO: do we support???
+            )
+            .nth(0)
+            .unwrap()
+            .unwrap();
+
+        let logits = get_mut_arcmutex!(self.target).forward_inputs(Box::new(inputs))?;
+        #[allow(irrefutable_let_patterns)]
+        let ForwardInputsResult::CausalGeneration { logits } = logits
+        else {
+            candle_core::bail!("Speculative decoding requires `CausalGeneration` forward results");
+        };
+
+        // Reset the prefill tokens
+        for seq in seqs.iter_mut() {
+            seq.reset_prefill_toks();
+        }
+
+        // ======================= Rejection sampling. ============================
+        // Map from each target sample to corresponding in draft sample
+        let mut accepted_tokens = Vec::with_capacity(n_seqs);
+        for ((seq, logits), draft) in
+            zip(seqs.iter_mut(), logits.chunk(n_seqs, 0)?).zip(&draft_tokens)
+        {
+            let samples = sample_target_sequence_speculative(
+                logits,
+                seq,
+                seq.return_logprobs(),
+                rng.clone(),
+                self.gamma,
+            )
+            .await?;
+
+            let n_accepted = n_accepted_draft_tokens(
+                draft,
+                &samples.iter().map(|x| x.sample.token).collect::<Vec<_>>(),
+            );
+            SPECULATIVE_STATS.record(draft.len(), n_accepted);
+            // Keep the agreeing tokens, and the target's own sample at the first disagreement.
+            accepted_tokens.push(
+                samples
+                    .into_iter()
+                    .take((n_accepted + 1).min(draft.len()))
+                    .map(|x| x.sample)
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        // ======================= Narrow caches to account for rejections ============================
+        // The caches hold the last token and all draft tokens but the last one. If the sequences
+        // keep different numbers of tokens, their lengths now differ, so they are bucketed apart
+        // and cloned in again next step: only the caches cloned out for each sequence are narrowed.
+        let n_not_accepted = accepted_tokens
+            .iter()
+            .map(|accepted| self.gamma - accepted.len())
+            .collect::<Vec<_>>();
+        let uniform = n_not_accepted.windows(2).all(|n| n[0] == n[1]);
+        if uniform {
+            for pipeline in [&self.draft, &self.target] {
+                let pipeline = get_mut_arcmutex!(pipeline);
+                narrow_cache(&mut pipeline.cache().lock(), n_not_accepted[0])?;
+                if pipeline.get_metadata().is_xlora {
+                    narrow_cache(&mut pipeline.cache().xlora_lock(), n_not_accepted[0])?;
+                }
+            }
+        }
+
+        let eos_owned = get_mut_arcmutex!(self.target)
+            .get_metadata()
+            .eos_tok
+            .clone();
+        let eos_tok = if disable_eos_stop {
+            None
+        } else {
+            Some(&eos_owned[..])
+        };
+        // Add the tokens to the seq and the trie
+        for (seq, accepted_tokens) in zip(seqs.iter_mut(), accepted_tokens) {
+            for accepted in accepted_tokens {
+                // Do not use the prefix cacher
+                finish_or_add_toks_to_seq(
+                    self,
+                    prefix_cacher,
+                    seq,
+                    accepted.clone(),
+                    eos_tok,
+                    false,
+                )
+                .await?;
+                if seq.is_finished_paged_attn() {
+                    break;
+                }
+                match seq.recognizer {
+                    SequenceRecognizer::Regex(ref mut rx) => {
+                        get_mut_arcmutex!(self.target)
+                            .get_metadata()
+                            .tok_trie
+                            .as_ref()
+                            .ok_or(candle_core::Error::Msg(
+                                "`SpeculativePipeline::step` requires a token trie".to_string(),
+                            ))?
+                            .append_token(rx.as_mut(), accepted.token)
+                            .map_err(candle_core::Error::msg)?;
+                    }
+                    SequenceRecognizer::Cfg(ref mut cfg) => {
+                        get_mut_arcmutex!(self.target)
+                            .get_metadata()
+                            .tok_trie
+                            .as_ref()
+                            .ok_or(candle_core::Error::Msg(
+                                "`SpeculativePipeline::step` requires a token trie".to_string(),
+                            ))?
+                            .append_token(cfg.as_mut(), accepted.token)
+                            .map_err(candle_core::Error::msg)?;
+                    }
+                    SequenceRecognizer::None => {}
+                }
+            }
+        }
+
+        match post_op {
+            CacheInstruction::Out => {
+                self.clone_out_cache(seqs, true);
+                if !uniform {
+                    let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
+                    for (seq, n) in zip(seqs.iter_mut(), n_not_accepted) {
+                        narrow_cache(seq.cache(), n)?;
+                        narrow_cache(seq.draft_cache(), n)?;
+                        if is_xlora {
+                            narrow_cache(seq.xlora_cache(), n)?;
+                        }
+                    }
+                }
+            }
+            CacheInstruction::Nothing(_) => (),
+            CacheInstruction::Reset {
+                reset_non_granular,
+                adapter_inst: _,
+            } => self.set_none_cache(*reset_non_granular, true),
+            _ => unreachable!("Unreachable post cache op."),
+        }
+
+        // Done! We have:
+        // - Run the draft model gamma times for every sequence
+        // - Sampled draft model's distributions
+        // - Run target model once over all draft tokens of all sequences
+        // - Execute speculative decoding algorithm on the resulting distributions
+        // - Added the accepted tokens to buffer and trie
+        // - Fixed up the caches based on accepted tokens.
+
+        Ok(())
+    }
+}
+
+/// Drop the last `n` positions of every layer of `cache`.
+fn narrow_cache(cache: &mut LayerCaches, n: usize) -> Result<()> {
+    for (k, v) in cache.iter_mut().flatten() {
+        *k = k.i((.., .., ..k.dims()[2] - n, ..))?;
+        *v = v.i((.., .., ..v.dims()[2] - n, ..))?;
+    }
+    Ok(())
 }
 
 impl PreProcessingMixin for SpeculativePipeline {
@@ -329,327 +656,47 @@ impl Pipeline for SpeculativePipeline {
         rng: Arc<Mutex<Isaac64Rng>>,
         backend_metadata: CacheBackendMetadata<'_>,
     ) -> Result<()> {
-        match backend_metadata {
-            CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
-                match pre_op {
-                    CacheInstruction::In(adapter_inst) => {
-                        match adapter_inst {
-                            AdapterInstruction::Activate(adapters) => {
-                                self.activate_adapters(adapters).map_err(|e| {
-                                    candle_core::Error::msg(<anyhow::Error as AsRef<
-                                        dyn std::error::Error,
-                                    >>::as_ref(
-                                        &e
-                                    ))
-                                })?
-                            }
-                            AdapterInstruction::None => 0,
-                        };
-                        self.clone_in_cache(input_seqs, false)
-                    }
-                    CacheInstruction::Nothing(adapter_inst) => {
-                        match adapter_inst {
-                            AdapterInstruction::Activate(adapters) => {
-                                self.activate_adapters(adapters).map_err(|e| {
-                                    candle_core::Error::msg(<anyhow::Error as AsRef<
-                                        dyn std::error::Error,
-                                    >>::as_ref(
-                                        &e
-                                    ))
-                                })?
-                            }
-                            AdapterInstruction::None => 0,
-                        };
-                    }
-                    CacheInstruction::Reset {
-                        reset_non_granular,
-                        adapter_inst,
-                    } => {
-                        match adapter_inst {
-                            AdapterInstruction::Activate(adapters) => {
-                                self.activate_adapters(adapters).map_err(|e| {
-                                    candle_core::Error::msg(<anyhow::Error as AsRef<
-                                        dyn std::error::Error,
-                                    >>::as_ref(
-                                        &e
-                                    ))
-                                })?
-                            }
-                            AdapterInstruction::None => 0,
-                        };
-                        self.set_none_cache(reset_non_granular, false)
-                    }
-                    _ => unreachable!("Unreachable PRE cache op."),
-                }
-
-                assert_eq!(input_seqs.len(), 1);
-
-                let seq = &mut input_seqs[0];
-
-                // ======================= Run draft model gamma times producing tokens ============================
-                // ======================= Sample the `gamma` logits. ============================
-                let mut draft_samples = Vec::new();
-                for i in 0..self.gamma {
-                    let is_xlora = get_mut_arcmutex!(self.draft).get_metadata().is_xlora;
-                    let device = get_mut_arcmutex!(self.draft).device();
-                    let has_no_kv_cache =
-                        get_mut_arcmutex!(self.draft).get_metadata().has_no_kv_cache;
-                    let inputs = self
-                        .get_processor()
-                        .inputs_processor()
-                        .process_inputs(
-                            self.tokenizer(),
-                            &mut [seq],
-                            is_prompt && i == 0, // Only prompt (no kv cache) if first
-                            is_xlora,
-                            &device,
-                            has_no_kv_cache,
-                            None,
-                            None,
-                            None, // TODO: get block tables/handle it
-                            None, // TODO: do we support???
-                        )
-                        .nth(0)
-                        .unwrap()
-                        .unwrap();
-                    let logits = get_mut_arcmutex!(self.draft).forward_inputs(Box::new(inputs))?;
-                    #[allow(irrefutable_let_patterns)]
-                    let ForwardInputsResult::CausalGeneration { logits } = logits
-                    else {
-                        candle_core::bail!(
-                            "Speculative decoding requires `CausalGeneration` forward results"
-                        );
-                    };
-
-                    let sample = sample_sequence(
-                        logits.clone(),
-                        seq,
-                        seq.return_logprobs(),
-                        rng.clone(),
-                        false, // todo tune
-                        false, // do not add to tok trie yet
-                        true,
-                    )
-                    .await?;
-                    seq.add_tmp_tok(sample.token);
-                    draft_samples.push(SpeculativeSample { sample });
-                }
-                seq.remove_tmp_tok(self.gamma);
-
-                // ======================= Add all draft tokens but the last one. Add the last from the seq. ============================
-                let mut draft_prefill_tokens = if is_prompt {
-                    seq.get_toks().to_vec()
-                } else {
-                    vec![*seq.get_toks().last().unwrap()]
-                };
-                for (i, sample) in draft_samples.iter().enumerate() {
-                    if i == draft_samples.len() - 1 {
-                        continue;
-                    }
-                    draft_prefill_tokens.push(sample.sample.token);
-                }
-                seq.set_prefill_toks(draft_prefill_tokens);
-
-                // ======================= Run the model with all draft tokens. ============================
-
-                let initial_cache_len = get_mut_arcmutex!(self.target).cache().lock()[0]
-                    .as_ref()
-                    .map(|(k, _)| k.dims()[2])
-                    .unwrap_or(0);
-
-                // ========= Run the model ============
-                let is_xlora = get_mut_arcmutex!(self.target).get_metadata().is_xlora;
-                let device = get_mut_arcmutex!(self.target).device();
-                let has_no_kv_cache = get_mut_arcmutex!(self.target)
-                    .get_metadata()
-                    .has_no_kv_cache;
-                let inputs = self
-                    .get_processor()
-                    .inputs_processor()
-                    .process_inputs(
-                        self.tokenizer(),
-                        &mut [seq],
-                        true, // use the "prefill" tokens
-                        is_xlora,
-                        &device,
-                        has_no_kv_cache,
-                        Some((self.gamma, initial_cache_len)), // Get the last gamma, see above
-                        None,
-                        None, // TODO: get block tables/handle it
-                        None, // TODO: do we support???
-                    )
-                    .nth(0)
-                    .unwrap()
-                    .unwrap();
-
-                let logits = get_mut_arcmutex!(self.target).forward_inputs(Box::new(inputs))?;
-                #[allow(irrefutable_let_patterns)]
-                let ForwardInputsResult::CausalGeneration { logits } = logits
-                else {
-                    candle_core::bail!(
-                        "Speculative decoding requires `CausalGeneration` forward results"
-                    );
-                };
-
-                // Reset the prefill tokens
-                seq.reset_prefill_toks();
-
-                // ======================= Rejection sampling. ============================
-                // Map from each target sample to corresponding in draft sample
-                let samples = sample_target_sequence_speculative(
-                    logits.clone(),
-                    seq,
-                    seq.return_logprobs(),
-                    rng.clone(),
-                    self.gamma,
-                )
-                .await?;
-
-                let mut accepted_tokens = Vec::new();
-                for (target_sample, draft_sample) in zip(samples, draft_samples) {
-                    let tok = target_sample.sample.token;
-                    accepted_tokens.push(target_sample.sample);
-                    if draft_sample.sample.token != tok {
-                        break;
-                    }
-                }
-
-                // ======================= Narrow caches to account for rejections ============================
-                let n_not_accepted = self.gamma - accepted_tokens.len();
-                for (k, v) in get_mut_arcmutex!(self.draft)
-                    .cache()
-                    .lock()
-                    .iter_mut()
-                    .flatten()
-                {
-                    *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                    *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-                    for (k, v) in get_mut_arcmutex!(self.draft)
-                        .cache()
-                        .xlora_lock()
-                        .iter_mut()
-                        .flatten()
-                    {
-                        *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                        *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                    }
-                }
-                for (k, v) in get_mut_arcmutex!(self.target)
-                    .cache()
-                    .lock()
-                    .iter_mut()
-                    .flatten()
-                {
-                    *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                    *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-                    for (k, v) in get_mut_arcmutex!(self.target)
-                        .cache()
-                        .xlora_lock()
-                        .iter_mut()
-                        .flatten()
-                    {
-                        *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                        *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                    }
-                }
+        let CacheBackendMetadata::DefaultInstructions { pre_op, post_op } = backend_metadata else {
+            unreachable!("Speculative decoding runs without PagedAttention.")
+        };
 
-                let eos_owned = get_mut_arcmutex!(self.target)
-                    .get_metadata()
-                    .eos_tok
-                    .clone();
-                let eos_tok = if disable_eos_stop {
-                    None
-                } else {
-                    Some(&eos_owned[..])
-                };
-                // Add the tokens to the seq and the trie
-                for accepted in accepted_tokens {
-                    // Do not use the prefix cacher
-                    finish_or_add_toks_to_seq(
-                        self,
-                        prefix_cacher,
-                        seq,
-                        accepted.clone(),
-                        eos_tok,
-                        false,
-                    )
-                    .await?;
-                    match seq.recognizer {
-                        SequenceRecognizer::Regex(ref mut rx) => {
-                            get_mut_arcmutex!(self.target)
-                                .get_metadata()
-                                .tok_trie
-                                .as_ref()
-                                .ok_or(candle_core::Error::Msg(
-                                    "`SpeculativePipeline::step` requires a token trie".to_string(),
-                                ))?
-                                .append_token(rx.as_mut(), accepted.token)
-                                .map_err(candle_core::Error::msg)?;
-                        }
-                        SequenceRecognizer::Cfg(ref mut cfg) => {
-                            get_mut_arcmutex!(self.target)
-                                .get_metadata()
-                                .tok_trie
-                                .as_ref()
-                                .ok_or(candle_core::Error::Msg(
-                                    "`SpeculativePipeline::step` requires a token trie".to_string(),
-                                ))?
-                                .append_token(cfg.as_mut(), accepted.token)
-                                .map_err(candle_core::Error::msg)?;
-                        }
-                        SequenceRecognizer::None => {}
-                    }
-                }
+        let (CacheInstruction::In(adapter_inst)
+        | CacheInstruction::Nothing(adapter_inst)
+        | CacheInstruction::Reset { adapter_inst, .. }) = &pre_op
+        else {
+            unreachable!("Unreachable PRE cache op.")
+        };
+        if let AdapterInstruction::Activate(adapters) = adapter_inst {
+            self.activate_adapters(adapters.clone())
+                .map_err(|e| candle_core::Error::msg(e.to_string()))?;
+        }
 
-                // Trick to improve lower bounds. Sample last token in multinomial
-                /*
-                let sample = sample_sequence(
-                    logits.clone(),
-                    seq,
-                    seq.return_logprobs(),
-                    rng.clone(),
-                    false, // todo tune
-                    true, // do not add to tok trie yet
+        if is_prompt {
+            // Prompts differ in length, so each one is run on its own, with its own caches.
+            for seq in input_seqs.iter_mut() {
+                self.speculate(
+                    &mut [&mut **seq],
                     true,
+                    &pre_op,
+                    &post_op,
+                    prefix_cacher,
+                    disable_eos_stop,
+                    rng.clone(),
                 )
                 .await?;
-                finish_or_add_toks_to_seq(self, prefix_cacher, seq, sample, eos_tok, false);
-                */
-
-                match post_op {
-                    CacheInstruction::Out => {
-                        self.clone_out_cache(input_seqs, true);
-                    }
-                    CacheInstruction::Nothing(_) => (),
-                    CacheInstruction::Reset {
-                        reset_non_granular,
-                        adapter_inst: _,
-                    } => self.set_none_cache(reset_non_granular, true),
-                    _ => unreachable!("Unreachable pre cache op."),
-                }
-
-                // Done! We have:
-                // - Run the draft model gamma times
-                // - Reset draft model cache fully
-                // - Sampled draft model's distributions
-                // - Run target model
-                // - Execute speculative decoding algorithm on the resulting distributions
-                // - Added the accepted tokens to buffer and trie
-                // - Maybe fixed up cache of base model based on accepted tokens.
-
-                Ok(())
             }
-            CacheBackendMetadata::PagedAttention {
-                metadata: _,
-                blocks_to_copy: _,
-                blocks_to_swap_in: _,
-                blocks_to_swap_out: _,
-            } => unreachable!(),
+            return Ok(());
         }
+        self.speculate(
+            input_seqs,
+            false,
+            &pre_op,
+            &post_op,
+            prefix_cacher,
+            disable_eos_stop,
+            rng,
+        )
+        .await
     }
     fn category(&self) -> ModelCategory {
         self.category
@@ -658,3 +705,89 @@ impl Pipeline for SpeculativePipeline {
 
 // TODO
 impl AnyMoePipelineMixin for SpeculativePipeline {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{Device, Tensor};
+
+    use crate::{
+        pipeline::testing::{complete, serve},
+        Pipeline, SamplingParams, TestPipeline,
+    };
+
+    use super::{n_accepted_draft_tokens, narrow_cache, SpeculativeConfig, SpeculativePipeline};
+
+    fn speculative(gamma: usize) -> Arc<tokio::sync::Mutex<dyn Pipeline>> {
+        let target = Arc::new(tokio::sync::Mutex::new(TestPipeline::new("target")));
+        let draft = Arc::new(tokio::sync::Mutex::new(TestPipeline::draft("draft")));
+        let pipeline = SpeculativePipeline::new(target, draft, SpeculativeConfig { gamma })
+            .expect("The models share a tokenizer.");
+        Arc::new(tokio::sync::Mutex::new(pipeline))
+    }
+
+    fn greedy(max_len: usize) -> SamplingParams {
+        SamplingParams {
+            temperature: Some(0.),
+            max_len: Some(max_len),
+            ..SamplingParams::deterministic()
+        }
+    }
+
+    #[test]
+    fn accepts_draft_tokens_until_the_first_disagreement() {
+        assert_eq!(n_accepted_draft_tokens(&[1, 2, 3], &[1, 2, 3]), 3);
+        assert_eq!(n_accepted_draft_tokens(&[1, 2, 3], &[1, 5, 3]), 1);
+        assert_eq!(n_accepted_draft_tokens(&[1, 2, 3], &[4, 2, 3]), 0);
+    }
+
+    #[test]
+    fn narrows_every_layer_by_the_rejected_draft_tokens() {
+        let kv = Tensor::arange(0f32, 12., &Device::Cpu)
+            .unwrap()
+            .reshape((1, 1, 6, 2))
+            .unwrap();
+        // Cross attention layers have no cache.
+        let mut cache = vec![Some((kv.clone(), kv.clone())), None, Some((kv.clone(), kv))];
+        narrow_cache(&mut cache, 2).unwrap();
+        for (k, v) in cache.iter().flatten() {
+            assert_eq!(k.dims(), &[1, 1, 4, 2]);
+            assert_eq!(v.flatten_all().unwrap().to_vec1::<f32>().unwrap()[7], 7.);
+        }
+        assert!(cache[1].is_none());
+    }
+
+    #[tokio::test]
+    async fn greedy_speculative_decoding_generates_the_target_tokens() {
+        let mistralrs = serve(speculative(3), true);
+        // The draft disagrees with the target on 4 of these tokens, so some are rejected.
+        let expected = TestPipeline::greedy_completion(&[0, 1, 2], 16);
+        assert_eq!(
+            complete(&mistralrs, &["a b c"], greedy(16)).await,
+            [expected.clone()]
+        );
+
+        let target_only = TestPipeline::new("target").build(true);
+        assert_eq!(
+            complete(&target_only, &["a b c"], greedy(16)).await,
+            [expected]
+        );
+    }
+
+    #[tokio::test]
+    async fn batched_verification_keeps_each_sequence_apart() {
+        let mistralrs = serve(speculative(3), true);
+        // Both prompts keep all 3 draft tokens of their prompt steps, so their completions are
+        // batched. In that batch, `a a a` keeps 1 and `a a d` 3, so each cache is narrowed apart.
+        let batched = complete(&mistralrs, &["a a a", "a a d"], greedy(12)).await;
+        assert_eq!(batched.len(), 2);
+        let alone = complete(&mistralrs, &["a a a"], greedy(12)).await;
+        assert_eq!(batched[0], alone[0]);
+        let alone = complete(&mistralrs, &["a a d"], greedy(12)).await;
+        assert_eq!(batched[1], alone[0]);
+
+        assert_eq!(batched[0], TestPipeline::greedy_completion(&[0, 0, 0], 12));
+        assert_eq!(batched[1], TestPipeline::greedy_completion(&[0, 0, 3], 12));
+    }
+}
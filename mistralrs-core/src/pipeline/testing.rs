@@ -0,0 +1,366 @@
+//! A deterministic stand-in for a text model, so that the engine, schedulers and servers can be
+//! tested end to end without model weights.
+
+use std::{any::Any, collections::HashMap, num::NonZeroUsize, str::FromStr, sync::Arc};
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use mistralrs_quant::IsqType;
+use rand_isaac::Isaac64Rng;
+use tokenizers::Tokenizer;
+
+#[cfg(test)]
+use tokio::sync::mpsc::{channel, Receiver};
+
+use crate::{
+    aici::{bytes::TokRxInfo, toktree::TokTrie},
+    pipeline::sampling::sample_and_add_toks,
+    prefix_cacher::PrefixCacheManager,
+    sequence::Sequence,
+    DefaultSchedulerMethod, MistralRs, MistralRsBuilder, SchedulerConfig,
+};
+#[cfg(test)]
+use crate::{NormalRequest, Request, RequestMessage, Response, SamplingParams};
+
+use super::{
+    cache_manager::DefaultCacheManager, chat_template::ChatTemplate, extract_logits,
+    text_models_inputs_processor::ModelInputs, AdapterActivationMixin, AnyMoePipelineMixin, Cache,
+    CacheManager, CacheManagerMixin, ForwardInputsResult, GeneralMetadata, IsqPipelineMixin,
+    MetadataMixin, ModelCategory, ModelKind, Pipeline, PreProcessingMixin,
+};
+
+/// The words `a` to `n`, then the EOS and unknown tokens.
+const VOCAB: [&str; 16] = [
+    "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "</s>", "<unk>",
+];
+/// Only words are predicted or sampled, so generation always runs to its length limit.
+const N_WORDS: u32 = 14;
+const EOS: u32 = 14;
+/// The logit of the predicted token, the other words being 0. It is small so that sampled choices
+/// differ.
+const LOGIT: f32 = 1.;
+/// The logit of the EOS and unknown tokens, which are never sampled.
+const BANNED_LOGIT: f32 = -100.;
+
+#[derive(Clone, Copy)]
+enum Rule {
+    Target,
+    Draft,
+}
+
+/// A one layer causal LM over [`VOCAB`] whose KV cache holds the tokens themselves. The next token
+/// is a function of every token before it, read back from the cache, so a cache which is reused,
+/// narrowed or batched wrongly changes the output.
+///
+/// Text is tokenized by whitespace, and chat messages are joined by spaces.
+pub struct TestPipeline {
+    name: String,
+    rule: Rule,
+    cache: Cache,
+    tokenizer: Arc<Tokenizer>,
+    chat_template: Arc<ChatTemplate>,
+    metadata: Arc<GeneralMetadata>,
+}
+
+impl TestPipeline {
+    /// A model generating [`TestPipeline::next_token`].
+    pub fn new(name: &str) -> Self {
+        Self::with_rule(name, Rule::Target)
+    }
+
+    /// A draft model for [`TestPipeline::new`], which disagrees with it wherever the target
+    /// predicts a multiple of 4.
+    pub fn draft(name: &str) -> Self {
+        Self::with_rule(name, Rule::Draft)
+    }
+
+    fn with_rule(name: &str, rule: Rule) -> Self {
+        let vocab = VOCAB
+            .iter()
+            .zip(0u32..)
+            .map(|(tok, id)| (tok.to_string(), id))
+            .collect::<HashMap<_, _>>();
+        let tokenizer = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [{
+                "id": EOS,
+                "content": VOCAB[EOS as usize],
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true,
+            }],
+            "normalizer": null,
+            "pre_tokenizer": { "type": "Whitespace" },
+            "post_processor": null,
+            "decoder": null,
+            "model": { "type": "WordLevel", "vocab": vocab, "unk_token": "<unk>" },
+        });
+        let tokenizer =
+            Tokenizer::from_str(&tokenizer.to_string()).expect("The test tokenizer is valid.");
+        let chat_template = serde_json::from_value(serde_json::json!({
+            "chat_template": "{% for message in messages %}{{ message['content'] }} {% endfor %}",
+            "eos_token": VOCAB[EOS as usize],
+        }))
+        .expect("The test chat template is valid.");
+        // Like `build_tok_trie`, special tokens have no bytes.
+        let token_bytes = VOCAB
+            .iter()
+            .zip(0u32..)
+            .map(|(tok, id)| {
+                if id == EOS {
+                    Vec::new()
+                } else {
+                    tok.as_bytes().to_vec()
+                }
+            })
+            .collect::<Vec<_>>();
+        let tok_trie = TokTrie::from(
+            &TokRxInfo {
+                vocab_size: VOCAB.len() as u32,
+                tok_eos: EOS,
+            },
+            &token_bytes,
+        );
+        Self {
+            name: name.to_string(),
+            rule,
+            cache: Cache::new(1, false),
+            tokenizer: Arc::new(tokenizer),
+            chat_template: Arc::new(chat_template),
+            metadata: Arc::new(GeneralMetadata {
+                max_seq_len: 4096,
+                tok_trie: Some(Arc::new(tok_trie)),
+                has_no_kv_cache: false,
+                num_hidden_layers: 1,
+                eos_tok: vec![EOS],
+                kind: ModelKind::Normal,
+                is_xlora: false,
+                activation_dtype: DType::F32,
+                sliding_window: None,
+                cache_config: None,
+                cache_engine: None,
+                prompt_batchsize: None,
+                adapter_names: Vec::new(),
+                isq: None,
+            }),
+        }
+    }
+
+    /// The token which [`TestPipeline::new`] predicts after `context`.
+    pub fn next_token(context: &[u32]) -> u32 {
+        let hash = context
+            .iter()
+            .fold(7u64, |hash, tok| (hash * 31 + *tok as u64 + 1) % 65_521);
+        (hash % N_WORDS as u64) as u32
+    }
+
+    /// The `n` tokens which [`TestPipeline::new`] generates greedily after `prompt`.
+    pub fn greedy_completion(prompt: &[u32], n: usize) -> Vec<u32> {
+        let mut toks = prompt.to_vec();
+        for _ in 0..n {
+            toks.push(Self::next_token(&toks));
+        }
+        toks.split_off(prompt.len())
+    }
+
+    fn predict(&self, context: &[u32]) -> u32 {
+        let next = Self::next_token(context);
+        match self.rule {
+            Rule::Target => next,
+            Rule::Draft if next % 4 == 0 => (next + 1) % N_WORDS,
+            Rule::Draft => next,
+        }
+    }
+}
+
+impl PreProcessingMixin for TestPipeline {
+    fn get_chat_template(&self) -> Option<Arc<ChatTemplate>> {
+        Some(self.chat_template.clone())
+    }
+    fn get_input_processor_config(&self) -> Option<Arc<dyn Any>> {
+        None
+    }
+}
+
+impl IsqPipelineMixin for TestPipeline {
+    fn re_isq_model(&mut self, _dtype: IsqType) -> Result<()> {
+        anyhow::bail!("The test pipeline cannot be quantized.")
+    }
+}
+
+impl CacheManagerMixin for TestPipeline {
+    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
+        DefaultCacheManager.clone_in_cache(self, seqs, modify_draft_cache)
+    }
+    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
+        DefaultCacheManager.clone_out_cache(self, seqs, modify_draft_cache)
+    }
+    fn set_none_cache(&self, _reset_non_granular: bool, modify_draft_cache: bool) {
+        DefaultCacheManager.set_none_cache(self, modify_draft_cache);
+    }
+    fn cache(&self) -> &Cache {
+        &self.cache
+    }
+}
+
+impl AdapterActivationMixin for TestPipeline {
+    fn activate_adapters(&mut self, _adapters: Vec<String>) -> Result<usize> {
+        anyhow::bail!("The test pipeline has no adapters.")
+    }
+}
+
+impl MetadataMixin for TestPipeline {
+    fn device(&self) -> Device {
+        Device::Cpu
+    }
+    fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
+        Some(self.tokenizer.clone())
+    }
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+    fn reset_non_granular_state(&self) {}
+    fn get_metadata(&self) -> Arc<GeneralMetadata> {
+        self.metadata.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl Pipeline for TestPipeline {
+    fn forward_inputs(
+        &mut self,
+        inputs: Box<dyn Any>,
+    ) -> Result<ForwardInputsResult, candle_core::Error> {
+        let ModelInputs {
+            input_ids,
+            context_lens,
+            ..
+        } = *inputs.downcast().expect("Downcast failed.");
+        let (bs, seq_len) = input_ids.dims2()?;
+        let input = input_ids
+            .to_dtype(DType::F32)?
+            .reshape((bs, 1, seq_len, 1))?;
+        let context = {
+            let mut cache = self.cache.lock();
+            let (k, v) = match &cache[0] {
+                None => (input.clone(), input),
+                Some((k, v)) => (Tensor::cat(&[k, &input], 2)?, Tensor::cat(&[v, &input], 2)?),
+            };
+            cache[0] = Some((k.clone(), v));
+            k
+        };
+        let context_len = context.dim(2)?;
+        let context = context
+            .flatten_all()?
+            .to_vec1::<f32>()?
+            .into_iter()
+            .map(|tok| tok as u32)
+            .collect::<Vec<_>>();
+
+        let mut logits = Vec::with_capacity(bs * seq_len * VOCAB.len());
+        for row in context.chunks(context_len) {
+            for pos in 0..seq_len {
+                let next = self.predict(&row[..context_len - seq_len + pos + 1]);
+                logits.extend((0..VOCAB.len() as u32).map(|tok| match tok {
+                    tok if tok == next => LOGIT,
+                    tok if tok >= N_WORDS => BANNED_LOGIT,
+                    _ => 0.,
+                }));
+            }
+        }
+        let logits = Tensor::from_vec(logits, (bs, seq_len, VOCAB.len()), &Device::Cpu)?;
+        Ok(ForwardInputsResult::CausalGeneration {
+            logits: extract_logits(&logits, context_lens)?,
+        })
+    }
+    async fn sample_causal_gen(
+        &self,
+        seqs: &mut [&mut Sequence],
+        logits: Vec<Tensor>,
+        prefix_cacher: &mut PrefixCacheManager,
+        disable_eos_stop: bool,
+        rng: Arc<std::sync::Mutex<Isaac64Rng>>,
+    ) -> Result<(), candle_core::Error> {
+        sample_and_add_toks(self, seqs, logits, prefix_cacher, disable_eos_stop, rng).await
+    }
+    fn category(&self) -> ModelCategory {
+        ModelCategory::Text
+    }
+}
+
+impl AnyMoePipelineMixin for TestPipeline {}
+
+impl TestPipeline {
+    /// Serve this model with the default scheduler.
+    pub fn build(self, no_prefix_cache: bool) -> Arc<MistralRs> {
+        serve(Arc::new(tokio::sync::Mutex::new(self)), no_prefix_cache)
+    }
+}
+
+/// Serve `pipeline`, such as one around [`TestPipeline`]s, with the default scheduler.
+pub(crate) fn serve(
+    pipeline: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    no_prefix_cache: bool,
+) -> Arc<MistralRs> {
+    let method = DefaultSchedulerMethod::Fixed(NonZeroUsize::new(16).expect("16 is not zero."));
+    MistralRsBuilder::new(pipeline, SchedulerConfig::DefaultScheduler { method })
+        .with_no_prefix_cache(no_prefix_cache)
+        .build()
+}
+
+/// Send a completion request for `prompts` to `mistralrs`, returning the receiver of its responses.
+#[cfg(test)]
+pub(crate) async fn send_completion(
+    mistralrs: &MistralRs,
+    prompts: &[&str],
+    params: SamplingParams,
+) -> Receiver<Response> {
+    let (tx, rx) = channel(16);
+    let request = NormalRequest::new_simple(
+        RequestMessage::Completion {
+            text: prompts.iter().map(|prompt| prompt.to_string()).collect(),
+            echo_prompt: false,
+            best_of: params.n_choices,
+            beam_search: None,
+        },
+        params,
+        tx,
+        mistralrs.next_request_id(),
+        None,
+        None,
+    );
+    mistralrs
+        .get_sender()
+        .expect("The engine is running.")
+        .send(Request::Normal(request))
+        .await
+        .expect("The engine is running.");
+    rx
+}
+
+/// Complete `prompts` with `mistralrs`, returning the raw tokens of each choice, by index.
+#[cfg(test)]
+pub(crate) async fn complete(
+    mistralrs: &MistralRs,
+    prompts: &[&str],
+    params: SamplingParams,
+) -> Vec<Vec<u32>> {
+    let params = SamplingParams {
+        return_raw_tokens: true,
+        ..params
+    };
+    let mut rx = send_completion(mistralrs, prompts, params).await;
+    let Some(Response::CompletionDone(mut response)) = rx.recv().await else {
+        panic!("Expected a completion.");
+    };
+    response.choices.sort_by_key(|choice| choice.index);
+    response
+        .choices
+        .into_iter()
+        .map(|choice| choice.raw_tokens.expect("Raw tokens were requested."))
+        .collect()
+}
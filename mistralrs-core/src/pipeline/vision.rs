@@ -362,6 +362,8 @@ impl Loader for VisionLoader {
                 cache_config,
                 cache_engine,
                 prompt_batchsize: self.config.prompt_batchsize,
+                adapter_names: Vec::new(),
+                isq: in_situ_quant,
             }),
             processor,
             preprocessor_config: Arc::new(preprocessor_config),
@@ -1,10 +1,17 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
 
 use candle_core::{Device, Result, Tensor};
 use radix_trie::{Trie, TrieCommon, TrieKey};
 
 use crate::{get_mut_arcmutex, pipeline::LayerCaches, sequence::Sequence};
 
+/// The number of tokens held by all prefix caches, on the device and the CPU, before the least
+/// recently used ones are dropped.
+const MAX_CACHED_TOKS: usize = 32 * 1024;
+
 #[derive(PartialEq, Eq)]
 struct Tokens(Vec<u32>);
 
@@ -23,57 +30,105 @@ impl From<Vec<u32>> for Tokens {
     }
 }
 
-type EvictionCacheGroup = (Arc<Mutex<LayerCaches>>, Option<Arc<Mutex<LayerCaches>>>);
+struct CacheEntry {
+    normal: Arc<Mutex<LayerCaches>>,
+    xlora: Option<Arc<Mutex<LayerCaches>>>,
+}
 
 pub struct PrefixCacheManager {
-    caches: Trie<Tokens, Arc<Mutex<LayerCaches>>>,
-    xlora_caches: Option<Trie<Tokens, Arc<Mutex<LayerCaches>>>>,
+    /// Keyed by the tokens the cache holds, so that the longest cached prefix of a prompt is its
+    /// closest ancestor.
+    caches: Trie<Tokens, CacheEntry>,
+    /// Keys of `caches`, least recently used first.
+    lru: VecDeque<Vec<u32>>,
+    n_cached_toks: usize,
+    max_cached_toks: usize,
     device: Device,
     pub n_on_device: usize,
     no_prefix_cache: bool,
-    eviction_cache_ptrs: Vec<EvictionCacheGroup>,
 }
 
 #[derive(Clone)]
 pub struct MatchingCache {
     pub normal: LayerCaches,
     pub xlora: Option<LayerCaches>,
+    /// The prompt tokens which are not covered by the cache.
     pub toks: Vec<u32>,
 }
 
 impl PrefixCacheManager {
-    pub fn new(device: Device, n_on_device: usize, is_xlora: bool, no_prefix_cache: bool) -> Self {
+    pub fn new(device: Device, n_on_device: usize, no_prefix_cache: bool) -> Self {
         PrefixCacheManager {
             caches: Trie::new(),
-            xlora_caches: if is_xlora { Some(Trie::new()) } else { None },
+            lru: VecDeque::new(),
+            n_cached_toks: 0,
+            max_cached_toks: MAX_CACHED_TOKS,
             device,
             n_on_device,
             no_prefix_cache,
-            eviction_cache_ptrs: Vec::new(),
         }
     }
 
     /// This always keeps the cache on the device. If later on, a new seq cannot be allocated due to memory shortage,
     /// some caches will be evicted.
     pub fn add_sequence(&mut self, seq: &mut Sequence) {
-        if self.no_prefix_cache {
+        // The cache depends on the active adapters and the images, which the tokens do not capture.
+        if self.no_prefix_cache || seq.get_adapters().is_some() || seq.images().is_some() {
+            return;
+        }
+        let Some(n_cached) = Self::cache_len(seq.cache()) else {
+            return;
+        };
+        // The last sampled token has not been run yet. If the cache holds fewer tokens than that,
+        // it was truncated (for example to a sliding window) and cannot be resumed from.
+        if n_cached == 0 || n_cached + 1 != seq.get_toks().len() {
+            return;
+        }
+        let toks = seq.get_toks()[..n_cached].to_vec();
+        let normal = seq.cache().clone();
+        let xlora = if seq.is_xlora() {
+            Some(seq.xlora_cache().clone())
+        } else {
+            None
+        };
+        self.insert(toks, normal, xlora);
+    }
+
+    fn insert(&mut self, toks: Vec<u32>, normal: LayerCaches, xlora: Option<LayerCaches>) {
+        if toks.len() > self.max_cached_toks {
             return;
         }
-        let cache = Arc::new(Mutex::new(seq.cache().clone()));
-        self.caches
-            .insert(seq.get_toks().to_vec().into(), cache.clone());
-        if seq.is_xlora() {
-            let xlora_cache = Arc::new(Mutex::new(seq.xlora_cache().clone()));
-            self.xlora_caches
-                .as_mut()
-                .unwrap()
-                .insert(seq.get_toks().to_vec().into(), xlora_cache.clone());
-            self.eviction_cache_ptrs.push((cache, Some(xlora_cache)));
+        let entry = CacheEntry {
+            normal: Arc::new(Mutex::new(normal)),
+            xlora: xlora.map(|xlora| Arc::new(Mutex::new(xlora))),
+        };
+        if self.caches.insert(toks.clone().into(), entry).is_some() {
+            self.lru.retain(|key| key != &toks);
         } else {
-            self.eviction_cache_ptrs.push((cache, None));
+            self.n_cached_toks += toks.len();
+        }
+        self.lru.push_back(toks);
+
+        while self.n_cached_toks > self.max_cached_toks {
+            let Some(key) = self.lru.pop_front() else {
+                break;
+            };
+            self.n_cached_toks -= key.len();
+            self.caches.remove(&Tokens(key));
         }
     }
 
+    /// The number of tokens held by `cache`, or `None` if it is empty.
+    fn cache_len(cache: &LayerCaches) -> Option<usize> {
+        cache[0].as_ref().map(|(k, _)| k.dims()[2])
+    }
+
+    fn is_on_device(cache: &LayerCaches) -> bool {
+        cache[0]
+            .as_ref()
+            .is_some_and(|(k, _)| !matches!(k.device(), Device::Cpu))
+    }
+
     fn cache_to<'a>(
         cache: impl Iterator<Item = &'a mut Option<(Tensor, Tensor)>>,
         device: &Device,
@@ -86,50 +141,31 @@ impl PrefixCacheManager {
         Ok(())
     }
 
-    /// Evict the caches to CPU. This will evict the first k seqs such that the number of sequences on device after the copy is
+    fn entry_to(entry: &CacheEntry, device: &Device) -> Result<()> {
+        Self::cache_to(get_mut_arcmutex!(entry.normal).iter_mut(), device)?;
+        if let Some(ref xlora) = entry.xlora {
+            Self::cache_to(get_mut_arcmutex!(xlora).iter_mut(), device)?;
+        }
+        Ok(())
+    }
+
+    /// Evict the caches to CPU. This will evict the least recently used seqs such that the number of sequences on device after the copy is
     /// the maximum allowed. Returns the number of evicted sequences.
     pub fn evict_to_cpu(&mut self) -> Result<usize> {
         if self.no_prefix_cache {
             return Ok(0);
         }
-        let mut n_on_device = 0;
-        for (cache, _) in &self.eviction_cache_ptrs {
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                n_on_device += 1;
-            }
-        }
-        let mut n_evicted = 0;
-        // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
-            if n_on_device - n_evicted == self.n_on_device {
-                break;
-            }
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
-
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
-                }
-                n_evicted += 1;
-            }
+        let on_device = self
+            .lru
+            .iter()
+            .filter_map(|key| self.caches.get(&Tokens(key.clone())))
+            .filter(|entry| Self::is_on_device(&get_mut_arcmutex!(entry.normal)))
+            .collect::<Vec<_>>();
+        let n_evicted = on_device.len().saturating_sub(self.n_on_device);
+        for entry in &on_device[..n_evicted] {
+            Self::entry_to(entry, &Device::Cpu)?;
         }
-        Ok(self.caches.len().saturating_sub(self.n_on_device))
+        Ok(n_evicted)
     }
 
     /// Evict all the caches to CPU.
@@ -137,60 +173,173 @@ impl PrefixCacheManager {
         if self.no_prefix_cache {
             return Ok(0);
         }
-        // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
-
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
-                }
-            }
+        for entry in self.caches.values() {
+            Self::entry_to(entry, &Device::Cpu)?;
         }
         Ok(self.caches.len())
     }
 
-    /// Search for a matching cache given some toks
+    /// Search for the longest cached prefix of `toks`. At least one token is always left uncovered,
+    /// as the prompt step needs it to produce logits.
     pub fn search_for_matching_cache(&mut self, toks: &[u32]) -> Result<Option<MatchingCache>> {
-        if self.no_prefix_cache || toks.is_empty() {
+        if self.no_prefix_cache || toks.len() < 2 {
             return Ok(None);
         }
 
-        let toks = Tokens(toks.to_vec());
-        if let Some(cache) = self.caches.get(&toks) {
-            Self::cache_to(get_mut_arcmutex!(cache.as_ref()).iter_mut(), &self.device)?;
-            let cache = get_mut_arcmutex!(cache.as_ref()).clone();
-            let xlora_cache = if let Some(ref xlora_caches) = self.xlora_caches {
-                let mut xlora_cache = get_mut_arcmutex!(xlora_caches.get(&toks).unwrap().as_ref());
-                Self::cache_to(xlora_cache.iter_mut(), &self.device)?;
-                Some(xlora_cache.clone())
-            } else {
-                None
-            };
-            let ancestor = &self
-                .caches
-                .get_ancestor(&toks)
-                .expect("No ancestor.")
-                .key()
-                .expect("Cannot get the key.")
-                .0;
-            // Know ancestor.len() < toks.len(), and toks[0..ancestor.len()] == toks
-            Ok(Some(MatchingCache {
-                normal: cache,
-                xlora: xlora_cache,
-                toks: toks.0[ancestor.len()..].to_vec(),
-            }))
-        } else {
-            Ok(None)
+        let query = Tokens(toks.to_vec());
+        let Some(ancestor) = self.caches.get_ancestor(&query) else {
+            return Ok(None);
+        };
+        let (Some(key), Some(entry)) = (ancestor.key(), ancestor.value()) else {
+            return Ok(None);
+        };
+        let key = key.0.clone();
+        let n_reused = key.len().min(toks.len() - 1);
+
+        Self::entry_to(entry, &self.device)?;
+        let narrow = |cache: &LayerCaches| -> Result<LayerCaches> {
+            cache
+                .iter()
+                .map(|layer| {
+                    layer
+                        .as_ref()
+                        .map(|(k, v)| -> Result<(Tensor, Tensor)> {
+                            Ok((
+                                k.narrow(2, 0, n_reused)?.contiguous()?,
+                                v.narrow(2, 0, n_reused)?.contiguous()?,
+                            ))
+                        })
+                        .transpose()
+                })
+                .collect()
+        };
+        let normal = narrow(&get_mut_arcmutex!(entry.normal))?;
+        let xlora = match entry.xlora {
+            Some(ref xlora) => Some(narrow(&get_mut_arcmutex!(xlora))?),
+            None => None,
+        };
+
+        self.lru.retain(|k| k != &key);
+        self.lru.push_back(key);
+
+        Ok(Some(MatchingCache {
+            normal,
+            xlora,
+            toks: toks[n_reused..].to_vec(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::PrefixCacheManager;
+    use crate::{pipeline::testing::complete, pipeline::LayerCaches, SamplingParams, TestPipeline};
+
+    fn cache(n_toks: usize) -> LayerCaches {
+        let kv = Tensor::zeros((1, 2, n_toks, 4), DType::F32, &Device::Cpu).unwrap();
+        vec![Some((kv.clone(), kv)); 2]
+    }
+
+    #[test]
+    fn reuses_longest_prefix() {
+        let mut cacher = PrefixCacheManager::new(Device::Cpu, 16, false);
+        cacher.insert(vec![1, 2], cache(2), None);
+        cacher.insert(vec![1, 2, 3, 4], cache(4), None);
+
+        let hit = cacher
+            .search_for_matching_cache(&[1, 2, 3, 4, 5, 6])
+            .unwrap()
+            .unwrap();
+        assert_eq!(hit.toks, vec![5, 6]);
+        assert_eq!(hit.normal[0].as_ref().unwrap().0.dims()[2], 4);
+
+        let hit = cacher
+            .search_for_matching_cache(&[1, 2, 3, 7])
+            .unwrap()
+            .unwrap();
+        assert_eq!(hit.toks, vec![3, 7]);
+
+        // An exact match still leaves the last token to run.
+        let hit = cacher
+            .search_for_matching_cache(&[1, 2, 3, 4])
+            .unwrap()
+            .unwrap();
+        assert_eq!(hit.toks, vec![4]);
+        assert_eq!(hit.normal[0].as_ref().unwrap().0.dims()[2], 3);
+
+        assert!(cacher
+            .search_for_matching_cache(&[9, 1, 2])
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cacher = PrefixCacheManager::new(Device::Cpu, 16, false);
+        cacher.max_cached_toks = 6;
+        cacher.insert(vec![1, 2, 3], cache(3), None);
+        cacher.insert(vec![4, 5, 6], cache(3), None);
+        // Touch the first entry so that the second one is the least recently used.
+        assert!(cacher
+            .search_for_matching_cache(&[1, 2, 3, 0])
+            .unwrap()
+            .is_some());
+        cacher.insert(vec![7, 8, 9], cache(3), None);
+
+        assert!(cacher
+            .search_for_matching_cache(&[1, 2, 3, 0])
+            .unwrap()
+            .is_some());
+        assert!(cacher
+            .search_for_matching_cache(&[4, 5, 6, 0])
+            .unwrap()
+            .is_none());
+        assert!(cacher
+            .search_for_matching_cache(&[7, 8, 9, 0])
+            .unwrap()
+            .is_some());
+        assert_eq!(cacher.n_cached_toks, 6);
+    }
+
+    #[tokio::test]
+    async fn generation_from_a_cached_prefix_matches_a_cold_run() {
+        let cached = TestPipeline::new("test").build(false);
+        let cold = TestPipeline::new("test").build(true);
+        let greedy = |max_len| SamplingParams {
+            temperature: Some(0.),
+            max_len: Some(max_len),
+            ..SamplingParams::deterministic()
+        };
+        let text = |toks: &[u32]| {
+            toks.iter()
+                .map(|tok| char::from(b'a' + *tok as u8).to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        // This caches the prompt and all but the last generated token.
+        let prompt = [0, 1, 2, 3];
+        let first = complete(&cached, &[text(&prompt).as_str()], greedy(4)).await;
+        let generated = [&prompt[..], &first[0][..]].concat();
+        assert_eq!(
+            first,
+            complete(&cold, &[text(&prompt).as_str()], greedy(4)).await
+        );
+
+        // The first prompt extends the cached tokens, and the second is them, so that the cache
+        // covers all of it but its last token.
+        for prompt in [
+            [&generated[..], &[4, 5][..]].concat(),
+            generated[..7].to_vec(),
+        ] {
+            let from_cache = complete(&cached, &[text(&prompt).as_str()], greedy(6)).await;
+            assert_eq!(
+                from_cache,
+                complete(&cold, &[text(&prompt).as_str()], greedy(6)).await
+            );
+            assert_eq!(from_cache, [TestPipeline::greedy_completion(&prompt, 6)]);
         }
     }
 }
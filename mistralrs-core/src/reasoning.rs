@@ -0,0 +1,134 @@
+//! Separation of the reasoning of models which think in a delimited block, such as
+//! `<think>...</think>`, from the content of streamed chat completions.
+
+/// The delimiters around the reasoning in the output of a model.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReasoningDelimiters {
+    pub open: String,
+    pub close: String,
+}
+
+/// Routes streamed text inside the reasoning delimiters to the reasoning, and the rest to the
+/// content. A delimiter may be split across tokens, so text which could be the start of one is
+/// held back until the next text shows whether it is.
+#[derive(Clone, Debug)]
+pub(crate) struct ReasoningParser {
+    delimiters: ReasoningDelimiters,
+    in_reasoning: bool,
+    /// Content after the reasoning starts at its first non-whitespace character.
+    trim_content: bool,
+    pending: String,
+}
+
+impl ReasoningParser {
+    pub(crate) fn new(delimiters: ReasoningDelimiters) -> Self {
+        Self {
+            delimiters,
+            in_reasoning: false,
+            trim_content: false,
+            pending: String::new(),
+        }
+    }
+
+    /// Split the next streamed `text` into its reasoning and its content.
+    pub(crate) fn push(&mut self, text: &str) -> (String, String) {
+        self.pending.push_str(text);
+        let mut reasoning = String::new();
+        let mut content = String::new();
+        loop {
+            let delimiter = if self.in_reasoning {
+                &self.delimiters.close
+            } else {
+                &self.delimiters.open
+            };
+            let (end, next) = match self.pending.find(delimiter.as_str()) {
+                Some(pos) => (pos, Some(pos + delimiter.len())),
+                None => (
+                    self.pending.len() - partial_delimiter_len(&self.pending, delimiter),
+                    None,
+                ),
+            };
+            if self.in_reasoning {
+                reasoning.push_str(&self.pending[..end]);
+            } else {
+                self.push_content(&mut content, end);
+            }
+            let Some(next) = next else {
+                self.pending.drain(..end);
+                return (reasoning, content);
+            };
+            self.pending.drain(..next);
+            self.trim_content = self.in_reasoning;
+            self.in_reasoning = !self.in_reasoning;
+        }
+    }
+
+    /// Split the text held back at the end of the output, which is no delimiter after all.
+    pub(crate) fn finish(&mut self) -> (String, String) {
+        let mut content = String::new();
+        if self.in_reasoning {
+            return (std::mem::take(&mut self.pending), content);
+        }
+        self.push_content(&mut content, self.pending.len());
+        self.pending.clear();
+        (String::new(), content)
+    }
+
+    fn push_content(&mut self, content: &mut String, end: usize) {
+        let text = &self.pending[..end];
+        if self.trim_content {
+            let text = text.trim_start();
+            self.trim_content = text.is_empty();
+            content.push_str(text);
+        } else {
+            content.push_str(text);
+        }
+    }
+}
+
+/// The length of the longest end of `text` which is the start of `delimiter`, but not all of it.
+fn partial_delimiter_len(text: &str, delimiter: &str) -> usize {
+    (1..delimiter.len())
+        .rev()
+        .find(|&len| delimiter.is_char_boundary(len) && text.ends_with(&delimiter[..len]))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReasoningDelimiters, ReasoningParser};
+
+    #[test]
+    fn think_block_is_streamed_apart_from_the_content() {
+        let mut parser = ReasoningParser::new(ReasoningDelimiters {
+            open: "<think>".to_string(),
+            close: "</think>".to_string(),
+        });
+        // The delimiters are split across tokens.
+        let deltas = [
+            "<th",
+            "ink>The user",
+            " greets me.</",
+            "think",
+            ">\n\nHello",
+            "! <",
+            "3",
+        ];
+        let mut reasoning = Vec::new();
+        let mut content = Vec::new();
+        for delta in deltas {
+            let (r, c) = parser.push(delta);
+            reasoning.push(r);
+            content.push(c);
+        }
+        let (r, c) = parser.finish();
+        reasoning.push(r);
+        content.push(c);
+
+        assert_eq!(
+            reasoning,
+            ["", "The user", " greets me.", "", "", "", "", ""]
+        );
+        assert_eq!(content, ["", "", "", "", "Hello", "! ", "<3", ""]);
+    }
+}
@@ -4,19 +4,28 @@ use mistralrs_quant::IsqType;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    beam_search::BeamSearchParams,
     response::Response,
     sampler::SamplingParams,
     tools::{Tool, ToolChoice},
-    CustomLogitsProcessor, DiffusionGenerationParams,
+    CustomLogitsProcessor, DiffusionGenerationParams, EngineStats,
 };
 use std::{fmt::Debug, sync::Arc};
 use tokio::sync::mpsc::Sender;
 
 #[derive(Clone)]
-/// Control the constraint with Regex or Yacc.
+/// Control the constraint with Regex, Yacc, GBNF, a JSON schema or a set of choices.
 pub enum Constraint {
     Regex(String),
     Yacc(String),
+    /// A grammar in llama.cpp's GBNF format, whose `root` rule is matched.
+    Gbnf(String),
+    /// Only generate JSON matching this schema.
+    JsonSchema(serde_json::Value),
+    /// Only generate a valid JSON value.
+    Json,
+    /// Only generate exactly one of these strings.
+    Choice(Vec<String>),
     None,
 }
 
@@ -34,10 +43,15 @@ pub type MessageContent = Either<String, Vec<IndexMap<String, String>>>;
 /// Message or messages for a [`Request`].
 pub enum RequestMessage {
     Chat(Vec<IndexMap<String, MessageContent>>),
+    /// Each prompt in `text` is generated for separately, with `n_choices` choices each. If
+    /// `best_of` is larger, that many candidates are generated and the `n_choices` with the highest
+    /// mean token logprob are returned. With `beam_search`, the `n_choices` best beams are returned
+    /// instead, and the sampling parameters are not used.
     Completion {
-        text: String,
+        text: Vec<String>,
         echo_prompt: bool,
         best_of: usize,
+        beam_search: Option<BeamSearchParams>,
     },
     CompletionTokens(Vec<u32>),
     VisionChat {
@@ -49,6 +63,10 @@ pub enum RequestMessage {
         format: ImageGenerationResponseFormat,
         generation_params: DiffusionGenerationParams,
     },
+    /// Each input is embedded separately, without sampling.
+    Embedding {
+        inputs: Vec<String>,
+    },
 }
 
 #[derive(Clone)]
@@ -64,6 +82,9 @@ pub enum RequestMessage {
 /// - `adapters`: Adapters to use in this request
 /// - `tools`: Tools available in this request
 /// - `tool_choice`: Choice of tools
+/// - `chat_template`: Jinja chat template to render chat messages with, instead of the model's
+/// - `user`: Identifier of the end user the request is made for, such as for logging
+/// - `priority`: Requests with a higher priority are scheduled first, 0 if unset
 /// - `logits_processors`: Custom logits processors. Order of application:
 ///     1) Apply penalties from `sampling_params`
 ///     2) Apply these custom logits processors sequentially
@@ -82,6 +103,9 @@ pub struct NormalRequest {
     pub tools: Option<Vec<Tool>>,
     pub tool_choice: Option<ToolChoice>,
     pub logits_processors: Option<Vec<Arc<dyn CustomLogitsProcessor>>>,
+    pub chat_template: Option<String>,
+    pub user: Option<String>,
+    pub priority: Option<i32>,
 }
 
 impl NormalRequest {
@@ -106,6 +130,9 @@ impl NormalRequest {
             suffix: None,
             adapters: None,
             logits_processors: None,
+            chat_template: None,
+            user: None,
+            priority: None,
         }
     }
 }
@@ -117,6 +144,36 @@ pub enum Request {
     Normal(NormalRequest),
     ReIsq(IsqType),
     ActivateAdapters(Vec<String>),
+    /// Load the LoRA adapter at `source` so that it may be activated as `name`. The outcome is sent
+    /// to `response`.
+    LoadAdapter {
+        name: String,
+        source: String,
+        response: Sender<anyhow::Result<()>>,
+    },
+    /// Cancel all sequences created by the [`NormalRequest`] with this ID, for example
+    /// because the client receiving the response has disconnected.
+    TerminateSeq {
+        id: usize,
+    },
+    /// Like [`Request::TerminateSeq`], but sends to `response` whether any of the sequences were
+    /// still running.
+    CancelRequest {
+        id: usize,
+        response: Sender<bool>,
+    },
+    /// Send a snapshot of the scheduler to `response`.
+    GetStats {
+        response: Sender<EngineStats>,
+    },
+    /// Apply the chat template to `messages` as a chat request would, and send the token IDs and
+    /// the text of the prompt to `response` without generating.
+    RenderPrompt {
+        messages: Vec<IndexMap<String, MessageContent>>,
+        tools: Vec<Tool>,
+        chat_template: Option<String>,
+        response: Sender<anyhow::Result<(Vec<u32>, String)>>,
+    },
     // Sending a terminate request causes the `run` function to return to the thread created in `MistralRs::new`,
     // and then Engine will be dropped.
     Terminate,
@@ -131,20 +188,62 @@ impl Debug for Request {
                 is_streaming,
                 adapters,
                 id,
+                user,
+                priority,
                 ..
             }) => {
                 write!(
                     f,
-                    "Request {id} {{ messages: `{messages:?}`, sampling_params: {sampling_params:?}, is_streaming: {is_streaming}, adapters: {adapters:?}}}",
+                    "Request {id} {{ messages: `{messages:?}`, sampling_params: {sampling_params:?}, is_streaming: {is_streaming}, adapters: {adapters:?}, user: {user:?}, priority: {priority:?}}}",
                 )
             }
             Request::ActivateAdapters(adapters) => {
                 write!(f, "Activate Adapters Request {adapters:?}",)
             }
+            Request::LoadAdapter { name, source, .. } => {
+                write!(f, "Load Adapter Request {name} from {source}",)
+            }
             Request::ReIsq(tp) => {
                 write!(f, "Re ISQ Request {tp:?}",)
             }
+            Request::TerminateSeq { id } => {
+                write!(f, "Terminate Sequence Request {id}",)
+            }
+            Request::CancelRequest { id, .. } => {
+                write!(f, "Cancel Request {id}",)
+            }
+            Request::GetStats { .. } => write!(f, "Get Stats Request"),
+            Request::RenderPrompt { messages, .. } => {
+                write!(f, "Render Prompt Request {{ messages: `{messages:?}` }}")
+            }
             Request::Terminate => write!(f, "Termination Request"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{NormalRequest, Request, RequestMessage};
+    use crate::SamplingParams;
+
+    #[test]
+    fn user_is_in_the_logged_representation() {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let mut request = NormalRequest::new_simple(
+            RequestMessage::Completion {
+                text: vec!["Hello".to_string()],
+                echo_prompt: false,
+                best_of: 1,
+                beam_search: None,
+            },
+            SamplingParams::deterministic(),
+            tx,
+            0,
+            None,
+            None,
+        );
+        request.user = Some("user-1234".to_string());
+        let repr = format!("{:?}", Request::Normal(request));
+        assert!(repr.ends_with(r#"user: Some("user-1234")}"#), "{repr}");
+    }
+}
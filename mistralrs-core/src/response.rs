@@ -3,11 +3,12 @@ use std::{
     fmt::{Debug, Display},
 };
 
+use either::Either;
 #[cfg(feature = "pyo3_macros")]
 use pyo3::{pyclass, pymethods};
 use serde::Serialize;
 
-use crate::{sampler::TopLogprob, tools::ToolCallResponse};
+use crate::tools::ToolCallResponse;
 
 pub const SYSTEM_FINGERPRINT: &str = "local";
 
@@ -42,10 +43,25 @@ generate_repr!(ResponseMessage);
 pub struct Delta {
     pub content: String,
     pub role: String,
+    /// Text inside the reasoning delimiters, if the engine has them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
 }
 
 generate_repr!(Delta);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// One of the most likely tokens at a position, with its logprob.
+pub struct ResponseTopLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Option<Vec<u8>>,
+}
+
+generate_repr!(ResponseTopLogprob);
+
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
 #[derive(Debug, Clone, Serialize)]
@@ -54,7 +70,7 @@ pub struct ResponseLogprob {
     pub token: String,
     pub logprob: f32,
     pub bytes: Option<Vec<u8>>,
-    pub top_logprobs: Vec<TopLogprob>,
+    pub top_logprobs: Vec<ResponseTopLogprob>,
 }
 
 generate_repr!(ResponseLogprob);
@@ -75,9 +91,15 @@ generate_repr!(Logprobs);
 /// Chat completion choice.
 pub struct Choice {
     pub finish_reason: String,
+    /// The stop string or token id, such as the EOS token, which ended generation, if any.
+    #[serde(with = "either::serde_untagged_optional")]
+    pub matched_stop: Option<Either<u32, String>>,
     pub index: usize,
     pub message: ResponseMessage,
     pub logprobs: Option<Logprobs>,
+    /// The ids of the generated tokens, if `return_raw_tokens` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_tokens: Option<Vec<u32>>,
 }
 
 generate_repr!(Choice);
@@ -88,9 +110,16 @@ generate_repr!(Choice);
 /// Chat completion streaming chunk choice.
 pub struct ChunkChoice {
     pub finish_reason: Option<String>,
+    /// The stop string or token id, such as the EOS token, which ended generation, if any.
+    #[serde(with = "either::serde_untagged_optional")]
+    pub matched_stop: Option<Either<u32, String>>,
     pub index: usize,
     pub delta: Delta,
-    pub logprobs: Option<ResponseLogprob>,
+    pub logprobs: Option<Logprobs>,
+    /// The ids of the tokens generated since the previous chunk, if `return_raw_tokens` was
+    /// requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_tokens: Option<Vec<u32>>,
 }
 
 generate_repr!(ChunkChoice);
@@ -104,6 +133,13 @@ pub struct CompletionChunkChoice {
     pub index: usize,
     pub logprobs: Option<ResponseLogprob>,
     pub finish_reason: Option<String>,
+    /// The stop string or token id, such as the EOS token, which ended generation, if any.
+    #[serde(with = "either::serde_untagged_optional")]
+    pub matched_stop: Option<Either<u32, String>>,
+    /// The ids of the tokens generated since the previous chunk, if `return_raw_tokens` was
+    /// requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_tokens: Option<Vec<u32>>,
 }
 
 generate_repr!(CompletionChunkChoice);
@@ -122,6 +158,8 @@ pub struct Usage {
     pub total_time_sec: f32,
     pub total_prompt_time_sec: f32,
     pub total_completion_time_sec: f32,
+    /// Time from receiving the request to its first generated token.
+    pub time_to_first_token_sec: f32,
 }
 
 generate_repr!(Usage);
@@ -138,6 +176,8 @@ pub struct ChatCompletionResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    /// The seed used for sampling, if one was given in the request.
+    pub seed: Option<u64>,
 }
 
 generate_repr!(ChatCompletionResponse);
@@ -153,6 +193,11 @@ pub struct ChatCompletionChunkResponse {
     pub model: String,
     pub system_fingerprint: String,
     pub object: String,
+    /// Token usage of the whole request, only set on the terminal chunk.
+    pub usage: Option<Usage>,
+    /// Token counts so far, set on the other chunks if `continuous_usage_stats` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub running_usage: Option<Usage>,
 }
 
 generate_repr!(ChatCompletionChunkResponse);
@@ -163,9 +208,19 @@ generate_repr!(ChatCompletionChunkResponse);
 /// Completion request choice.
 pub struct CompletionChoice {
     pub finish_reason: String,
+    /// The stop string or token id, such as the EOS token, which ended generation, if any.
+    #[serde(with = "either::serde_untagged_optional")]
+    pub matched_stop: Option<Either<u32, String>>,
     pub index: usize,
     pub text: String,
     pub logprobs: Option<()>,
+    /// The logprob of each prompt token under the model, if `prompt_logprobs` was requested. The
+    /// first token has none, as nothing precedes it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_logprobs: Option<Vec<Option<ResponseLogprob>>>,
+    /// The ids of the generated tokens, if `return_raw_tokens` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_tokens: Option<Vec<u32>>,
 }
 
 generate_repr!(CompletionChoice);
@@ -182,6 +237,8 @@ pub struct CompletionResponse {
     pub system_fingerprint: String,
     pub object: String,
     pub usage: Usage,
+    /// The seed used for sampling, if one was given in the request.
+    pub seed: Option<u64>,
 }
 
 generate_repr!(CompletionResponse);
@@ -197,6 +254,11 @@ pub struct CompletionChunkResponse {
     pub model: String,
     pub system_fingerprint: String,
     pub object: String,
+    /// Token usage of the whole request, only set on the terminal chunk.
+    pub usage: Option<Usage>,
+    /// Token counts so far, set on the other chunks if `continuous_usage_stats` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub running_usage: Option<Usage>,
 }
 
 generate_repr!(CompletionChunkResponse);
@@ -221,6 +283,38 @@ pub struct ImageGenerationResponse {
 
 generate_repr!(ImageGenerationResponse);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingChoice {
+    pub index: usize,
+    /// The L2-normalized final hidden state of the last input token.
+    pub embedding: Vec<f32>,
+}
+
+generate_repr!(EmbeddingChoice);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+generate_repr!(EmbeddingUsage);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbeddingResponse {
+    pub data: Vec<EmbeddingChoice>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+generate_repr!(EmbeddingResponse);
+
 /// The response enum contains 3 types of variants:
 /// - Error (-Error suffix)
 /// - Chat (no prefix)
@@ -238,6 +332,8 @@ pub enum Response {
     CompletionChunk(CompletionChunkResponse),
     // Image generation
     ImageGeneration(ImageGenerationResponse),
+    // Embeddings
+    Embeddings(EmbeddingResponse),
 }
 
 #[derive(Debug, Clone)]
@@ -250,6 +346,8 @@ pub enum ResponseOk {
     CompletionChunk(CompletionChunkResponse),
     // Image generation
     ImageGeneration(ImageGenerationResponse),
+    // Embeddings
+    Embeddings(EmbeddingResponse),
 }
 
 pub enum ResponseErr {
@@ -312,6 +410,7 @@ impl Response {
                 Err(Box::new(ResponseErr::CompletionModelError(e, x)))
             }
             Self::ImageGeneration(x) => Ok(ResponseOk::ImageGeneration(x)),
+            Self::Embeddings(x) => Ok(ResponseOk::Embeddings(x)),
         }
     }
 }
@@ -6,7 +6,7 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use candle_core::{Device, Error, Result, Tensor, D};
+use candle_core::{DType, Device, Error, Result, Tensor, D};
 #[cfg(feature = "pyo3_macros")]
 use pyo3::pyclass;
 
@@ -14,12 +14,66 @@ use once_cell::sync::Lazy;
 use rand::distributions::{Distribution, WeightedIndex};
 use rand_isaac::Isaac64Rng;
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use regex::bytes::Regex;
 use serde::{Deserialize, Serialize};
 use tokenizers::Tokenizer;
 
+/// The largest magnitude of a logit bias, as in the OpenAI API. At this magnitude a token is
+/// effectively banned or forced.
+pub const MAX_LOGIT_BIAS: f32 = 100.;
+
+/// Temperatures below this, including 0, mean greedy decoding: the logits are not scaled and the
+/// most likely token is always taken.
+const MIN_TEMPERATURE: f64 = 1e-7;
+
 static DRY_SEQUENCE_BREAKERS: Lazy<Vec<String>> =
     Lazy::new(|| ["\n", ":", "\"", "*"].map(String::from).to_vec());
 
+/// A step of the sampler whose place in the order of steps a request may set. Truncations before
+/// the temperature see the distribution without it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplerStep {
+    Temperature,
+    TopK,
+    TopP,
+    MinP,
+}
+
+/// The order the steps are applied in unless a request sets one.
+pub const DEFAULT_SAMPLER_ORDER: [SamplerStep; 4] = [
+    SamplerStep::Temperature,
+    SamplerStep::MinP,
+    SamplerStep::TopK,
+    SamplerStep::TopP,
+];
+
+impl SamplerStep {
+    /// Parse an order of steps named `temperature`, `top_k`, `top_p` and `min_p`, each at most
+    /// once. Steps left out are applied after the given ones, in the default order.
+    pub fn parse_order(names: &[String]) -> std::result::Result<Vec<Self>, String> {
+        let mut order = Vec::with_capacity(DEFAULT_SAMPLER_ORDER.len());
+        for name in names {
+            let step = match name.as_str() {
+                "temperature" => Self::Temperature,
+                "top_k" => Self::TopK,
+                "top_p" => Self::TopP,
+                "min_p" => Self::MinP,
+                other => {
+                    return Err(format!(
+                        "Unknown sampler step `{other}`, expected `temperature`, `top_k`, `top_p` or `min_p`."
+                    ))
+                }
+            };
+            if order.contains(&step) {
+                return Err(format!("Sampler step `{name}` is given more than once."));
+            }
+            order.push(step);
+        }
+        Ok(order)
+    }
+}
+
 #[derive(Clone, Debug)]
 /// Stop sequences or ids.
 pub enum StopTokens {
@@ -34,39 +88,180 @@ pub struct SamplingParams {
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
     pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    /// Eta sampling: remove the tokens less likely than `min(eta, sqrt(eta) * exp(-entropy))`.
+    pub eta_cutoff: Option<f64>,
+    /// Epsilon sampling: remove the tokens less likely than this.
+    pub epsilon_cutoff: Option<f64>,
     pub top_n_logprobs: usize,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
+    /// Whether `frequency_penalty` and `presence_penalty` count the prompt tokens as well as the
+    /// generated ones. Defaults to true.
+    pub penalize_prompt: Option<bool>,
+    /// Multiplicative penalty for tokens within the last `repetition_context_size` tokens, as in
+    /// llama.cpp's `repeat_penalty`. Positive logits are divided by it and negative ones multiplied.
+    pub repetition_penalty: Option<f64>,
+    /// How many of the most recent tokens `repetition_penalty` considers. Defaults to all of them.
+    pub repetition_context_size: Option<usize>,
     pub stop_toks: Option<StopTokens>,
     pub max_len: Option<usize>,
+    /// The most characters of text to generate, cutting the last token if it goes past them.
+    pub max_chars: Option<usize>,
+    /// Biases added to the logits of token ids, clamped to `[-100, 100]`.
     pub logits_bias: Option<HashMap<u32, f32>>,
+    /// Like `logits_bias`, keyed by the text of tokens. Each key must encode to a single token.
+    pub logits_bias_strs: Option<HashMap<String, f32>>,
+    /// Biases added to the logits of every token whose text matches a regex, on top of
+    /// `logits_bias` and of each other.
+    pub logits_bias_patterns: Option<HashMap<String, f32>>,
     pub n_choices: usize,
     pub dry_params: Option<DrySamplingParams>,
+    pub seed: Option<u64>,
+    pub mirostat: Option<MirostatParams>,
+    /// Weight of the degeneration penalty of contrastive search, in `[0, 1]`. Requires `top_k`,
+    /// whose candidates it chooses between deterministically, replacing sampling.
+    pub penalty_alpha: Option<f64>,
+    /// Keep a matched stop string at the end of the output, instead of cutting it.
+    pub include_stop_str_in_output: bool,
+    /// Return the logprob of each prompt token after the first, with this many top logprobs at
+    /// each position. The prompt is then never resumed from the prefix cache.
+    pub prompt_logprobs: Option<usize>,
+    /// Return the ids of the generated tokens with each choice, or with each streamed chunk.
+    pub return_raw_tokens: bool,
+    /// When streaming, attach the token counts so far to every chunk as its `running_usage`.
+    pub continuous_usage_stats: bool,
+    /// When streaming, send a chunk once this many tokens were generated since the last one. The
+    /// final chunk is always sent. Defaults to every 3 tokens.
+    pub stream_granularity: Option<usize>,
+    /// The order to apply the temperature, top-k, top-p and min-p in. Defaults to
+    /// [`DEFAULT_SAMPLER_ORDER`].
+    pub sampler_order: Option<Vec<SamplerStep>>,
 }
 
 impl SamplingParams {
     /// This sets up the parameters so that there is:
-    /// - No temperature, topk, topp, minp
+    /// - No temperature, topk, topp, minp, typical-p, eta or epsilon
     /// - No penalties, stop tokens, or logit bias
     /// - No maximum length
+    /// - No seed
+    /// - No mirostat or contrastive search
+    /// - No prompt logprobs or raw tokens
+    /// - The default sampler order
     pub fn deterministic() -> Self {
         Self {
             temperature: None,
             top_k: None,
             top_p: None,
             min_p: None,
+            typical_p: None,
+            eta_cutoff: None,
+            epsilon_cutoff: None,
+            penalize_prompt: None,
             top_n_logprobs: 0,
             frequency_penalty: None,
             presence_penalty: None,
+            repetition_penalty: None,
+            repetition_context_size: None,
             stop_toks: None,
             max_len: None,
+            max_chars: None,
             logits_bias: None,
+            logits_bias_strs: None,
+            logits_bias_patterns: None,
             n_choices: 1,
             dry_params: None,
+            seed: None,
+            mirostat: None,
+            penalty_alpha: None,
+            include_stop_str_in_output: false,
+            prompt_logprobs: None,
+            return_raw_tokens: false,
+            continuous_usage_stats: false,
+            stream_granularity: None,
+            sampler_order: None,
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+/// Mirostat v2 sampling, which keeps the surprise (negative log2 probability) of the sampled
+/// tokens close to `tau`. When active, top-k and top-p are not used.
+pub struct MirostatParams {
+    /// Target surprise.
+    pub tau: f32,
+    /// Learning rate of the `mu` update.
+    pub eta: f32,
+}
+
+/// Per-sequence mirostat state. Cloning gives a fresh state so that each sequence learns its own `mu`.
+struct MirostatState {
+    params: MirostatParams,
+    /// Maximum surprise allowed for the next token, starting at `2 * tau`.
+    mu: Mutex<f32>,
+}
+
+impl MirostatState {
+    fn new(params: MirostatParams) -> Self {
+        Self {
+            params,
+            mu: Mutex::new(2. * params.tau),
+        }
+    }
+}
+
+impl Clone for MirostatState {
+    fn clone(&self) -> Self {
+        Self::new(self.params)
+    }
+}
+
+/// Contrastive search: of the `top_k` most likely tokens, take the one maximizing
+/// `(1 - penalty_alpha) * p - penalty_alpha * s`, where `s` is the highest cosine similarity of its
+/// representation to that of any token before it. A token's representation is its row of the
+/// model's input embeddings.
+#[derive(Clone)]
+struct ContrastiveSearch {
+    penalty_alpha: f32,
+    /// `(vocab_size, hidden_size)`
+    token_embeddings: Tensor,
+}
+
+impl ContrastiveSearch {
+    /// The representation of each of `toks`, normalized to unit length.
+    fn representations(&self, toks: &[u32]) -> Result<Tensor> {
+        let device = self.token_embeddings.device();
+        let representations = self
+            .token_embeddings
+            .index_select(&Tensor::new(toks, device)?, 0)?
+            .to_dtype(DType::F32)?;
+        let norms = representations
+            .sqr()?
+            .sum_keepdim(D::Minus1)?
+            .sqrt()?
+            .clamp(f32::EPSILON, f32::INFINITY)?;
+        representations.broadcast_div(&norms)
+    }
+
+    /// For each candidate, its highest cosine similarity to a token of `context`.
+    fn max_similarities(&self, candidates: &[u32], context: &[u32]) -> Result<Vec<f32>> {
+        let vocab_size = self.token_embeddings.dim(0)?;
+        let context = context
+            .iter()
+            .copied()
+            .filter(|tok| (*tok as usize) < vocab_size)
+            .collect::<Vec<_>>();
+        if context.is_empty() {
+            return Ok(vec![0.; candidates.len()]);
+        }
+        self.representations(candidates)?
+            .matmul(&self.representations(&context)?.t()?)?
+            .max(D::Minus1)?
+            .to_device(&Device::Cpu)?
+            .to_vec1()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct DrySamplingParams {
     pub sequence_breakers: Vec<String>,
@@ -185,11 +380,23 @@ pub struct Sampler {
     tokenizer: Option<Arc<Tokenizer>>,
     frequency_penalty: Option<f32>,
     presence_penalty: Option<f32>,
+    /// The number of leading context tokens the frequency and presence penalties skip.
+    penalty_start: usize,
     dry_params: Option<DrySamplingParamsInner>,
     top_k: i64,
     top_p: f64,
     min_p: f64,
+    typical_p: f64,
+    eta_cutoff: f32,
+    epsilon_cutoff: f32,
+    mirostat: Option<MirostatState>,
+    contrastive: Option<ContrastiveSearch>,
+    logits_bias: Option<HashMap<u32, f32>>,
+    repetition_penalty: Option<f32>,
+    repetition_context_size: Option<usize>,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
+    /// Every step, in the order they are applied.
+    order: Vec<SamplerStep>,
 }
 
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
@@ -214,6 +421,166 @@ fn argmax_sample_last_dim(logits: &Tensor) -> Result<Tensor> {
     logits.argmax(D::Minus1)
 }
 
+/// The ids of the tokens whose text matches `pattern`, from the bytes of every token in id order.
+pub(crate) fn tokens_matching<'a>(
+    pattern: &Regex,
+    tokens: impl IntoIterator<Item = &'a [u8]>,
+) -> Vec<u32> {
+    tokens
+        .into_iter()
+        .zip(0u32..)
+        .filter(|(tok, _)| pattern.is_match(tok))
+        .map(|(_, id)| id)
+        .collect()
+}
+
+/// Locally typical sampling: keep the smallest set of tokens whose surprise is closest to the entropy of
+/// the distribution and whose cumulative probability reaches `typical_p`, clamping the rest to zero.
+/// Like top-p, this is relative to the remaining mass, so it composes with the other filters. A
+/// `typical_p` outside of `(0, 1)` disables it. The probabilities are not renormalized.
+fn truncate_typical_p(probs: &mut [f32], typical_p: f32) {
+    if typical_p <= 0.0 || typical_p >= 1.0 {
+        return;
+    }
+    let total = probs.iter().sum::<f32>();
+    let entropy = -probs
+        .iter()
+        .filter(|p| **p > 0.0)
+        .map(|p| (p / total) * (p / total).ln())
+        .sum::<f32>();
+
+    let mut typical_indices = (0..probs.len())
+        .filter(|i| probs[*i] > 0.0)
+        .collect::<Vec<_>>();
+    let distance = |i: usize| (-(probs[i] / total).ln() - entropy).abs();
+    typical_indices.sort_by(|&i, &j| distance(i).partial_cmp(&distance(j)).expect("No ordering."));
+
+    let threshold = typical_p * total;
+    let mut cumsum = 0.;
+    for index in typical_indices {
+        if cumsum >= threshold {
+            probs[index] = 0.0;
+        } else {
+            cumsum += probs[index];
+        }
+    }
+}
+
+/// Eta and epsilon sampling, as in HF transformers: the probability below which tokens are removed,
+/// if either is enabled. Epsilon sampling removes the tokens less likely than `epsilon_cutoff`, and
+/// eta sampling those less likely than `min(eta_cutoff, sqrt(eta_cutoff) * exp(-entropy))`, so that
+/// it truncates less when the model is uncertain. A cutoff outside of `(0, 1)` disables it. `probs`
+/// must be the whole distribution, before any other filter.
+fn eta_epsilon_threshold(probs: &[f32], eta_cutoff: f32, epsilon_cutoff: f32) -> Option<f32> {
+    let enabled = |cutoff: f32| cutoff > 0.0 && cutoff < 1.0;
+    let epsilon = enabled(epsilon_cutoff).then_some(epsilon_cutoff);
+    let eta = enabled(eta_cutoff).then(|| {
+        let entropy = -probs
+            .iter()
+            .filter(|p| **p > 0.0)
+            .map(|p| p * p.ln())
+            .sum::<f32>();
+        eta_cutoff.min(eta_cutoff.sqrt() * (-entropy).exp())
+    });
+    match (eta, epsilon) {
+        (Some(eta), Some(epsilon)) => Some(eta.max(epsilon)),
+        (eta, epsilon) => eta.or(epsilon),
+    }
+}
+
+/// Clamp the probabilities below `threshold` to zero, always keeping the most likely token.
+/// `argsort_indices` must sort `probs` by descending probability.
+fn truncate_below(probs: &mut [f32], argsort_indices: &[usize], threshold: f32) {
+    for index in argsort_indices.iter().skip(1) {
+        if probs[*index] < threshold {
+            probs[*index] = 0.0;
+        }
+    }
+}
+
+/// Clamp the probabilities of tokens excluded by min-p, top-k and then top-p (in that order) to zero.
+/// `argsort_indices` must sort `probs` by descending probability. A `top_p` or `min_p` outside of
+/// `(0, 1)` disables that filter. The probabilities are not renormalized.
+fn truncate_top_kp_min_p(
+    probs: &mut [f32],
+    argsort_indices: &[usize],
+    top_k: i64,
+    top_p: f32,
+    min_p: f32,
+) {
+    for step in &DEFAULT_SAMPLER_ORDER[1..] {
+        truncate_step(*step, probs, argsort_indices, top_k, top_p, min_p);
+    }
+}
+
+/// Clamp the probabilities of tokens excluded by the truncation `step` to zero, as in
+/// [`truncate_top_kp_min_p`]. The temperature truncates nothing.
+fn truncate_step(
+    step: SamplerStep,
+    probs: &mut [f32],
+    argsort_indices: &[usize],
+    top_k: i64,
+    top_p: f32,
+    min_p: f32,
+) {
+    match step {
+        SamplerStep::Temperature => (),
+        SamplerStep::MinP => truncate_min_p(probs, argsort_indices, min_p),
+        SamplerStep::TopK => truncate_top_k(probs, argsort_indices, top_k),
+        SamplerStep::TopP => truncate_top_p(probs, argsort_indices, top_p),
+    }
+}
+
+fn truncate_min_p(probs: &mut [f32], argsort_indices: &[usize], min_p: f32) {
+    // MIN P
+
+    // min-p sampling samples from the tokens whose prob are at least
+    // (max prob of token in dist) * min_p
+
+    if min_p > 0.0 && min_p < 1.0 {
+        let min_prob = probs[argsort_indices[0]] * min_p;
+        // Clamp smaller probabilities to zero.
+        for index in argsort_indices {
+            if probs[*index] < min_prob {
+                probs[*index] = 0.0;
+            }
+        }
+    }
+}
+
+fn truncate_top_k(probs: &mut [f32], argsort_indices: &[usize], top_k: i64) {
+    if top_k > 0 {
+        // Clamp smaller probabilities to zero.
+        for (index, val) in argsort_indices.iter().enumerate() {
+            if index >= top_k as usize {
+                probs[*val] = 0.0;
+            }
+        }
+    }
+}
+
+fn truncate_top_p(probs: &mut [f32], argsort_indices: &[usize], top_p: f32) {
+    // TOP P
+
+    // top-p sampling (or "nucleus sampling") samples from the smallest set of
+    // tokens that exceed probability top_p. This way we never sample tokens that
+    // have very low probabilities and are less likely to go "off the rails".
+
+    if top_p > 0.0 && top_p < 1.0 {
+        // Relative to what is left after the earlier truncations, as if it had been renormalized.
+        let threshold = top_p * probs.iter().sum::<f32>();
+        // Clamp smaller probabilities to zero.
+        let mut cumsum = 0.;
+        for index in argsort_indices {
+            if cumsum >= threshold {
+                probs[*index] = 0.0;
+            } else {
+                cumsum += probs[*index];
+            }
+        }
+    }
+}
+
 impl Sampler {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -226,13 +593,15 @@ impl Sampler {
         top_k: i64,
         top_p: f64,
         min_p: f64,
+        typical_p: f64,
+        mirostat: Option<MirostatParams>,
+        logits_bias: Option<HashMap<u32, f32>>,
+        repetition_penalty: Option<f64>,
+        repetition_context_size: Option<usize>,
         logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
     ) -> anyhow::Result<Self> {
-        let temperature = if temperature.map_or(true, |v| v < 1e-7) {
-            None
-        } else {
-            temperature
-        };
+        // `NaN` fails the comparison too, so it never reaches the logits.
+        let temperature = temperature.filter(|v| *v >= MIN_TEMPERATURE);
         let dry_params = if let Some(ref tokenizer) = tokenizer {
             dry_params.map(|params| DrySamplingParamsInner::from(params, tokenizer))
         } else {
@@ -248,14 +617,139 @@ impl Sampler {
             tokenizer,
             frequency_penalty,
             presence_penalty,
+            penalty_start: 0,
             dry_params,
             top_k,
             top_p,
             min_p,
+            typical_p,
+            eta_cutoff: 0.0,
+            epsilon_cutoff: 0.0,
+            mirostat: mirostat.map(MirostatState::new),
+            contrastive: None,
+            logits_bias: logits_bias.map(|biases| {
+                biases
+                    .into_iter()
+                    .map(|(tok, bias)| (tok, bias.clamp(-MAX_LOGIT_BIAS, MAX_LOGIT_BIAS)))
+                    .collect()
+            }),
+            repetition_penalty: repetition_penalty.map(|penalty| penalty as f32),
+            repetition_context_size,
             logits_processors,
+            order: DEFAULT_SAMPLER_ORDER.to_vec(),
         })
     }
 
+    /// Use contrastive search between the `top_k` candidates, with the rows of `token_embeddings`
+    /// as the representations of tokens. This replaces sampling outside of speculative decoding.
+    pub fn with_contrastive_search(mut self, penalty_alpha: f64, token_embeddings: Tensor) -> Self {
+        self.contrastive = Some(ContrastiveSearch {
+            penalty_alpha: penalty_alpha as f32,
+            token_embeddings,
+        });
+        self
+    }
+
+    /// Also truncate with eta and epsilon sampling, keeping only the tokens which they and top-k,
+    /// top-p, min-p and typical-p all keep. A cutoff outside of `(0, 1)` disables it.
+    pub fn with_eta_epsilon_cutoffs(
+        mut self,
+        eta_cutoff: Option<f64>,
+        epsilon_cutoff: Option<f64>,
+    ) -> Self {
+        self.eta_cutoff = eta_cutoff.unwrap_or(0.0) as f32;
+        self.epsilon_cutoff = epsilon_cutoff.unwrap_or(0.0) as f32;
+        self
+    }
+
+    /// Unless `penalize_prompt`, count only the tokens after the first `prompt_len` for the
+    /// frequency and presence penalties, so that they only penalize repeating generated tokens.
+    pub fn with_penalized_prompt(mut self, penalize_prompt: bool, prompt_len: usize) -> Self {
+        self.penalty_start = if penalize_prompt { 0 } else { prompt_len };
+        self
+    }
+
+    /// Apply the steps in `order`, where steps which are left out are applied after the given
+    /// ones, in the default order.
+    pub fn with_sampler_order(mut self, order: Option<&[SamplerStep]>) -> Self {
+        if let Some(order) = order {
+            let mut full = order.to_vec();
+            full.extend(
+                DEFAULT_SAMPLER_ORDER
+                    .iter()
+                    .filter(|step| !order.contains(step)),
+            );
+            self.order = full;
+        }
+        self
+    }
+
+    fn steps_after_temperature(&self) -> &[SamplerStep] {
+        let temperature = self
+            .order
+            .iter()
+            .position(|step| *step == SamplerStep::Temperature)
+            .expect("The order has every step.");
+        &self.order[temperature + 1..]
+    }
+
+    /// Ban the tokens excluded by the truncations ordered before the temperature, which see the
+    /// distribution without it. The other truncations run on the probabilities after it.
+    fn truncate_before_temperature(&self, logits: Tensor) -> Result<Tensor> {
+        let steps = self
+            .order
+            .iter()
+            .take_while(|step| **step != SamplerStep::Temperature)
+            .collect::<Vec<_>>();
+        if steps.is_empty() {
+            return Ok(logits);
+        }
+        let mut probs: Vec<f32> = candle_nn::ops::softmax_last_dim(&logits)?.to_vec1()?;
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        // Sort by descending probability.
+        argsort_indices.sort_unstable_by(|&i, &j| probs[j].total_cmp(&probs[i]));
+        let untruncated = probs.clone();
+        for step in steps {
+            truncate_step(
+                *step,
+                &mut probs,
+                &argsort_indices,
+                self.top_k,
+                self.top_p as f32,
+                self.min_p as f32,
+            );
+        }
+        let mut logits: Vec<f32> = logits.to_vec1()?;
+        for (logit, (prob, untruncated)) in logits.iter_mut().zip(zip(probs, untruncated)) {
+            if prob == 0.0 && untruncated > 0.0 {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        let vocab_size = logits.len();
+        Tensor::from_vec(logits, vocab_size, &Device::Cpu)
+    }
+
+    /// Clamp the probabilities of the tokens excluded by any of the truncations to zero.
+    fn truncate(
+        &self,
+        probs: &mut [f32],
+        argsort_indices: &[usize],
+        top_k: i64,
+        top_p: f32,
+        min_p: f32,
+    ) {
+        // Computed on the whole distribution, and applied last: as the probabilities are not
+        // renormalized in between, the result keeps the tokens kept by every truncation.
+        let threshold = eta_epsilon_threshold(probs, self.eta_cutoff, self.epsilon_cutoff);
+        for step in self.steps_after_temperature() {
+            truncate_step(*step, probs, argsort_indices, top_k, top_p, min_p);
+        }
+        truncate_typical_p(probs, self.typical_p as f32);
+        if let Some(threshold) = threshold {
+            truncate_below(probs, argsort_indices, threshold);
+        }
+    }
+
     fn get_top_logprobs(
         &self,
         probs: &[f32],
@@ -270,7 +764,7 @@ impl Sampler {
         // The top n's values
         let top_n_logprobs = argsort_indices_sorted[top_n_toks_range.clone()]
             .iter()
-            .map(|x| probs[*x].log(10.0))
+            .map(|x| probs[*x].ln())
             .collect::<Vec<_>>();
         // Find where they actually are in the logits
         let mut top_n_toks = Vec::new();
@@ -312,7 +806,62 @@ impl Sampler {
         let probs: Vec<f32> = logits.to_vec1()?;
 
         let argsort_indices = (0..probs.len()).collect::<Vec<_>>();
-        let logprob = probs[next_token as usize].log(10.0);
+        let logprob = probs[next_token as usize].ln();
+
+        let top_logprobs = if return_logprobs {
+            Some(self.get_top_logprobs(&probs, &argsort_indices)?)
+        } else {
+            None
+        };
+
+        let bytes = if let Some(tokenizer) = &self.tokenizer {
+            Some(
+                tokenizer
+                    .decode(&[next_token], false)
+                    .map_err(|x| Error::Msg(x.to_string()))?,
+            )
+        } else {
+            None
+        };
+
+        Ok(Logprobs {
+            token: next_token,
+            logprob,
+            top_logprobs,
+            bytes,
+        })
+    }
+
+    fn sample_contrastive(
+        &self,
+        logits: Tensor,
+        context: &[u32],
+        contrastive: &ContrastiveSearch,
+        return_logprobs: bool,
+    ) -> Result<Logprobs> {
+        let probs: Vec<f32> = candle_nn::ops::softmax_last_dim(&logits)?.to_vec1()?;
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        // Sort by descending probability.
+        argsort_indices.sort_unstable_by(|&i, &j| probs[j].total_cmp(&probs[i]));
+
+        let top_k = usize::try_from(self.top_k).unwrap_or(0).max(1);
+        let candidates = argsort_indices
+            .iter()
+            .take(top_k)
+            .map(|tok| *tok as u32)
+            .collect::<Vec<_>>();
+        let similarities = contrastive.max_similarities(&candidates, context)?;
+        let alpha = contrastive.penalty_alpha;
+        let next_token = zip(&candidates, similarities)
+            .map(|(tok, similarity)| {
+                let score = (1. - alpha) * probs[*tok as usize] - alpha * similarity;
+                (*tok, score)
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(tok, _)| tok)
+            .expect("There is at least one candidate.");
+
+        let logprob = probs[next_token as usize].ln();
 
         let top_logprobs = if return_logprobs {
             Some(self.get_top_logprobs(&probs, &argsort_indices)?)
@@ -353,50 +902,13 @@ impl Sampler {
         argsort_indices
             .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
 
-        if top_k > 0 {
-            // Clamp smaller probabilities to zero.
-            for (index, val) in argsort_indices.iter().enumerate() {
-                if index >= top_k as usize {
-                    probs[*val] = 0.0;
-                }
-            }
-        }
-
-        // TOP P
-
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
-
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
-                probs[*index] = 0.0;
-            } else {
-                cumsum += probs[*index];
-            }
-        }
-
-        let max_p = probs[argsort_indices[0]];
-
-        // MIN P
-
-        // min-p sampling samples from the tokens whose prob are greater than
-        // (max prob of token in dist) * min_p
-
-        // Clamp smaller probabilities to zero.
-        for index in &argsort_indices {
-            if max_p * min_p >= probs[*index] {
-                probs[*index] = 0.0;
-            }
-        }
+        self.truncate(&mut probs, &argsort_indices, top_k, top_p, min_p);
 
         let logits = Tensor::from_slice(&probs, logits.shape(), &Device::Cpu)?;
 
         let next_token = argmax_sample_last_dim(&logits)?.to_scalar::<u32>()?;
 
-        let logprob = probs[next_token as usize].log(10.0);
+        let logprob = probs[next_token as usize].ln();
 
         let top_logprobs = if return_logprobs {
             Some(self.get_top_logprobs(&probs, &argsort_indices)?)
@@ -433,7 +945,7 @@ impl Sampler {
 
         let mut mut_ref_rng = &mut *rng.lock().expect("could not lock rng mutex");
         let next_token = distr.sample(&mut mut_ref_rng); // "Find the first item which has a weight *higher* than the chosen weight."
-        let logprob = probs[next_token].log(10.0);
+        let logprob = probs[next_token].ln();
 
         let top_logprobs = if return_logprobs {
             Some(self.get_top_logprobs(probs, &argsort_indices)?)
@@ -473,55 +985,41 @@ impl Sampler {
         argsort_indices
             .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
 
-        if top_k > 0 {
-            // Clamp smaller probabilities to zero.
-            for (index, val) in argsort_indices.iter().enumerate() {
-                if index >= top_k as usize {
-                    probs[*val] = 0.0;
-                }
-            }
-        }
+        self.truncate(probs, &argsort_indices, top_k, top_p, min_p);
 
-        if top_p <= 0.0 || top_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
-        }
+        // Sample with clamped probabilities.
+        self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
+    }
 
-        // TOP P
+    fn sample_mirostat_v2(
+        &self,
+        probs: &mut Vec<f32>,
+        mirostat: &MirostatState,
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<Logprobs> {
+        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+        // Sort by descending probability.
+        argsort_indices
+            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
 
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
+        let mut mu = mirostat.mu.lock().expect("could not lock mirostat mutex");
 
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
+        // Clamp tokens more surprising than `mu` to zero, always keeping the most likely one.
+        for index in argsort_indices.iter().skip(1) {
+            if -probs[*index].log2() > *mu {
                 probs[*index] = 0.0;
-            } else {
-                cumsum += probs[*index];
             }
         }
+        let total = probs.iter().sum::<f32>();
 
-        if min_p <= 0.0 || min_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
-        }
-
-        let max_p = probs[argsort_indices[0]];
+        let next_token = self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)?;
 
-        // MIN P
+        // Move `mu` towards the target surprise, measured on the truncated distribution.
+        let observed_surprise = -(probs[next_token.token as usize] / total).log2();
+        *mu -= mirostat.params.eta * (observed_surprise - mirostat.params.tau);
 
-        // min-p sampling samples from the tokens whose prob are greater than
-        // (max prob of token in dist) * min_p
-
-        // Clamp smaller probabilities to zero.
-        for index in &argsort_indices {
-            if max_p * min_p >= probs[*index] {
-                probs[*index] = 0.0;
-            }
-        }
-
-        // Sample with clamped probabilities.
-        self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
+        Ok(next_token)
     }
 
     fn apply_penalties(&self, mut logits: Vec<f32>, context: &[u32]) -> Result<Tensor> {
@@ -532,22 +1030,57 @@ impl Sampler {
         // Dry penalty
         self.apply_dry_penalty(&mut logits, context)?;
 
+        // Repetition penalty
+        self.apply_repetition_penalty(&mut logits, context);
+
         // Frequency and Presence penalty
         self.apply_freq_presc_penalty(&mut logits, context)?;
 
+        // Logit bias
+        if let Some(ref logits_bias) = self.logits_bias {
+            for (tok, bias) in logits_bias {
+                if let Some(logit) = logits.get_mut(*tok as usize) {
+                    *logit += bias;
+                }
+            }
+        }
+
         let vocab_size = logits.len();
         Tensor::from_vec(logits, vocab_size, &Device::Cpu)
     }
 
-    fn apply_freq_presc_penalty(&self, logits: &mut [f32], context: &[u32]) -> Result<()> {
-        if self.frequency_penalty.is_some() || self.presence_penalty.is_some() {
-            let frequency_penalty = self.frequency_penalty.unwrap_or(0.);
-            let presence_penalty = self.presence_penalty.unwrap_or(0.);
+    fn apply_repetition_penalty(&self, logits: &mut [f32], context: &[u32]) {
+        let Some(penalty) = self.repetition_penalty else {
+            return;
+        };
+        let window_start = self
+            .repetition_context_size
+            .map_or(0, |size| context.len().saturating_sub(size));
+        let mut seen = vec![false; logits.len()];
+        for ctx in &context[window_start..] {
+            let Some(seen) = seen.get_mut(*ctx as usize) else {
+                continue;
+            };
+            if !*seen {
+                *seen = true;
+                let logit = &mut logits[*ctx as usize];
+                *logit = if *logit > 0. {
+                    *logit / penalty
+                } else {
+                    *logit * penalty
+                };
+            }
+        }
+    }
 
+    fn apply_freq_presc_penalty(&self, logits: &mut [f32], context: &[u32]) -> Result<()> {
+        let frequency_penalty = self.frequency_penalty.unwrap_or(0.);
+        let presence_penalty = self.presence_penalty.unwrap_or(0.);
+        if frequency_penalty != 0. || presence_penalty != 0. {
             //mu[j] -> mu[j] - c[j] * alpha_frequency - float(c[j] > 0) * alpha_presence
 
             let mut counts = vec![0.0f32; logits.len()];
-            for ctx in context.iter() {
+            for ctx in context.get(self.penalty_start..).unwrap_or_default() {
                 // Llama 3.2 uses a hack triggering this error... we wouldn't want a weight on it anyway
                 if *ctx as usize >= logits.len() {
                     continue;
@@ -635,8 +1168,12 @@ impl Sampler {
 
     /// Sample the provided tokens.
     ///
-    /// If the temperature is `None`, argmax sampling is used. Otherwise, the selected sampling is used.
+    /// If the temperature is `None` or below [`MIN_TEMPERATURE`], argmax sampling is used, without
+    /// top-k, top-p, min-p, typical-p, eta or epsilon. Otherwise, the selected sampling is used.
     /// With `top-p` sampling, if the `top-p` value is `<= 0.0` or `>= 1.0`, multinomial sampling is used.
+    /// Mirostat, if set, replaces top-k, top-p and min-p outside of speculative sampling.
+    /// Contrastive search, if set, replaces all of these, and the temperature, outside of
+    /// speculative sampling.
     pub fn sample(
         &self,
         logits: Tensor,
@@ -650,16 +1187,14 @@ impl Sampler {
         for processor in &self.logits_processors {
             logits = processor.apply(&logits, context)?;
         }
+        if let (Some(contrastive), false) = (&self.contrastive, sample_speculative) {
+            return self.sample_contrastive(logits, context, contrastive, return_logprobs);
+        }
         let next_token = if sample_speculative {
             match self.temperature {
-                None => self.sample_speculative_top_kp_min_p(
-                    logits,
-                    return_logprobs,
-                    self.top_k,
-                    self.top_p as f32,
-                    self.min_p as f32,
-                )?,
+                None => self.sample_argmax(logits, return_logprobs)?,
                 Some(temperature) => {
+                    let logits = self.truncate_before_temperature(logits)?;
                     let logits = (&logits / temperature)?;
                     let probs = candle_nn::ops::softmax_last_dim(&logits)?;
 
@@ -676,18 +1211,28 @@ impl Sampler {
             match self.temperature {
                 None => self.sample_argmax(logits, return_logprobs)?,
                 Some(temperature) => {
+                    // Mirostat replaces the truncations.
+                    let logits = if self.mirostat.is_some() {
+                        logits
+                    } else {
+                        self.truncate_before_temperature(logits)?
+                    };
                     let logits = (&logits / temperature)?;
                     let probs = candle_nn::ops::softmax_last_dim(&logits)?;
                     let mut probs: Vec<f32> = probs.to_vec1()?;
 
-                    self.sample_top_kp_min_p(
-                        &mut probs,
-                        self.top_k,
-                        self.top_p as f32,
-                        self.min_p as f32,
-                        return_logprobs,
-                        rng,
-                    )?
+                    if let Some(mirostat) = &self.mirostat {
+                        self.sample_mirostat_v2(&mut probs, mirostat, return_logprobs, rng)?
+                    } else {
+                        self.sample_top_kp_min_p(
+                            &mut probs,
+                            self.top_k,
+                            self.top_p as f32,
+                            self.min_p as f32,
+                            return_logprobs,
+                            rng,
+                        )?
+                    }
                 }
             }
         };
@@ -731,6 +1276,11 @@ mod tests {
             32,
             0.1,
             0.05,
+            1.0,
+            None,
+            None,
+            None,
+            None,
             vec![],
         )
         .unwrap();
@@ -741,7 +1291,7 @@ mod tests {
             .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
-        assert_eq!(res.logprob, 1023f64.log(10.) as f32)
+        assert_eq!(res.logprob, 1023f64.ln() as f32)
     }
 
     #[test]
@@ -763,6 +1313,11 @@ mod tests {
             32,
             0.1,
             0.05,
+            1.0,
+            None,
+            None,
+            None,
+            None,
             vec![],
         )
         .unwrap();
@@ -773,6 +1328,406 @@ mod tests {
             .unwrap();
         assert_eq!(res.token, 1023);
         assert_eq!(res.top_logprobs, None);
-        assert_eq!(res.logprob, 1023f64.log(10.) as f32)
+        assert_eq!(res.logprob, 1023f64.ln() as f32)
+    }
+
+    #[test]
+    fn test_zero_temperature_is_greedy() {
+        use super::Sampler;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        // With top-p and min-p this loose, sampling at any real temperature could pick other tokens.
+        let logits = [1f32, 3., 3.5, -2., 0.5];
+        for temperature in [Some(0.0), Some(1e-9), None] {
+            let sampler = Sampler::new(
+                temperature,
+                0,
+                None,
+                None,
+                None,
+                None,
+                -1,
+                1.0,
+                0.0,
+                1.0,
+                None,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap();
+            // Different seeds, as for two requests without one, pick the same tokens.
+            for seed in 0..20 {
+                for sample_speculative in [false, true] {
+                    let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(seed)));
+                    let res = sampler
+                        .sample(
+                            Tensor::new(&logits, &Device::Cpu).unwrap(),
+                            &[0],
+                            false,
+                            rng,
+                            sample_speculative,
+                        )
+                        .unwrap();
+                    assert_eq!(res.token, 2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_contrastive_search_avoids_repetition() {
+        use super::Sampler;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        // A model stuck on token 0, whose tokens have orthogonal embeddings.
+        let logits = [3f32, 2., 1., 0.];
+        let token_embeddings = Tensor::eye(4, candle_core::DType::F32, &Device::Cpu).unwrap();
+        let sampler = Sampler::new(
+            Some(1.0),
+            0,
+            None,
+            None,
+            None,
+            None,
+            3,
+            1.0,
+            0.0,
+            1.0,
+            None,
+            None,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap()
+        .with_contrastive_search(0.6, token_embeddings);
+
+        let mut context = vec![0, 0];
+        for _ in 0..3 {
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(0)));
+            let res = sampler
+                .sample(
+                    Tensor::new(&logits, &Device::Cpu).unwrap(),
+                    &context,
+                    false,
+                    rng,
+                    false,
+                )
+                .unwrap();
+            context.push(res.token);
+        }
+        // Once every candidate has been seen, the most likely one wins again.
+        assert_eq!(context[2..], [1, 2, 0]);
+    }
+
+    #[test]
+    fn test_min_p_prunes_tail() {
+        use super::truncate_top_kp_min_p;
+
+        // One dominant token followed by a long tail of unlikely ones.
+        let mut probs = vec![0.1f32 / 100.; 101];
+        probs[0] = 0.9;
+        let argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+
+        truncate_top_kp_min_p(&mut probs, &argsort_indices, -1, 1.0, 0.1);
+        assert_eq!(probs[0], 0.9);
+        assert!(probs[1..].iter().all(|p| *p == 0.0));
+    }
+
+    #[test]
+    fn test_sampler_order_changes_the_distribution() {
+        use super::{Sampler, SamplerStep};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        // Top-p 0.7 keeps 3 tokens of the flatter distribution at temperature 2, but only 2 of
+        // the distribution without it.
+        let logits = [2f32, 1.5, 1., 0.];
+        let kept = |order: Option<&[SamplerStep]>| {
+            let sampler = Sampler::new(
+                Some(2.0),
+                logits.len(),
+                None,
+                None,
+                None,
+                None,
+                -1,
+                0.7,
+                0.0,
+                1.0,
+                None,
+                None,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap()
+            .with_sampler_order(order);
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+            let res = sampler
+                .sample(
+                    Tensor::new(&logits, &Device::Cpu).unwrap(),
+                    &[0],
+                    true,
+                    rng,
+                    false,
+                )
+                .unwrap();
+            let mut kept = res
+                .top_logprobs
+                .unwrap()
+                .into_iter()
+                .filter(|top| top.logprob.is_finite())
+                .map(|top| top.token)
+                .collect::<Vec<_>>();
+            kept.sort();
+            kept
+        };
+
+        assert_eq!(kept(None), [0, 1, 2]);
+        assert_eq!(
+            kept(Some(&[SamplerStep::TopP, SamplerStep::Temperature])),
+            [0, 1]
+        );
+        assert_eq!(
+            SamplerStep::parse_order(&["top_k".to_string(), "top_k".to_string()]),
+            Err("Sampler step `top_k` is given more than once.".to_string())
+        );
+        assert!(SamplerStep::parse_order(&["typical_p".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_typical_p_keeps_tokens_closest_to_entropy() {
+        use super::truncate_typical_p;
+
+        // The entropy is 1.75 bits and the surprises are 1, 2, 3 and 3 bits, so ordered by
+        // closeness to the entropy the tokens are 1 (0.25 away), 0 (0.75), then 2 and 3 (1.25).
+        let dist = [0.5f32, 0.25, 0.125, 0.125];
+
+        let mut probs = dist.to_vec();
+        truncate_typical_p(&mut probs, 0.2);
+        assert_eq!(probs, vec![0.0, 0.25, 0.0, 0.0]);
+
+        let mut probs = dist.to_vec();
+        truncate_typical_p(&mut probs, 0.5);
+        assert_eq!(probs, vec![0.5, 0.25, 0.0, 0.0]);
+
+        // Relative to the remaining mass, as after top-p removed the last token.
+        let mut probs = vec![0.5f32, 0.25, 0.125, 0.0];
+        truncate_typical_p(&mut probs, 0.8);
+        assert_eq!(probs, vec![0.5, 0.25, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_eta_epsilon_cutoffs() {
+        use super::{eta_epsilon_threshold, truncate_below, truncate_top_kp_min_p};
+
+        // The entropy is 1.875 ln 2 nats, so eta 0.1 cuts below
+        // min(0.1, sqrt(0.1) * 2^-1.875) = 0.0862.
+        let dist = [0.5f32, 0.25, 0.125, 0.0625, 0.0625];
+        let argsort_indices = (0..dist.len()).collect::<Vec<_>>();
+        let truncated = |eta: f32, epsilon: f32, top_p: f32| {
+            let mut probs = dist.to_vec();
+            let threshold = eta_epsilon_threshold(&probs, eta, epsilon);
+            truncate_top_kp_min_p(&mut probs, &argsort_indices, -1, top_p, 0.0);
+            if let Some(threshold) = threshold {
+                truncate_below(&mut probs, &argsort_indices, threshold);
+            }
+            probs
+        };
+
+        let threshold = eta_epsilon_threshold(&dist, 0.1, 0.0).unwrap();
+        assert!((threshold - 0.1f32.sqrt() * 2f32.powf(-1.875)).abs() < 1e-6);
+        assert_eq!(truncated(0.1, 0.0, 1.0), [0.5, 0.25, 0.125, 0.0, 0.0]);
+        assert_eq!(truncated(0.0, 0.2, 1.0), [0.5, 0.25, 0.0, 0.0, 0.0]);
+        // Together, the higher threshold wins.
+        assert_eq!(truncated(0.1, 0.2, 1.0), [0.5, 0.25, 0.0, 0.0, 0.0]);
+        // The most likely token is always kept.
+        assert_eq!(truncated(0.0, 0.6, 1.0), [0.5, 0.0, 0.0, 0.0, 0.0]);
+        // Disabled outside of (0, 1).
+        assert_eq!(eta_epsilon_threshold(&dist, 0.0, 1.0), None);
+
+        // Top-p 0.9 alone keeps 4 tokens, so with eta the 3 kept by both remain.
+        assert_eq!(truncated(0.0, 0.0, 0.9), [0.5, 0.25, 0.125, 0.0625, 0.0]);
+        assert_eq!(truncated(0.1, 0.0, 0.9), [0.5, 0.25, 0.125, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_negative_logit_bias_bans_token() {
+        use super::Sampler;
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::collections::HashMap;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        // Token 3 dominates, but is biased away. The bias is clamped to -100, which still bans it.
+        let sampler = Sampler::new(
+            Some(1.0),
+            0,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            None,
+            Some(HashMap::from([(3, -1000.)])),
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        for _ in 0..100 {
+            let logits = Tensor::new(&[1f32, 1., 1., 10.], &Device::Cpu).unwrap();
+            let res = sampler
+                .sample(logits, &[0], false, rng.clone(), false)
+                .unwrap();
+            assert_ne!(res.token, 3);
+        }
+    }
+
+    #[test]
+    fn test_logit_bias_pattern_discourages_digits() {
+        use super::{tokens_matching, Sampler};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use regex::bytes::Regex;
+        use std::collections::HashMap;
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        let vocab: [&[u8]; 6] = [b"a", b"1", b" b2", b"cd", b"42", b" e"];
+        let digits = tokens_matching(&Regex::new(r"\d").unwrap(), vocab);
+        assert_eq!(digits, vec![1, 2, 4]);
+
+        let sampler = Sampler::new(
+            Some(1.0),
+            0,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            None,
+            Some(
+                digits
+                    .iter()
+                    .map(|tok| (*tok, -10.))
+                    .collect::<HashMap<_, _>>(),
+            ),
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+        let mut sampled_digits = 0;
+        for _ in 0..1000 {
+            let logits = Tensor::new(&[1f32, 1., 1., 1., 2., 1.], &Device::Cpu).unwrap();
+            let res = sampler
+                .sample(logits, &[0], false, rng.clone(), false)
+                .unwrap();
+            if digits.contains(&res.token) {
+                sampled_digits += 1;
+            }
+        }
+        assert!(sampled_digits <= 5, "{sampled_digits} digits sampled");
+    }
+
+    #[test]
+    fn test_repetition_penalty_window() {
+        use super::Sampler;
+
+        let sampler = Sampler::new(
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            None,
+            None,
+            Some(2.0),
+            Some(3),
+            vec![],
+        )
+        .unwrap();
+        // Token 0 is just outside of the window of the last 3 tokens, and token 2 repeats inside it.
+        let mut logits = vec![1f32, 1., -1., 1., 1.];
+        sampler.apply_repetition_penalty(&mut logits, &[0, 1, 2, 2, 3]);
+        assert_eq!(logits, vec![1., 1., -2., 0.5, 1.]);
+
+        // Without a context size the whole context is penalized.
+        let sampler = Sampler {
+            repetition_context_size: None,
+            ..sampler
+        };
+        let mut logits = vec![1f32, 1., -1., 1., 1.];
+        sampler.apply_repetition_penalty(&mut logits, &[0, 1, 2, 2, 3]);
+        assert_eq!(logits, vec![0.5, 0.5, -2., 0.5, 1.]);
+    }
+
+    #[test]
+    fn test_penalize_prompt() {
+        use super::Sampler;
+
+        let sampler = Sampler::new(
+            None,
+            0,
+            None,
+            Some(0.5),
+            Some(1.0),
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            None,
+            None,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        // Token 1 appears twice in the prompt `[0, 1, 1]`, and token 2 was generated once.
+        let context = [0, 1, 1, 2];
+        let mut logits = vec![0f32; 4];
+        sampler
+            .apply_freq_presc_penalty(&mut logits, &context)
+            .unwrap();
+        assert_eq!(logits, vec![-1.5, -2., -1.5, 0.]);
+
+        let sampler = sampler.with_penalized_prompt(false, 3);
+        let mut logits = vec![0f32; 4];
+        sampler
+            .apply_freq_presc_penalty(&mut logits, &context)
+            .unwrap();
+        assert_eq!(logits, vec![0., 0., -1.5, 0.]);
     }
 }
@@ -10,14 +10,16 @@ use crate::{
     sequence::{Sequence, SequenceState, StopReason},
 };
 
-use super::{Scheduler, SchedulerOutput};
+use super::{waiting_order, Scheduler, SchedulerOutput};
 
 pub trait FcfsBacker: Default {
     fn new() -> Self;
     fn add(&mut self, item: Sequence);
+    fn retain_mut(&mut self, f: impl FnMut(&mut Sequence) -> bool);
     fn into_iter(self) -> impl Iterator<Item = Sequence>;
     fn len(&self) -> usize;
-    fn sort_ascending_ids(&mut self);
+    /// Sort by descending priority, and then by ascending id.
+    fn sort_by_priority(&mut self);
 }
 
 impl FcfsBacker for VecDeque<Sequence> {
@@ -27,12 +29,16 @@ impl FcfsBacker for VecDeque<Sequence> {
     fn add(&mut self, item: Sequence) {
         self.push_back(item)
     }
+    fn retain_mut(&mut self, f: impl FnMut(&mut Sequence) -> bool) {
+        VecDeque::retain_mut(self, f)
+    }
     fn into_iter(self) -> impl Iterator<Item = Sequence> {
         <Self as IntoIterator>::into_iter(self)
     }
-    fn sort_ascending_ids(&mut self) {
+    fn sort_by_priority(&mut self) {
+        let order = waiting_order();
         let slice = self.make_contiguous();
-        slice.sort_by_key(|seq| *seq.id());
+        slice.sort_by_key(|seq| (order(seq), *seq.id()));
     }
     fn len(&self) -> usize {
         VecDeque::len(self)
@@ -176,6 +182,8 @@ pub struct DefaultScheduler<Backer: FcfsBacker> {
     running: Vec<Sequence>,
     method: DefaultSchedulerMethod,
     bucketing_manager: Box<dyn BucketingManager<Backer>>,
+    /// The most sequences to run at once, below what `method` allows, if set.
+    batch_size_cap: Option<usize>,
 }
 
 impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
@@ -188,9 +196,16 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             waiting: Backer::new(),
             method,
             bucketing_manager,
+            batch_size_cap: None,
         }
     }
 
+    /// Never run more than `cap` sequences in one step.
+    pub fn with_batch_size_cap(mut self, cap: Option<usize>) -> Self {
+        self.batch_size_cap = cap;
+        self
+    }
+
     /// Move the seuqences into buckets, and run the ones with the shortest lengths.
     /// The others are moved to the waiting list (retaining high priority due to start time),
     /// without a state modification.
@@ -222,11 +237,20 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
                 };
             }
             (_, 0) => {
+                let cap = self.batch_size_cap.unwrap_or(usize::MAX);
+                if self.batch_size_cap.is_some() {
+                    waiting.sort_by_priority();
+                }
+                let mut new_waiting = Backer::new();
                 for seq in waiting.into_iter() {
-                    seq.set_state(SequenceState::RunningPrompt);
-                    self.running.push(seq);
+                    if self.running.len() < cap {
+                        seq.set_state(SequenceState::RunningPrompt);
+                        self.running.push(seq);
+                    } else {
+                        new_waiting.add(seq);
+                    }
                 }
-                self.waiting = Backer::new();
+                self.waiting = new_waiting;
                 let running = std::mem::take(&mut self.running);
                 self.running = self.bucket_and_waitlist_seqs(running);
                 return DefaultSchedulerOutput {
@@ -251,7 +275,7 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
         }
 
         // Sort the waiting seqs
-        waiting.sort_ascending_ids();
+        waiting.sort_by_priority();
 
         // If the waiting sequence will fit, add it. Otherwise remove it
         let mut new_waiting = Backer::new();
@@ -293,9 +317,10 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
     }
 
     fn sequence_fits(&self, running: &[Sequence], _seq: &Sequence) -> bool {
-        match &self.method {
-            DefaultSchedulerMethod::Fixed(n) => (running.len() + 1) <= (*n).into(),
-        }
+        let max = match &self.method {
+            DefaultSchedulerMethod::Fixed(n) => usize::from(*n),
+        };
+        running.len() < self.batch_size_cap.map_or(max, |cap| max.min(cap))
     }
 }
 
@@ -319,6 +344,26 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
             self.waiting.add(seq);
         }
     }
+    fn cancel_request(&mut self, request_id: usize) -> bool {
+        // Sequences finish as canceled at their next step, which sends their final response.
+        // Waiting sequences whose client went away are dropped, as no one would receive it.
+        let mut canceled = false;
+        self.waiting.retain_mut(|seq| {
+            if seq.request_id() != request_id {
+                return true;
+            }
+            canceled = true;
+            seq.request_cancel();
+            !seq.responder().is_closed()
+        });
+        for seq in &mut self.running {
+            if seq.request_id() == request_id && !seq.is_finished_paged_attn() {
+                seq.request_cancel();
+                canceled = true;
+            }
+        }
+        canceled
+    }
     fn block_tables(&self) -> Option<&BlockTables> {
         None
     }
@@ -329,4 +374,170 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         None
     }
+    fn kv_cache_usage(&self) -> Option<(usize, usize)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        num::NonZeroUsize,
+        sync::Arc,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use tokio::sync::{mpsc::channel, Mutex};
+
+    use super::{DefaultScheduler, DefaultSchedulerMethod};
+    use crate::{
+        sampler::Sampler,
+        scheduler::Scheduler,
+        sequence::{SeqStepType, Sequence, SequenceGroup, SequenceRecognizer, SequenceState},
+    };
+
+    fn sequence(id: usize, priority: Option<i32>) -> Sequence {
+        sequence_with_prompt(id, priority, vec![1, 2])
+    }
+
+    fn sequence_with_prompt(id: usize, priority: Option<i32>, prompt: Vec<u32>) -> Sequence {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let sampler = Sampler::new(
+            None,
+            0,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            None,
+            None,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        let group = Arc::new(Mutex::new(SequenceGroup::new(1, 1, false, false, 1)));
+        Sequence::new_waiting(
+            prompt,
+            String::new(),
+            id,
+            id,
+            now,
+            1,
+            channel(1).0,
+            sampler,
+            vec![],
+            vec![],
+            None,
+            false,
+            false,
+            group,
+            0,
+            0,
+            SequenceRecognizer::None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            SeqStepType::PromptAndDecode,
+            None,
+        )
+        .with_priority(priority)
+    }
+
+    /// The ids of the sequences which start their prompt when a running sequence leaves room for
+    /// one of the waiting ones, added in the order of `priorities`.
+    fn scheduled_prompts(priorities: &[Option<i32>]) -> Vec<usize> {
+        let mut scheduler = DefaultScheduler::<VecDeque<Sequence>>::new(
+            DefaultSchedulerMethod::Fixed(NonZeroUsize::new(2).unwrap()),
+        );
+        let running = sequence(0, None);
+        running.set_state(SequenceState::RunningCompletion);
+        scheduler.add_seq(running);
+        for (id, priority) in priorities.iter().enumerate() {
+            scheduler.add_seq(sequence(id + 1, *priority));
+        }
+        let output = scheduler.schedule();
+        output.prompt.iter().map(|seq| *seq.id()).collect()
+    }
+
+    #[test]
+    fn forced_batch_size_is_respected() {
+        let mut scheduler = DefaultScheduler::<VecDeque<Sequence>>::new(
+            DefaultSchedulerMethod::Fixed(NonZeroUsize::new(16).unwrap()),
+        )
+        .with_batch_size_cap(Some(3));
+        for id in 0..5 {
+            scheduler.add_seq(sequence(id, None));
+        }
+        let output = scheduler.schedule();
+        assert_eq!(output.prompt.len() + output.completion.len(), 3);
+        for seq in output.prompt.iter() {
+            seq.set_state(SequenceState::RunningCompletion);
+        }
+
+        // The waiting sequences stay waiting while the first three run.
+        let output = scheduler.schedule();
+        assert_eq!(output.prompt.len() + output.completion.len(), 3);
+        assert_eq!(scheduler.waiting_len(), 2);
+    }
+
+    #[test]
+    fn high_priority_request_is_scheduled_first() {
+        assert_eq!(scheduled_prompts(&[Some(-1), Some(-1), Some(-1)]), [1]);
+        assert_eq!(scheduled_prompts(&[Some(-1), Some(-1), Some(1)]), [3]);
+        // Without priorities the queue is first come, first served.
+        assert_eq!(scheduled_prompts(&[None, None, None]), [1]);
+    }
+
+    #[test]
+    fn long_prompt_is_prefilled_in_chunks_between_decode_steps() {
+        let mut scheduler = DefaultScheduler::<VecDeque<Sequence>>::new(
+            DefaultSchedulerMethod::Fixed(NonZeroUsize::new(2).unwrap()),
+        );
+        let short = sequence(0, None);
+        short.set_state(SequenceState::RunningCompletion);
+        scheduler.add_seq(short);
+        scheduler.add_seq(sequence_with_prompt(1, None, vec![1; 64]));
+
+        // Run the steps as the engine does, until the long prompt is prefilled.
+        let mut chunks = 0;
+        let mut decoded_while_prefilling = 0;
+        loop {
+            let mut output = scheduler.schedule();
+            let mut prefilled = false;
+            for seq in output.prompt.iter_mut() {
+                seq.set_prefill_chunk(8);
+                assert!(seq.get_toks().len() <= 8);
+                if seq.is_partial_prefill() {
+                    seq.finish_prefill_chunk();
+                    chunks += 1;
+                } else {
+                    seq.set_state(SequenceState::RunningCompletion);
+                    prefilled = true;
+                }
+            }
+            if prefilled {
+                break;
+            }
+            for seq in output.completion.iter_mut() {
+                seq.add_tmp_tok(0);
+                decoded_while_prefilling += 1;
+            }
+        }
+        assert_eq!(chunks, 7);
+        assert!(decoded_while_prefilling > 0);
+    }
 }
@@ -1,5 +1,15 @@
 mod default_scheduler;
 
+use std::{
+    cmp::Reverse,
+    env,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
 pub use default_scheduler::{DefaultScheduler, DefaultSchedulerMethod, DefaultSchedulerOutput};
 
 use crate::{
@@ -10,6 +20,40 @@ use crate::{
     sequence::Sequence,
 };
 
+/// Waiting this long raises the priority of a sequence by one, so that a steady stream of higher
+/// priority requests does not starve the lower priority ones.
+const PRIORITY_AGING_MS: u128 = 5_000;
+
+/// The priority a waiting sequence is scheduled by: that of its request, plus one for every
+/// [`PRIORITY_AGING_MS`] since the sequence was added at `timestamp`.
+fn effective_priority(priority: i32, timestamp: u128, now: u128) -> i64 {
+    let aging = now.saturating_sub(timestamp) / PRIORITY_AGING_MS;
+    i64::from(priority).saturating_add(i64::try_from(aging).unwrap_or(i64::MAX))
+}
+
+/// The key to sort the waiting queue by so that the highest effective priority comes first. With
+/// equal priorities a stable sort keeps the queue in order.
+pub(crate) fn waiting_order() -> impl Fn(&Sequence) -> Reverse<i64> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time travel has occurred!")
+        .as_millis();
+    move |seq| Reverse(effective_priority(seq.priority(), seq.timestamp(), now))
+}
+
+/// The most sequences to run in one step, from `MISTRALRS_FORCE_BATCH_SIZE`, so that tests can
+/// reproduce a batching configuration. Unset or 0, the schedulers are unchanged.
+static FORCED_BATCH_SIZE: Lazy<Option<usize>> = Lazy::new(|| {
+    env::var("MISTRALRS_FORCE_BATCH_SIZE")
+        .ok()
+        .and_then(|val| val.parse::<usize>().ok())
+        .filter(|size| *size > 0)
+});
+
+pub(crate) fn forced_batch_size() -> Option<usize> {
+    *FORCED_BATCH_SIZE
+}
+
 #[derive(Clone)]
 pub enum SchedulerConfig {
     DefaultScheduler {
@@ -24,12 +68,17 @@ pub enum SchedulerConfig {
 impl SchedulerConfig {
     pub fn into_scheduler(self) -> Box<dyn Scheduler> {
         match self {
-            Self::DefaultScheduler { method } => Box::new(DefaultScheduler::new(method)),
+            Self::DefaultScheduler { method } => {
+                Box::new(DefaultScheduler::new(method).with_batch_size_cap(forced_batch_size()))
+            }
             Self::PagedAttentionMeta {
                 max_num_seqs,
                 config,
             } => Box::new(PagedAttentionScheduler::new(
-                PagedAttentionSchedulerConfig { max_num_seqs },
+                PagedAttentionSchedulerConfig {
+                    max_num_seqs: forced_batch_size()
+                        .map_or(max_num_seqs, |cap| max_num_seqs.min(cap)),
+                },
                 config,
             )),
         }
@@ -50,6 +99,10 @@ pub trait Scheduler {
     fn waiting_len(&self) -> usize;
     fn running_len(&self) -> usize;
     fn add_seq(&mut self, seq: Sequence);
+    /// Cancel all sequences belonging to the request with this ID. They will be
+    /// removed from the running set at the next scheduling step. Returns whether any of them had
+    /// not finished yet.
+    fn cancel_request(&mut self, request_id: usize) -> bool;
     /// This may do nothing. It depends on the implementation
     fn free_finished_sequence_groups(&mut self);
 
@@ -57,4 +110,85 @@ pub trait Scheduler {
     fn block_tables(&self) -> Option<&BlockTables>;
     fn block_size(&self) -> Option<usize>;
     fn block_engine(&mut self) -> Option<&mut BlockEngine>;
+    /// The number of KV cache blocks in use and the total number of blocks, if the cache is
+    /// allocated in blocks.
+    fn kv_cache_usage(&self) -> Option<(usize, usize)>;
+}
+
+#[derive(Default)]
+/// Sizes of the scheduler queues, published by the engine before every scheduling step.
+pub struct SchedulerStats {
+    waiting: AtomicUsize,
+    running: AtomicUsize,
+}
+
+impl SchedulerStats {
+    pub(crate) fn update(&self, scheduler: &dyn Scheduler) {
+        self.waiting
+            .store(scheduler.waiting_len(), Ordering::Relaxed);
+        self.running
+            .store(scheduler.running_len(), Ordering::Relaxed);
+    }
+
+    /// Number of sequences waiting to be scheduled.
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// Number of sequences currently being decoded.
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+/// The request IDs of the sequences run in one step, with one entry per sequence.
+pub struct BatchComposition {
+    /// Sequences which ran their prompt.
+    pub prompt: Vec<usize>,
+    /// Sequences which generated a token.
+    pub completion: Vec<usize>,
+}
+
+impl BatchComposition {
+    pub fn len(&self) -> usize {
+        self.prompt.len() + self.completion.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+/// A snapshot of the engine's scheduler, taken between steps by [`crate::Request::GetStats`].
+pub struct EngineStats {
+    /// Number of sequences currently being decoded.
+    pub running_sequences: usize,
+    /// Number of sequences waiting to be scheduled.
+    pub waiting_sequences: usize,
+    /// Number of sequences run in the last step.
+    pub batch_size: usize,
+    /// The requests of the sequences run in the last step.
+    pub last_batch: BatchComposition,
+    /// The cap on the sequences run in one step from `MISTRALRS_FORCE_BATCH_SIZE`, if set.
+    pub forced_batch_size: Option<usize>,
+    /// Percentage of the KV cache blocks in use, if PagedAttention is enabled.
+    pub kv_cache_utilization: Option<f64>,
+    /// Fraction of the draft tokens accepted with speculative decoding, once any were proposed.
+    pub speculative_acceptance_rate: Option<f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{effective_priority, PRIORITY_AGING_MS};
+
+    #[test]
+    fn low_priority_requests_age_into_running() {
+        assert_eq!(effective_priority(-2, 0, 0), -2);
+        assert_eq!(effective_priority(-2, 0, PRIORITY_AGING_MS - 1), -2);
+        // After waiting for long enough, a low priority request overtakes new high priority ones.
+        assert_eq!(effective_priority(-2, 0, 5 * PRIORITY_AGING_MS), 3);
+        assert_eq!(effective_priority(i32::MAX, 0, u128::MAX), i64::MAX);
+    }
 }
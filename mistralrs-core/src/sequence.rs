@@ -1,4 +1,6 @@
+use either::Either;
 use std::{
+    collections::HashMap,
     fmt::Display,
     sync::{Arc, RwLock},
     time::{SystemTime, UNIX_EPOCH},
@@ -9,13 +11,21 @@ use tokio::sync::{
 };
 
 use crate::{
-    aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx, toktree::TokTrie},
+    aici::{
+        cfg::CfgParser,
+        recognizer::StackRecognizer,
+        rx::RecRx,
+        toktree::{Recognizer, SpecialToken, TokTrie},
+    },
+    beam_search::{normalized_logprob, BeamSearchParams},
+    output_filter::{content_filter_reason, OutputFilter},
     paged_attention::{BlockEngineSequence, LogicalTokenBlock},
     pipeline::DiffusionGenerationParams,
+    reasoning::{ReasoningDelimiters, ReasoningParser},
     response::CompletionChoice,
     tools::ToolCallingMatcher,
-    CompletionChunkChoice, CompletionChunkResponse, CompletionResponse, ImageChoice,
-    ImageGenerationResponse, ImageGenerationResponseFormat,
+    CompletionChunkChoice, CompletionChunkResponse, CompletionResponse, EmbeddingChoice,
+    EmbeddingResponse, ImageChoice, ImageGenerationResponse, ImageGenerationResponseFormat,
 };
 use crate::{
     get_mut_group,
@@ -25,6 +35,8 @@ use crate::{
     ChatCompletionResponse, Usage,
 };
 use candle_core::Tensor;
+use rand::SeedableRng;
+use rand_isaac::Isaac64Rng;
 use regex_automata::util::primitives::StateID;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -33,22 +45,40 @@ pub enum StopReason {
     StopTok(u32),
     Length(usize),
     ModelLength(usize),
+    /// The completion reached `max_chars` characters, and is cut at `completion_bytes_pos`.
+    CharLength {
+        max_chars: usize,
+        completion_bytes_pos: usize,
+    },
     StopString {
         stop_string_idx: usize,
         completion_bytes_pos: usize,
     },
     Canceled,
+    /// The output constraint allows nothing more, as once a `guided_choice` choice is generated.
+    ConstraintComplete,
+    /// The output filter tripped, and the completion is cut at `completion_bytes_pos`.
+    ContentFilter {
+        completion_bytes_pos: usize,
+    },
     GeneratedImage,
+    GeneratedEmbedding,
 }
 
 impl Display for StopReason {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             StopReason::Eos => write!(f, "stop"),
-            StopReason::Length(_) | StopReason::ModelLength(_) => write!(f, "length"),
-            StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
+            StopReason::Length(_) | StopReason::ModelLength(_) | StopReason::CharLength { .. } => {
+                write!(f, "length")
+            }
+            StopReason::StopTok(_)
+            | StopReason::StopString { .. }
+            | StopReason::ConstraintComplete => write!(f, "stop"),
+            StopReason::ContentFilter { .. } => write!(f, "content_filter"),
             StopReason::Canceled => write!(f, "canceled"),
             StopReason::GeneratedImage => write!(f, "generated-image"),
+            StopReason::GeneratedEmbedding => write!(f, "generated-embedding"),
         }
     }
 }
@@ -151,24 +181,51 @@ impl SequenceCustomMetadata {
 pub enum SeqStepType {
     PromptAndDecode,
     OneShot,
+    /// Run the prompt once and return the embedding instead of sampling.
+    Embedding,
+}
+
+/// What [`Sequence::beam_state`] copies from one beam to another.
+pub(crate) struct BeamState {
+    tokens: Vec<u32>,
+    logprobs: Vec<Logprobs>,
+    cumulative_logprob: f32,
+    last_logprob: f32,
+    last_completion_bytes_len: usize,
+    completion_bytes: Vec<u8>,
+    cache: LayerCaches,
+    xlora_cache: Option<LayerCaches>,
+    scaling_cache: Option<Tensor>,
 }
 
 pub struct Sequence {
     // Metadata, const
     id: usize,
+    request_id: usize,
     prompt_len: usize,
     max_len: Option<usize>,
+    max_chars: Option<usize>,
     timestamp: u128,
     sampler: Arc<Sampler>,
     stop_tokens: Vec<u32>,
     stop_strings: Vec<String>,
+    include_stop_str_in_output: bool,
     return_logprobs: bool,
+    // The number of top logprobs for each prompt position, if prompt logprobs were requested
+    prompt_top_logprobs: Option<usize>,
+    // The logprobs of the prompt tokens after the first, recorded by the prompt step
+    prompt_logprobs: Vec<Logprobs>,
+    return_raw_tokens: bool,
     responder: Sender<Response>,
     response_index: usize,
     creation_time: u64,
     prompt: String,
     sequence_stepping_type: SeqStepType,
 
+    // Reproducible sampling
+    seed: Option<u64>,
+    rng: Option<Arc<std::sync::Mutex<Isaac64Rng>>>,
+
     // Image generation
     image_gen_response_format: Option<ImageGenerationResponseFormat>,
     diffusion_params: Option<DiffusionGenerationParams>,
@@ -185,6 +242,14 @@ pub struct Sequence {
 
     // Prefix caching
     prefill_prompt_toks: Option<Vec<u32>>,
+    prefix_cache_len: usize,
+    // Whether the next prompt step runs a chunk of the prompt before its end
+    partial_prefill: bool,
+
+    // Other choices for the same prompt, forked from this one after its prompt step
+    forks: Vec<Sequence>,
+    // Continued together with the other beams of its prompt instead of sampled
+    beam_search: Option<BeamSearchParams>,
 
     // Adapter dynamic config
     adapters: Option<Vec<String>>,
@@ -202,10 +267,15 @@ pub struct Sequence {
     last_logprob: f32,
     last_completion_bytes_len: usize,
     last_is_done: Option<StopReason>,
+    cancel_requested: bool,
     completion_bytes: Vec<u8>,
     stream_idx: usize,
+    stream_logprobs_idx: usize,
+    reasoning: Option<ReasoningParser>,
+    output_filter: Option<Arc<dyn OutputFilter>>,
     pub recognizer: SequenceRecognizer,
     scheduling_urgency: usize, // The number of passes since scheduling
+    priority: i32,
     input_images: Option<Vec<image::DynamicImage>>,
 
     // GPU things
@@ -255,6 +325,7 @@ impl Sequence {
         tokens: Vec<u32>,
         prompt: String,
         id: usize,
+        request_id: usize,
         timestamp: u128,
         layers: usize,
         responder: Sender<Response>,
@@ -298,6 +369,7 @@ impl Sequence {
             logprobs: Vec::new(),
             prompt_len,
             id,
+            request_id,
             timestamp,
             state: RwLock::new(SequenceState::Waiting),
             cache: vec![None; layers],
@@ -311,6 +383,10 @@ impl Sequence {
             sampler: sampler.into(),
             stop_tokens,
             stop_strings,
+            include_stop_str_in_output: false,
+            prompt_top_logprobs: None,
+            prompt_logprobs: Vec::new(),
+            return_raw_tokens: false,
             max_len,
             return_logprobs,
             prompt_tok_per_sec: 0.,
@@ -321,16 +397,26 @@ impl Sequence {
             creation_time,
             recognizer,
             prefill_prompt_toks: None,
+            prefix_cache_len: 0,
+            partial_prefill: false,
+            forks: Vec::new(),
+            beam_search: None,
             suffix,
             prefix,
             cumulative_logprob: 0.,
             completion_bytes: Vec::new(),
             stream_idx: 0,
+            stream_logprobs_idx: 0,
+            reasoning: None,
+            output_filter: None,
             last_completion_bytes_len: 0,
             last_logprob: 0.0,
             last_is_done: None,
+            cancel_requested: false,
             is_tmp: false,
             scheduling_urgency: 0,
+            priority: 0,
+            max_chars: None,
             adapters,
             input_images,
             custom_metadata,
@@ -339,9 +425,71 @@ impl Sequence {
             image_gen_response_format,
             sequence_stepping_type,
             diffusion_params,
+            seed: None,
+            rng: None,
         }
     }
 
+    /// Keep a matched stop string at the end of the output, instead of cutting it.
+    pub fn with_include_stop_str_in_output(mut self, include_stop_str_in_output: bool) -> Self {
+        self.include_stop_str_in_output = include_stop_str_in_output;
+        self
+    }
+
+    /// Record the logprobs of the prompt tokens, with `top_n` top logprobs at each position.
+    pub fn with_prompt_logprobs(mut self, top_n: Option<usize>) -> Self {
+        self.prompt_top_logprobs = top_n;
+        self
+    }
+
+    /// Schedule this sequence before waiting sequences of a lower priority. Unset is 0.
+    pub fn with_priority(mut self, priority: Option<i32>) -> Self {
+        self.priority = priority.unwrap_or(0);
+        self
+    }
+
+    /// Stop once the completion has `max_chars` characters.
+    pub fn with_max_chars(mut self, max_chars: Option<usize>) -> Self {
+        self.max_chars = max_chars;
+        self
+    }
+
+    /// Stream the text inside `delimiters` as the reasoning instead of the content.
+    pub fn with_reasoning_delimiters(mut self, delimiters: Option<&ReasoningDelimiters>) -> Self {
+        self.reasoning = delimiters.cloned().map(ReasoningParser::new);
+        self
+    }
+
+    /// Stop once the completion trips `filter`.
+    pub fn with_output_filter(mut self, filter: Arc<dyn OutputFilter>) -> Self {
+        self.output_filter = Some(filter);
+        self
+    }
+
+    /// Decode this sequence as one of the beams of its prompt, which are its other choices.
+    pub fn with_beam_search(mut self, beam_search: Option<BeamSearchParams>) -> Self {
+        self.beam_search = beam_search;
+        self
+    }
+
+    /// Return the ids of the generated tokens alongside the text.
+    pub fn with_return_raw_tokens(mut self, return_raw_tokens: bool) -> Self {
+        self.return_raw_tokens = return_raw_tokens;
+        self
+    }
+
+    /// Sample this sequence with its own RNG seeded from `seed`, so that concurrent requests
+    /// do not affect its output. Choices of the same request get distinct seeds.
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self.rng = seed.map(|seed| {
+            Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(
+                choice_seed(seed, self.response_index),
+            )))
+        });
+        self
+    }
+
     pub fn add_urgency(mut self) -> Self {
         self.scheduling_urgency += 1;
         self
@@ -368,11 +516,124 @@ impl Sequence {
     ) -> Self {
         self.cache = cache;
         self.xlora_cache = xlora_cache;
+        self.prefix_cache_len = self.tokens.len() - toks.len();
         self.prefill_prompt_toks = Some(toks);
         self.set_state(SequenceState::RunningPrefillPrompt);
         self
     }
 
+    /// The number of prompt tokens restored from the prefix cache, which the prompt step skips.
+    pub fn prefix_cache_len(&self) -> usize {
+        self.prefix_cache_len
+    }
+
+    /// Limit the next prompt step to the next `chunk_size` prompt tokens. Like those restored from
+    /// the prefix cache, the tokens before them are in the KV cache of the sequence. Prompts with
+    /// images or prompt logprobs, and those of other than text generation, run all at once.
+    pub fn set_prefill_chunk(&mut self, chunk_size: usize) {
+        if self.input_images.is_some()
+            || self.prompt_top_logprobs.is_some()
+            || !matches!(self.sequence_stepping_type, SeqStepType::PromptAndDecode)
+        {
+            return;
+        }
+        let end = self.prefix_cache_len + chunk_size;
+        self.partial_prefill = end < self.tokens.len();
+        if self.partial_prefill || self.prefix_cache_len > 0 {
+            let end = end.min(self.tokens.len());
+            self.prefill_prompt_toks = Some(self.tokens[self.prefix_cache_len..end].to_vec());
+        }
+    }
+
+    /// Whether the prompt step runs a chunk before the end of the prompt, which samples nothing.
+    pub fn is_partial_prefill(&self) -> bool {
+        self.partial_prefill
+    }
+
+    /// Count the chunk run by the last prompt step as cached, so that the next one follows it.
+    pub fn finish_prefill_chunk(&mut self) {
+        self.prefix_cache_len += self.prefill_prompt_toks.take().map_or(0, |toks| toks.len());
+        self.partial_prefill = false;
+    }
+
+    /// Attach the other choices for the same prompt. Rather than processing the prompt themselves,
+    /// they copy this sequence's KV cache after its prompt step and sample their first token from
+    /// the same logits.
+    pub fn set_forks(&mut self, forks: Vec<Sequence>) {
+        self.forks = forks;
+    }
+
+    pub fn has_forks(&self) -> bool {
+        !self.forks.is_empty()
+    }
+
+    /// Take the attached choices. Those which were forked are running, any others still need
+    /// their prompt processed.
+    pub fn take_forks(&mut self) -> Vec<Sequence> {
+        std::mem::take(&mut self.forks)
+    }
+
+    /// Continue from the prompt step of `leader`, which processed the same prompt.
+    pub fn fork_from(&mut self, leader: &mut Sequence) {
+        self.cache = leader.cache.clone();
+        self.xlora_cache = leader.xlora_cache.clone();
+        self.scaling_cache = leader.scaling_cache.clone();
+        self.prompt_tok_per_sec = leader.prompt_tok_per_sec;
+        self.prompt_logprobs = leader.prompt_logprobs.clone();
+        self.prompt_timestamp = Some(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time travel has occurred!")
+                .as_millis(),
+        );
+        self.set_state(SequenceState::RunningCompletion);
+    }
+
+    pub fn beam_search(&self) -> Option<BeamSearchParams> {
+        self.beam_search
+    }
+
+    /// The total logprob of the generated tokens.
+    pub fn cumulative_logprob(&self) -> f32 {
+        self.cumulative_logprob
+    }
+
+    /// The score of the choice made of this sequence, by which the best choices of a prompt are
+    /// returned: the mean token logprob, or for a beam its length normalized score.
+    pub fn choice_score(&self) -> f32 {
+        let n_generated = self.tokens.len().saturating_sub(self.prompt_len);
+        let length_penalty = self.beam_search.map_or(1., |params| params.length_penalty);
+        normalized_logprob(self.cumulative_logprob, n_generated, length_penalty)
+    }
+
+    /// The generated tokens and the KV cache of this beam, for another beam to continue it.
+    pub(crate) fn beam_state(&self) -> BeamState {
+        BeamState {
+            tokens: self.tokens.clone(),
+            logprobs: self.logprobs.clone(),
+            cumulative_logprob: self.cumulative_logprob,
+            last_logprob: self.last_logprob,
+            last_completion_bytes_len: self.last_completion_bytes_len,
+            completion_bytes: self.completion_bytes.clone(),
+            cache: self.cache.clone(),
+            xlora_cache: self.xlora_cache.clone(),
+            scaling_cache: self.scaling_cache.clone(),
+        }
+    }
+
+    /// Continue the beam whose state is `state` instead of this one.
+    pub(crate) fn set_beam_state(&mut self, state: BeamState) {
+        self.tokens = state.tokens;
+        self.logprobs = state.logprobs;
+        self.cumulative_logprob = state.cumulative_logprob;
+        self.last_logprob = state.last_logprob;
+        self.last_completion_bytes_len = state.last_completion_bytes_len;
+        self.completion_bytes = state.completion_bytes;
+        self.cache = state.cache;
+        self.xlora_cache = state.xlora_cache;
+        self.scaling_cache = state.scaling_cache;
+    }
+
     /// This is the number of tokens. If the KV cache is Some, then it will use that.
     pub fn len(&self) -> usize {
         if let Some(toks) = &self.prefill_prompt_toks {
@@ -400,6 +661,11 @@ impl Sequence {
         &self.id
     }
 
+    /// The ID of the [`crate::NormalRequest`] which created this sequence.
+    pub fn request_id(&self) -> usize {
+        self.request_id
+    }
+
     pub fn is_running(&self) -> bool {
         matches!(
             *self.state.read().unwrap(),
@@ -525,6 +791,10 @@ impl Sequence {
         completion_bytes: Vec<u8>,
         is_done: &Option<StopReason>,
     ) {
+        if !keeps_sampled_token(is_done) {
+            self.last_is_done = *is_done;
+            return;
+        }
         let stopped_by_token = matches!(
             is_done,
             Some(StopReason::Eos) | Some(StopReason::StopTok(_))
@@ -546,6 +816,7 @@ impl Sequence {
         self.tokens.push(tok.token);
         self.logprobs.push(tok);
         self.prefill_prompt_toks = None;
+        self.prefix_cache_len = 0;
     }
 
     pub fn responder(&self) -> Sender<Response> {
@@ -556,6 +827,12 @@ impl Sequence {
         self.creation_time
     }
 
+    /// Finish this sequence as canceled at its next step, which sends its final response. The
+    /// token sampled at that step is dropped.
+    pub fn request_cancel(&mut self) {
+        self.cancel_requested = true;
+    }
+
     pub fn set_state(&self, state: SequenceState) {
         if matches!(state, SequenceState::Error) {
             get_mut_group!(self).n_choices -= 1;
@@ -567,9 +844,12 @@ impl Sequence {
         *self.state.read().unwrap()
     }
 
+    /// `tok_bytes` are the decoded bytes of `tok`, which has not been added yet, so that a stop
+    /// string completed by this token is detected right away.
     pub fn is_done(
         &self,
         tok: u32,
+        tok_bytes: &[u8],
         eos_tok: Option<&[u32]>,
         max_model_len: usize,
     ) -> Option<StopReason> {
@@ -579,36 +859,87 @@ impl Sequence {
         };
         if is_eos {
             Some(StopReason::Eos)
-        } else if matches!(
-            &*self.state.read().unwrap(),
-            SequenceState::Done(StopReason::Canceled)
-        ) {
+        } else if self.cancel_requested
+            || matches!(
+                &*self.state.read().unwrap(),
+                SequenceState::Done(StopReason::Canceled)
+            )
+        {
             Some(StopReason::Canceled)
         } else if self.stop_tokens.contains(&tok) {
             Some(StopReason::StopTok(tok))
-        } else if self.max_len.is_some()
-            && self.tokens.len().saturating_sub(self.prompt_len) == self.max_len.unwrap()
-        {
-            // add_token was already called
-            Some(StopReason::Length(self.max_len.unwrap()))
-        } else if self.tokens.len().saturating_sub(self.prompt_len) == max_model_len {
-            Some(StopReason::ModelLength(max_model_len))
         } else {
-            if !self.stop_strings.is_empty() {
-                for (idx, s) in self.stop_strings.iter().enumerate() {
-                    if let Some(pos) = galil_seiferas::gs_find(&self.completion_bytes, s.as_bytes())
-                    {
-                        return Some(StopReason::StopString {
-                            stop_string_idx: idx,
-                            completion_bytes_pos: pos,
-                        });
-                    }
+            // A stop string or the character limit take precedence over the length, as they cut
+            // the output, and the one cutting it first wins. `tok` has not been added yet, but
+            // counts towards the length.
+            let n_generated = (self.tokens.len() + 1).saturating_sub(self.prompt_len);
+            [
+                self.completed_stop_string(tok_bytes),
+                self.reached_max_chars(tok_bytes),
+                self.tripped_output_filter(tok_bytes),
+            ]
+            .into_iter()
+            .flatten()
+            .min_by_key(|reason| match reason {
+                StopReason::StopString {
+                    completion_bytes_pos,
+                    ..
                 }
-            }
-            None
+                | StopReason::CharLength {
+                    completion_bytes_pos,
+                    ..
+                }
+                | StopReason::ContentFilter {
+                    completion_bytes_pos,
+                } => *completion_bytes_pos,
+                _ => usize::MAX,
+            })
+            .or_else(|| length_stop_reason(n_generated, self.max_len, max_model_len))
         }
     }
 
+    /// Whether the output constraint is complete, so that the sequence can stop without sampling
+    /// an EOS token. Only regex constraints, such as `guided_choice`, are checked, as trying every
+    /// byte against a grammar after each token would be too slow.
+    pub fn constraint_complete(&mut self) -> bool {
+        match &mut self.recognizer {
+            SequenceRecognizer::Regex(rx) => recognizer_complete(rx.as_mut()),
+            SequenceRecognizer::Cfg(_) | SequenceRecognizer::None => false,
+        }
+    }
+
+    /// Whether appending `tok_bytes` to the completion brings it to `max_chars` characters.
+    fn reached_max_chars(&self, tok_bytes: &[u8]) -> Option<StopReason> {
+        let max_chars = self.max_chars?;
+        let completion_bytes = [self.completion_bytes.as_slice(), tok_bytes].concat();
+        char_length_reason(&completion_bytes, max_chars)
+    }
+
+    /// The stop string or token id which ended generation for `reason`, if any.
+    pub fn matched_stop(&self, reason: &StopReason) -> Option<Either<u32, String>> {
+        matched_stop(reason, &self.stop_strings, self.tokens.last().copied())
+    }
+
+    /// Whether appending `tok_bytes` to the completion trips the output filter.
+    fn tripped_output_filter(&self, tok_bytes: &[u8]) -> Option<StopReason> {
+        let filter = self.output_filter.as_deref()?;
+        let completion_bytes = [self.completion_bytes.as_slice(), tok_bytes].concat();
+        content_filter_reason(&completion_bytes, filter)
+    }
+
+    /// The stop string completed by appending `tok_bytes` to the completion, if any.
+    fn completed_stop_string(&self, tok_bytes: &[u8]) -> Option<StopReason> {
+        if self.stop_strings.is_empty() {
+            return None;
+        }
+        let completion_bytes = [self.completion_bytes.as_slice(), tok_bytes].concat();
+        stop_string_reason(
+            &completion_bytes,
+            &self.stop_strings,
+            self.include_stop_str_in_output,
+        )
+    }
+
     pub fn logprobs(&self) -> &[Logprobs] {
         &self.logprobs
     }
@@ -617,10 +948,35 @@ impl Sequence {
         self.return_logprobs
     }
 
+    pub fn return_raw_tokens(&self) -> bool {
+        self.return_raw_tokens
+    }
+
+    /// The ids of the generated tokens making up the completion text, which leaves out the EOS or
+    /// stop token it stopped at.
+    pub fn completion_toks(&self) -> Vec<u32> {
+        text_toks(&self.logprobs, &self.last_is_done)
+    }
+
     pub fn prompt_tokens(&self) -> usize {
         self.prompt_len
     }
 
+    /// The number of top logprobs to record for each prompt position, if prompt logprobs were
+    /// requested.
+    pub fn prompt_top_logprobs(&self) -> Option<usize> {
+        self.prompt_top_logprobs
+    }
+
+    /// The logprobs of the prompt tokens after the first recorded so far.
+    pub fn prompt_logprobs(&self) -> &[Logprobs] {
+        &self.prompt_logprobs
+    }
+
+    pub fn add_prompt_logprobs(&mut self, logprobs: Vec<Logprobs>) {
+        self.prompt_logprobs.extend(logprobs);
+    }
+
     pub fn stop_strings(&self) -> &[String] {
         &self.stop_strings
     }
@@ -630,26 +986,85 @@ impl Sequence {
         &mut self,
     ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
         let is_first = self.stream_idx == 0;
-        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..]);
+        // Never stream the stop string, or any bytes that might turn out to be the start of one.
+        let end = match self.last_is_done {
+            Some(StopReason::StopString {
+                completion_bytes_pos,
+                ..
+            })
+            | Some(StopReason::CharLength {
+                completion_bytes_pos,
+                ..
+            })
+            | Some(StopReason::ContentFilter {
+                completion_bytes_pos,
+            }) => completion_bytes_pos,
+            Some(_) => self.completion_bytes.len(),
+            None => streamable_len(&self.completion_bytes, &self.stop_strings),
+        }
+        .max(self.stream_idx);
+        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..end]);
         // Check if the sequence ends with valid utf8, if not skip it as it probably is a multi token sequence
-        if new_decoded.ends_with('�') {
+        if self.last_is_done.is_none() && (end == self.stream_idx || new_decoded.ends_with('�')) {
             return Ok(None);
         }
-        self.stream_idx = self.completion_bytes.len();
+        self.stream_idx = end;
 
         // The first token usually starts with a space. We don't want to add that to the delta.
         // Since we're using the completion_bytes, we need to take care of that ourselves.
         // Had we used HF's Tokenizer, it would have taken care of that for us.
-        if is_first {
-            return Ok(Some(new_decoded.trim_start().to_string()));
+        let mut delta = if is_first {
+            // An echoed prompt leads the first delta, as it leads the whole completion.
+            format!(
+                "{}{}",
+                self.prefix.as_deref().unwrap_or(""),
+                new_decoded.trim_start()
+            )
+        } else {
+            new_decoded.to_string()
+        };
+        if self.last_is_done.is_some() {
+            delta.push_str(self.suffix.as_deref().unwrap_or(""));
+        }
+        Ok(Some(delta))
+    }
+
+    /// The number of generated tokens not yet covered by a streaming chunk.
+    pub fn unstreamed_toks(&self) -> usize {
+        self.logprobs.len() - self.stream_logprobs_idx
+    }
+
+    /// Returns the logprobs of the tokens generated since the last call, i.e. those covered by the
+    /// latest delta.
+    pub fn get_delta_logprobs(&mut self) -> &[Logprobs] {
+        let start = self.stream_logprobs_idx;
+        self.stream_logprobs_idx = self.logprobs.len();
+        &self.logprobs[start..]
+    }
+
+    /// Split a streamed `delta` into its reasoning, if the sequence has reasoning delimiters, and
+    /// its content. Text held back in case it starts a delimiter is flushed once `is_done`.
+    pub fn split_reasoning(&mut self, delta: &str, is_done: bool) -> (Option<String>, String) {
+        let Some(parser) = &mut self.reasoning else {
+            return (None, delta.to_string());
+        };
+        let (mut reasoning, mut content) = parser.push(delta);
+        if is_done {
+            let (rest_reasoning, rest_content) = parser.finish();
+            reasoning.push_str(&rest_reasoning);
+            content.push_str(&rest_content);
         }
-        Ok(Some(new_decoded.to_string()))
+        ((!reasoning.is_empty()).then_some(reasoning), content)
     }
 
     pub fn timestamp(&self) -> u128 {
         self.timestamp
     }
 
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
     pub fn prompt_timestamp(&self) -> Option<u128> {
         self.prompt_timestamp
     }
@@ -663,6 +1078,14 @@ impl Sequence {
         if let Some(ts) = self.prompt_timestamp {
             get_mut_group!(self).total_completion_time += now - ts;
             get_mut_group!(self).total_prompt_time += ts - self.timestamp;
+            // The prompt step samples the first token.
+            let time_to_first_token = ts - self.timestamp;
+            let mut group = get_mut_group!(self);
+            group.time_to_first_token = Some(
+                group
+                    .time_to_first_token
+                    .map_or(time_to_first_token, |t| t.min(time_to_first_token)),
+            );
         }
 
         get_mut_group!(self).total_time += now - self.timestamp;
@@ -676,6 +1099,11 @@ impl Sequence {
         self.update_time_info();
     }
 
+    pub fn add_embedding_choice_to_group(&self, choice: EmbeddingChoice) {
+        get_mut_group!(self).embedding_choices.push(choice);
+        self.update_time_info();
+    }
+
     pub fn add_choice_to_group(&self, choice: Choice) {
         get_mut_group!(self).choices.push(choice);
         self.update_time_info();
@@ -688,12 +1116,23 @@ impl Sequence {
             choice.text,
             self.suffix.as_deref().unwrap_or("")
         );
+        let score = self.choice_score();
         get_mut_group!(self)
             .completion_choices
-            .push((self.cumulative_logprob, choice));
+            .push((score, choice));
         self.update_time_info();
     }
 
+    /// The seed given in the request, if any.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// The per-sequence RNG, if this sequence is seeded.
+    pub fn rng(&self) -> Option<Arc<std::sync::Mutex<Isaac64Rng>>> {
+        self.rng.clone()
+    }
+
     pub fn get_response_index(&self) -> usize {
         self.response_index
     }
@@ -703,11 +1142,28 @@ impl Sequence {
     }
 
     pub fn add_streaming_chunk_choice_to_group(&self, chunk: ChunkChoice) {
+        let is_done = chunk.finish_reason.is_some();
         get_mut_group!(self).chat_streaming_chunks.push(chunk);
+        self.record_running_toks();
+        if is_done {
+            self.update_time_info();
+        }
     }
 
     pub fn add_streaming_completion_chunk_choice_to_group(&self, chunk: CompletionChunkChoice) {
+        let is_done = chunk.finish_reason.is_some();
         get_mut_group!(self).completion_streaming_chunks.push(chunk);
+        self.record_running_toks();
+        if is_done {
+            self.update_time_info();
+        }
+    }
+
+    fn record_running_toks(&self) {
+        let toks = (self.prompt_len, self.len());
+        get_mut_group!(self)
+            .running_toks
+            .insert(self.response_index, toks);
     }
 
     pub fn get_adapters(&self) -> Option<Vec<String>> {
@@ -735,28 +1191,163 @@ impl Sequence {
     }
 }
 
+/// The seed of choice `response_index` of a request seeded with `seed`. Both are mixed, rather
+/// than added, so that choice 1 of seed `s` does not get the same RNG stream as choice 0 of seed
+/// `s + 1`.
+fn choice_seed(seed: u64, response_index: usize) -> u64 {
+    splitmix64(splitmix64(seed).wrapping_add(response_index as u64))
+}
+
+/// The SplitMix64 finalizer, which spreads each bit of `x` over the whole output.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Find the first stop string in `completion_bytes`, returning its index and the position at which
+/// the output should be truncated.
+fn find_stop_string(completion_bytes: &[u8], stop_strings: &[String]) -> Option<(usize, usize)> {
+    stop_strings
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, s)| {
+            galil_seiferas::gs_find(completion_bytes, s.as_bytes()).map(|pos| (idx, pos))
+        })
+        .min_by_key(|(_, pos)| *pos)
+}
+
+/// The stop reason for the first stop string in `completion_bytes`, if any. The output is cut
+/// before the stop string, or after it with `include_stop_str`, dropping any text after it.
+fn stop_string_reason(
+    completion_bytes: &[u8],
+    stop_strings: &[String],
+    include_stop_str: bool,
+) -> Option<StopReason> {
+    find_stop_string(completion_bytes, stop_strings).map(|(stop_string_idx, pos)| {
+        StopReason::StopString {
+            stop_string_idx,
+            completion_bytes_pos: if include_stop_str {
+                pos + stop_strings[stop_string_idx].len()
+            } else {
+                pos
+            },
+        }
+    })
+}
+
+/// The stop string or token id which ended generation for `reason`, where an EOS token is the
+/// last token, `last_tok`.
+fn matched_stop(
+    reason: &StopReason,
+    stop_strings: &[String],
+    last_tok: Option<u32>,
+) -> Option<Either<u32, String>> {
+    match reason {
+        StopReason::Eos => last_tok.map(Either::Left),
+        StopReason::StopTok(tok) => Some(Either::Left(*tok)),
+        StopReason::StopString {
+            stop_string_idx, ..
+        } => Some(Either::Right(stop_strings[*stop_string_idx].clone())),
+        _ => None,
+    }
+}
+
+/// Whether `recognizer` accepts the end of the sequence, and no byte after it.
+fn recognizer_complete(recognizer: &mut impl Recognizer) -> bool {
+    recognizer.special_allowed(SpecialToken::EndOfSentence)
+        && !(0..=u8::MAX).any(|byte| recognizer.byte_allowed(byte))
+}
+
+/// The stop reason for a completion of at least `max_chars` characters, cut after the last of
+/// them. As the output is trimmed, leading whitespace does not count, and neither does a
+/// trailing partial UTF-8 character.
+fn char_length_reason(completion_bytes: &[u8], max_chars: usize) -> Option<StopReason> {
+    let text = match std::str::from_utf8(completion_bytes) {
+        Ok(text) => text,
+        Err(e) => std::str::from_utf8(&completion_bytes[..e.valid_up_to()]).ok()?,
+    };
+    let trimmed = text.trim_start();
+    let start = text.len() - trimmed.len();
+    if trimmed.chars().count() < max_chars {
+        return None;
+    }
+    let end = trimmed
+        .char_indices()
+        .nth(max_chars)
+        .map_or(trimmed.len(), |(pos, _)| pos);
+    Some(StopReason::CharLength {
+        max_chars,
+        completion_bytes_pos: start + end,
+    })
+}
+
+/// The number of leading bytes of `completion_bytes` which can be streamed without risking a stop
+/// string being sent. A trailing partial match of some stop string is held back until the next
+/// tokens either complete it or rule it out.
+fn streamable_len(completion_bytes: &[u8], stop_strings: &[String]) -> usize {
+    if let Some((_, pos)) = find_stop_string(completion_bytes, stop_strings) {
+        return pos;
+    }
+    stop_strings
+        .iter()
+        .filter_map(|s| {
+            (1..s.len())
+                .rev()
+                .find(|k| completion_bytes.ends_with(&s.as_bytes()[..*k]))
+        })
+        .max()
+        .map_or(completion_bytes.len(), |held_back| {
+            completion_bytes.len() - held_back
+        })
+}
+
 pub struct SequenceGroup {
-    n_choices: usize, // The target number of choices to return. Can be decreased if an error is thrown.
-    best_of: usize,   // Top n seqs based on cumulative logprobs.
+    n_choices: usize, // The target number of sequences to finish. Can be decreased if an error is thrown.
+    n_per_prompt: usize, // Completion choices returned per prompt, the best of its candidates.
+    choices_per_prompt: usize, // Candidates generated per prompt.
     pub total_prompt_toks: usize,
     pub total_toks: usize,
     pub total_prompt_time: u128,
     pub total_time: u128,
     pub total_completion_time: u128,
+    /// Time from receiving the request to the first token of any of its sequences, in ms.
+    pub time_to_first_token: Option<u128>,
     choices: Vec<Choice>,
     image_choices: Vec<ImageChoice>,
+    embedding_choices: Vec<EmbeddingChoice>,
     completion_choices: Vec<(f32, CompletionChoice)>,
     pub chat_streaming_chunks: Vec<ChunkChoice>,
     pub completion_streaming_chunks: Vec<CompletionChunkChoice>,
+    finished_streaming_choices: usize, // Choices whose final streaming chunk has been sent.
+    // The prompt and total tokens of each choice as of its latest streaming chunk, by index.
+    running_toks: HashMap<usize, (usize, usize)>,
     pub is_streaming: bool,
     pub is_chat: bool,
+    /// Attach the usage so far to every streaming chunk, not only to the one ending the stream.
+    pub continuous_usage_stats: bool,
+    /// The number of tokens each streaming chunk batches, if not the default.
+    pub stream_granularity: Option<usize>,
+    /// The `system_fingerprint` of the model generating the responses.
+    pub system_fingerprint: String,
 }
 
 impl SequenceGroup {
-    pub fn new(n_choices: usize, is_streaming: bool, is_chat: bool, best_of: usize) -> Self {
+    /// `n_choices` sequences over all prompts, of which the best `n_per_prompt` of each prompt are
+    /// returned for completions.
+    pub fn new(
+        n_choices: usize,
+        n_prompts: usize,
+        is_streaming: bool,
+        is_chat: bool,
+        n_per_prompt: usize,
+    ) -> Self {
         Self {
+            choices_per_prompt: n_choices / n_prompts.max(1),
             choices: Vec::new(),
             image_choices: Vec::new(),
+            embedding_choices: Vec::new(),
             completion_choices: Vec::new(),
             n_choices,
             total_prompt_toks: 0,
@@ -764,11 +1355,17 @@ impl SequenceGroup {
             total_prompt_time: 0,
             total_time: 0,
             total_completion_time: 0,
+            time_to_first_token: None,
             chat_streaming_chunks: Vec::new(),
             completion_streaming_chunks: Vec::new(),
+            finished_streaming_choices: 0,
+            running_toks: HashMap::new(),
             is_streaming,
             is_chat,
-            best_of,
+            continuous_usage_stats: false,
+            stream_granularity: None,
+            system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+            n_per_prompt,
         }
     }
 
@@ -777,15 +1374,30 @@ impl SequenceGroup {
         &self.choices
     }
 
-    /// This applies the best_of.
+    /// This applies the best_of to the choices of each prompt: its candidates with the highest mean
+    /// token logprob are kept, and numbered in that order.
     pub fn get_completion_choices(&self) -> Vec<CompletionChoice> {
         let mut choices = self.completion_choices.clone();
-        // Sort by descending logprobs
-        choices.sort_by(|a, b| b.0.partial_cmp(&a.0).expect("No ordering."));
+        // Group by prompt, then sort by descending logprobs
+        let prompt_of = |choice: &CompletionChoice| choice.index / self.choices_per_prompt.max(1);
+        choices.sort_by(|a, b| {
+            prompt_of(&a.1)
+                .cmp(&prompt_of(&b.1))
+                .then(b.0.total_cmp(&a.0))
+        });
+        let mut taken = HashMap::new();
         choices
             .into_iter()
-            .take(self.best_of)
-            .map(|(_, x)| x)
+            .filter_map(|(_, mut x)| {
+                let prompt = prompt_of(&x);
+                let n = taken.entry(prompt).or_insert(0);
+                if *n >= self.n_per_prompt {
+                    return None;
+                }
+                x.index = prompt * self.n_per_prompt + *n;
+                *n += 1;
+                Some(x)
+            })
             .collect::<Vec<_>>()
     }
 
@@ -793,6 +1405,13 @@ impl SequenceGroup {
         &self.image_choices
     }
 
+    /// The embeddings, ordered by input index.
+    pub fn get_embedding_choices(&self) -> Vec<EmbeddingChoice> {
+        let mut choices = self.embedding_choices.clone();
+        choices.sort_by_key(|choice| choice.index);
+        choices
+    }
+
     pub fn get_usage(&self) -> Usage {
         #[allow(clippy::cast_precision_loss)]
         Usage {
@@ -808,9 +1427,36 @@ impl SequenceGroup {
             total_time_sec: self.total_time as f32 / 1000.,
             total_completion_time_sec: self.total_completion_time as f32 / 1000.,
             total_prompt_time_sec: self.total_prompt_time as f32 / 1000.,
+            time_to_first_token_sec: self.time_to_first_token.unwrap_or(0) as f32 / 1000.,
         }
     }
 
+    /// The token counts of the choices as of their latest streaming chunks. Only the counts are
+    /// running: the times and rates are 0.
+    pub fn get_running_usage(&self) -> Usage {
+        let (prompt_tokens, total_tokens) = self
+            .running_toks
+            .values()
+            .fold((0, 0), |(prompt, total), (p, t)| (prompt + p, total + t));
+        Usage {
+            completion_tokens: total_tokens - prompt_tokens,
+            prompt_tokens,
+            total_tokens,
+            avg_tok_per_sec: 0.,
+            avg_prompt_tok_per_sec: 0.,
+            avg_compl_tok_per_sec: 0.,
+            total_time_sec: 0.,
+            total_prompt_time_sec: 0.,
+            total_completion_time_sec: 0.,
+            time_to_first_token_sec: 0.,
+        }
+    }
+
+    /// The usage so far to attach to a streaming chunk which does not end the stream.
+    fn running_usage(&self, usage: &Option<Usage>) -> Option<Usage> {
+        (usage.is_none() && self.continuous_usage_stats).then(|| self.get_running_usage())
+    }
+
     pub async fn maybe_send_chat_done_response(
         &self,
         response: ChatCompletionResponse,
@@ -835,48 +1481,123 @@ impl SequenceGroup {
         Ok(())
     }
 
+    pub async fn maybe_send_embedding_response(
+        &self,
+        response: EmbeddingResponse,
+        sender: Sender<Response>,
+    ) -> Result<(), SendError<Response>> {
+        if self.embedding_choices.len() == self.n_choices {
+            sender.send(Response::Embeddings(response)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Take the chunks of `chunks` to send, if they are due, and whether they end the stream.
+    /// Chunks are due once every running choice has one, or as soon as a choice finishes, so that
+    /// the final chunk of a choice is not held back by choices which are still running.
+    fn take_streaming_chunks<T>(
+        chunks: &mut Vec<T>,
+        is_final: impl Fn(&T) -> bool,
+        finished_choices: &mut usize,
+        n_choices: usize,
+    ) -> Option<(Vec<T>, bool)> {
+        let running = n_choices.saturating_sub(*finished_choices);
+        if chunks.is_empty() || (chunks.len() < running && !chunks.iter().any(&is_final)) {
+            return None;
+        }
+        let chunks = std::mem::take(chunks);
+        *finished_choices += chunks.iter().filter(|chunk| is_final(chunk)).count();
+        Some((chunks, *finished_choices >= n_choices))
+    }
+
+    /// The chat streaming chunks to send, if they are due, with the usage if they end the stream.
+    fn take_chat_streaming_chunks(&mut self) -> Option<(Vec<ChunkChoice>, Option<Usage>)> {
+        let (chunks, is_last) = Self::take_streaming_chunks(
+            &mut self.chat_streaming_chunks,
+            |chunk| chunk.finish_reason.is_some(),
+            &mut self.finished_streaming_choices,
+            self.n_choices,
+        )?;
+        Some((chunks, is_last.then(|| self.get_usage())))
+    }
+
+    /// Like [`Self::take_chat_streaming_chunks`], for completion streaming chunks.
+    fn take_completion_streaming_chunks(
+        &mut self,
+    ) -> Option<(Vec<CompletionChunkChoice>, Option<Usage>)> {
+        let (chunks, is_last) = Self::take_streaming_chunks(
+            &mut self.completion_streaming_chunks,
+            |chunk| chunk.finish_reason.is_some(),
+            &mut self.finished_streaming_choices,
+            self.n_choices,
+        )?;
+        Some((chunks, is_last.then(|| self.get_usage())))
+    }
+
+    /// Send the streaming chunks, if they are due. Only the chunk ending the stream has a usage,
+    /// and a chunk may hold just finished choices while others are still running. With
+    /// `continuous_usage_stats`, the other chunks have the usage so far as their `running_usage`.
     pub async fn maybe_send_streaming_response(
         &mut self,
         seq: &Sequence,
         model: String,
     ) -> Result<(), Box<SendError<Response>>> {
-        if self.chat_streaming_chunks.len() == self.n_choices && self.is_streaming {
-            let mut swap_streaming_chunks = vec![];
-
-            std::mem::swap(&mut swap_streaming_chunks, &mut self.chat_streaming_chunks);
-
+        if !self.is_streaming {
+            return Ok(());
+        }
+        if let Some((swap_streaming_chunks, usage)) = self.take_chat_streaming_chunks() {
+            let running_usage = self.running_usage(&usage);
             seq.responder()
                 .send(Response::Chunk(ChatCompletionChunkResponse {
-                    id: seq.id.to_string(),
+                    id: seq.request_id.to_string(),
                     choices: swap_streaming_chunks,
                     created: seq.timestamp,
                     model: model.clone(),
-                    system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                    system_fingerprint: self.system_fingerprint.clone(),
                     object: "chat.completion.chunk".to_string(),
+                    usage,
+                    running_usage,
                 }))
                 .await?;
-        } else if self.completion_streaming_chunks.len() == self.n_choices && self.is_streaming {
-            let mut swap_streaming_chunks = vec![];
-
-            std::mem::swap(
-                &mut swap_streaming_chunks,
-                &mut self.completion_streaming_chunks,
-            );
-
+        } else if let Some((swap_streaming_chunks, usage)) = self.take_completion_streaming_chunks()
+        {
+            let running_usage = self.running_usage(&usage);
             seq.responder()
                 .send(Response::CompletionChunk(CompletionChunkResponse {
-                    id: seq.id.to_string(),
+                    id: seq.request_id.to_string(),
                     choices: swap_streaming_chunks,
                     created: seq.timestamp,
                     model: model.clone(),
-                    system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
-                    object: "text_completion".to_string(),
+                    system_fingerprint: self.system_fingerprint.clone(),
+                    object: "text_completion.chunk".to_string(),
+                    usage,
+                    running_usage,
                 }))
                 .await?;
         }
         Ok(())
     }
 
+    /// The scores of the finished completion choices of the `prompt_index`-th prompt.
+    pub fn completion_scores(&self, prompt_index: usize) -> Vec<f32> {
+        self.completion_choices
+            .iter()
+            .filter(|(_, choice)| choice.index / self.choices_per_prompt.max(1) == prompt_index)
+            .map(|(score, _)| *score)
+            .collect()
+    }
+
+    /// The number of completion choices returned for each prompt.
+    pub fn n_per_prompt(&self) -> usize {
+        self.n_per_prompt
+    }
+
+    /// Stop waiting for a choice which was given up on, like a beam which was pruned.
+    pub fn drop_choice(&mut self) {
+        self.n_choices -= 1;
+    }
+
     pub async fn maybe_send_completion_done_response(
         &self,
         response: CompletionResponse,
@@ -888,3 +1609,399 @@ impl SequenceGroup {
         Ok(())
     }
 }
+
+/// Whether a sequence which has generated `n_generated` tokens has reached its `max_len`, or else
+/// the maximum length of the model.
+fn length_stop_reason(
+    n_generated: usize,
+    max_len: Option<usize>,
+    max_model_len: usize,
+) -> Option<StopReason> {
+    match max_len {
+        Some(max_len) if n_generated >= max_len => Some(StopReason::Length(max_len)),
+        _ if n_generated >= max_model_len => Some(StopReason::ModelLength(max_model_len)),
+        _ => None,
+    }
+}
+
+/// The ids of `logprobs`, the last of which was sampled with `is_done`, leaving out an EOS or stop
+/// token as it has no text.
+pub(crate) fn text_toks(logprobs: &[Logprobs], is_done: &Option<StopReason>) -> Vec<u32> {
+    let mut toks = logprobs
+        .iter()
+        .map(|logprob| logprob.token)
+        .collect::<Vec<_>>();
+    if matches!(
+        is_done,
+        Some(StopReason::Eos) | Some(StopReason::StopTok(_))
+    ) {
+        toks.pop();
+    }
+    toks
+}
+
+/// Whether the token sampled as a sequence stops for `is_done` is kept. With a `max_len` of 0 the
+/// token sampled after the prefill is dropped, so the completion is empty and an echoed prompt is
+/// returned on its own. The token sampled after a cancel is dropped too, as it was not asked for.
+fn keeps_sampled_token(is_done: &Option<StopReason>) -> bool {
+    !matches!(
+        is_done,
+        Some(StopReason::Length(0)) | Some(StopReason::Canceled)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        aici::{bytes::TokRxInfo, recognizer::StackRecognizer, rx::RecRx, toktree::TokTrie},
+        ChunkChoice, CompletionChoice, Delta, Logprobs,
+    };
+    use either::Either;
+
+    use super::{
+        char_length_reason, choice_seed, find_stop_string, keeps_sampled_token, length_stop_reason,
+        matched_stop, recognizer_complete, stop_string_reason, streamable_len, text_toks,
+        SequenceGroup, StopReason,
+    };
+
+    #[test]
+    fn best_of_returns_highest_scoring_candidate() {
+        // `best_of: 4, n: 1` for each of two prompts.
+        let mut group = SequenceGroup::new(8, 2, false, false, 1);
+        let scores = [-1.5, -0.2, -3.0, -0.9, -0.4, -2.0, -0.1, -5.0];
+        for (index, score) in scores.into_iter().enumerate() {
+            group.completion_choices.push((
+                score,
+                CompletionChoice {
+                    finish_reason: "stop".to_string(),
+                    matched_stop: None,
+                    index,
+                    text: format!("candidate {index}"),
+                    logprobs: None,
+                    prompt_logprobs: None,
+                    raw_tokens: None,
+                },
+            ));
+        }
+
+        let choices = group.get_completion_choices();
+        let best = choices
+            .iter()
+            .map(|choice| (choice.index, choice.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(best, [(0, "candidate 1"), (1, "candidate 6")]);
+    }
+
+    #[test]
+    fn stop_string_split_across_tokens() {
+        let stop_strings = vec!["lo w".to_string()];
+        let mut completion_bytes = Vec::new();
+
+        completion_bytes.extend_from_slice(b"Hel");
+        assert_eq!(find_stop_string(&completion_bytes, &stop_strings), None);
+        // The trailing `l` might start the stop string, so it is held back from streaming.
+        assert_eq!(streamable_len(&completion_bytes, &stop_strings), 2);
+
+        completion_bytes.extend_from_slice(b"lo wor");
+        assert_eq!(
+            find_stop_string(&completion_bytes, &stop_strings),
+            Some((0, 3))
+        );
+        assert_eq!(streamable_len(&completion_bytes, &stop_strings), 3);
+        assert_eq!(&completion_bytes[..3], b"Hel");
+    }
+
+    #[test]
+    fn earliest_stop_string_wins() {
+        let stop_strings = vec!["world".to_string(), "o".to_string()];
+        assert_eq!(
+            find_stop_string(b"Hello world", &stop_strings),
+            Some((1, 4))
+        );
+        assert_eq!(streamable_len(b"no match", &stop_strings), 1);
+        assert_eq!(streamable_len(b"plain", &[]), 5);
+    }
+
+    #[test]
+    fn stop_string_kept_with_include_stop_str_in_output() {
+        // The stop string is completed by a token which also carries text after it.
+        let stop_strings = vec!["</answer>".to_string()];
+        let completion_bytes = b"42</ans";
+        assert_eq!(
+            stop_string_reason(completion_bytes, &stop_strings, false),
+            None
+        );
+        let completion_bytes = b"42</answer>\n";
+
+        let Some(StopReason::StopString {
+            completion_bytes_pos,
+            ..
+        }) = stop_string_reason(completion_bytes, &stop_strings, false)
+        else {
+            panic!("Expected a stop string.");
+        };
+        assert_eq!(&completion_bytes[..completion_bytes_pos], b"42");
+
+        let Some(StopReason::StopString {
+            completion_bytes_pos,
+            ..
+        }) = stop_string_reason(completion_bytes, &stop_strings, true)
+        else {
+            panic!("Expected a stop string.");
+        };
+        assert_eq!(&completion_bytes[..completion_bytes_pos], b"42</answer>");
+    }
+
+    #[test]
+    fn matched_stop_string_is_reported() {
+        let stop_strings = vec!["Observation:".to_string(), "\n\n".to_string()];
+        let reason = stop_string_reason(b"Action: search\n\nObservation:", &stop_strings, false);
+        assert_eq!(
+            matched_stop(&reason.unwrap(), &stop_strings, Some(7)),
+            Some(Either::Right("\n\n".to_string()))
+        );
+
+        assert_eq!(
+            matched_stop(&StopReason::Eos, &stop_strings, Some(2)),
+            Some(Either::Left(2))
+        );
+        assert_eq!(
+            matched_stop(&StopReason::StopTok(128009), &stop_strings, Some(128009)),
+            Some(Either::Left(128009))
+        );
+        assert_eq!(
+            matched_stop(&StopReason::Length(16), &stop_strings, Some(7)),
+            None
+        );
+    }
+
+    #[test]
+    fn stops_at_max_chars_before_max_tokens() {
+        // With a `max_len` of 16, the third token reaches 8 characters first, and is cut inside.
+        let tokens: [&[u8]; 4] = [" Héllo".as_bytes(), b" w", "ör".as_bytes(), b"ld"];
+        let mut completion_bytes = Vec::new();
+        let mut is_done = None;
+        for (n_generated, tok_bytes) in (1..).zip(tokens) {
+            completion_bytes.extend_from_slice(tok_bytes);
+            is_done = char_length_reason(&completion_bytes, 8)
+                .or_else(|| length_stop_reason(n_generated, Some(16), 4096));
+            if is_done.is_some() {
+                break;
+            }
+        }
+        let Some(StopReason::CharLength {
+            completion_bytes_pos,
+            ..
+        }) = is_done
+        else {
+            panic!("Expected the character limit.");
+        };
+        let text = std::str::from_utf8(&completion_bytes[..completion_bytes_pos]).unwrap();
+        assert_eq!(text.trim_start(), "Héllo wö");
+        assert_eq!(is_done.unwrap().to_string(), "length");
+
+        // A partial UTF-8 character does not count yet.
+        assert_eq!(char_length_reason(&"abé".as_bytes()[..3], 3), None);
+    }
+
+    #[test]
+    fn stops_at_max_tokens() {
+        // The `max_len`th token is the last one generated.
+        assert_eq!(length_stop_reason(15, Some(16), 4096), None);
+        assert_eq!(
+            length_stop_reason(16, Some(16), 4096),
+            Some(StopReason::Length(16))
+        );
+        assert_eq!(StopReason::Length(16).to_string(), "length");
+
+        assert_eq!(length_stop_reason(4095, None, 4096), None);
+        assert_eq!(
+            length_stop_reason(4096, None, 4096),
+            Some(StopReason::ModelLength(4096))
+        );
+        assert_eq!(StopReason::ModelLength(4096).to_string(), "length");
+    }
+
+    #[test]
+    fn max_tokens_zero_echoes_only_the_prompt() {
+        // `echo: true, max_tokens: 0`: the token sampled after the prefill already reaches the
+        // length, and is dropped so that only the prompt is returned.
+        let is_done = length_stop_reason(1, Some(0), 4096);
+        assert_eq!(is_done, Some(StopReason::Length(0)));
+        assert!(!keeps_sampled_token(&is_done));
+
+        assert!(keeps_sampled_token(&length_stop_reason(1, Some(1), 4096)));
+        assert!(keeps_sampled_token(&None));
+    }
+
+    #[test]
+    fn token_sampled_after_a_cancel_is_dropped() {
+        assert!(!keeps_sampled_token(&Some(StopReason::Canceled)));
+        assert!(keeps_sampled_token(&Some(StopReason::Eos)));
+    }
+
+    #[test]
+    fn choice_completes_without_eos() {
+        let words = ["positive", "neg", "ative", "yes", " please", "</s>"];
+        let trie = TokTrie::from(
+            &TokRxInfo {
+                vocab_size: 6,
+                tok_eos: 5,
+            },
+            &words.map(|word| word.as_bytes().to_vec()),
+        );
+        let complete_after = |rx: &str, toks: &[u32]| {
+            let mut recognizer = StackRecognizer::from(RecRx::from_rx(rx, None).unwrap());
+            toks.iter()
+                .map(|tok| {
+                    trie.append_token(&mut recognizer, *tok).unwrap();
+                    recognizer_complete(&mut recognizer)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // A classification is done in the step sampling its only token, without sampling an EOS.
+        let rx = "(?:positive|negative)";
+        assert_eq!(complete_after(rx, &[0]), [true]);
+        assert_eq!(complete_after(rx, &[1, 2]), [false, true]);
+        // A choice which other choices extend may still go on.
+        assert_eq!(complete_after("(?:yes|yes please)", &[3, 4]), [false, true]);
+    }
+
+    #[test]
+    fn raw_tokens_detokenize_to_the_text() {
+        let words = ["Hello", " wor", "ld", "!", "</s>"].map(|word| word.as_bytes().to_vec());
+        let eos = 4;
+        let trie = TokTrie::from(
+            &TokRxInfo {
+                vocab_size: 5,
+                tok_eos: eos,
+            },
+            &words,
+        );
+        let sampled = [0, 1, 2, 3, eos].map(|token| Logprobs {
+            token,
+            logprob: 0.,
+            bytes: None,
+            top_logprobs: None,
+        });
+        // Like `add_token`, the text has the bytes of every token but the EOS.
+        let text = trie.decode_str(&[0, 1, 2, 3]);
+        assert_eq!(text, "Hello world!");
+
+        let raw_tokens = text_toks(&sampled, &Some(StopReason::Eos));
+        assert_eq!(trie.decode_str(&raw_tokens), text);
+
+        // Streamed, the tokens of each chunk follow on from the previous one.
+        let mut streamed = text_toks(&sampled[..2], &None);
+        streamed.extend(text_toks(&sampled[2..], &Some(StopReason::Eos)));
+        assert_eq!(streamed, raw_tokens);
+
+        // Stopping at the length limit keeps the last token.
+        let raw_tokens = text_toks(&sampled[..4], &Some(StopReason::Length(4)));
+        assert_eq!(trie.decode_str(&raw_tokens), text);
+    }
+
+    #[test]
+    fn choices_which_finish_early_flush_their_final_chunk() {
+        // `n: 2`, where the first choice reaches its `max_tokens` after 2 tokens and the second
+        // after 4. Each step adds a chunk for every running choice.
+        let mut group = SequenceGroup::new(2, 1, true, true, 2);
+        let chunk = |index: usize, step: usize, max_tokens: usize| ChunkChoice {
+            finish_reason: (step == max_tokens).then(|| "length".to_string()),
+            matched_stop: None,
+            index,
+            delta: Delta {
+                content: format!("{step} "),
+                role: "assistant".to_string(),
+                reasoning_content: None,
+            },
+            logprobs: None,
+            raw_tokens: None,
+        };
+
+        let mut sent = Vec::new();
+        for step in 1..=4 {
+            for (index, max_tokens) in [(0, 2), (1, 4)] {
+                if step > max_tokens {
+                    continue;
+                }
+                group
+                    .chat_streaming_chunks
+                    .push(chunk(index, step, max_tokens));
+                if let Some((chunks, usage)) = group.take_chat_streaming_chunks() {
+                    let chunks = chunks
+                        .into_iter()
+                        .map(|c| (c.index, c.finish_reason))
+                        .collect::<Vec<_>>();
+                    sent.push((step, chunks, usage.is_some()));
+                }
+            }
+        }
+
+        let length = || Some("length".to_string());
+        assert_eq!(
+            sent,
+            [
+                (1, vec![(0, None), (1, None)], false),
+                // The final chunk of the first choice is sent as soon as it finishes.
+                (2, vec![(0, length())], false),
+                (2, vec![(1, None)], false),
+                (3, vec![(1, None)], false),
+                // The stream only ends with the last choice.
+                (4, vec![(1, length())], true),
+            ]
+        );
+    }
+
+    #[test]
+    fn continuous_usage_stats_count_up_on_every_chunk() {
+        // As above, with a prompt of 3 tokens for each choice.
+        let mut group = SequenceGroup::new(2, 1, true, true, 2);
+        group.continuous_usage_stats = true;
+        let chunk = |index: usize, step: usize, max_tokens: usize| ChunkChoice {
+            finish_reason: (step == max_tokens).then(|| "length".to_string()),
+            matched_stop: None,
+            index,
+            delta: Delta {
+                content: format!("{step} "),
+                role: "assistant".to_string(),
+                reasoning_content: None,
+            },
+            logprobs: None,
+            raw_tokens: None,
+        };
+
+        let mut completion_tokens = Vec::new();
+        for step in 1..=4 {
+            for (index, max_tokens) in [(0, 2), (1, 4)] {
+                if step > max_tokens {
+                    continue;
+                }
+                group
+                    .chat_streaming_chunks
+                    .push(chunk(index, step, max_tokens));
+                group.running_toks.insert(index, (3, 3 + step));
+                if let Some((_, usage)) = group.take_chat_streaming_chunks() {
+                    match group.running_usage(&usage) {
+                        Some(running) => {
+                            assert_eq!(running.prompt_tokens, 6);
+                            completion_tokens.push(running.completion_tokens);
+                        }
+                        // The chunk ending the stream has the final usage instead.
+                        None => assert!(usage.is_some()),
+                    }
+                }
+            }
+        }
+        assert_eq!(completion_tokens, [2, 3, 4, 5]);
+        assert!(completion_tokens.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn choice_seeds_differ_from_the_next_request_seed() {
+        assert_ne!(choice_seed(7, 1), choice_seed(8, 0));
+        assert_ne!(choice_seed(7, 0), choice_seed(7, 1));
+        assert_eq!(choice_seed(7, 1), choice_seed(7, 1));
+    }
+}
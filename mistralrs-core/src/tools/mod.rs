@@ -7,6 +7,14 @@ use serde_json::Value;
 use std::collections::HashMap;
 use uuid::Uuid;
 
+/// Regex which forces the output to be a call of `tool`, in a format understood by [`ToolCallingMatcher`].
+pub(crate) fn forced_tool_call_regex(tool: &Tool) -> String {
+    format!(
+        r#"\{{\s*"name"\s*:\s*"{}"\s*,\s*"parameters"\s*:\s*\{{(?s:.*)\}}\s*\}}"#,
+        regex::escape(&tool.function.name)
+    )
+}
+
 pub struct ToolCallingMatcher {
     tool_choice: ToolChoice,
 }
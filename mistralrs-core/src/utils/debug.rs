@@ -22,7 +22,15 @@ pub fn initialize_logging() {
                 LevelFilter::INFO.into()
             })
             .from_env_lossy();
-        tracing_subscriber::fmt().with_env_filter(filter).init();
+        let json = std::env::var("MISTRALRS_LOG_FORMAT").is_ok_and(|format| format == "json");
+        if json {
+            tracing_subscriber::fmt()
+                .json()
+                .with_env_filter(filter)
+                .init();
+        } else {
+            tracing_subscriber::fmt().with_env_filter(filter).init();
+        }
     });
 }
 
@@ -93,7 +93,6 @@ macro_rules! handle_pipeline_forward_error {
                 };
                 use $crate::response::Response;
                 use $crate::sequence::SequenceState;
-                use $crate::response::SYSTEM_FINGERPRINT;
                 use tracing::error;
                 error!("{} - Model failed with error: {:?}", $stage, &e);
                 for seq in $seq_slice.iter_mut() {
@@ -110,6 +109,7 @@ macro_rules! handle_pipeline_forward_error {
                     if seq.get_mut_group().is_chat {
                         let choice = Choice {
                             finish_reason: "error".to_string(),
+                            matched_stop: None,
                             index: seq.get_response_index(),
                             message: ResponseMessage {
                                 content: Some(res),
@@ -117,14 +117,18 @@ macro_rules! handle_pipeline_forward_error {
                                 tool_calls: Vec::new(),
                             },
                             logprobs: None,
+                            raw_tokens: None,
                         };
                         seq.add_choice_to_group(choice);
                     } else {
                         let choice = CompletionChoice {
                             finish_reason: "error".to_string(),
+                            matched_stop: None,
                             index: seq.get_response_index(),
                             text: res,
                             logprobs: None,
+                            prompt_logprobs: None,
+                            raw_tokens: None,
                         };
                         seq.add_completion_choice_to_group(choice);
                     }
@@ -135,13 +139,14 @@ macro_rules! handle_pipeline_forward_error {
 
                     if group.is_chat {
                         let partial_completion_response = ChatCompletionResponse {
-                            id: seq.id().to_string(),
+                            id: seq.request_id().to_string(),
                             choices: group.get_choices().to_vec(),
                             created: seq.creation_time(),
                             model: pipeline_name.clone(),
-                            system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                            system_fingerprint: group.system_fingerprint.clone(),
                             object: "chat.completion".to_string(),
                             usage: group.get_usage(),
+                            seed: seq.seed(),
                         };
 
                         seq.responder()
@@ -153,13 +158,14 @@ macro_rules! handle_pipeline_forward_error {
                             .unwrap();
                     } else {
                         let partial_completion_response = CompletionResponse {
-                            id: seq.id().to_string(),
+                            id: seq.request_id().to_string(),
                             choices: group.get_completion_choices().to_vec(),
                             created: seq.creation_time(),
                             model: pipeline_name.clone(),
-                            system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                            system_fingerprint: group.system_fingerprint.clone(),
                             object: "text_completion".to_string(),
                             usage: group.get_usage(),
+                            seed: seq.seed(),
                         };
 
                         seq.responder()
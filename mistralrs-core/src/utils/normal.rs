@@ -152,15 +152,66 @@ fn determine_auto_dtype_all(devices: &[&Device]) -> candle_core::Result<DType> {
     Ok(DType::F32)
 }
 
+/// Check that an explicitly chosen `dtype` can be computed in on all of `devices`, where the
+/// hardware supports the reduced precision `dev_dtypes`. This catches, for example, BF16 on a GPU
+/// older than compute capability 8.0 before any weights are loaded.
+fn check_dtype_supported(dtype: DType, dev_dtypes: &[DType], devices: &[&Device]) -> Result<()> {
+    if dtype != DType::F32 && !dev_dtypes.contains(&dtype) {
+        let supported = dev_dtypes
+            .iter()
+            .chain([&DType::F32])
+            .map(|dtype| format!("{dtype:?}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        anyhow::bail!(
+            "DType {dtype:?} is not supported by the detected device, which supports {supported}. \
+             Choose one of those, or `auto`."
+        );
+    }
+    for device in devices {
+        let x = Tensor::zeros((2, 2), dtype, device)?;
+        if let Err(e) = x.matmul(&x) {
+            anyhow::bail!(
+                "DType {dtype:?} is not supported on device {:?} ({e}). \
+                 Choose another dtype, or `auto`.",
+                device.location()
+            );
+        }
+    }
+    Ok(())
+}
+
 impl TryIntoDType for ModelDType {
     fn try_into_dtype(&self, devices: &[&Device]) -> Result<DType> {
         let dtype = match self {
-            Self::Auto => Ok(determine_auto_dtype_all(devices).map_err(anyhow::Error::msg)?),
-            Self::BF16 => Ok(DType::BF16),
-            Self::F16 => Ok(DType::F16),
-            Self::F32 => Ok(DType::F32),
+            Self::Auto => determine_auto_dtype_all(devices).map_err(anyhow::Error::msg)?,
+            Self::BF16 => DType::BF16,
+            Self::F16 => DType::F16,
+            Self::F32 => DType::F32,
         };
-        info!("DType selected is {:?}.", dtype.as_ref().unwrap());
-        dtype
+        if *self != Self::Auto {
+            check_dtype_supported(dtype, &get_dtypes(), devices)?;
+        }
+        info!("DType selected is {dtype:?}.");
+        Ok(dtype)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device};
+
+    use super::check_dtype_supported;
+
+    #[test]
+    fn test_unsupported_dtype_is_a_clear_error() {
+        // As on a GPU with compute capability 7.5, which has no BF16.
+        let err = check_dtype_supported(DType::BF16, &[DType::F16], &[&Device::Cpu]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "DType BF16 is not supported by the detected device, which supports F16, F32. \
+             Choose one of those, or `auto`."
+        );
+        assert!(check_dtype_supported(DType::F32, &[], &[&Device::Cpu]).is_ok());
     }
 }
@@ -56,6 +56,7 @@ impl Processor for Idefics2Processor {
         messages: Vec<IndexMap<String, MessageContent>>,
         add_generation_prompt: bool,
         tools: Vec<Tool>,
+        template_override: Option<&str>,
     ) -> anyhow::Result<(Vec<u32>, String)> {
         let mut prompt = apply_chat_template(
             pipeline,
@@ -63,6 +64,7 @@ impl Processor for Idefics2Processor {
             add_generation_prompt,
             self.template_action(),
             tools,
+            template_override,
         )?;
 
         let mut image_str = format!(
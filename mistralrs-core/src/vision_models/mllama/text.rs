@@ -417,8 +417,9 @@ impl MLlamaTextCrossAttention {
 
             (k, v) = Cache::update_kv_cache(kv_cache, k, v, false)?;
             (k, v)
-        } else if let Some((k_cache, v_cache)) = kv_cache {
-            (k_cache.clone(), v_cache.clone())
+        } else if let Some((k, v)) = Cache::read_kv_cache(kv_cache, q.dtype())? {
+            // The cache may be quantized, so it is not attended over as stored.
+            (k, v)
         } else {
             candle_core::bail!("Cross attn cannot find k,v cache or cross attn hidden states!")
         };
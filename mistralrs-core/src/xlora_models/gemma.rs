@@ -893,6 +893,37 @@ impl NormalModel for XLoraModel {
         }
         Ok(sum)
     }
+    fn load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter loading is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.layers.iter_mut() {
+            sum += Arc::get_mut(&mut layer.self_attn.k_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.o_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.q_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.self_attn.v_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.down_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.gate_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.up_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+        }
+        Ok(sum)
+    }
     fn config(&self) -> &ModelConfigMetadata {
         &self.cfg
     }
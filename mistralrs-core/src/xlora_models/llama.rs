@@ -855,6 +855,37 @@ impl NormalModel for XLoraLlama {
         }
         Ok(sum)
     }
+    fn load_adapter(&mut self, name: &str, vb: &VarBuilder, cfg: &LoraConfig) -> Result<usize> {
+        if self.xlora_classifier.is_some() {
+            candle_core::bail!("Adapter loading is not supported for X-LoRA models as the adapter set must remain the same.");
+        }
+        let mut sum = 0;
+        for layer in self.blocks.iter_mut() {
+            sum += Arc::get_mut(&mut layer.attn.k_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.attn.o_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.attn.q_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.attn.v_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+
+            sum += Arc::get_mut(&mut layer.mlp.c_fc1)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_fc2)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+            sum += Arc::get_mut(&mut layer.mlp.c_proj)
+                .unwrap()
+                .load_adapter(name, vb, cfg)?;
+        }
+        Ok(sum)
+    }
     fn config(&self) -> &ModelConfigMetadata {
         &self.cfg
     }
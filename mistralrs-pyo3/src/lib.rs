@@ -653,9 +653,16 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("gbnf".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyApiErr::from(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::Gbnf(request.grammar.as_ref().unwrap().clone())
             } else if request.grammar_type.is_some() {
                 return Err(PyApiErr::from(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc` or `gbnf`",
                 ));
             } else {
                 Constraint::None
@@ -687,6 +694,10 @@ impl Runner {
                                     "role".to_string(),
                                     Either::Left(message["role"].as_ref().left().unwrap().clone()),
                                 );
+                                if let Some(Either::Left(name)) = message.get("name") {
+                                    message_map
+                                        .insert("name".to_string(), Either::Left(name.clone()));
+                                }
                                 message_map.insert(
                                     "content".to_string(),
                                     Either::Left(content.to_string()),
@@ -764,6 +775,10 @@ impl Runner {
                                     "role".to_string(),
                                     Either::Left(message["role"].as_ref().left().unwrap().clone()),
                                 );
+                                if let Some(Either::Left(name)) = message.get("name") {
+                                    message_map
+                                        .insert("name".to_string(), Either::Left(name.clone()));
+                                }
                                 let (content, url) = if items[0] == "text" {
                                     get_content_and_url(0, 1, image_messages)?
                                 } else {
@@ -846,12 +861,30 @@ impl Runner {
                     top_n_logprobs: request.top_logprobs.unwrap_or(1),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    repetition_penalty: None,
+                    repetition_context_size: None,
                     max_len: request.max_tokens,
+                    max_chars: None,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
+                    logits_bias_strs: None,
+                    logits_bias_patterns: None,
                     n_choices: request.n_choices,
                     min_p: request.min_p,
                     dry_params,
+                    seed: None,
+                    mirostat: None,
+                    penalty_alpha: None,
+                    include_stop_str_in_output: false,
+                    prompt_logprobs: None,
+                    return_raw_tokens: false,
+                    continuous_usage_stats: false,
+                    stream_granularity: None,
+                    sampler_order: None,
+                    typical_p: None,
+                    eta_cutoff: None,
+                    epsilon_cutoff: None,
+                    penalize_prompt: None,
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -862,6 +895,9 @@ impl Runner {
                 tool_choice,
                 tools,
                 logits_processors: None,
+                chat_template: None,
+                user: None,
+                priority: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -884,6 +920,7 @@ impl Runner {
                     Response::CompletionModelError(_, _) => unreachable!(),
                     Response::CompletionChunk(_) => unreachable!(),
                     Response::ImageGeneration(_) => unreachable!(),
+                    Response::Embeddings(_) => unreachable!(),
                 }
             }
         })
@@ -915,9 +952,16 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("gbnf".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyApiErr::from(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::Gbnf(request.grammar.as_ref().unwrap().clone())
             } else if request.grammar_type.is_some() {
                 return Err(PyApiErr::from(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc` or `gbnf`",
                 ));
             } else {
                 Constraint::None
@@ -958,9 +1002,10 @@ impl Runner {
                     last_v
                 },
                 messages: RequestMessage::Completion {
-                    text: request.prompt.clone(),
+                    text: vec![request.prompt.clone()],
                     echo_prompt: request.echo_prompt,
                     best_of: request.best_of,
+                    beam_search: None,
                 },
                 sampling_params: SamplingParams {
                     temperature: request.temperature,
@@ -969,12 +1014,30 @@ impl Runner {
                     top_n_logprobs: 1,
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    repetition_penalty: None,
+                    repetition_context_size: None,
                     max_len: request.max_tokens,
+                    max_chars: None,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
+                    logits_bias_strs: None,
+                    logits_bias_patterns: None,
                     n_choices: request.n_choices,
                     min_p: request.min_p,
                     dry_params,
+                    seed: None,
+                    mirostat: None,
+                    penalty_alpha: None,
+                    include_stop_str_in_output: false,
+                    prompt_logprobs: None,
+                    return_raw_tokens: false,
+                    continuous_usage_stats: false,
+                    stream_granularity: None,
+                    sampler_order: None,
+                    typical_p: None,
+                    eta_cutoff: None,
+                    epsilon_cutoff: None,
+                    penalize_prompt: None,
                 },
                 response: tx,
                 return_logprobs: false,
@@ -985,6 +1048,9 @@ impl Runner {
                 tool_choice,
                 tools,
                 logits_processors: None,
+                chat_template: None,
+                user: None,
+                priority: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1003,6 +1069,7 @@ impl Runner {
                 Response::ModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
             }
         })
     }
@@ -1040,6 +1107,9 @@ impl Runner {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            chat_template: None,
+            user: None,
+            priority: None,
         });
 
         let sender = self.runner.get_sender()?;
@@ -1092,6 +1162,7 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<mistralrs_core::ResponseMessage>()?;
     m.add_class::<mistralrs_core::Delta>()?;
     m.add_class::<mistralrs_core::ResponseLogprob>()?;
+    m.add_class::<mistralrs_core::ResponseTopLogprob>()?;
     m.add_class::<mistralrs_core::Logprobs>()?;
     m.add_class::<mistralrs_core::Choice>()?;
     m.add_class::<mistralrs_core::ChunkChoice>()?;
@@ -30,7 +30,7 @@ impl ChatCompletionStreamer {
                 Response::ValidationError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
                 Response::InternalError(e) => Some(Err(PyValueError::new_err(e.to_string()))),
                 Response::Chunk(response) => {
-                    if response.choices.iter().all(|x| x.finish_reason.is_some()) {
+                    if response.usage.is_some() {
                         this.is_done = true;
                     }
                     Some(Ok(response))
@@ -40,6 +40,7 @@ impl ChatCompletionStreamer {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
             },
             None => Some(Err(PyValueError::new_err(
                 "Received none in ChatCompletionStreamer".to_string(),
@@ -0,0 +1,66 @@
+//! One access log line per chat completion request, under the `mistralrs_server::access_log`
+//! target, once its response has been sent or, for a stream, once the stream has ended. With
+//! `MISTRALRS_LOG_FORMAT=json` each line is a JSON object with the fields below.
+
+use axum::http::StatusCode;
+use mistralrs_core::Usage;
+use tokio::time::Instant;
+use tracing::info;
+
+#[derive(Clone, Debug)]
+pub struct AccessLog {
+    path: &'static str,
+    received_at: Instant,
+    request_id: Option<usize>,
+    /// The `X-Request-Id` of the request, given by the client or generated.
+    x_request_id: Option<String>,
+    /// The `user` of the request, if it gave one.
+    user: Option<String>,
+    prompt_tokens: Option<usize>,
+    completion_tokens: Option<usize>,
+}
+
+impl AccessLog {
+    /// The log for a `POST` to `path` with `X-Request-Id` `x_request_id`, received now.
+    pub fn new(path: &'static str, x_request_id: Option<String>) -> Self {
+        Self {
+            path,
+            received_at: Instant::now(),
+            request_id: None,
+            x_request_id,
+            user: None,
+            prompt_tokens: None,
+            completion_tokens: None,
+        }
+    }
+
+    pub fn set_request_id(&mut self, request_id: usize) {
+        self.request_id = Some(request_id);
+    }
+
+    pub fn set_user(&mut self, user: Option<String>) {
+        self.user = user;
+    }
+
+    pub fn set_usage(&mut self, usage: &Usage) {
+        self.prompt_tokens = Some(usage.prompt_tokens);
+        self.completion_tokens = Some(usage.completion_tokens);
+    }
+
+    /// Log the request as completed with `status`.
+    pub fn log(&self, status: StatusCode) {
+        let latency_ms = u64::try_from(self.received_at.elapsed().as_millis()).unwrap_or(u64::MAX);
+        info!(
+            method = "POST",
+            path = self.path,
+            status = status.as_u16(),
+            request_id = self.request_id,
+            x_request_id = self.x_request_id.as_deref(),
+            user = self.user.as_deref(),
+            prompt_tokens = self.prompt_tokens,
+            completion_tokens = self.completion_tokens,
+            latency_ms,
+            "request completed"
+        );
+    }
+}
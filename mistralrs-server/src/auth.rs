@@ -0,0 +1,294 @@
+//! Authentication of the protected routes. The backend is chosen at startup by
+//! `MISTRALRS_AUTH_BACKEND`: `api_key`, the default, accepts static bearer tokens, and `hmac`
+//! verifies requests signed with a shared secret, as sent by a gateway in front of the server.
+
+use std::{
+    collections::HashSet,
+    env,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{Request, State},
+    http::{self, HeaderName, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{error::JsonError, MB_TO_B, N_INPUT_SIZE};
+
+/// The Unix time in seconds at which a signed request was signed.
+const TIMESTAMP_HEADER: HeaderName = HeaderName::from_static("x-mistralrs-timestamp");
+/// The base64 HMAC-SHA256 signature of a signed request.
+const SIGNATURE_HEADER: HeaderName = HeaderName::from_static("x-mistralrs-signature");
+const DEFAULT_MAX_SKEW_SECS: u64 = 300;
+
+/// A way of authenticating requests to the protected routes.
+#[async_trait]
+pub trait AuthVerifier: Send + Sync {
+    /// Pass on `request` if it is authenticated, or reject it with a 401.
+    async fn verify(&self, request: Request) -> Result<Request, Response>;
+
+    /// Whether requests carry an `Authorization: Bearer <key>` identifying the client, which token
+    /// budgets are kept per.
+    fn identifies_clients(&self) -> bool {
+        false
+    }
+}
+
+/// The authentication backend from `MISTRALRS_AUTH_BACKEND`, or `None` if it is disabled: with
+/// `api_key` or unset, when there are no API keys.
+pub fn verifier_from_env(api_keys: ApiKeys) -> anyhow::Result<Option<Arc<dyn AuthVerifier>>> {
+    match env::var("MISTRALRS_AUTH_BACKEND").as_deref() {
+        Err(_) | Ok("api_key") if api_keys.is_enabled() => Ok(Some(Arc::new(api_keys))),
+        Err(_) | Ok("api_key") => Ok(None),
+        Ok("hmac") => Ok(Some(Arc::new(HmacVerifier::from_env()?))),
+        Ok(other) => anyhow::bail!(
+            "Unknown `MISTRALRS_AUTH_BACKEND` `{other}`, expected `api_key` or `hmac`."
+        ),
+    }
+}
+
+/// API keys accepted by the server. Empty means authentication is disabled.
+#[derive(Clone, Default)]
+pub struct ApiKeys(Arc<HashSet<String>>);
+
+impl ApiKeys {
+    /// Keys from the CLI, plus the comma-separated keys in `MISTRALRS_API_KEY`.
+    pub fn new(cli_keys: Vec<String>) -> Self {
+        let env_keys = env::var("MISTRALRS_API_KEY").unwrap_or_default();
+        let keys = cli_keys
+            .into_iter()
+            .chain(env_keys.split(',').map(str::to_string))
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty())
+            .collect();
+        Self(Arc::new(keys))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+/// Reject requests without a `Authorization: Bearer <key>` header carrying one of the API keys.
+#[async_trait]
+impl AuthVerifier for ApiKeys {
+    async fn verify(&self, request: Request) -> Result<Request, Response> {
+        let token = request
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if self.0.contains(token.trim()) => Ok(request),
+            Some(_) => Err(unauthorized("Incorrect API key provided.")),
+            None => Err(unauthorized(
+                "Missing API key. Provide it as `Authorization: Bearer <key>`.",
+            )),
+        }
+    }
+
+    fn identifies_clients(&self) -> bool {
+        true
+    }
+}
+
+/// Verifies requests signed with a secret shared with the client. A request carries the Unix time
+/// it was signed at in `X-Mistralrs-Timestamp`, and in `X-Mistralrs-Signature` the base64
+/// HMAC-SHA256 of the timestamp, the method and the path with the query, each followed by a
+/// newline, and then the body. Requests signed too long ago are rejected, so that a captured
+/// request cannot be replayed later.
+pub struct HmacVerifier {
+    secret: Vec<u8>,
+    max_skew: Duration,
+}
+
+impl HmacVerifier {
+    /// The secret from `MISTRALRS_HMAC_SECRET`, which is required, and the largest difference
+    /// between the signing time and the server's clock from `MISTRALRS_HMAC_MAX_SKEW_SECS`, by
+    /// default 300 seconds.
+    fn from_env() -> anyhow::Result<Self> {
+        let secret = env::var("MISTRALRS_HMAC_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .ok_or_else(|| {
+                anyhow::Error::msg("The `hmac` auth backend requires `MISTRALRS_HMAC_SECRET`.")
+            })?;
+        let max_skew = env::var("MISTRALRS_HMAC_MAX_SKEW_SECS")
+            .ok()
+            .and_then(|val| val.parse().ok())
+            .unwrap_or(DEFAULT_MAX_SKEW_SECS);
+        Ok(Self {
+            secret: secret.into_bytes(),
+            max_skew: Duration::from_secs(max_skew),
+        })
+    }
+
+    fn mac(&self, timestamp: &str, method: &str, path: &str, body: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(format!("{timestamp}\n{method}\n{path}\n").as_bytes());
+        mac.update(body);
+        mac
+    }
+}
+
+#[async_trait]
+impl AuthVerifier for HmacVerifier {
+    async fn verify(&self, request: Request) -> Result<Request, Response> {
+        let header = |name: &HeaderName| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let (Some(timestamp), Some(signature)) =
+            (header(&TIMESTAMP_HEADER), header(&SIGNATURE_HEADER))
+        else {
+            return Err(unauthorized(
+                "Missing request signature. Provide `X-Mistralrs-Timestamp` and `X-Mistralrs-Signature`.",
+            ));
+        };
+        let signed_at = timestamp
+            .parse::<u64>()
+            .map(Duration::from_secs)
+            .map_err(|_| unauthorized("`X-Mistralrs-Timestamp` must be a Unix time in seconds."))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time travel has occurred!");
+        let skew = now
+            .checked_sub(signed_at)
+            .unwrap_or_else(|| signed_at - now);
+        if skew > self.max_skew {
+            return Err(unauthorized(
+                "The request was signed too long ago, or its clock is off.",
+            ));
+        }
+
+        let (parts, body) = request.into_parts();
+        let body = axum::body::to_bytes(body, N_INPUT_SIZE * MB_TO_B)
+            .await
+            .map_err(|e| {
+                JsonError::invalid_request(e.to_string()).to_response(StatusCode::PAYLOAD_TOO_LARGE)
+            })?;
+        let path = parts
+            .uri
+            .path_and_query()
+            .map_or(parts.uri.path(), |path| path.as_str());
+        let signature = STANDARD
+            .decode(signature.trim())
+            .map_err(|_| unauthorized("Incorrect request signature."))?;
+        self.mac(&timestamp, parts.method.as_str(), path, &body)
+            .verify_slice(&signature)
+            .map_err(|_| unauthorized("Incorrect request signature."))?;
+        Ok(Request::from_parts(parts, Body::from(body)))
+    }
+}
+
+/// Reject requests which the configured [`AuthVerifier`] does not authenticate.
+pub async fn require_auth(
+    State(verifier): State<Arc<dyn AuthVerifier>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match verifier.verify(request).await {
+        Ok(request) => next.run(request).await,
+        Err(response) => response,
+    }
+}
+
+fn unauthorized(message: &str) -> Response {
+    JsonError::invalid_request(message.to_string())
+        .with_code("invalid_api_key")
+        .to_response(StatusCode::UNAUTHORIZED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)], body: &'static str) -> Request {
+        let mut request = http::Request::post("/v1/chat/completions?stream=true");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(Body::from(body)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_api_key_verifier() {
+        let keys = ApiKeys(Arc::new(HashSet::from(["secret".to_string()])));
+        let authorized = request(&[("authorization", "Bearer secret")], "");
+        assert!(keys.verify(authorized).await.is_ok());
+
+        let wrong_key = request(&[("authorization", "Bearer wrong")], "");
+        for request in [wrong_key, request(&[], "")] {
+            let response = keys.verify(request).await.unwrap_err();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hmac_verifier() {
+        let verifier = HmacVerifier {
+            secret: b"shared secret".to_vec(),
+            max_skew: Duration::from_secs(DEFAULT_MAX_SKEW_SECS),
+        };
+        let body = r#"{"model": "default"}"#;
+        let sign = |timestamp: u64, body: &str| {
+            let mac = verifier.mac(
+                &timestamp.to_string(),
+                "POST",
+                "/v1/chat/completions?stream=true",
+                body.as_bytes(),
+            );
+            STANDARD.encode(mac.finalize().into_bytes())
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let signed = |timestamp: u64, signature: &str| {
+            let timestamp = timestamp.to_string();
+            request(
+                &[
+                    ("x-mistralrs-timestamp", timestamp.as_str()),
+                    ("x-mistralrs-signature", signature),
+                ],
+                body,
+            )
+        };
+
+        // The body is passed on unchanged.
+        let verified = verifier
+            .verify(signed(now, &sign(now, body)))
+            .await
+            .unwrap();
+        let passed_on = axum::body::to_bytes(verified.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(passed_on, body);
+
+        // A tampered signature, a signature of another body, a stale signature and none at all.
+        let mut tampered = STANDARD.decode(sign(now, body)).unwrap();
+        tampered[0] ^= 1;
+        let stale = now - DEFAULT_MAX_SKEW_SECS - 1;
+        for request in [
+            signed(now, &STANDARD.encode(tampered)),
+            signed(now, &sign(now, r#"{"model": "other"}"#)),
+            signed(stale, &sign(stale, body)),
+            request(&[], body),
+        ] {
+            let response = verifier.verify(request).await.unwrap_err();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+}
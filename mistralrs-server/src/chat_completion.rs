@@ -1,35 +1,55 @@
 use std::{
-    collections::HashMap,
     env,
     error::Error,
-    ops::Deref,
+    future::Future,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{
+        mpsc::{Receiver, Sender},
+        OwnedSemaphorePermit,
+    },
+    time::{Instant, Sleep},
 };
-use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 use crate::{
-    openai::{ChatCompletionRequest, Grammar, MessageInnerContent, StopTokens},
+    access_log::AccessLog,
+    concurrency,
+    error::{JsonBody, JsonError},
+    metrics,
+    openai::{self, ChatCompletionRequest, Grammar, ResponseFormat, WithTimings},
+    rate_limit::TokenReservation,
+    registry::{registry, Registration},
+    request_id::RequestId,
     util,
 };
 use anyhow::{Context as _, Result};
 use axum::{
-    extract::{Json, State},
-    http::{self, StatusCode},
-    response::{
-        sse::{Event, KeepAlive},
-        IntoResponse, Sse,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Extension, Json, State,
     },
+    http,
+    response::{sse::Event, IntoResponse},
 };
 use either::Either;
+use futures::StreamExt;
 use indexmap::IndexMap;
 use mistralrs_core::{
+    json_schema_to_yacc, validate_chat_template, validate_json_schema, ChatCompletionChunkResponse,
     ChatCompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
-    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
+    RequestMessage, Response, SamplerStep, SamplingParams, StopTokens as InternalStopTokens, Usage,
 };
 use serde::Serialize;
+use serde_json::Value;
+use tracing::warn;
+use utoipa::ToSchema;
+
+/// The most `json_schema_retries` a request may ask for.
+const MAX_JSON_SCHEMA_RETRIES: usize = 3;
 
 #[derive(Debug)]
 struct ModelErrorMessage(String);
@@ -40,112 +60,469 @@ impl std::fmt::Display for ModelErrorMessage {
 }
 impl std::error::Error for ModelErrorMessage {}
 
+/// The engine sent nothing for a request within its timeout.
+#[derive(Debug)]
+struct RequestTimeout(Duration);
+impl std::fmt::Display for RequestTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "No response from the model within {} seconds.",
+            self.0.as_secs()
+        )
+    }
+}
+impl std::error::Error for RequestTimeout {}
+
+/// The timeout from the request, or else from `MISTRALRS_REQUEST_TIMEOUT_SECS`. Unset means no timeout.
+fn request_timeout(oairequest: &ChatCompletionRequest) -> Option<Duration> {
+    oairequest
+        .timeout_secs
+        .or_else(|| {
+            env::var("MISTRALRS_REQUEST_TIMEOUT_SECS")
+                .ok()
+                .and_then(|val| val.parse::<u64>().ok())
+        })
+        .map(Duration::from_secs)
+}
+
+fn terminate_request(state: &MistralRs, request_id: usize) {
+    if let Ok(sender) = state.get_sender() {
+        let _ = sender.try_send(Request::TerminateSeq { id: request_id });
+    }
+}
+
+/// Take the usage off the final chunk of a stream, returning the usage-only chunk to send after it
+/// if the request asked for one. With `continuous_usage_stats`, the final chunk keeps its usage.
+fn take_usage_chunk(
+    response: &mut ChatCompletionChunkResponse,
+    include_usage: bool,
+    continuous_usage_stats: bool,
+    return_timings: bool,
+) -> Option<WithTimings<ChatCompletionChunkResponse>> {
+    let usage = if continuous_usage_stats {
+        response.usage.clone()
+    } else {
+        response.usage.take()
+    };
+    if let Some(usage) = &usage {
+        metrics::record_generated_tokens(usage.completion_tokens);
+        metrics::record_request_latency(Duration::from_secs_f32(usage.total_time_sec));
+    }
+    if !include_usage && !return_timings {
+        return None;
+    }
+    let usage_chunk = ChatCompletionChunkResponse {
+        choices: Vec::new(),
+        usage: usage.clone(),
+        running_usage: None,
+        ..response.clone()
+    };
+    Some(WithTimings::new(
+        usage_chunk,
+        usage.as_ref(),
+        return_timings,
+    ))
+}
+
+/// Move the usage so far of a chunk which does not end the stream, sent with
+/// `continuous_usage_stats`, to its `usage`.
+fn attach_running_usage(response: &mut ChatCompletionChunkResponse) {
+    if let Some(running_usage) = response.running_usage.take() {
+        response.usage = Some(running_usage);
+    }
+}
+
+/// Why the output of a choice does not match `schema`, if it does not.
+fn schema_violation(schema: &Value, index: usize, content: &str) -> Option<String> {
+    let result = serde_json::from_str::<Value>(content)
+        .map_err(anyhow::Error::from)
+        .and_then(|value| validate_json_schema(schema, &value));
+    result.err().map(|e| {
+        format!(
+            "The output of choice {index} does not match the `response_format` JSON schema: {e}"
+        )
+    })
+}
+
+/// The `response_format` JSON schema the output of a stream must match, and the content streamed
+/// so far for each choice.
+struct StreamSchemaCheck {
+    schema: Value,
+    contents: Vec<String>,
+}
+
+impl StreamSchemaCheck {
+    fn push(&mut self, response: &ChatCompletionChunkResponse) {
+        for choice in &response.choices {
+            if self.contents.len() <= choice.index {
+                self.contents.resize(choice.index + 1, String::new());
+            }
+            self.contents[choice.index].push_str(&choice.delta.content);
+        }
+    }
+
+    fn violation(&self) -> Option<String> {
+        self.contents
+            .iter()
+            .enumerate()
+            .find_map(|(index, content)| schema_violation(&self.schema, index, content))
+    }
+}
+
+/// Empty chunks sent every `interval` until the first token arrives, so that clients and proxies
+/// which only count data events as activity do not give up during a long prefill.
+struct Heartbeat {
+    interval: Duration,
+    deadline: Pin<Box<Sleep>>,
+    id: String,
+    model: String,
+    system_fingerprint: String,
+    created: u128,
+}
+
+impl Heartbeat {
+    fn new(
+        interval: Duration,
+        request_id: usize,
+        model: String,
+        system_fingerprint: String,
+    ) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time travel has occurred!")
+            .as_millis();
+        Self {
+            interval,
+            deadline: Box::pin(tokio::time::sleep(interval)),
+            id: request_id.to_string(),
+            model,
+            system_fingerprint,
+            created,
+        }
+    }
+
+    /// The next heartbeat chunk, once `interval` has passed since the last one.
+    fn poll_beat(&mut self, cx: &mut Context<'_>) -> Poll<ChatCompletionChunkResponse> {
+        self.deadline.as_mut().poll(cx).map(|()| {
+            self.deadline.as_mut().reset(Instant::now() + self.interval);
+            ChatCompletionChunkResponse {
+                id: self.id.clone(),
+                choices: Vec::new(),
+                created: self.created,
+                model: self.model.clone(),
+                system_fingerprint: self.system_fingerprint.clone(),
+                object: "chat.completion.chunk".to_string(),
+                usage: None,
+                running_usage: None,
+            }
+        })
+    }
+}
+
+/// An item of a chat completion stream, sent as an SSE event or as a websocket message.
+pub enum StreamItem {
+    /// A chunk, as JSON.
+    Chunk(serde_json::Result<String>),
+    /// An error, which ends the stream.
+    Error(JsonError),
+    /// The end of the stream, sent as `data: [DONE]` over SSE and not at all over websockets.
+    Done,
+}
+
+impl StreamItem {
+    fn chunk(chunk: &impl Serialize) -> Self {
+        Self::Chunk(serde_json::to_string(chunk))
+    }
+
+    fn into_sse_event(self) -> Result<Event, axum::Error> {
+        match self {
+            Self::Chunk(json) => json
+                .map(|json| Event::default().data(json))
+                .map_err(axum::Error::new),
+            Self::Error(e) => e.to_sse_event(),
+            Self::Done => Ok(Event::default().data("[DONE]")),
+        }
+    }
+}
+
 pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
+    /// Whether the terminal `[DONE]` event has been sent.
+    done_sent: bool,
     state: Arc<MistralRs>,
+    request_id: usize,
+    include_usage: bool,
+    /// Whether every chunk has the usage so far, and the final one keeps the usage of the request.
+    continuous_usage_stats: bool,
+    /// Whether to attach timings to the usage chunk, which is then sent even without `include_usage`.
+    return_timings: bool,
+    /// Usage-only chunk to send once all choices have finished.
+    usage_chunk: Option<WithTimings<ChatCompletionChunkResponse>>,
+    /// Response received while waiting for the first chunk, sent before polling `rx` again.
+    first_response: Option<Response>,
+    /// Time allowed between two responses, and when the current wait runs out.
+    timeout: Option<(Duration, Pin<Box<Sleep>>)>,
+    /// When the request was received, or else when the last chunk was, for metrics.
+    last_event: (Instant, bool),
+    /// Sends empty chunks until the first token, if `--heartbeat-interval-ms` is set.
+    heartbeat: Option<Heartbeat>,
+    /// Slot under `MISTRALRS_MAX_CONCURRENT`, held for as long as the stream is open.
+    _permit: Option<OwnedSemaphorePermit>,
+    /// Logged once the stream ends.
+    access_log: AccessLog,
+    /// The status of the error which ended the stream, if any, for the access log.
+    status: http::StatusCode,
+    /// Checked before the final chunk is sent, which is replaced by an error if the output does
+    /// not match.
+    schema_check: Option<StreamSchemaCheck>,
+    /// Tokens reserved under `MISTRALRS_TOKENS_PER_MINUTE`, settled once the stream ends.
+    reservation: Option<TokenReservation>,
+    /// Keeps the request in the registry for as long as the stream is open.
+    _registration: Registration,
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) {
+        if !self.is_done {
+            // The client went away before generation finished, so stop decoding for it.
+            terminate_request(&self.state, self.request_id);
+            // Logged as 499 Client Closed Request, as nginx does.
+            self.status = http::StatusCode::from_u16(499).expect("499 is a valid status");
+        }
+        self.access_log.log(self.status);
+    }
 }
 
 impl futures::Stream for Streamer {
-    type Item = Result<Event, axum::Error>;
+    type Item = StreamItem;
 
-    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if self.is_done {
+            if let Some(usage_chunk) = self.usage_chunk.take() {
+                return Poll::Ready(Some(StreamItem::chunk(&usage_chunk)));
+            }
+            if !self.done_sent {
+                self.done_sent = true;
+                return Poll::Ready(Some(StreamItem::Done));
+            }
             return Poll::Ready(None);
         }
-        match self.rx.try_recv() {
-            Ok(resp) => match resp {
-                Response::ModelError(msg, _) => {
+        let received = match self.first_response.take() {
+            Some(resp) => Poll::Ready(Some(resp)),
+            None => self.rx.poll_recv(cx),
+        };
+        if received.is_ready() {
+            // A progressing stream only times out if it stalls again.
+            if let Some((timeout, deadline)) = &mut self.timeout {
+                deadline.as_mut().reset(Instant::now() + *timeout);
+            }
+        }
+        match received {
+            Poll::Ready(Some(resp)) => match resp {
+                Response::ModelError(msg, response) => {
                     MistralRs::maybe_log_error(
                         self.state.clone(),
                         &ModelErrorMessage(msg.to_string()),
                     );
-                    Poll::Ready(Some(Ok(Event::default().data(msg))))
+                    self.is_done = true;
+                    self.status = http::StatusCode::INTERNAL_SERVER_ERROR;
+                    self.access_log.set_usage(&response.usage);
+                    if let Some(reservation) = &self.reservation {
+                        reservation.add_usage(&response.usage);
+                    }
+                    Poll::Ready(Some(StreamItem::Error(JsonError::model_error(
+                        msg, response,
+                    ))))
                 }
                 Response::ValidationError(e) => {
-                    Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
+                    self.is_done = true;
+                    self.status = http::StatusCode::UNPROCESSABLE_ENTITY;
+                    Poll::Ready(Some(StreamItem::Error(JsonError::invalid_request(
+                        e.to_string(),
+                    ))))
                 }
                 Response::InternalError(e) => {
                     MistralRs::maybe_log_error(self.state.clone(), &*e);
-                    Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
+                    self.is_done = true;
+                    self.status = http::StatusCode::INTERNAL_SERVER_ERROR;
+                    Poll::Ready(Some(StreamItem::Error(JsonError::server_error(
+                        e.to_string(),
+                    ))))
                 }
-                Response::Chunk(response) => {
-                    if response.choices.iter().all(|x| x.finish_reason.is_some()) {
+                Response::Chunk(mut response) => {
+                    let (last_event, is_first) = self.last_event;
+                    if is_first {
+                        metrics::record_time_to_first_token(last_event.elapsed());
+                    } else {
+                        metrics::record_inter_token_latency(last_event.elapsed());
+                    }
+                    self.last_event = (Instant::now(), false);
+                    if let Some(check) = &mut self.schema_check {
+                        check.push(&response);
+                    }
+
+                    // Only the chunk ending the stream has a usage, while earlier chunks may
+                    // already hold the final chunks of the choices which finished first.
+                    if response.usage.is_some() {
                         self.is_done = true;
+                        if let Some(usage) = &response.usage {
+                            self.access_log.set_usage(usage);
+                            if let Some(reservation) = &self.reservation {
+                                reservation.add_usage(usage);
+                            }
+                        }
+                        if let Some(violation) = self
+                            .schema_check
+                            .as_ref()
+                            .and_then(StreamSchemaCheck::violation)
+                        {
+                            MistralRs::maybe_log_error(
+                                self.state.clone(),
+                                &ModelErrorMessage(violation.clone()),
+                            );
+                            self.status = http::StatusCode::INTERNAL_SERVER_ERROR;
+                            return Poll::Ready(Some(StreamItem::Error(JsonError::model_error(
+                                violation, response,
+                            ))));
+                        }
+                        self.usage_chunk = take_usage_chunk(
+                            &mut response,
+                            self.include_usage,
+                            self.continuous_usage_stats,
+                            self.return_timings,
+                        );
                     }
+                    attach_running_usage(&mut response);
                     MistralRs::maybe_log_response(self.state.clone(), &response);
-                    Poll::Ready(Some(Event::default().json_data(response)))
+                    Poll::Ready(Some(StreamItem::chunk(&response)))
                 }
                 Response::Done(_) => unreachable!(),
                 Response::CompletionDone(_) => unreachable!(),
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
             },
-            Err(_) => Poll::Pending,
+            Poll::Ready(None) => {
+                // The engine dropped its sender, so nothing more will arrive.
+                self.is_done = true;
+                self.done_sent = true;
+                Poll::Ready(Some(StreamItem::Done))
+            }
+            Poll::Pending => {
+                let waiting_for_first = self.last_event.1;
+                if let Some(heartbeat) = self.heartbeat.as_mut().filter(|_| waiting_for_first) {
+                    if let Poll::Ready(chunk) = heartbeat.poll_beat(cx) {
+                        return Poll::Ready(Some(StreamItem::chunk(&chunk)));
+                    }
+                }
+                let timed_out = match &mut self.timeout {
+                    Some((timeout, deadline)) => deadline
+                        .as_mut()
+                        .poll(cx)
+                        .map(|()| RequestTimeout(*timeout)),
+                    None => Poll::Pending,
+                };
+                timed_out.map(|e| {
+                    MistralRs::maybe_log_error(self.state.clone(), &e);
+                    terminate_request(&self.state, self.request_id);
+                    self.is_done = true;
+                    self.status = http::StatusCode::GATEWAY_TIMEOUT;
+                    Some(StreamItem::Error(
+                        JsonError::server_error(e.to_string()).with_code("timeout"),
+                    ))
+                })
+            }
         }
     }
 }
 
+/// The prompt of a `dry_run` request, as the model would have received it.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DryRunResponse {
+    pub object: &'static str,
+    pub model: String,
+    /// The messages rendered with the chat template.
+    pub prompt: String,
+    pub prompt_tokens: usize,
+}
+
 pub enum ChatCompletionResponder {
-    Sse(Sse<Streamer>),
-    Json(ChatCompletionResponse),
+    Sse(Streamer),
+    Json(WithTimings<ChatCompletionResponse>),
+    DryRun(DryRunResponse),
     ModelError(String, ChatCompletionResponse),
     InternalError(Box<dyn Error>),
     ValidationError(Box<dyn Error>),
+    ModelNotFound(String),
+    Saturated,
 }
 
-trait ErrorToResponse: Serialize {
-    fn to_response(&self, code: StatusCode) -> axum::response::Response {
-        let mut r = Json(self).into_response();
-        *r.status_mut() = code;
-        r
-    }
-}
-
-#[derive(Serialize)]
-struct JsonError {
-    message: String,
-}
-
-impl JsonError {
-    fn new(message: String) -> Self {
-        Self { message }
+impl ChatCompletionResponder {
+    /// The usage of a finished request. A stream reports it once it ends instead.
+    fn usage(&self) -> Option<&Usage> {
+        match self {
+            ChatCompletionResponder::Json(response) => Some(&response.response.usage),
+            ChatCompletionResponder::ModelError(_, response) => Some(&response.usage),
+            _ => None,
+        }
     }
-}
-impl ErrorToResponse for JsonError {}
 
-#[derive(Serialize)]
-struct JsonModelError {
-    message: String,
-    partial_response: ChatCompletionResponse,
-}
-
-impl JsonModelError {
-    fn new(message: String, partial_response: ChatCompletionResponse) -> Self {
-        Self {
-            message,
-            partial_response,
+    /// The error envelope and status of a failed request, or `None` if it succeeded.
+    fn to_error(&self) -> Option<(JsonError, http::StatusCode)> {
+        match self {
+            ChatCompletionResponder::Sse(_)
+            | ChatCompletionResponder::Json(_)
+            | ChatCompletionResponder::DryRun(_) => None,
+            ChatCompletionResponder::InternalError(e) => {
+                let error = JsonError::server_error(e.to_string());
+                Some(if e.is::<RequestTimeout>() {
+                    (
+                        error.with_code("timeout"),
+                        http::StatusCode::GATEWAY_TIMEOUT,
+                    )
+                } else {
+                    (error, http::StatusCode::INTERNAL_SERVER_ERROR)
+                })
+            }
+            ChatCompletionResponder::ValidationError(e) if e.is::<util::PromptTooLong>() => Some((
+                JsonError::invalid_request(e.to_string()).with_code("context_length_exceeded"),
+                http::StatusCode::BAD_REQUEST,
+            )),
+            ChatCompletionResponder::ValidationError(e) => Some((
+                JsonError::invalid_request(e.to_string()),
+                http::StatusCode::UNPROCESSABLE_ENTITY,
+            )),
+            ChatCompletionResponder::ModelError(msg, response) => Some((
+                JsonError::model_error(msg.clone(), response),
+                http::StatusCode::INTERNAL_SERVER_ERROR,
+            )),
+            ChatCompletionResponder::ModelNotFound(model) => Some((
+                JsonError::model_not_found(model),
+                http::StatusCode::NOT_FOUND,
+            )),
+            ChatCompletionResponder::Saturated => Some((
+                concurrency::saturated_error(),
+                http::StatusCode::TOO_MANY_REQUESTS,
+            )),
         }
     }
 }
 
-impl ErrorToResponse for JsonModelError {}
-
 impl IntoResponse for ChatCompletionResponder {
     fn into_response(self) -> axum::response::Response {
         match self {
-            ChatCompletionResponder::Sse(s) => s.into_response(),
-            ChatCompletionResponder::Json(s) => Json(s).into_response(),
-            ChatCompletionResponder::InternalError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            ChatCompletionResponder::Sse(s) => {
+                util::sse(s.map(StreamItem::into_sse_event)).into_response()
             }
-            ChatCompletionResponder::ValidationError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
-            }
-            ChatCompletionResponder::ModelError(msg, response) => {
-                JsonModelError::new(msg, response)
-                    .to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            ChatCompletionResponder::Json(s) => Json(s).into_response(),
+            ChatCompletionResponder::DryRun(s) => Json(s).into_response(),
+            ChatCompletionResponder::Saturated => concurrency::saturated_response(),
+            responder => {
+                let (error, status) = responder.to_error().expect("not an error");
+                error.to_response(status)
             }
         }
     }
@@ -159,102 +536,112 @@ async fn parse_request(
     let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
 
-    let stop_toks = match oairequest.stop_seqs {
-        Some(StopTokens::Multi(m)) => Some(InternalStopTokens::Seqs(m)),
-        Some(StopTokens::Single(s)) => Some(InternalStopTokens::Seqs(vec![s])),
-        None => None,
-    };
+    let stop_toks = oairequest.stop_seqs.map(InternalStopTokens::from);
     let messages = match oairequest.messages {
-        Either::Left(req_messages) => {
+        Either::Left(mut req_messages) => {
+            util::apply_default_system_prompt(&mut req_messages);
             let mut messages = Vec::new();
             let mut image_urls = Vec::new();
+            // Assistant messages which only contain tool calls have no content.
+            let no_content = Either::Left(String::new());
             for message in req_messages {
-                match message.content.deref() {
+                match message.content.as_deref().unwrap_or(&no_content) {
                     Either::Left(content) => {
                         let mut message_map: IndexMap<
                             String,
                             Either<String, Vec<IndexMap<String, String>>>,
                         > = IndexMap::new();
                         message_map.insert("role".to_string(), Either::Left(message.role));
+                        if let Some(name) = message.name {
+                            message_map.insert("name".to_string(), Either::Left(name));
+                        }
                         message_map
                             .insert("content".to_string(), Either::Left(content.to_string()));
+                        if let Some(tool_call_id) = message.tool_call_id {
+                            message_map
+                                .insert("tool_call_id".to_string(), Either::Left(tool_call_id));
+                        }
+                        if let Some(tool_calls) = message.tool_calls {
+                            let tool_calls = tool_calls
+                                .into_iter()
+                                .map(|call| {
+                                    IndexMap::from([
+                                        ("id".to_string(), call.id),
+                                        ("name".to_string(), call.function.name),
+                                        ("arguments".to_string(), call.function.arguments),
+                                    ])
+                                })
+                                .collect();
+                            message_map.insert("tool_calls".to_string(), Either::Right(tool_calls));
+                        }
                         messages.push(message_map);
                     }
-                    Either::Right(image_messages) => {
-                        if image_messages.len() != 2 {
-                            anyhow::bail!(
-                                "Expected 2 items for the content of a message with an image."
-                            );
+                    Either::Right(content_parts) => {
+                        let mut content_map = Vec::new();
+                        let mut text_parts = Vec::new();
+                        let mut message_image_urls = Vec::new();
+                        for part in content_parts {
+                            let Some(Either::Left(part_type)) = part.get("type").map(|x| &**x)
+                            else {
+                                anyhow::bail!("Expected a string `type` in every content part.");
+                            };
+                            match part_type.as_str() {
+                                "text" => {
+                                    let Some(Either::Left(text)) = part.get("text").map(|x| &**x)
+                                    else {
+                                        anyhow::bail!(
+                                            "Expected a string `text` in a `text` content part."
+                                        );
+                                    };
+                                    content_map.push(IndexMap::from([
+                                        ("type".to_string(), "text".to_string()),
+                                        ("text".to_string(), text.clone()),
+                                    ]));
+                                    text_parts.push(text.clone());
+                                }
+                                "image_url" => {
+                                    let url = match part.get("image_url").map(|x| &**x) {
+                                        Some(Either::Right(image_url)) if image_url.contains_key("url") => {
+                                            image_url["url"].clone()
+                                        }
+                                        Some(Either::Left(url)) => url.clone(),
+                                        _ => anyhow::bail!("Expected content of format {{`type`: `image_url`, `image_url`: {{`url`: ...}}}}"),
+                                    };
+                                    content_map.push(IndexMap::from([(
+                                        "type".to_string(),
+                                        "image".to_string(),
+                                    )]));
+                                    message_image_urls.push(url);
+                                }
+                                other => anyhow::bail!(
+                                    "Unsupported content part type `{other}`, expected `text` or `image_url`."
+                                ),
+                            }
                         }
-                        if message.role != "user" {
+                        if !message_image_urls.is_empty() && message.role != "user" {
                             anyhow::bail!(
                                 "Role for an image message must be `user`, but it is {}",
                                 message.role
                             );
                         }
 
-                        let mut items = Vec::new();
-                        for image_message in image_messages {
-                            if image_message.len() != 2 {
-                                anyhow::bail!("Expected 2 items for the sub-content of a message with an image.");
-                            }
-                            if !image_message.contains_key("type") {
-                                anyhow::bail!("Expected `type` key in input message.");
-                            }
-                            if image_message["type"].is_right() {
-                                anyhow::bail!("Expected string value in `type`.");
-                            }
-                            items.push(image_message["type"].as_ref().unwrap_left().clone())
-                        }
-
-                        fn get_content_and_url(
-                            text_idx: usize,
-                            url_idx: usize,
-                            image_messages: &[HashMap<String, MessageInnerContent>],
-                        ) -> Result<(String, String)> {
-                            if image_messages[text_idx]["text"].is_right() {
-                                anyhow::bail!("Expected string value in `text`.");
-                            }
-                            let content = image_messages[text_idx]["text"]
-                                .as_ref()
-                                .unwrap_left()
-                                .clone();
-                            if image_messages[url_idx]["image_url"].is_left()
-                                || !image_messages[url_idx]["image_url"]
-                                    .as_ref()
-                                    .unwrap_right()
-                                    .contains_key("url")
-                            {
-                                anyhow::bail!("Expected content of format {{`type`: `text`, `text`: ...}} and {{`type`: `url`, `image_url`: {{`url`: ...}}}}")
-                            }
-                            let url = image_messages[url_idx]["image_url"].as_ref().unwrap_right()
-                                ["url"]
-                                .clone();
-                            Ok((content, url))
-                        }
                         let mut message_map: IndexMap<
                             String,
                             Either<String, Vec<IndexMap<String, String>>>,
                         > = IndexMap::new();
                         message_map.insert("role".to_string(), Either::Left(message.role));
-                        let (content, url) = if items[0] == "text" {
-                            get_content_and_url(0, 1, image_messages)?
+                        if let Some(name) = message.name {
+                            message_map.insert("name".to_string(), Either::Left(name));
+                        }
+                        if message_image_urls.is_empty() {
+                            // Text-only parts are joined, so that text models' templates see a string.
+                            message_map
+                                .insert("content".to_string(), Either::Left(text_parts.join("\n")));
                         } else {
-                            get_content_and_url(1, 0, image_messages)?
-                        };
-
-                        let mut content_map = Vec::new();
-                        let mut content_image_map = IndexMap::new();
-                        content_image_map.insert("type".to_string(), "image".to_string());
-                        content_map.push(content_image_map);
-                        let mut content_text_map = IndexMap::new();
-                        content_text_map.insert("type".to_string(), "text".to_string());
-                        content_text_map.insert("text".to_string(), content);
-                        content_map.push(content_text_map);
-
-                        message_map.insert("content".to_string(), Either::Right(content_map));
+                            message_map.insert("content".to_string(), Either::Right(content_map));
+                        }
                         messages.push(message_map);
-                        image_urls.push(url);
+                        image_urls.extend(message_image_urls);
                     }
                 }
             }
@@ -296,12 +683,14 @@ async fn parse_request(
         None
     };
 
+    let (logits_bias, logits_bias_strs) = util::split_logit_bias(oairequest.logit_bias);
+
     let is_streaming = oairequest.stream.unwrap_or(false);
     Ok((
         Request::Normal(NormalRequest {
             id: state.next_request_id(),
             messages,
-            sampling_params: SamplingParams {
+            sampling_params: util::with_sampling_defaults(SamplingParams {
                 temperature: oairequest.temperature,
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
@@ -309,30 +698,151 @@ async fn parse_request(
                 top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
-                max_len: oairequest.max_tokens,
+                penalize_prompt: oairequest.penalize_prompt,
+                repetition_penalty: oairequest.repetition_penalty,
+                repetition_context_size: oairequest.repetition_context_size,
+                max_len: Some(util::resolve_max_tokens(oairequest.max_tokens)),
+                max_chars: oairequest.max_chars,
                 stop_toks,
-                logits_bias: oairequest.logit_bias,
+                logits_bias,
+                logits_bias_strs,
+                logits_bias_patterns: oairequest.logit_bias_patterns,
                 n_choices: oairequest.n_choices,
                 dry_params,
-            },
+                seed: oairequest.seed,
+                mirostat: oairequest.mirostat,
+                penalty_alpha: oairequest.penalty_alpha,
+                include_stop_str_in_output: oairequest.include_stop_str_in_output.unwrap_or(false),
+                prompt_logprobs: None,
+                return_raw_tokens: oairequest.return_raw_tokens.unwrap_or(false),
+                continuous_usage_stats: oairequest
+                    .stream_options
+                    .as_ref()
+                    .is_some_and(|options| options.continuous_usage_stats),
+                stream_granularity: oairequest.stream_granularity,
+                sampler_order: oairequest
+                    .sampler_order
+                    .as_deref()
+                    .map(SamplerStep::parse_order)
+                    .transpose()
+                    .map_err(anyhow::Error::msg)?,
+                typical_p: oairequest.typical_p,
+                eta_cutoff: oairequest.eta_cutoff,
+                epsilon_cutoff: oairequest.epsilon_cutoff,
+            }),
             response: tx,
             return_logprobs: oairequest.logprobs,
             is_streaming,
             suffix: None,
-            constraint: match oairequest.grammar {
-                Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
-                Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
-                None => Constraint::None,
+            constraint: match (
+                oairequest.guided_choice,
+                oairequest.grammar,
+                oairequest.response_format,
+            ) {
+                (Some(choices), _, _) => Constraint::Choice(choices),
+                (None, Some(Grammar::Yacc(yacc)), _) => Constraint::Yacc(yacc),
+                (None, Some(Grammar::Regex(regex)), _) => Constraint::Regex(regex),
+                (None, Some(Grammar::Gbnf(gbnf)), _) => Constraint::Gbnf(gbnf),
+                (None, None, Some(ResponseFormat::JsonObject)) => Constraint::Json,
+                (None, None, Some(ResponseFormat::JsonSchema { json_schema })) => {
+                    Constraint::JsonSchema(json_schema.schema)
+                }
+                (None, None, Some(ResponseFormat::Text) | None) => Constraint::None,
             },
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
             logits_processors: None,
+            chat_template: util::chat_template_for(oairequest.chat_template),
+            user: oairequest.user,
+            priority: oairequest.priority,
         }),
         is_streaming,
     ))
 }
 
+/// The text of the messages, without the chat template around them. Its token count is a lower
+/// bound on that of the templated prompt, so that checking it never rejects a prompt which fits.
+fn prompt_text(messages: &Either<Vec<openai::Message>, String>) -> String {
+    let messages = match messages {
+        Either::Left(messages) => messages,
+        Either::Right(prompt) => return prompt.clone(),
+    };
+    let mut texts = Vec::new();
+    for content in messages
+        .iter()
+        .filter_map(|message| message.content.as_deref())
+    {
+        match content {
+            Either::Left(text) => texts.push(text.as_str()),
+            Either::Right(parts) => texts.extend(
+                parts
+                    .iter()
+                    .filter_map(|part| part.get("text"))
+                    .filter_map(|text| text.as_ref().left())
+                    .map(String::as_str),
+            ),
+        }
+    }
+    texts.join("\n")
+}
+
+/// Checks which need no model, so that an invalid request fails before it is queued.
+fn validate_request(oairequest: &ChatCompletionRequest, state: &MistralRs) -> Result<()> {
+    util::validate_adapters(oairequest.adapters.as_deref(), &state.get_adapter_names())?;
+    util::validate_sampling_params(
+        oairequest.temperature,
+        oairequest.top_p,
+        oairequest.frequency_penalty,
+        oairequest.presence_penalty,
+    )?;
+    if let Some(template) = &oairequest.chat_template {
+        if let Err(e) = validate_chat_template(template) {
+            anyhow::bail!("Invalid `chat_template`: {e}");
+        }
+    }
+    util::validate_guided_choice(
+        oairequest.guided_choice.as_deref(),
+        oairequest.grammar.is_some()
+            || !matches!(
+                oairequest.response_format,
+                Some(ResponseFormat::Text) | None
+            ),
+    )?;
+    if oairequest.stream_granularity == Some(0) {
+        anyhow::bail!("`stream_granularity` must be at least 1.");
+    }
+    if let Some(order) = &oairequest.sampler_order {
+        SamplerStep::parse_order(order).map_err(anyhow::Error::msg)?;
+    }
+    if oairequest.max_chars == Some(0) {
+        anyhow::bail!("`max_chars` must be at least 1.");
+    }
+    if let Some(retries) = oairequest.json_schema_retries {
+        if retries > MAX_JSON_SCHEMA_RETRIES {
+            anyhow::bail!(
+                "`json_schema_retries` must be at most {MAX_JSON_SCHEMA_RETRIES}, got {retries}."
+            );
+        }
+        if oairequest.stream.unwrap_or(false) {
+            anyhow::bail!("`json_schema_retries` is not supported when streaming.");
+        }
+    }
+    match &oairequest.response_format {
+        Some(ResponseFormat::Text) | None => (),
+        Some(_) if oairequest.grammar.is_some() => {
+            anyhow::bail!("`grammar` and `response_format` cannot both be set.");
+        }
+        Some(ResponseFormat::JsonObject) => (),
+        Some(ResponseFormat::JsonSchema { json_schema }) => {
+            if let Err(e) = json_schema_to_yacc(&json_schema.schema) {
+                anyhow::bail!("Invalid JSON schema `{}`: {e}", json_schema.name);
+            }
+        }
+    }
+    Ok(())
+}
+
 #[utoipa::path(
     post,
     tag = "Mistral.rs",
@@ -342,9 +852,163 @@ async fn parse_request(
 )]
 pub async fn chatcompletions(
     State(state): State<Arc<MistralRs>>,
-    Json(oairequest): Json<ChatCompletionRequest>,
+    reservation: Option<Extension<TokenReservation>>,
+    request_id: Option<Extension<RequestId>>,
+    JsonBody(oairequest): JsonBody<ChatCompletionRequest>,
+) -> ChatCompletionResponder {
+    let request_id = request_id.map(|Extension(RequestId(id))| id);
+    let reservation = reservation.map(|Extension(reservation)| reservation);
+    let access_log = AccessLog::new(CHAT_COMPLETIONS_PATH, request_id);
+    serve_chatcompletion(state, oairequest, reservation, access_log).await
+}
+
+const CHAT_COMPLETIONS_PATH: &str = "/v1/chat/completions";
+const CHAT_COMPLETIONS_WS_PATH: &str = "/v1/chat/completions/ws";
+
+/// Runs one chat completion request under `MISTRALRS_MAX_CONCURRENT`, logging it and charging its
+/// usage to `reservation`. Requests over SSE and over websockets both go through here.
+async fn serve_chatcompletion(
+    state: Arc<MistralRs>,
+    oairequest: ChatCompletionRequest,
+    reservation: Option<TokenReservation>,
+    mut access_log: AccessLog,
 ) -> ChatCompletionResponder {
-    let (tx, mut rx) = channel(10_000);
+    let Ok(permit) = concurrency::try_acquire() else {
+        metrics::record_request();
+        access_log.log(http::StatusCode::TOO_MANY_REQUESTS);
+        return ChatCompletionResponder::Saturated;
+    };
+    let responder = chatcompletion(
+        state,
+        oairequest,
+        permit,
+        reservation.clone(),
+        &mut access_log,
+    )
+    .await;
+    log_response(&mut access_log, &responder);
+    if let (Some(reservation), Some(usage)) = (reservation, responder.usage()) {
+        reservation.add_usage(usage);
+    }
+    responder
+}
+
+/// Log a finished `responder`. A stream is logged by its `Streamer` instead, once it ends.
+fn log_response(access_log: &mut AccessLog, responder: &ChatCompletionResponder) {
+    if let ChatCompletionResponder::Sse(_) = responder {
+        return;
+    }
+    if let Some(usage) = responder.usage() {
+        access_log.set_usage(usage);
+    }
+    let status = responder
+        .to_error()
+        .map_or(http::StatusCode::OK, |(_, status)| status);
+    access_log.log(status);
+}
+
+/// Runs one chat completion request, holding `permit` until it has finished. An output which does
+/// not match the `response_format` JSON schema fails the request, after being generated again up
+/// to `json_schema_retries` times.
+async fn chatcompletion(
+    state: Arc<MistralRs>,
+    oairequest: ChatCompletionRequest,
+    permit: Option<OwnedSemaphorePermit>,
+    reservation: Option<TokenReservation>,
+    access_log: &mut AccessLog,
+) -> ChatCompletionResponder {
+    let schema = match &oairequest.response_format {
+        Some(ResponseFormat::JsonSchema { json_schema }) => json_schema.schema.clone(),
+        _ => {
+            return run_chatcompletion(state, oairequest, permit, reservation, access_log, None)
+                .await
+        }
+    };
+    if oairequest.stream.unwrap_or(false) {
+        let schema = Some(schema);
+        return run_chatcompletion(state, oairequest, permit, reservation, access_log, schema)
+            .await;
+    }
+
+    let retries = oairequest.json_schema_retries.unwrap_or(0);
+    for attempt in 0.. {
+        let responder = run_chatcompletion(
+            state.clone(),
+            oairequest.clone(),
+            None,
+            None,
+            access_log,
+            None,
+        )
+        .await;
+        let ChatCompletionResponder::Json(response) = &responder else {
+            return responder;
+        };
+        let violation = response.response.choices.iter().find_map(|choice| {
+            let content = choice.message.content.as_ref()?;
+            schema_violation(&schema, choice.index, content)
+        });
+        match violation {
+            None => return responder,
+            Some(violation) if attempt < retries => {
+                // The attempt is charged here, as only the last one is returned.
+                if let Some(reservation) = &reservation {
+                    reservation.add_usage(&response.response.usage);
+                }
+                warn!(
+                    "{violation} Generating again, attempt {} of {retries}.",
+                    attempt + 1
+                );
+            }
+            Some(violation) => {
+                let ChatCompletionResponder::Json(response) = responder else {
+                    unreachable!()
+                };
+                return ChatCompletionResponder::ModelError(violation, response.response);
+            }
+        }
+    }
+    unreachable!("The retries are bounded.")
+}
+
+async fn run_chatcompletion(
+    state: Arc<MistralRs>,
+    oairequest: ChatCompletionRequest,
+    permit: Option<OwnedSemaphorePermit>,
+    reservation: Option<TokenReservation>,
+    access_log: &mut AccessLog,
+    schema: Option<Value>,
+) -> ChatCompletionResponder {
+    let received_at = Instant::now();
+    metrics::record_request();
+    let Some(state) = state.get_model(&oairequest.model) else {
+        return ChatCompletionResponder::ModelNotFound(oairequest.model);
+    };
+    let (tx, mut rx) = util::response_channel();
+    let timeout = request_timeout(&oairequest);
+    let include_usage = oairequest
+        .stream_options
+        .as_ref()
+        .is_some_and(|options| options.include_usage);
+    let continuous_usage_stats = oairequest
+        .stream_options
+        .as_ref()
+        .is_some_and(|options| options.continuous_usage_stats);
+    let return_timings = oairequest.return_timings;
+    let model = oairequest.model.clone();
+    access_log.set_user(oairequest.user.clone());
+    if let Err(e) = validate_request(&oairequest, &state) {
+        return ChatCompletionResponder::ValidationError(e.into());
+    }
+    // A dry run is for inspecting the prompt, so one which is too long is still rendered.
+    let dry_run = oairequest.dry_run.unwrap_or(false);
+    let prompt = prompt_text(&oairequest.messages);
+    if let Err(e) = util::validate_prompt_length(&state, &prompt, oairequest.max_tokens) {
+        if !dry_run {
+            return ChatCompletionResponder::ValidationError(Box::new(e));
+        }
+    }
+
     let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
         Ok(x) => x,
         Err(e) => {
@@ -353,6 +1017,14 @@ pub async fn chatcompletions(
             return ChatCompletionResponder::InternalError(e.into());
         }
     };
+    if dry_run {
+        return render_prompt(&state, request, model).await;
+    }
+    let request_id = match &request {
+        Request::Normal(NormalRequest { id, .. }) => *id,
+        _ => unreachable!(),
+    };
+    access_log.set_request_id(request_id);
     let sender = state.get_sender().unwrap();
 
     if let Err(e) = sender.send(request).await {
@@ -360,27 +1032,69 @@ pub async fn chatcompletions(
         MistralRs::maybe_log_error(state, &*e);
         return ChatCompletionResponder::InternalError(e.into());
     }
+    let model_state = state.clone();
+    let registration = registry().register(state.get_id(), request_id, is_streaming, move || {
+        terminate_request(&model_state, request_id)
+    });
+
+    let heartbeat = util::heartbeat_interval()
+        .filter(|_| is_streaming)
+        .map(|interval| {
+            Heartbeat::new(
+                interval,
+                request_id,
+                state.get_id(),
+                state.get_system_fingerprint(),
+            )
+        });
+
+    // Wait for the first response here so that a stalled engine still gets a proper status code,
+    // unless heartbeats are to be sent while waiting, in which case the stream times out instead.
+    let first_response = match timeout {
+        Some(timeout) if heartbeat.is_none() => {
+            match tokio::time::timeout(timeout, rx.recv()).await {
+                Ok(response) => response,
+                Err(_) => {
+                    let e = RequestTimeout(timeout);
+                    MistralRs::maybe_log_error(state.clone(), &e);
+                    terminate_request(&state, request_id);
+                    return ChatCompletionResponder::InternalError(e.into());
+                }
+            }
+        }
+        _ if is_streaming => None,
+        _ => rx.recv().await,
+    };
 
     if is_streaming {
         let streamer = Streamer {
             rx,
             is_done: false,
+            done_sent: false,
             state,
+            request_id,
+            include_usage,
+            continuous_usage_stats,
+            return_timings,
+            usage_chunk: None,
+            first_response,
+            timeout: timeout.map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout)))),
+            last_event: (received_at, true),
+            heartbeat,
+            _permit: permit,
+            access_log: access_log.clone(),
+            status: http::StatusCode::OK,
+            schema_check: schema.map(|schema| StreamSchemaCheck {
+                schema,
+                contents: Vec::new(),
+            }),
+            reservation,
+            _registration: registration,
         };
 
-        ChatCompletionResponder::Sse(
-            Sse::new(streamer).keep_alive(
-                KeepAlive::new()
-                    .interval(Duration::from_millis(
-                        env::var("KEEP_ALIVE_INTERVAL")
-                            .map(|val| val.parse::<u64>().unwrap_or(1000))
-                            .unwrap_or(1000),
-                    ))
-                    .text("keep-alive-text"),
-            ),
-        )
+        ChatCompletionResponder::Sse(streamer)
     } else {
-        let response = match rx.recv().await {
+        let response = match first_response {
             Some(response) => response,
             None => {
                 let e = anyhow::Error::msg("No response received from the model.");
@@ -401,14 +1115,384 @@ pub async fn chatcompletions(
             }
             Response::ValidationError(e) => ChatCompletionResponder::ValidationError(e),
             Response::Done(response) => {
+                metrics::record_time_to_first_token(Duration::from_secs_f32(
+                    response.usage.time_to_first_token_sec,
+                ));
+                metrics::record_generated_tokens(response.usage.completion_tokens);
+                metrics::record_request_latency(Duration::from_secs_f32(
+                    response.usage.total_time_sec,
+                ));
                 MistralRs::maybe_log_response(state, &response);
-                ChatCompletionResponder::Json(response)
+                let usage = response.usage.clone();
+                ChatCompletionResponder::Json(WithTimings::new(
+                    response,
+                    Some(&usage),
+                    return_timings,
+                ))
             }
             Response::Chunk(_) => unreachable!(),
             Response::CompletionDone(_) => unreachable!(),
             Response::CompletionModelError(_, _) => unreachable!(),
             Response::CompletionChunk(_) => unreachable!(),
             Response::ImageGeneration(_) => unreachable!(),
+            Response::Embeddings(_) => unreachable!(),
+        }
+    }
+}
+
+/// Render the prompt of `request` through the engine, without generating.
+async fn render_prompt(
+    state: &MistralRs,
+    request: Request,
+    model: String,
+) -> ChatCompletionResponder {
+    let Request::Normal(NormalRequest {
+        messages: RequestMessage::Chat(messages) | RequestMessage::VisionChat { messages, .. },
+        tools,
+        chat_template,
+        ..
+    }) = request
+    else {
+        unreachable!("Chat completion requests have messages.")
+    };
+    match state
+        .render_chat_prompt(messages, tools.unwrap_or_default(), chat_template)
+        .await
+    {
+        Ok((tokens, prompt)) => ChatCompletionResponder::DryRun(DryRunResponse {
+            object: "chat.completion.dry_run",
+            model,
+            prompt,
+            prompt_tokens: tokens.len(),
+        }),
+        Err(e) => ChatCompletionResponder::ValidationError(e.into()),
+    }
+}
+
+/// One entry of a batch response: the completion, or the error of a request which failed.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum BatchItem {
+    Completion(WithTimings<ChatCompletionResponse>),
+    DryRun(DryRunResponse),
+    Error(JsonError),
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/chat/completions/batch",
+    request_body = Vec<ChatCompletionRequest>,
+    responses((status = 200, description = "Chat completions, in the order of the requests"))
+)]
+pub async fn chatcompletions_batch(
+    State(state): State<Arc<MistralRs>>,
+    reservation: Option<Extension<TokenReservation>>,
+    request_id: Option<Extension<RequestId>>,
+    JsonBody(oairequests): JsonBody<Vec<ChatCompletionRequest>>,
+) -> axum::response::Response {
+    // The requests in a batch share its `X-Request-Id`.
+    let request_id = request_id.map(|Extension(RequestId(id))| id);
+    let reservation = reservation.map(|Extension(reservation)| reservation);
+    // A batch takes a single slot, as its requests are scheduled together.
+    let Ok(_permit) = concurrency::try_acquire() else {
+        return concurrency::saturated_response();
+    };
+    let items = futures::future::join_all(oairequests.into_iter().map(|oairequest| {
+        let state = state.clone();
+        let request_id = request_id.clone();
+        let reservation = reservation.clone();
+        async move {
+            if oairequest.stream.is_some_and(|stream| stream) {
+                return BatchItem::Error(JsonError::invalid_request(
+                    "Streaming is not supported for batched requests.".to_string(),
+                ));
+            }
+            let mut access_log = AccessLog::new("/v1/chat/completions/batch", request_id);
+            let responder = chatcompletion(
+                state,
+                oairequest,
+                None,
+                reservation.clone(),
+                &mut access_log,
+            )
+            .await;
+            log_response(&mut access_log, &responder);
+            if let (Some(reservation), Some(usage)) = (reservation, responder.usage()) {
+                reservation.add_usage(usage);
+            }
+            match responder {
+                ChatCompletionResponder::Json(response) => BatchItem::Completion(response),
+                ChatCompletionResponder::DryRun(response) => BatchItem::DryRun(response),
+                responder => {
+                    let (error, _) = responder.to_error().expect("not an error");
+                    BatchItem::Error(error)
+                }
+            }
+        }
+    }))
+    .await;
+    Json(items).into_response()
+}
+
+/// Streaming chat completions over a websocket. The client sends a `ChatCompletionRequest` as the
+/// first text message and receives the same chunks as with SSE, one per text message, followed by
+/// a close frame. Closing the socket early cancels the request.
+pub async fn chatcompletions_ws(
+    State(state): State<Arc<MistralRs>>,
+    reservation: Option<Extension<TokenReservation>>,
+    request_id: Option<Extension<RequestId>>,
+    ws: WebSocketUpgrade,
+) -> axum::response::Response {
+    let reservation = reservation.map(|Extension(reservation)| reservation);
+    let access_log = AccessLog::new(
+        CHAT_COMPLETIONS_WS_PATH,
+        request_id.map(|Extension(RequestId(id))| id),
+    );
+    ws.on_upgrade(|socket| stream_to_websocket(socket, state, reservation, access_log))
+}
+
+async fn stream_to_websocket(
+    mut socket: WebSocket,
+    state: Arc<MistralRs>,
+    reservation: Option<TokenReservation>,
+    access_log: AccessLog,
+) {
+    if let Err(e) = handle_websocket(&mut socket, state, reservation, access_log).await {
+        if let Ok(e) = serde_json::to_string(&e) {
+            let _ = socket.send(Message::Text(e)).await;
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+async fn handle_websocket(
+    socket: &mut WebSocket,
+    state: Arc<MistralRs>,
+    reservation: Option<TokenReservation>,
+    access_log: AccessLog,
+) -> Result<(), JsonError> {
+    let mut oairequest = loop {
+        match socket.recv().await {
+            Some(Ok(Message::Text(text))) => {
+                break serde_json::from_str::<ChatCompletionRequest>(&text)
+                    .map_err(|e| JsonError::invalid_request(e.to_string()))?;
+            }
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+            Some(Ok(Message::Binary(_))) => {
+                return Err(JsonError::invalid_request(
+                    "Expected a chat completion request as a text message.".to_string(),
+                ));
+            }
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return Ok(()),
+        }
+    };
+    if oairequest.dry_run.unwrap_or(false) {
+        return Err(JsonError::invalid_request(
+            "`dry_run` is not supported over websockets.".to_string(),
+        ));
+    }
+    oairequest.stream = Some(true);
+
+    // The request is routed, checked, registered, logged and timed out as one sent over SSE.
+    let responder = serve_chatcompletion(state, oairequest, reservation, access_log).await;
+    let mut streamer = match responder {
+        ChatCompletionResponder::Sse(streamer) => streamer,
+        responder => {
+            let (error, _) = responder.to_error().expect("not an error");
+            return Err(error);
+        }
+    };
+    loop {
+        tokio::select! {
+            item = streamer.next() => match item {
+                Some(StreamItem::Chunk(chunk)) => {
+                    let chunk = chunk.map_err(|e| JsonError::server_error(e.to_string()))?;
+                    if socket.send(Message::Text(chunk)).await.is_err() {
+                        // The client went away, so dropping the stream stops decoding for it.
+                        return Ok(());
+                    }
+                }
+                Some(StreamItem::Error(e)) => return Err(e),
+                Some(StreamItem::Done) | None => return Ok(()),
+            },
+            message = socket.recv() => {
+                if !matches!(message, Some(Ok(Message::Close(_))) | Some(Err(_)) | None) {
+                    continue;
+                }
+                // The client closed the socket before generation finished, so dropping the
+                // stream stops decoding for it.
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Router};
+    use futures::SinkExt;
+    use mistralrs_core::TestPipeline;
+    use serde_json::json;
+    use tokio::{net::TcpStream, sync::mpsc::channel};
+    use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+
+    use super::*;
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// A client connected to the websocket endpoint of a server for `state` on a free port.
+    async fn connect_websocket(state: Arc<MistralRs>) -> Client {
+        let app = Router::new()
+            .route(CHAT_COMPLETIONS_WS_PATH, get(chatcompletions_ws))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let url = format!("ws://{addr}{CHAT_COMPLETIONS_WS_PATH}");
+        tokio_tungstenite::connect_async(url).await.unwrap().0
+    }
+
+    /// The text messages `client` receives until the socket is closed, as JSON.
+    async fn receive(client: &mut Client) -> Vec<Value> {
+        let mut messages = Vec::new();
+        while let Some(message) = client.next().await {
+            match message.unwrap() {
+                tungstenite::Message::Text(text) => {
+                    messages.push(serde_json::from_str(&text).unwrap())
+                }
+                tungstenite::Message::Close(_) => break,
+                _ => (),
+            }
+        }
+        messages
+    }
+
+    /// The status and body of a chat completion request for `model`.
+    async fn chat(state: &Arc<MistralRs>, model: &str) -> (http::StatusCode, Value) {
+        let request = json!({
+            "model": model,
+            "messages": [{"role": "user", "content": "a b c"}],
+            "max_tokens": 3,
+        });
+        let request = serde_json::from_value(request).unwrap();
+        let response = chatcompletions(State(state.clone()), None, None, JsonBody(request))
+            .await
+            .into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_routed_by_model() {
+        let state = TestPipeline::new("selected").build(false);
+        // With a single model, every ID names it.
+        let (status, response) = chat(&state, "missing").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(response["model"], "selected");
+
+        state
+            .add_model(TestPipeline::new("other").build(false))
+            .unwrap();
+        for (model, served) in [
+            ("selected", "selected"),
+            ("default", "selected"),
+            ("other", "other"),
+        ] {
+            let (status, response) = chat(&state, model).await;
+            assert_eq!(status, http::StatusCode::OK);
+            assert_eq!(response["model"], served);
+        }
+        let (status, response) = chat(&state, "missing").await;
+        assert_eq!(status, http::StatusCode::NOT_FOUND);
+        assert_eq!(response["error"]["code"], "model_not_found");
+        assert_eq!(response["error"]["type"], "invalid_request_error");
+    }
+
+    #[tokio::test]
+    async fn test_batch_responds_in_order_of_the_requests() {
+        let state = TestPipeline::new("test").build(false);
+        let messages = json!([{"role": "user", "content": "a b c"}]);
+        let requests = json!([
+            {"messages": messages, "max_tokens": 3},
+            {"messages": messages, "max_tokens": 3, "stream": true},
+        ]);
+        let requests = serde_json::from_value(requests).unwrap();
+        let response = chatcompletions_batch(State(state), None, None, JsonBody(requests)).await;
+        assert_eq!(response.status(), http::StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let items: Vec<Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0]["object"], "chat.completion");
+        assert_eq!(items[0]["choices"][0]["finish_reason"], "length");
+        assert_eq!(items[1]["error"]["type"], "invalid_request_error");
+        assert!(items[1].get("choices").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_streams_chunks() {
+        let mut client = connect_websocket(TestPipeline::new("test").build(false)).await;
+        let request = json!({
+            "messages": [{"role": "user", "content": "a b c"}],
+            "max_tokens": 3,
+        });
+        let message = tungstenite::Message::Text(request.to_string());
+        client.send(message).await.unwrap();
+
+        let chunks = receive(&mut client).await;
+        assert!(!chunks.is_empty());
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk["object"] == "chat.completion.chunk"));
+        assert!(chunks
+            .iter()
+            .any(|chunk| chunk["choices"][0]["finish_reason"] == "length"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_rejects_binary_requests() {
+        let mut client = connect_websocket(TestPipeline::new("test").build(false)).await;
+        let message = tungstenite::Message::Binary(b"{}".to_vec());
+        client.send(message).await.unwrap();
+
+        let messages = receive(&mut client).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["error"]["type"], "invalid_request_error");
+    }
+
+    #[tokio::test]
+    async fn test_slow_prefill_sends_heartbeats() {
+        let mut heartbeat = Heartbeat::new(
+            Duration::from_millis(10),
+            7,
+            "default".to_string(),
+            "fp_0".to_string(),
+        );
+        let (tx, mut rx) = channel(1);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            tx.send(()).await.unwrap();
+        });
+
+        let mut beats = Vec::new();
+        loop {
+            tokio::select! {
+                _ = rx.recv() => break,
+                chunk = futures::future::poll_fn(|cx| heartbeat.poll_beat(cx)) => beats.push(chunk),
+            }
+        }
+        assert!(!beats.is_empty());
+        for chunk in beats {
+            assert_eq!(chunk.id, "7");
+            assert_eq!(chunk.model, "default");
+            assert_eq!(chunk.object, "chat.completion.chunk");
+            assert!(chunk.choices.is_empty());
         }
     }
 }
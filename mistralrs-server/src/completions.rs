@@ -1,30 +1,37 @@
 use anyhow::Result;
 use std::{
-    env,
     error::Error,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
-    time::Duration,
 };
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::{Receiver, Sender};
 
-use crate::openai::{CompletionRequest, Grammar, StopTokens};
-use axum::{
-    extract::{Json, State},
-    http::{self, StatusCode},
-    response::{
-        sse::{Event, KeepAlive},
-        IntoResponse, Sse,
+use crate::{
+    error::{JsonBody, JsonError},
+    openai::{CompletionRequest, Grammar, WithTimings},
+    rate_limit::TokenReservation,
+    util::{
+        resolve_max_tokens, response_channel, split_logit_bias, sse, validate_adapters,
+        validate_guided_choice, validate_prompt_length, validate_sampling_params,
+        with_sampling_defaults, PromptTooLong,
     },
 };
+use axum::{
+    extract::{Extension, Json, State},
+    http,
+    response::{sse::Event, IntoResponse, Sse},
+};
 use mistralrs_core::{
-    CompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
-    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
+    BeamSearchParams, CompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest,
+    Request, RequestMessage, Response, SamplerStep, SamplingParams,
+    StopTokens as InternalStopTokens,
 };
-use serde::Serialize;
 use tracing::warn;
 
+/// The most top logprobs a request may ask for at each prompt position.
+const MAX_PROMPT_LOGPROBS: usize = 20;
+
 #[derive(Debug)]
 struct ModelErrorMessage(String);
 impl std::fmt::Display for ModelErrorMessage {
@@ -37,112 +44,182 @@ impl std::error::Error for ModelErrorMessage {}
 pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
+    /// Whether the terminal `[DONE]` event has been sent.
+    done_sent: bool,
     state: Arc<MistralRs>,
+    request_id: usize,
+    /// Whether to attach timings to the terminal chunk.
+    return_timings: bool,
+    /// Tokens reserved under `MISTRALRS_TOKENS_PER_MINUTE`, settled once the stream ends.
+    reservation: Option<TokenReservation>,
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) {
+        if !self.is_done {
+            // The client went away before generation finished, so stop decoding for it.
+            if let Ok(sender) = self.state.get_sender() {
+                let _ = sender.try_send(Request::TerminateSeq {
+                    id: self.request_id,
+                });
+            }
+        }
+    }
 }
 
 impl futures::Stream for Streamer {
     type Item = Result<Event, axum::Error>;
 
-    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if self.is_done {
+            if !self.done_sent {
+                self.done_sent = true;
+                return Poll::Ready(Some(Ok(Event::default().data("[DONE]"))));
+            }
             return Poll::Ready(None);
         }
-        match self.rx.try_recv() {
-            Ok(resp) => match resp {
-                Response::ModelError(msg, _) => {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(resp)) => match resp {
+                Response::CompletionModelError(msg, response) => {
                     MistralRs::maybe_log_error(
                         self.state.clone(),
                         &ModelErrorMessage(msg.to_string()),
                     );
-                    Poll::Ready(Some(Ok(Event::default().data(msg))))
+                    self.is_done = true;
+                    if let Some(reservation) = &self.reservation {
+                        reservation.add_usage(&response.usage);
+                    }
+                    Poll::Ready(Some(JsonError::model_error(msg, response).to_sse_event()))
                 }
                 Response::ValidationError(e) => {
-                    Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
+                    self.is_done = true;
+                    Poll::Ready(Some(
+                        JsonError::invalid_request(e.to_string()).to_sse_event(),
+                    ))
                 }
                 Response::InternalError(e) => {
                     MistralRs::maybe_log_error(self.state.clone(), &*e);
-                    Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
+                    self.is_done = true;
+                    Poll::Ready(Some(JsonError::server_error(e.to_string()).to_sse_event()))
                 }
-                Response::CompletionChunk(response) => {
-                    if response.choices.iter().all(|x| x.finish_reason.is_some()) {
+                Response::CompletionChunk(mut response) => {
+                    if response.usage.is_some() {
                         self.is_done = true;
                     }
                     MistralRs::maybe_log_response(self.state.clone(), &response);
+                    let usage = response.usage.clone();
+                    if let (Some(reservation), Some(usage)) = (&self.reservation, &usage) {
+                        reservation.add_usage(usage);
+                    }
+                    // With `continuous_usage_stats`, the other chunks have the usage so far.
+                    if let Some(running_usage) = response.running_usage.take() {
+                        response.usage = Some(running_usage);
+                    }
+                    let response = WithTimings::new(response, usage.as_ref(), self.return_timings);
                     Poll::Ready(Some(Event::default().json_data(response)))
                 }
                 Response::Done(_) => unreachable!(),
                 Response::CompletionDone(_) => unreachable!(),
-                Response::CompletionModelError(_, _) => unreachable!(),
+                Response::ModelError(_, _) => unreachable!(),
                 Response::Chunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
             },
-            Err(_) => Poll::Pending,
+            Poll::Ready(None) => {
+                // The engine dropped its sender, so nothing more will arrive.
+                self.is_done = true;
+                self.done_sent = true;
+                Poll::Ready(Some(Ok(Event::default().data("[DONE]"))))
+            }
+            Poll::Pending => Poll::Pending,
         }
     }
 }
 
 pub enum CompletionResponder {
     Sse(Sse<Streamer>),
-    Json(CompletionResponse),
+    Json(WithTimings<CompletionResponse>),
     ModelError(String, CompletionResponse),
     InternalError(Box<dyn Error>),
     ValidationError(Box<dyn Error>),
+    ModelNotFound(String),
 }
 
-trait ErrorToResponse: Serialize {
-    fn to_response(&self, code: StatusCode) -> axum::response::Response {
-        let mut r = Json(self).into_response();
-        *r.status_mut() = code;
-        r
-    }
-}
-
-#[derive(Serialize)]
-struct JsonError {
-    message: String,
-}
-
-impl JsonError {
-    fn new(message: String) -> Self {
-        Self { message }
-    }
-}
-impl ErrorToResponse for JsonError {}
-
-#[derive(Serialize)]
-struct JsonModelError {
-    message: String,
-    partial_response: CompletionResponse,
-}
-
-impl JsonModelError {
-    fn new(message: String, partial_response: CompletionResponse) -> Self {
-        Self {
-            message,
-            partial_response,
-        }
-    }
-}
-
-impl ErrorToResponse for JsonModelError {}
-
 impl IntoResponse for CompletionResponder {
     fn into_response(self) -> axum::response::Response {
         match self {
             CompletionResponder::Sse(s) => s.into_response(),
             CompletionResponder::Json(s) => Json(s).into_response(),
-            CompletionResponder::InternalError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
-            }
-            CompletionResponder::ValidationError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            CompletionResponder::InternalError(e) => JsonError::server_error(e.to_string())
+                .to_response(http::StatusCode::INTERNAL_SERVER_ERROR),
+            CompletionResponder::ValidationError(e) if e.is::<PromptTooLong>() => {
+                JsonError::invalid_request(e.to_string())
+                    .with_code("context_length_exceeded")
+                    .to_response(http::StatusCode::BAD_REQUEST)
             }
-            CompletionResponder::ModelError(msg, response) => JsonModelError::new(msg, response)
+            CompletionResponder::ValidationError(e) => JsonError::invalid_request(e.to_string())
+                .to_response(http::StatusCode::UNPROCESSABLE_ENTITY),
+            CompletionResponder::ModelError(msg, response) => JsonError::model_error(msg, response)
                 .to_response(http::StatusCode::INTERNAL_SERVER_ERROR),
+            CompletionResponder::ModelNotFound(model) => {
+                JsonError::model_not_found(&model).to_response(http::StatusCode::NOT_FOUND)
+            }
         }
     }
 }
 
+/// Beam search picks tokens by their logprobs alone, so it cannot be combined with the parameters
+/// shaping how tokens are sampled.
+fn validate_beam_search(oairequest: &CompletionRequest) -> Result<(), String> {
+    if oairequest.num_beams.is_none() {
+        if oairequest.length_penalty.is_some() || oairequest.early_stopping.is_some() {
+            return Err("`length_penalty` and `early_stopping` only apply to beam search, which `num_beams` enables.".to_string());
+        }
+        return Ok(());
+    }
+    let sampling_params = [
+        ("temperature", oairequest.temperature.is_some()),
+        ("top_p", oairequest.top_p.is_some()),
+        ("top_k", oairequest.top_k.is_some()),
+        ("min_p", oairequest.min_p.is_some()),
+        ("eta_cutoff", oairequest.eta_cutoff.is_some()),
+        ("epsilon_cutoff", oairequest.epsilon_cutoff.is_some()),
+        ("sampler_order", oairequest.sampler_order.is_some()),
+        ("seed", oairequest.seed.is_some()),
+        ("penalty_alpha", oairequest.penalty_alpha.is_some()),
+        ("dry_multiplier", oairequest.dry_multiplier.is_some()),
+        ("presence_penalty", oairequest.presence_penalty.is_some()),
+        ("frequency_penalty", oairequest.frequency_penalty.is_some()),
+        (
+            "repetition_penalty",
+            oairequest.repetition_penalty.is_some(),
+        ),
+        ("logit_bias", oairequest.logit_bias.is_some()),
+        (
+            "logit_bias_patterns",
+            oairequest.logit_bias_patterns.is_some(),
+        ),
+    ];
+    let conflicts = sampling_params
+        .iter()
+        .filter(|(_, is_set)| *is_set)
+        .map(|(name, _)| format!("`{name}`"))
+        .collect::<Vec<_>>();
+    if !conflicts.is_empty() {
+        return Err(format!(
+            "Beam search picks tokens by their logprobs, so it cannot be combined with {}.",
+            conflicts.join(", ")
+        ));
+    }
+    if oairequest
+        .length_penalty
+        .is_some_and(|length_penalty| !length_penalty.is_finite())
+    {
+        return Err("`length_penalty` must be a finite number.".to_string());
+    }
+    Ok(())
+}
+
 fn parse_request(
     oairequest: CompletionRequest,
     state: Arc<MistralRs>,
@@ -151,17 +228,14 @@ fn parse_request(
     let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
     MistralRs::maybe_log_request(state.clone(), repr);
 
-    let stop_toks = match oairequest.stop_seqs {
-        Some(StopTokens::Multi(m)) => Some(InternalStopTokens::Seqs(m)),
-        Some(StopTokens::Single(s)) => Some(InternalStopTokens::Seqs(vec![s])),
-        None => None,
-    };
+    let stop_toks = oairequest.stop_seqs.map(InternalStopTokens::from);
 
     if oairequest.logprobs.is_some() {
         warn!("Completion requests do not support logprobs.");
     }
 
     let is_streaming = oairequest.stream.unwrap_or(false);
+    let (logits_bias, logits_bias_strs) = split_logit_bias(oairequest.logit_bias);
 
     let dry_params = if let Some(dry_multiplier) = oairequest.dry_multiplier {
         Some(DrySamplingParams::new_with_defaults(
@@ -177,11 +251,18 @@ fn parse_request(
         Request::Normal(NormalRequest {
             id: state.next_request_id(),
             messages: RequestMessage::Completion {
-                text: oairequest.prompt,
+                text: oairequest
+                    .prompt
+                    .either(|prompts| prompts, |prompt| vec![prompt]),
                 echo_prompt: oairequest.echo_prompt,
-                best_of: oairequest.best_of,
+                best_of: oairequest.best_of.unwrap_or(oairequest.n_choices),
+                beam_search: oairequest.num_beams.map(|num_beams| BeamSearchParams {
+                    num_beams,
+                    length_penalty: oairequest.length_penalty.unwrap_or(1.0),
+                    early_stopping: oairequest.early_stopping.unwrap_or(false),
+                }),
             },
-            sampling_params: SamplingParams {
+            sampling_params: with_sampling_defaults(SamplingParams {
                 temperature: oairequest.temperature,
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
@@ -189,25 +270,56 @@ fn parse_request(
                 top_n_logprobs: 1,
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
-                max_len: oairequest.max_tokens,
+                penalize_prompt: oairequest.penalize_prompt,
+                repetition_penalty: oairequest.repetition_penalty,
+                repetition_context_size: oairequest.repetition_context_size,
+                max_len: Some(resolve_max_tokens(oairequest.max_tokens)),
+                max_chars: oairequest.max_chars,
                 stop_toks,
-                logits_bias: oairequest.logit_bias,
+                logits_bias,
+                logits_bias_strs,
+                logits_bias_patterns: oairequest.logit_bias_patterns,
                 n_choices: oairequest.n_choices,
                 dry_params,
-            },
+                seed: oairequest.seed,
+                mirostat: None,
+                penalty_alpha: oairequest.penalty_alpha,
+                include_stop_str_in_output: oairequest.include_stop_str_in_output.unwrap_or(false),
+                prompt_logprobs: oairequest.prompt_logprobs,
+                return_raw_tokens: oairequest.return_raw_tokens.unwrap_or(false),
+                continuous_usage_stats: oairequest
+                    .stream_options
+                    .as_ref()
+                    .is_some_and(|options| options.continuous_usage_stats),
+                stream_granularity: oairequest.stream_granularity,
+                sampler_order: oairequest
+                    .sampler_order
+                    .as_deref()
+                    .map(SamplerStep::parse_order)
+                    .transpose()
+                    .map_err(anyhow::Error::msg)?,
+                typical_p: None,
+                eta_cutoff: oairequest.eta_cutoff,
+                epsilon_cutoff: oairequest.epsilon_cutoff,
+            }),
             response: tx,
             return_logprobs: false,
             is_streaming,
             suffix: oairequest.suffix,
-            constraint: match oairequest.grammar {
-                Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
-                Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
-                None => Constraint::None,
+            constraint: match (oairequest.guided_choice, oairequest.grammar) {
+                (Some(choices), _) => Constraint::Choice(choices),
+                (None, Some(Grammar::Yacc(yacc))) => Constraint::Yacc(yacc),
+                (None, Some(Grammar::Regex(regex))) => Constraint::Regex(regex),
+                (None, Some(Grammar::Gbnf(gbnf))) => Constraint::Gbnf(gbnf),
+                (None, None) => Constraint::None,
             },
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
             logits_processors: None,
+            chat_template: None,
+            user: oairequest.user,
+            priority: oairequest.priority,
         }),
         is_streaming,
     ))
@@ -220,18 +332,101 @@ fn parse_request(
     request_body = CompletionRequest,
     responses((status = 200, description = "Completions"))
 )]
-
 pub async fn completions(
     State(state): State<Arc<MistralRs>>,
-    Json(oairequest): Json<CompletionRequest>,
+    reservation: Option<Extension<TokenReservation>>,
+    JsonBody(oairequest): JsonBody<CompletionRequest>,
 ) -> CompletionResponder {
-    let (tx, mut rx) = channel(10_000);
+    let reservation = reservation.map(|Extension(reservation)| reservation);
+    let Some(state) = state.get_model(&oairequest.model) else {
+        return CompletionResponder::ModelNotFound(oairequest.model);
+    };
+    let (tx, mut rx) = response_channel();
     if oairequest.logprobs.is_some() {
         return CompletionResponder::ValidationError(
             "Completion requests do not support logprobs.".into(),
         );
     }
+    if let Err(e) = validate_adapters(oairequest.adapters.as_deref(), &state.get_adapter_names()) {
+        return CompletionResponder::ValidationError(e.into());
+    }
+    if let Err(e) = validate_sampling_params(
+        oairequest.temperature,
+        oairequest.top_p,
+        oairequest.frequency_penalty,
+        oairequest.presence_penalty,
+    ) {
+        return CompletionResponder::ValidationError(e.into());
+    }
+    if let Err(e) = validate_guided_choice(
+        oairequest.guided_choice.as_deref(),
+        oairequest.grammar.is_some(),
+    ) {
+        return CompletionResponder::ValidationError(e.into());
+    }
+    if let Some(prompt_logprobs) = oairequest.prompt_logprobs {
+        if prompt_logprobs > MAX_PROMPT_LOGPROBS {
+            let msg = format!(
+                "`prompt_logprobs` must be at most {MAX_PROMPT_LOGPROBS}, got {prompt_logprobs}."
+            );
+            return CompletionResponder::ValidationError(msg.into());
+        }
+        if oairequest.stream.unwrap_or(false) {
+            return CompletionResponder::ValidationError(
+                "`prompt_logprobs` is not supported when streaming.".into(),
+            );
+        }
+    }
+    if oairequest.stream_granularity == Some(0) {
+        return CompletionResponder::ValidationError(
+            "`stream_granularity` must be at least 1.".into(),
+        );
+    }
+    if oairequest.max_chars == Some(0) {
+        return CompletionResponder::ValidationError("`max_chars` must be at least 1.".into());
+    }
+    if let Some(Err(e)) = oairequest
+        .sampler_order
+        .as_deref()
+        .map(SamplerStep::parse_order)
+    {
+        return CompletionResponder::ValidationError(e.into());
+    }
+    if oairequest.prompt.as_ref().left().is_some_and(Vec::is_empty) {
+        return CompletionResponder::ValidationError(
+            "`prompt` must contain at least one prompt.".into(),
+        );
+    }
+    if let Err(e) = validate_beam_search(&oairequest) {
+        return CompletionResponder::ValidationError(e.into());
+    }
+    if let Some(best_of) = oairequest.best_of {
+        if best_of < oairequest.n_choices {
+            return CompletionResponder::ValidationError(
+                format!(
+                    "`best_of` ({best_of}) must be at least `n` ({}), as the `n` choices are the best of the `best_of` candidates.",
+                    oairequest.n_choices
+                )
+                .into(),
+            );
+        }
+        if best_of > oairequest.n_choices && oairequest.stream.unwrap_or(false) {
+            return CompletionResponder::ValidationError(
+                "`best_of` cannot be larger than `n` when streaming, as the candidates are only compared once all have finished.".into(),
+            );
+        }
+    }
+    let prompts = oairequest
+        .prompt
+        .as_ref()
+        .either(Vec::as_slice, std::slice::from_ref);
+    for prompt in prompts {
+        if let Err(e) = validate_prompt_length(&state, prompt, oairequest.max_tokens) {
+            return CompletionResponder::ValidationError(Box::new(e));
+        }
+    }
 
+    let return_timings = oairequest.return_timings;
     let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx) {
         Ok(x) => x,
         Err(e) => {
@@ -240,6 +435,10 @@ pub async fn completions(
             return CompletionResponder::InternalError(e.into());
         }
     };
+    let request_id = match &request {
+        Request::Normal(NormalRequest { id, .. }) => *id,
+        _ => unreachable!(),
+    };
     let sender = state.get_sender().unwrap();
 
     if let Err(e) = sender.send(request).await {
@@ -252,20 +451,14 @@ pub async fn completions(
         let streamer = Streamer {
             rx,
             is_done: false,
+            done_sent: false,
             state,
+            request_id,
+            return_timings,
+            reservation,
         };
 
-        CompletionResponder::Sse(
-            Sse::new(streamer).keep_alive(
-                KeepAlive::new()
-                    .interval(Duration::from_millis(
-                        env::var("KEEP_ALIVE_INTERVAL")
-                            .map(|val| val.parse::<u64>().unwrap_or(1000))
-                            .unwrap_or(1000),
-                    ))
-                    .text("keep-alive-text"),
-            ),
-        )
+        CompletionResponder::Sse(sse(streamer))
     } else {
         let response = match rx.recv().await {
             Some(response) => response,
@@ -284,18 +477,26 @@ pub async fn completions(
             Response::CompletionModelError(msg, response) => {
                 MistralRs::maybe_log_error(state.clone(), &ModelErrorMessage(msg.to_string()));
                 MistralRs::maybe_log_response(state, &response);
+                if let Some(reservation) = reservation {
+                    reservation.add_usage(&response.usage);
+                }
                 CompletionResponder::ModelError(msg, response)
             }
             Response::ValidationError(e) => CompletionResponder::ValidationError(e),
             Response::CompletionDone(response) => {
                 MistralRs::maybe_log_response(state, &response);
-                CompletionResponder::Json(response)
+                let usage = response.usage.clone();
+                if let Some(reservation) = reservation {
+                    reservation.add_usage(&usage);
+                }
+                CompletionResponder::Json(WithTimings::new(response, Some(&usage), return_timings))
             }
             Response::CompletionChunk(_) => unreachable!(),
             Response::Chunk(_) => unreachable!(),
             Response::Done(_) => unreachable!(),
             Response::ModelError(_, _) => unreachable!(),
             Response::ImageGeneration(_) => unreachable!(),
+            Response::Embeddings(_) => unreachable!(),
         }
     }
 }
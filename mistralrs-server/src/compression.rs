@@ -0,0 +1,95 @@
+use tower_http::compression::CompressionLayer;
+
+/// Build the layer compressing responses with gzip or deflate, as negotiated by `Accept-Encoding`.
+///
+/// SSE streams are never compressed, as that would hold back their chunks until enough data for
+/// a compressed block has been generated, nor are responses too small to benefit.
+pub fn compression_layer() -> CompressionLayer {
+    CompressionLayer::new().gzip(true).deflate(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request, StatusCode},
+        response::{sse::Event, Sse},
+        routing::post,
+        Json, Router,
+    };
+    use flate2::read::GzDecoder;
+    use futures::stream;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn embeddings_response() -> Value {
+        json!({
+            "object": "list",
+            "data": (0..4)
+                .map(|index| json!({
+                    "object": "embedding",
+                    "index": index,
+                    "embedding": vec![0.125; 256],
+                }))
+                .collect::<Vec<_>>(),
+            "model": "default",
+            "usage": {"prompt_tokens": 16, "total_tokens": 16},
+        })
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .route(
+                "/v1/embeddings",
+                post(|| async { Json(embeddings_response()) }),
+            )
+            .route(
+                "/v1/chat/completions",
+                post(|| async {
+                    Sse::new(stream::iter(
+                        ["Hello", "world"]
+                            .map(|data| Ok::<_, axum::Error>(Event::default().data(data))),
+                    ))
+                }),
+            )
+            .layer(compression_layer())
+    }
+
+    fn request(path: &str) -> Request<Body> {
+        Request::post(path)
+            .header(header::ACCEPT_ENCODING, "gzip, deflate")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_compressed_embeddings_response_decodes() {
+        let response = router().oneshot(request("/v1/embeddings")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut decoded = String::new();
+        GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert!(body.len() < decoded.len());
+        assert_eq!(
+            serde_json::from_str::<Value>(&decoded).unwrap(),
+            embeddings_response()
+        );
+
+        // Streams are sent as they are.
+        let response = router()
+            .oneshot(request("/v1/chat/completions"))
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "data: Hello\n\ndata: world\n\n");
+    }
+}
@@ -0,0 +1,90 @@
+//! A limit on the chat completion requests in flight, set by `MISTRALRS_MAX_CONCURRENT`. Once it is
+//! reached new requests are rejected with a 429 instead of queuing without bound. A streaming
+//! request counts until its stream ends.
+
+use std::{env, sync::Arc};
+
+use axum::{
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    response::Response,
+};
+use once_cell::sync::Lazy;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::JsonError;
+
+/// Seconds a rejected client is asked to wait before retrying.
+const RETRY_AFTER_SECS: u64 = 1;
+
+static LIMIT: Lazy<Option<ConcurrencyLimit>> = Lazy::new(|| {
+    env::var("MISTRALRS_MAX_CONCURRENT")
+        .ok()
+        .and_then(|val| val.parse::<usize>().ok())
+        .filter(|max| *max > 0)
+        .map(ConcurrencyLimit::new)
+});
+
+pub struct ConcurrencyLimit {
+    semaphore: Arc<Semaphore>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_concurrent)),
+        }
+    }
+
+    /// A slot for one request, freed when the permit is dropped, or `None` if all are taken.
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.semaphore.clone().try_acquire_owned().ok()
+    }
+}
+
+/// All slots under `MISTRALRS_MAX_CONCURRENT` are taken.
+#[derive(Debug)]
+pub struct Saturated;
+
+/// A slot under the server-wide limit, or `None` if there is no limit.
+pub fn try_acquire() -> Result<Option<OwnedSemaphorePermit>, Saturated> {
+    match &*LIMIT {
+        Some(limit) => limit.try_acquire().map(Some).ok_or(Saturated),
+        None => Ok(None),
+    }
+}
+
+/// The error for a request rejected because the server is saturated.
+pub fn saturated_error() -> JsonError {
+    JsonError::server_error("Too many concurrent requests, please retry later.".to_string())
+        .with_code("rate_limit_exceeded")
+}
+
+/// The 429 for a request rejected because the server is saturated.
+pub fn saturated_response() -> Response {
+    let mut response = saturated_error().to_response(StatusCode::TOO_MANY_REQUESTS);
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_requests_over_the_limit() {
+        let limit = ConcurrencyLimit::new(2);
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+
+        // Finishing a request frees its slot.
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+
+        let response = saturated_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+    }
+}
@@ -0,0 +1,52 @@
+use std::{env, time::Duration};
+
+use anyhow::Context;
+use axum::http::{self, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::request_id::REQUEST_ID_HEADER;
+
+/// How long browsers may cache a preflight response.
+const PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Build the CORS layer from the CLI origins plus the comma-separated `MISTRALRS_CORS_ORIGINS`.
+/// With no origins configured, or if any of them is `*`, every origin is allowed.
+///
+/// Preflight `OPTIONS` requests are answered by the layer itself and never reach a handler.
+pub fn cors_layer(cli_origins: Vec<String>) -> anyhow::Result<CorsLayer> {
+    let env_origins = env::var("MISTRALRS_CORS_ORIGINS").unwrap_or_default();
+    let origins = cli_origins
+        .into_iter()
+        .chain(env_origins.split(',').map(str::to_string))
+        .map(|origin| origin.trim().to_string())
+        .filter(|origin| !origin.is_empty())
+        .collect::<Vec<_>>();
+
+    let allow_origin = if origins.is_empty() || origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            origins
+                .iter()
+                .map(|origin| {
+                    HeaderValue::from_str(origin)
+                        .with_context(|| format!("Invalid CORS origin `{origin}`."))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        )
+    };
+
+    Ok(CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_headers([
+            http::header::CONTENT_TYPE,
+            http::header::AUTHORIZATION,
+            // Sent by SSE clients when streaming.
+            http::header::ACCEPT,
+            http::header::CACHE_CONTROL,
+            REQUEST_ID_HEADER,
+        ])
+        .expose_headers([REQUEST_ID_HEADER])
+        .max_age(PREFLIGHT_MAX_AGE)
+        .allow_origin(allow_origin))
+}
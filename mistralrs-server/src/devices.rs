@@ -0,0 +1,59 @@
+//! Selection of the GPUs the model is loaded on, from `--device-ids` or `MISTRALRS_DEVICE`.
+
+use std::env;
+
+use anyhow::Result;
+use candle_core::Device;
+
+/// The device ordinals from `--device-ids`, or else from the comma-separated
+/// `MISTRALRS_DEVICE`. These are empty if neither is set.
+pub fn resolve_device_ids(arg: Vec<usize>) -> Result<Vec<usize>> {
+    let ids = if !arg.is_empty() {
+        arg
+    } else if let Ok(val) = env::var("MISTRALRS_DEVICE") {
+        val.split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(|id| {
+                id.parse::<usize>().map_err(|_| {
+                    anyhow::anyhow!("`MISTRALRS_DEVICE` must list device ordinals, got `{id}`.")
+                })
+            })
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        Vec::new()
+    };
+    for (i, id) in ids.iter().enumerate() {
+        if ids[..i].contains(id) {
+            anyhow::bail!("Device {id} is selected more than once.");
+        }
+    }
+    Ok(ids)
+}
+
+/// Open the GPUs with `ordinals`, failing with the first which is not available.
+pub fn select_devices(ordinals: &[usize]) -> Result<Vec<Device>> {
+    ordinals
+        .iter()
+        .map(|&ordinal| {
+            #[cfg(feature = "metal")]
+            let device = Device::new_metal(ordinal);
+            #[cfg(not(feature = "metal"))]
+            let device = Device::new_cuda(ordinal);
+            device.map_err(|e| anyhow::anyhow!("Device {ordinal} is not available: {e}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve_device_ids, select_devices};
+
+    #[test]
+    fn test_unavailable_device_is_an_error() {
+        let err = select_devices(&[1000]).unwrap_err();
+        assert!(err.to_string().starts_with("Device 1000 is not available"));
+
+        assert!(resolve_device_ids(vec![0, 1, 0]).is_err());
+    }
+}
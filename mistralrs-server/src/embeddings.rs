@@ -0,0 +1,207 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{error::Error, sync::Arc};
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    error::{JsonBody, JsonError},
+    openai::{EmbeddingRequest, EncodingFormat},
+    util,
+};
+use axum::{
+    extract::{Json, State},
+    http,
+    response::IntoResponse,
+};
+use mistralrs_core::{
+    Constraint, EmbeddingResponse, MistralRs, NormalRequest, Request, RequestMessage, Response,
+    SamplingParams,
+};
+use serde::Serialize;
+use serde_json::Value;
+
+/// The OpenAI embeddings response body.
+#[derive(Serialize)]
+pub struct EmbeddingList {
+    object: &'static str,
+    data: Vec<EmbeddingObject>,
+    model: String,
+    usage: EmbeddingListUsage,
+}
+
+#[derive(Serialize)]
+struct EmbeddingObject {
+    object: &'static str,
+    /// An array of floats, or a base64 string of little-endian `f32`s.
+    embedding: Value,
+    index: usize,
+}
+
+#[derive(Serialize)]
+struct EmbeddingListUsage {
+    prompt_tokens: usize,
+    total_tokens: usize,
+}
+
+impl EmbeddingList {
+    fn new(response: EmbeddingResponse, encoding_format: EncodingFormat) -> Self {
+        let data = response
+            .data
+            .into_iter()
+            .map(|choice| EmbeddingObject {
+                object: "embedding",
+                embedding: match encoding_format {
+                    EncodingFormat::Float => Value::from(choice.embedding),
+                    EncodingFormat::Base64 => Value::from(
+                        STANDARD.encode(
+                            choice
+                                .embedding
+                                .iter()
+                                .flat_map(|x| x.to_le_bytes())
+                                .collect::<Vec<_>>(),
+                        ),
+                    ),
+                },
+                index: choice.index,
+            })
+            .collect();
+        Self {
+            object: "list",
+            data,
+            model: response.model,
+            usage: EmbeddingListUsage {
+                prompt_tokens: response.usage.prompt_tokens,
+                total_tokens: response.usage.total_tokens,
+            },
+        }
+    }
+}
+
+pub enum EmbeddingResponder {
+    Json(EmbeddingList),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+    ModelNotFound(String),
+}
+
+impl IntoResponse for EmbeddingResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            EmbeddingResponder::Json(s) => Json(s).into_response(),
+            EmbeddingResponder::InternalError(e) => JsonError::server_error(e.to_string())
+                .to_response(http::StatusCode::INTERNAL_SERVER_ERROR),
+            EmbeddingResponder::ValidationError(e) => JsonError::invalid_request(e.to_string())
+                .to_response(http::StatusCode::UNPROCESSABLE_ENTITY),
+            EmbeddingResponder::ModelNotFound(model) => {
+                JsonError::model_not_found(&model).to_response(http::StatusCode::NOT_FOUND)
+            }
+        }
+    }
+}
+
+fn parse_request(
+    oairequest: EmbeddingRequest,
+    state: Arc<MistralRs>,
+    tx: Sender<Response>,
+) -> Result<Request> {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    Ok(Request::Normal(NormalRequest {
+        id: state.next_request_id(),
+        messages: RequestMessage::Embedding {
+            inputs: oairequest
+                .input
+                .either(|inputs| inputs, |input| vec![input]),
+        },
+        sampling_params: SamplingParams::deterministic(),
+        response: tx,
+        return_logprobs: false,
+        is_streaming: false,
+        suffix: None,
+        constraint: Constraint::None,
+        adapters: None,
+        tool_choice: None,
+        tools: None,
+        logits_processors: None,
+        chat_template: None,
+        user: None,
+        priority: None,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/embeddings",
+    request_body = EmbeddingRequest,
+    responses((status = 200, description = "Embeddings"))
+)]
+pub async fn embeddings(
+    State(state): State<Arc<MistralRs>>,
+    JsonBody(oairequest): JsonBody<EmbeddingRequest>,
+) -> EmbeddingResponder {
+    let Some(state) = state.get_model(&oairequest.model) else {
+        return EmbeddingResponder::ModelNotFound(oairequest.model);
+    };
+    let (tx, mut rx) = util::response_channel();
+    let encoding_format = oairequest.encoding_format;
+
+    if oairequest
+        .input
+        .as_ref()
+        .left()
+        .is_some_and(|inputs| inputs.is_empty())
+    {
+        return EmbeddingResponder::ValidationError(
+            "`input` must contain at least one string.".into(),
+        );
+    }
+
+    let request = match parse_request(oairequest, state.clone(), tx) {
+        Ok(x) => x,
+        Err(e) => {
+            let e = anyhow::Error::msg(e.to_string());
+            MistralRs::maybe_log_error(state, &*e);
+            return EmbeddingResponder::InternalError(e.into());
+        }
+    };
+    let sender = state.get_sender().unwrap();
+
+    if let Err(e) = sender.send(request).await {
+        let e = anyhow::Error::msg(e.to_string());
+        MistralRs::maybe_log_error(state, &*e);
+        return EmbeddingResponder::InternalError(e.into());
+    }
+
+    let response = match rx.recv().await {
+        Some(response) => response,
+        None => {
+            let e = anyhow::Error::msg("No response received from the model.");
+            MistralRs::maybe_log_error(state, &*e);
+            return EmbeddingResponder::InternalError(e.into());
+        }
+    };
+
+    match response {
+        Response::InternalError(e) => {
+            MistralRs::maybe_log_error(state, &*e);
+            EmbeddingResponder::InternalError(e)
+        }
+        Response::ValidationError(e) => EmbeddingResponder::ValidationError(e),
+        Response::Embeddings(response) => {
+            MistralRs::maybe_log_response(state, &response);
+            EmbeddingResponder::Json(EmbeddingList::new(response, encoding_format))
+        }
+        Response::CompletionModelError(m, _) | Response::ModelError(m, _) => {
+            let e = anyhow::Error::msg(m.to_string());
+            MistralRs::maybe_log_error(state, &*e);
+            EmbeddingResponder::InternalError(e.into())
+        }
+        Response::CompletionDone(_) => unreachable!(),
+        Response::CompletionChunk(_) => unreachable!(),
+        Response::Chunk(_) => unreachable!(),
+        Response::Done(_) => unreachable!(),
+        Response::ImageGeneration(_) => unreachable!(),
+    }
+}
@@ -0,0 +1,198 @@
+//! Error responses in the OpenAI format, `{"error": {"message", "type", "param", "code"}}`.
+
+use axum::{
+    async_trait,
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::StatusCode,
+    response::{sse::Event, IntoResponse, Response},
+    Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+/// The `type` of an error, which tells clients whether retrying the same request can succeed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorType {
+    /// The request is invalid and will fail again if retried unchanged.
+    InvalidRequestError,
+    /// The server or the model failed while handling a valid request.
+    ServerError,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    message: String,
+    #[serde(rename = "type")]
+    error_type: ErrorType,
+    param: Option<String>,
+    code: Option<&'static str>,
+    /// What the model generated before it failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partial_response: Option<Value>,
+}
+
+#[derive(Serialize)]
+pub struct JsonError {
+    error: ErrorBody,
+}
+
+impl JsonError {
+    pub fn new(message: String, error_type: ErrorType) -> Self {
+        Self {
+            error: ErrorBody {
+                message,
+                error_type,
+                param: None,
+                code: None,
+                partial_response: None,
+            },
+        }
+    }
+
+    /// A request the server rejected before running it.
+    pub fn invalid_request(message: String) -> Self {
+        Self::new(message, ErrorType::InvalidRequestError)
+    }
+
+    /// A failure while handling the request.
+    pub fn server_error(message: String) -> Self {
+        Self::new(message, ErrorType::ServerError)
+    }
+
+    /// A failure of the model partway through generating `partial_response`.
+    pub fn model_error(message: String, partial_response: impl Serialize) -> Self {
+        let mut this = Self::server_error(message).with_code("model_error");
+        this.error.partial_response = serde_json::to_value(partial_response).ok();
+        this
+    }
+
+    /// A request naming a model which is not being served.
+    pub fn model_not_found(model: &str) -> Self {
+        Self::invalid_request(format!("The model `{model}` does not exist."))
+            .with_code("model_not_found")
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.error.code = Some(code);
+        self
+    }
+
+    pub fn to_response(&self, code: StatusCode) -> Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = code;
+        r
+    }
+
+    /// This error as an SSE event named `error`, for failures after a stream has started.
+    pub fn to_sse_event(&self) -> Result<Event, axum::Error> {
+        Event::default().event("error").json_data(self)
+    }
+}
+
+/// Axum's `Json` extractor, but rejecting a body which is not valid JSON or does not match the
+/// request type with this error format rather than axum's plain text, as OpenAI SDKs expect.
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(Self(value)),
+            Err(rejection) => Err(json_rejection_response(rejection)),
+        }
+    }
+}
+
+/// Malformed JSON is a 400, as is a body which is not JSON. A body of the wrong shape keeps
+/// axum's 422, like the other validation errors.
+fn json_rejection_response(rejection: JsonRejection) -> Response {
+    let status = match rejection {
+        JsonRejection::JsonDataError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    JsonError::invalid_request(rejection.body_text()).to_response(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::header,
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_invalid_request_body() {
+        let response =
+            JsonError::invalid_request("`input` must contain at least one string.".into())
+                .to_response(StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "error": {
+                    "message": "`input` must contain at least one string.",
+                    "type": "invalid_request_error",
+                    "param": null,
+                    "code": null,
+                }
+            })
+        );
+    }
+
+    async fn post_json(body: &'static str) -> (StatusCode, Value) {
+        let request = axum::http::Request::post("/v1/chat/completions")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        let Err(response) = JsonBody::<Vec<u32>>::from_request(request, &()).await else {
+            panic!("Expected the body to be rejected.");
+        };
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_json_body() {
+        let (status, body) = post_json("{bad json").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(message.starts_with("Failed to parse the request body as JSON"));
+        assert!(message.contains("line 1 column 2"), "{message}");
+
+        let (status, body) = post_json(r#"{"model": "default"}"#).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+    }
+
+    #[test]
+    fn test_model_error_nests_partial_response() {
+        let error = JsonError::model_error("Out of memory.".into(), json!({"choices": []}));
+        assert_eq!(
+            serde_json::to_value(error).unwrap(),
+            json!({
+                "error": {
+                    "message": "Out of memory.",
+                    "type": "server_error",
+                    "param": null,
+                    "code": "model_error",
+                    "partial_response": {"choices": []},
+                }
+            })
+        );
+    }
+}
@@ -0,0 +1,436 @@
+//! Deduplication of completion requests by their `Idempotency-Key` header, so that a client or
+//! proxy retrying a request does not run the same generation twice. The first request with a key
+//! runs to completion even if its client disconnects, and its response is recorded as it is
+//! produced. Any other request with the key gets the recorded response replayed, followed live if
+//! it is still being generated, which fans out a stream to every client. Responses are kept for
+//! `MISTRALRS_IDEMPOTENCY_TTL_SECS` seconds, 60 by default, after they finish, except for server
+//! errors, which may succeed when retried, and responses larger than [`MAX_RECORDED_BYTES`], which
+//! are only sent to the request which ran them.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    env,
+    future::Future,
+    hash::{Hash, Hasher},
+    io,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use futures::{stream, StreamExt};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    time::Instant,
+};
+
+use crate::{error::JsonError, MB_TO_B, N_INPUT_SIZE};
+
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+/// Set on responses which were replayed rather than generated for the request.
+const IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+const DEFAULT_TTL_SECS: u64 = 60;
+/// The largest response body which is recorded for replay.
+pub const MAX_RECORDED_BYTES: usize = 16 * MB_TO_B;
+
+type Entries = Mutex<HashMap<String, Arc<Entry>>>;
+
+/// A response, as far as it has been produced.
+#[derive(Default)]
+struct Recording {
+    head: Option<(StatusCode, HeaderMap)>,
+    chunks: Vec<Bytes>,
+    /// The length of `chunks`, in bytes.
+    n_bytes: usize,
+    /// The body outgrew the maximum, so that it is no longer recorded and cannot be replayed.
+    overflowed: bool,
+    finished_at: Option<Instant>,
+}
+
+impl Recording {
+    /// Record `chunk`, unless the body would outgrow `max_bytes`, in which case the recorded
+    /// chunks are dropped too. Returns whether the body overflowed with this chunk.
+    fn push(&mut self, chunk: Bytes, max_bytes: usize) -> bool {
+        if self.overflowed {
+            return false;
+        }
+        if self.n_bytes + chunk.len() > max_bytes {
+            self.overflowed = true;
+            self.chunks = Vec::new();
+            return true;
+        }
+        self.n_bytes += chunk.len();
+        self.chunks.push(chunk);
+        false
+    }
+}
+
+struct Entry {
+    /// Hash of the request which was run, to detect a key being reused for another request.
+    request_hash: u64,
+    recording: watch::Sender<Recording>,
+}
+
+/// A request got a key which was already used for a different request.
+#[derive(Debug)]
+pub struct KeyReused;
+
+#[derive(Clone)]
+pub struct IdempotencyStore {
+    entries: Arc<Entries>,
+    ttl: Duration,
+    max_recorded_bytes: usize,
+}
+
+impl IdempotencyStore {
+    /// A store keeping finished responses for `ttl`. Expired responses are dropped every `ttl` in
+    /// the background, as well as whenever a new key is used.
+    pub fn new(ttl: Duration) -> Self {
+        let entries = Arc::new(Mutex::new(HashMap::new()));
+        tokio::spawn(sweep(Arc::downgrade(&entries), ttl));
+        Self {
+            entries,
+            ttl,
+            max_recorded_bytes: MAX_RECORDED_BYTES,
+        }
+    }
+
+    /// A store keeping finished responses for `MISTRALRS_IDEMPOTENCY_TTL_SECS`.
+    pub fn from_env() -> Self {
+        let ttl_secs = env::var("MISTRALRS_IDEMPOTENCY_TTL_SECS")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_TTL_SECS);
+        Self::new(Duration::from_secs(ttl_secs))
+    }
+
+    /// The response for the request with `key` and `request_hash`: that of `run` if no response is
+    /// recorded for `key`, or else the recorded one.
+    pub async fn respond(
+        &self,
+        key: String,
+        request_hash: u64,
+        run: impl Future<Output = Response> + Send + 'static,
+    ) -> Result<Response, KeyReused> {
+        let (entry, is_new) = self.entry(&key, request_hash);
+        if entry.request_hash != request_hash {
+            return Err(KeyReused);
+        }
+        if !is_new {
+            let mut response = replay(entry.recording.subscribe()).await;
+            response
+                .headers_mut()
+                .insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+            return Ok(response);
+        }
+
+        // The request which runs the generation gets its body directly, as it may not be recorded
+        // in full.
+        let (head_tx, head_rx) = oneshot::channel();
+        let (body_tx, body_rx) = mpsc::channel(16);
+        tokio::spawn(self.clone().record(key, entry, run, head_tx, body_tx));
+        let Ok((status, headers)) = head_rx.await else {
+            return Ok(
+                JsonError::server_error("The request was dropped.".to_string())
+                    .to_response(StatusCode::INTERNAL_SERVER_ERROR),
+            );
+        };
+        let body = stream::unfold(body_rx, |mut body_rx| async move {
+            let chunk = body_rx.recv().await?;
+            Some((Ok::<_, io::Error>(chunk), body_rx))
+        });
+        let mut response = Response::new(Body::from_stream(body));
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        Ok(response)
+    }
+
+    /// The entry for `key`, and whether it was created by this call.
+    fn entry(&self, key: &str, request_hash: u64) -> (Arc<Entry>, bool) {
+        let mut entries = self.entries.lock().expect("`entries` was poisoned");
+        prune(&mut entries, self.ttl);
+        if let Some(entry) = entries.get(key) {
+            return (entry.clone(), false);
+        }
+        let entry = Arc::new(Entry {
+            request_hash,
+            recording: watch::channel(Recording::default()).0,
+        });
+        entries.insert(key.to_string(), entry.clone());
+        (entry, true)
+    }
+
+    /// Run the request, sending its response to `head_tx` and `body_tx` and recording it into
+    /// `entry` as it is produced. The generation runs to completion even if they are dropped.
+    async fn record(
+        self,
+        key: String,
+        entry: Arc<Entry>,
+        run: impl Future<Output = Response> + Send + 'static,
+        head_tx: oneshot::Sender<(StatusCode, HeaderMap)>,
+        body_tx: mpsc::Sender<Bytes>,
+    ) {
+        let (parts, body) = run.await.into_parts();
+        let status = parts.status;
+        entry
+            .recording
+            .send_modify(|recording| recording.head = Some((status, parts.headers.clone())));
+        let _ = head_tx.send((status, parts.headers));
+        let mut body = body.into_data_stream();
+        while let Some(Ok(chunk)) = body.next().await {
+            let _ = body_tx.send(chunk.clone()).await;
+            let mut overflowed = false;
+            entry.recording.send_modify(|recording| {
+                overflowed = recording.push(chunk, self.max_recorded_bytes);
+            });
+            if overflowed {
+                // Retries run the request again, rather than getting part of its response.
+                self.release(&key, &entry);
+            }
+        }
+        entry
+            .recording
+            .send_modify(|recording| recording.finished_at = Some(Instant::now()));
+
+        if status.is_server_error() {
+            self.release(&key, &entry);
+        }
+    }
+
+    /// Forget the response of `entry`, if it is still the one recorded for `key`.
+    fn release(&self, key: &str, entry: &Arc<Entry>) {
+        let mut entries = self.entries.lock().expect("`entries` was poisoned");
+        if entries
+            .get(key)
+            .is_some_and(|other| Arc::ptr_eq(other, entry))
+        {
+            entries.remove(key);
+        }
+    }
+}
+
+/// Drop the responses which finished more than `ttl` ago.
+fn prune(entries: &mut HashMap<String, Arc<Entry>>, ttl: Duration) {
+    entries.retain(|_, entry| {
+        entry
+            .recording
+            .borrow()
+            .finished_at
+            .map_or(true, |finished_at| finished_at.elapsed() < ttl)
+    });
+}
+
+/// Prune `entries` every `ttl`, so that responses do not outlive it when no new keys are used,
+/// until the store is dropped.
+async fn sweep(entries: Weak<Entries>, ttl: Duration) {
+    let mut interval = tokio::time::interval(ttl.max(Duration::from_secs(1)));
+    loop {
+        interval.tick().await;
+        let Some(entries) = entries.upgrade() else {
+            return;
+        };
+        prune(&mut entries.lock().expect("`entries` was poisoned"), ttl);
+    }
+}
+
+/// The recorded response, whose body follows the recording until it finishes. If the recording
+/// overflows, the body is cut off with an error.
+async fn replay(mut recording: watch::Receiver<Recording>) -> Response {
+    let (status, headers) = loop {
+        if let Some(head) = recording.borrow_and_update().head.clone() {
+            break head;
+        }
+        if recording.changed().await.is_err() {
+            return JsonError::server_error("The original request was dropped.".to_string())
+                .to_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let body = stream::unfold(Some((recording, 0)), |state| async move {
+        let (mut recording, sent) = state?;
+        loop {
+            let (chunks, finished) = {
+                let current = recording.borrow_and_update();
+                if current.overflowed {
+                    let e = io::Error::other("The response is too large to be replayed.");
+                    return Some((Err(e), None));
+                }
+                (
+                    current.chunks[sent..].to_vec(),
+                    current.finished_at.is_some(),
+                )
+            };
+            if !chunks.is_empty() {
+                let sent = sent + chunks.len();
+                return Some((
+                    Ok::<_, io::Error>(Bytes::from(chunks.concat())),
+                    Some((recording, sent)),
+                ));
+            }
+            if finished || recording.changed().await.is_err() {
+                return None;
+            }
+        }
+    });
+
+    let mut response = Response::new(Body::from_stream(body));
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    response
+}
+
+/// Deduplicate requests carrying an `Idempotency-Key` header with `store`. Keys are scoped to the
+/// `Authorization` header, so that clients with different API keys never share responses.
+pub async fn deduplicate(
+    State(store): State<IdempotencyStore>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = request
+        .headers()
+        .get(IDEMPOTENCY_KEY)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+    else {
+        return next.run(request).await;
+    };
+    let authorization = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let key = format!("{authorization}\n{key}");
+
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, N_INPUT_SIZE * MB_TO_B).await {
+        Ok(body) => body,
+        Err(e) => {
+            return JsonError::invalid_request(e.to_string())
+                .to_response(StatusCode::PAYLOAD_TOO_LARGE)
+        }
+    };
+    let mut hasher = DefaultHasher::new();
+    parts.uri.path().hash(&mut hasher);
+    body.hash(&mut hasher);
+    let request_hash = hasher.finish();
+
+    let request = Request::from_parts(parts, Body::from(body));
+    match store.respond(key, request_hash, next.run(request)).await {
+        Ok(response) => response,
+        Err(KeyReused) => JsonError::invalid_request(
+            "This `Idempotency-Key` was already used for a different request.".to_string(),
+        )
+        .with_code("idempotency_key_reused")
+        .to_response(StatusCode::UNPROCESSABLE_ENTITY),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use axum::body::to_bytes;
+
+    use super::*;
+
+    /// A generation which streams two chunks, counting how often it was run.
+    fn generation(runs: Arc<AtomicUsize>) -> impl Future<Output = Response> + Send + 'static {
+        async move {
+            runs.fetch_add(1, Ordering::SeqCst);
+            let chunks =
+                stream::iter(["data: Hello\n\n", "data: world\n\n"]).then(|chunk| async move {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    Ok::<_, Infallible>(chunk)
+                });
+            Response::new(Body::from_stream(chunks))
+        }
+    }
+
+    async fn body(response: Response) -> Bytes {
+        to_bytes(response.into_body(), usize::MAX).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_with_a_key_run_once() {
+        let store = IdempotencyStore::new(Duration::from_secs(60));
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let (first, second) = tokio::join!(
+            store.respond("key".to_string(), 1, generation(runs.clone())),
+            store.respond("key".to_string(), 1, generation(runs.clone())),
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert!(!first.headers().contains_key(IDEMPOTENT_REPLAYED));
+        assert_eq!(second.headers()[IDEMPOTENT_REPLAYED], "true");
+        let (first, second) = tokio::join!(body(first), body(second));
+        assert_eq!(first, "data: Hello\n\ndata: world\n\n");
+        assert_eq!(first, second);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // A finished response is replayed too, but not for a different request.
+        let third = store
+            .respond("key".to_string(), 1, generation(runs.clone()))
+            .await
+            .unwrap();
+        assert_eq!(body(third).await, first);
+        assert!(store
+            .respond("key".to_string(), 2, generation(runs.clone()))
+            .await
+            .is_err());
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_expired_responses_are_swept() {
+        let store = IdempotencyStore::new(Duration::from_secs(1));
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let response = store
+            .respond("key".to_string(), 1, generation(runs.clone()))
+            .await
+            .unwrap();
+        body(response).await;
+        assert_eq!(store.entries.lock().unwrap().len(), 1);
+
+        // Without any other request with a key.
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert!(store.entries.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_responses_over_the_maximum_are_not_recorded() {
+        let store = IdempotencyStore {
+            max_recorded_bytes: "data: Hello\n\n".len(),
+            ..IdempotencyStore::new(Duration::from_secs(60))
+        };
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let (first, second) = tokio::join!(
+            store.respond("key".to_string(), 1, generation(runs.clone())),
+            store.respond("key".to_string(), 1, generation(runs.clone())),
+        );
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_eq!(second.headers()[IDEMPOTENT_REPLAYED], "true");
+        let (first, second) = tokio::join!(body(first), to_bytes(second.into_body(), usize::MAX));
+        // The request which ran gets the whole response, and the replay is cut off.
+        assert_eq!(first, "data: Hello\n\ndata: world\n\n");
+        assert!(second.is_err());
+        assert!(store.entries.lock().unwrap().is_empty());
+
+        // So that a retry runs again.
+        let third = store
+            .respond("key".to_string(), 1, generation(runs.clone()))
+            .await
+            .unwrap();
+        assert_eq!(body(third).await, first);
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,54 +1,41 @@
 use anyhow::Result;
 use std::{error::Error, sync::Arc};
-use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::mpsc::Sender;
 
-use crate::openai::ImageGenerationRequest;
+use crate::{
+    error::{JsonBody, JsonError},
+    openai::ImageGenerationRequest,
+    util,
+};
 use axum::{
     extract::{Json, State},
-    http::{self, StatusCode},
+    http,
     response::IntoResponse,
 };
 use mistralrs_core::{
     Constraint, DiffusionGenerationParams, ImageGenerationResponse, MistralRs, NormalRequest,
     Request, RequestMessage, Response, SamplingParams,
 };
-use serde::Serialize;
 
 pub enum ImageGenerationResponder {
     Json(ImageGenerationResponse),
     InternalError(Box<dyn Error>),
     ValidationError(Box<dyn Error>),
+    ModelNotFound(String),
 }
 
-trait ErrorToResponse: Serialize {
-    fn to_response(&self, code: StatusCode) -> axum::response::Response {
-        let mut r = Json(self).into_response();
-        *r.status_mut() = code;
-        r
-    }
-}
-
-#[derive(Serialize)]
-struct JsonError {
-    message: String,
-}
-
-impl JsonError {
-    fn new(message: String) -> Self {
-        Self { message }
-    }
-}
-impl ErrorToResponse for JsonError {}
-
 impl IntoResponse for ImageGenerationResponder {
     fn into_response(self) -> axum::response::Response {
         match self {
             ImageGenerationResponder::Json(s) => Json(s).into_response(),
-            ImageGenerationResponder::InternalError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
-            }
+            ImageGenerationResponder::InternalError(e) => JsonError::server_error(e.to_string())
+                .to_response(http::StatusCode::INTERNAL_SERVER_ERROR),
             ImageGenerationResponder::ValidationError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+                JsonError::invalid_request(e.to_string())
+                    .to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            }
+            ImageGenerationResponder::ModelNotFound(model) => {
+                JsonError::model_not_found(&model).to_response(http::StatusCode::NOT_FOUND)
             }
         }
     }
@@ -82,6 +69,9 @@ fn parse_request(
         tool_choice: None,
         tools: None,
         logits_processors: None,
+        chat_template: None,
+        user: None,
+        priority: None,
     }))
 }
 
@@ -95,9 +85,12 @@ fn parse_request(
 
 pub async fn image_generation(
     State(state): State<Arc<MistralRs>>,
-    Json(oairequest): Json<ImageGenerationRequest>,
+    JsonBody(oairequest): JsonBody<ImageGenerationRequest>,
 ) -> ImageGenerationResponder {
-    let (tx, mut rx) = channel(10_000);
+    let Some(state) = state.get_model(&oairequest.model) else {
+        return ImageGenerationResponder::ModelNotFound(oairequest.model);
+    };
+    let (tx, mut rx) = util::response_channel();
 
     let request = match parse_request(oairequest, state.clone(), tx) {
         Ok(x) => x,
@@ -144,5 +137,6 @@ pub async fn image_generation(
         Response::Chunk(_) => unreachable!(),
         Response::Done(_) => unreachable!(),
         Response::ModelError(_, _) => unreachable!(),
+        Response::Embeddings(_) => unreachable!(),
     }
 }
@@ -11,7 +11,6 @@ use std::{
     sync::{atomic::Ordering, Arc, Mutex},
     time::Instant,
 };
-use tokio::sync::mpsc::channel;
 use tracing::{error, info};
 
 use crate::util;
@@ -88,11 +87,29 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repetition_context_size: None,
         max_len: Some(4096),
+        max_chars: None,
         stop_toks: None,
         logits_bias: None,
+        logits_bias_strs: None,
+        logits_bias_patterns: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        seed: None,
+        mirostat: None,
+        penalty_alpha: None,
+        include_stop_str_in_output: false,
+        prompt_logprobs: None,
+        return_raw_tokens: false,
+        continuous_usage_stats: false,
+        stream_granularity: None,
+        sampler_order: None,
+        typical_p: None,
+        eta_cutoff: None,
+        epsilon_cutoff: None,
+        penalize_prompt: None,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -160,7 +177,7 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
 
         let request_messages = RequestMessage::Chat(messages.clone());
 
-        let (tx, mut rx) = channel(10_000);
+        let (tx, mut rx) = util::response_channel();
         let req = Request::Normal(NormalRequest {
             id: mistralrs.next_request_id(),
             messages: request_messages,
@@ -174,6 +191,9 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            chat_template: util::chat_template_for(None),
+            user: None,
+            priority: None,
         });
         sender.send(req).await.unwrap();
 
@@ -213,6 +233,7 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
             }
         }
         if throughput {
@@ -242,11 +263,29 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repetition_context_size: None,
         max_len: Some(4096),
+        max_chars: None,
         stop_toks: None,
         logits_bias: None,
+        logits_bias_strs: None,
+        logits_bias_patterns: None,
         n_choices: 1,
         dry_params: Some(DrySamplingParams::default()),
+        seed: None,
+        mirostat: None,
+        penalty_alpha: None,
+        include_stop_str_in_output: false,
+        prompt_logprobs: None,
+        return_raw_tokens: false,
+        continuous_usage_stats: false,
+        stream_granularity: None,
+        sampler_order: None,
+        typical_p: None,
+        eta_cutoff: None,
+        epsilon_cutoff: None,
+        penalize_prompt: None,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -342,7 +381,7 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
             messages: messages.clone(),
         };
 
-        let (tx, mut rx) = channel(10_000);
+        let (tx, mut rx) = util::response_channel();
         let req = Request::Normal(NormalRequest {
             id: mistralrs.next_request_id(),
             messages: request_messages,
@@ -356,6 +395,9 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            chat_template: util::chat_template_for(None),
+            user: None,
+            priority: None,
         });
         sender.send(req).await.unwrap();
 
@@ -395,6 +437,7 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embeddings(_) => unreachable!(),
             }
         }
         if throughput {
@@ -459,7 +502,7 @@ async fn diffusion_interactive_mode(mistralrs: Arc<MistralRs>) {
         // Set the handler to terminate all seqs, so allowing cancelling running
         *CTRLC_HANDLER.lock().unwrap() = &terminate_handler;
 
-        let (tx, mut rx) = channel(10_000);
+        let (tx, mut rx) = util::response_channel();
         let req = Request::Normal(NormalRequest {
             id: 0,
             messages: RequestMessage::ImageGeneration {
@@ -477,6 +520,9 @@ async fn diffusion_interactive_mode(mistralrs: Arc<MistralRs>) {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            chat_template: None,
+            user: None,
+            priority: None,
         });
         sender.send(req).await.unwrap();
 
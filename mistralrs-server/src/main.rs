@@ -1,7 +1,8 @@
 use anyhow::Result;
 use axum::{
-    extract::{DefaultBodyLimit, Json, State},
-    http::{self, Method},
+    extract::{DefaultBodyLimit, Json, Path, Query, State},
+    http, middleware,
+    response::IntoResponse,
     routing::{get, post},
     Router,
 };
@@ -9,34 +10,75 @@ use candle_core::Device;
 use clap::Parser;
 use mistralrs_core::{
     get_model_dtype, get_tgt_non_granular_index, initialize_logging, paged_attn_supported,
-    parse_isq_value, DefaultSchedulerMethod, DeviceLayerMapMetadata, DeviceMapMetadata, IsqType,
-    Loader, LoaderBuilder, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelSelected,
-    PagedAttentionConfig, Request, SchedulerConfig, TokenSource,
+    parse_isq_value, DefaultSchedulerMethod, DeviceLayerMapMetadata, DeviceMapMetadata,
+    EngineStats, IsqType, KvCacheDtype, Loader, LoaderBuilder, MemoryGpuConfig, MistralRs,
+    MistralRsBuilder, ModelSelected, NGramSpeculativeConfig, NGramSpeculativeLoader,
+    PagedAttentionConfig, ReasoningDelimiters, RegexOutputFilter, Request, SchedulerConfig,
+    SpeculativeConfig, SpeculativeLoader, TokenSource,
 };
 use openai::{
-    ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, Message, ModelObjects,
-    StopTokens,
+    ChatCompletionRequest, CompletionRequest, DetokenizeRequest, EmbeddingRequest, EncodingFormat,
+    ImageGenerationRequest, Message, ModelObjects, StopTokens, TokenizeRequest,
 };
 use serde::{Deserialize, Serialize};
-use std::{num::NonZeroUsize, sync::Arc};
+use std::{num::NonZeroUsize, sync::Arc, time::Duration};
 
+mod access_log;
+mod auth;
 mod chat_completion;
 mod completions;
+mod compression;
+mod concurrency;
+mod cors;
+mod devices;
+mod embeddings;
+mod error;
+mod idempotency;
 mod image_generation;
 mod interactive_mode;
+mod metrics;
+mod models;
 mod openai;
+mod rate_limit;
+mod registry;
+mod request_id;
+mod shutdown;
+mod tokenize;
 mod util;
+mod warmup;
 
 use crate::openai::ModelObject;
 use crate::{
-    chat_completion::{__path_chatcompletions, chatcompletions},
-    completions::completions,
+    auth::{require_auth, verifier_from_env, ApiKeys, AuthVerifier},
+    chat_completion::{
+        __path_chatcompletions, __path_chatcompletions_batch, chatcompletions,
+        chatcompletions_batch, chatcompletions_ws, DryRunResponse,
+    },
+    completions::{__path_completions, completions},
+    compression::compression_layer,
+    cors::cors_layer,
+    embeddings::{__path_embeddings, embeddings},
+    error::{JsonBody, JsonError},
+    idempotency::{deduplicate, IdempotencyStore},
     image_generation::image_generation,
+    models::{
+        __path_load_model, __path_models, __path_unload_model, load_model, models, unload_model,
+        ModelLoadRequest, ModelLoader, ModelUnloadRequest,
+    },
+    rate_limit::{limit_tokens, TokenBudgets},
+    registry::{registry, InFlightRequest},
+    request_id::propagate_request_id,
+    shutdown::reject_during_shutdown,
+    tokenize::{
+        __path_detokenize, __path_tokenize, detokenize, tokenize, DetokenizeResponse,
+        TokenizeResponse,
+    },
 };
 
 use interactive_mode::interactive_mode;
-use tower_http::cors::{AllowOrigin, CorsLayer};
-use tracing::{info, warn};
+use metrics_exporter_prometheus::PrometheusHandle;
+use tower_http::cors::CorsLayer;
+use tracing::{debug, info, warn};
 use utoipa::{OpenApi, ToSchema};
 use utoipa_swagger_ui::SwaggerUi;
 
@@ -75,7 +117,17 @@ struct Args {
 
     /// Model selector
     #[clap(subcommand)]
-    model: ModelSelected,
+    model: Option<ModelSelected>,
+
+    /// Serve this GGUF file instead of selecting a model with a subcommand. Its tokenizer and chat
+    /// template are read from the GGUF metadata, unless `--tokenizer` is given.
+    #[arg(long)]
+    gguf: Option<String>,
+
+    /// With `--gguf`, the local or remote model ID with the `tokenizer.json` and
+    /// `tokenizer_config.json` to use over those in the GGUF metadata.
+    #[arg(long, requires = "gguf")]
+    tokenizer: Option<String>,
 
     /// Maximum running sequences at any time. If the `tgt_non_granular_index` flag is set for X-LoRA models, this will be set to 1.
     #[arg(long, default_value_t = 16)]
@@ -85,11 +137,31 @@ struct Args {
     #[arg(long, default_value_t = false)]
     no_kv_cache: bool,
 
+    /// How to store the KV cache: `f16` keeps the model's dtype, while `q8` and `q4` quantize it to
+    /// save memory. Quantizing the KV cache disables PagedAttention.
+    #[arg(long, default_value_t = KvCacheDtype::F16)]
+    kv_cache_dtype: KvCacheDtype,
+
     /// JINJA chat template with `messages`, `add_generation_prompt`, `bos_token`, `eos_token`, and `unk_token` as inputs.
-    /// Used if the automatic deserialization fails. If this ends with `.json` (ie., it is a file) then that template is loaded.
+    /// Overrides the model's chat template. If this ends with `.json` (ie., it is a file) then the `chat_template` there is
+    /// loaded, and otherwise it may be the path of a template file or the template itself.
     #[arg(short, long)]
     chat_template: Option<String>,
 
+    /// Delimiters around the reasoning of models which think before answering, such as `<think> </think>`. Streamed
+    /// chat completions send the text inside them as `delta.reasoning_content` instead of `delta.content`.
+    #[arg(long, num_args = 2, value_names = ["OPEN", "CLOSE"])]
+    reasoning_delimiters: Option<Vec<String>>,
+
+    /// Regex which stops a completion with the `content_filter` finish reason once its output matches it, such as
+    /// `(?i)\bbanned\b`. May be given multiple times.
+    #[arg(long = "content-filter")]
+    content_filters: Vec<String>,
+
+    /// Cut outputs stopped by `--content-filter` before the matching text rather than after it.
+    #[arg(long = "redact-content-filter", requires = "content_filters")]
+    redact_content_filter: bool,
+
     /// Source of the token for authentication.
     /// Can be in the formats: `literal:<value>`, `env:<value>`, `path:<value>`, `cache` to use a cached token, or `none` to use no token.
     /// Defaults to `cache`.
@@ -110,6 +182,21 @@ struct Args {
     #[arg(short, long, value_parser, value_delimiter = ';')]
     num_device_layers: Option<Vec<String>>,
 
+    /// Comma-separated ordinals of the GPUs to load the model on, such as `1` or `0,1`. The model is loaded on the
+    /// first one unless `--num-shards` is set. Falls back to `MISTRALRS_DEVICE`, and else to GPU 0 if there is one.
+    #[arg(long = "device-ids", value_delimiter = ',')]
+    device_ids: Vec<usize>,
+
+    /// Number of the `--device-ids` GPUs to shard the model across. Its repeating layers are split evenly over the
+    /// first this many, as with `--num-device-layers`. mistral.rs has no tensor parallelism, so the GPUs run their
+    /// layers in turn.
+    #[arg(
+        long = "num-shards",
+        default_value_t = 1,
+        conflicts_with = "num_device_layers"
+    )]
+    num_shards: usize,
+
     /// In-situ quantization to apply. You may specify one of the GGML data type (except F32 or F16): formatted like this: `Q4_0` or `Q4K`.
     #[arg(long = "isq", value_parser = parse_isq_value)]
     in_situ_quant: Option<IsqType>,
@@ -146,37 +233,123 @@ struct Args {
     #[arg(long = "throughput", default_value_t = false)]
     throughput_log: bool,
 
+    /// API key clients must send as `Authorization: Bearer <key>`. May be given multiple times, and keys from the
+    /// comma-separated `MISTRALRS_API_KEY` environment variable are also accepted. If none are set, there is no authentication.
+    #[arg(long = "api-key")]
+    api_keys: Vec<String>,
+
+    /// Origin allowed to make cross-origin requests, such as `https://example.com`. May be given multiple times, and
+    /// origins from the comma-separated `MISTRALRS_CORS_ORIGINS` environment variable are also allowed. If none are
+    /// set, or any is `*`, all origins are allowed.
+    #[arg(long = "cors-origin")]
+    cors_origins: Vec<String>,
+
+    /// Seconds to let running sequences finish after `SIGTERM` or Ctrl-C before they are cut off. New requests are
+    /// rejected with a 503 meanwhile.
+    #[arg(long = "shutdown-grace-secs", default_value_t = 30)]
+    shutdown_grace_secs: u64,
+
+    /// Number of responses the engine may queue for a request before waiting for the client to read them. Defaults to
+    /// the `MISTRALRS_RESPONSE_BUFFER_SIZE` environment variable, or else 256.
+    #[arg(long)]
+    response_buffer_size: Option<usize>,
+
+    /// Milliseconds without a chunk after which a stream sends a keep-alive comment. Defaults to the
+    /// `KEEP_ALIVE_INTERVAL` environment variable, or else 1000. 0 disables keep-alive comments.
+    #[arg(long)]
+    keep_alive_interval_ms: Option<u64>,
+
+    /// Text of the keep-alive comments. Defaults to the `KEEP_ALIVE_TEXT` environment variable, or else
+    /// `keep-alive-text`.
+    #[arg(long)]
+    keep_alive_text: Option<String>,
+
+    /// Never send keep-alive comments in streams, for SSE clients or proxies which do not handle them.
+    #[arg(long, default_value_t = false)]
+    no_keep_alive: bool,
+
+    /// Milliseconds between the empty chunks a chat stream sends while waiting for its first token, for clients
+    /// which only count data events as activity. Defaults to the `MISTRALRS_HEARTBEAT_INTERVAL_MS` environment
+    /// variable, or else no heartbeats.
+    #[arg(long)]
+    heartbeat_interval_ms: Option<u64>,
+
+    /// System prompt to prepend to chat requests which do not include a system message.
+    #[arg(long)]
+    default_system_prompt: Option<String>,
+
+    /// Record Prometheus metrics and serve them at `/metrics`.
+    #[arg(long, default_value_t = false)]
+    metrics: bool,
+
+    /// Serve a snapshot of the scheduler at `/v1/internal/state`, for debugging.
+    #[arg(long = "enable-internal-state", default_value_t = false)]
+    enable_internal_state: bool,
+
+    /// Compress responses with gzip or deflate for clients sending a matching `Accept-Encoding`. SSE streams are
+    /// never compressed.
+    #[arg(long = "enable-compression", default_value_t = false)]
+    enable_compression: bool,
+
+    /// Run a short generation through every model at startup, reporting ready at `/health` only once it is done, so
+    /// that the first request is not slowed down by cold kernels and caches. Also enabled by `MISTRALRS_WARMUP=1`.
+    #[arg(long, default_value_t = false)]
+    warmup: bool,
+
     /// Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
     #[arg(long = "prompt-batchsize")]
     prompt_batchsize: Option<usize>,
-}
 
-#[utoipa::path(
-    get,
-    tag = "Mistral.rs",
-    path = "/v1/models",
-    responses((status = 200, description = "Served model info", body = ModelObjects))
-)]
-async fn models(State(state): State<Arc<MistralRs>>) -> Json<ModelObjects> {
-    Json(ModelObjects {
-        object: "list",
-        data: vec![ModelObject {
-            id: state.get_id(),
-            object: "model",
-            created: state.get_creation_time(),
-            owned_by: "local",
-        }],
-    })
+    /// Run at most this many tokens of a prompt per step, so that other sequences keep generating while a long prompt
+    /// is prefilled, which bounds their latency spikes. The output is unchanged. This disables PagedAttention.
+    #[arg(long = "prefill-chunk-size", conflicts_with = "draft_model")]
+    prefill_chunk_size: Option<usize>,
+
+    /// Model ID of a small plain model to draft tokens with for speculative decoding. This may be a HF hub repo or a
+    /// local path, and it must share the tokenizer of the main model. The draft tokens of all running sequences are
+    /// verified in one batched forward pass of the main model. Speculative decoding runs without PagedAttention.
+    #[arg(long = "draft-model")]
+    draft_model: Option<String>,
+
+    /// Speculative decoding without a draft model: tokens are drafted by looking up the last few tokens of the
+    /// sequence earlier in it, which speeds up outputs repeating their context. Unlike `--draft-model`, this runs one
+    /// sequence at a time, and like it without PagedAttention.
+    #[arg(long = "ngram-speculative", conflicts_with = "draft_model")]
+    ngram_speculative: bool,
+
+    /// Number of tokens proposed per step with `--draft-model` or, at most, with `--ngram-speculative`.
+    #[arg(long = "num-speculative-tokens", default_value_t = 4)]
+    num_speculative_tokens: usize,
+
+    /// Model ID of a further plain model to serve alongside the selected one. This may be a HF hub repo or a local
+    /// path. Requests are routed by their `model` field, and `/v1/models` lists every model. It is loaded with the
+    /// same dtype and ISQ, without device mapping or PagedAttention. May be given multiple times.
+    #[arg(long = "serve-model")]
+    serve_models: Vec<String>,
 }
 
 #[utoipa::path(
     get,
     tag = "Mistral.rs",
     path = "/health",
-    responses((status = 200, description = "Server is healthy"))
+    responses(
+        (status = 200, description = "Server is healthy"),
+        (status = 503, description = "The engine is not running, or is warming up")
+    )
 )]
-async fn health() -> &'static str {
-    "OK"
+async fn health(State(state): State<Arc<MistralRs>>) -> (http::StatusCode, &'static str) {
+    if shutdown::is_shutting_down() {
+        (http::StatusCode::SERVICE_UNAVAILABLE, "Shutting down")
+    } else if warmup::is_warming_up() {
+        (http::StatusCode::SERVICE_UNAVAILABLE, "Warming up")
+    } else if state.is_ready() {
+        (http::StatusCode::OK, "OK")
+    } else {
+        (
+            http::StatusCode::SERVICE_UNAVAILABLE,
+            "Engine is not running",
+        )
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -194,7 +367,7 @@ struct AdapterActivationRequest {
 )]
 async fn activate_adapters(
     State(state): State<Arc<MistralRs>>,
-    Json(request): Json<AdapterActivationRequest>,
+    JsonBody(request): JsonBody<AdapterActivationRequest>,
 ) -> String {
     let repr = format!("Adapter activation: {:?}", request.adapter_names);
     MistralRs::maybe_log_request(state.clone(), repr.clone());
@@ -203,6 +376,119 @@ async fn activate_adapters(
     repr
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+struct AdapterLoadRequest {
+    /// The name under which requests activate the adapter.
+    #[schema(example = "adapter_4")]
+    name: String,
+    /// A local directory or a Hugging Face model ID containing `adapter_config.json` and
+    /// `adapter_model.safetensors`.
+    #[schema(example = "username/my-lora-adapter")]
+    path: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+struct AdapterList {
+    adapters: Vec<String>,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/adapters",
+    request_body = AdapterLoadRequest,
+    responses((status = 200, description = "Load a LoRA adapter into the running model", body = AdapterList))
+)]
+async fn load_adapter(
+    State(state): State<Arc<MistralRs>>,
+    JsonBody(request): JsonBody<AdapterLoadRequest>,
+) -> Result<Json<AdapterList>, axum::response::Response> {
+    let repr = format!("Adapter load: {} from {}", request.name, request.path);
+    MistralRs::maybe_log_request(state.clone(), repr);
+    if request.name.is_empty() {
+        return Err(
+            JsonError::invalid_request("`name` must not be empty.".to_string())
+                .to_response(http::StatusCode::UNPROCESSABLE_ENTITY),
+        );
+    }
+    match state.load_adapter(request.name, request.path).await {
+        Ok(adapters) => Ok(Json(AdapterList { adapters })),
+        Err(e) => {
+            MistralRs::maybe_log_error(state, &*e);
+            Err(JsonError::invalid_request(e.to_string())
+                .to_response(http::StatusCode::UNPROCESSABLE_ENTITY))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CancelQuery {
+    model: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/cancel/{request_id}",
+    params(
+        ("request_id" = usize, Path, description = "The `id` of a response or chunk of the request"),
+        ("model" = Option<String>, Query, description = "The model the request was sent to, by default the default model")
+    ),
+    responses(
+        (status = 200, description = "The request was running and has been canceled"),
+        (status = 404, description = "No running request has this ID, or there is no such model")
+    )
+)]
+async fn cancel_request(
+    State(state): State<Arc<MistralRs>>,
+    Path(request_id): Path<usize>,
+    Query(query): Query<CancelQuery>,
+) -> axum::response::Response {
+    // Request IDs are only unique within a model, so the request is looked up on its model.
+    let model = query.model.unwrap_or_else(|| "default".to_string());
+    let Some(state) = state.get_model(&model) else {
+        return JsonError::model_not_found(&model).to_response(http::StatusCode::NOT_FOUND);
+    };
+    if registry().cancel(&state.get_id(), request_id) {
+        return http::StatusCode::OK.into_response();
+    }
+    match state.cancel_request(request_id).await {
+        Ok(true) => http::StatusCode::OK.into_response(),
+        Ok(false) => JsonError::invalid_request(format!(
+            "No running request with ID {request_id}. It may have already finished."
+        ))
+        .to_response(http::StatusCode::NOT_FOUND),
+        Err(e) => {
+            MistralRs::maybe_log_error(state, &*e);
+            JsonError::server_error(e.to_string())
+                .to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// The snapshot served at `/v1/internal/state`.
+#[derive(Serialize)]
+struct InternalState {
+    #[serde(flatten)]
+    engine: EngineStats,
+    requests: Vec<InFlightRequest>,
+}
+
+async fn internal_state(State(state): State<Arc<MistralRs>>) -> axum::response::Response {
+    match state.get_engine_stats().await {
+        Ok(engine) => Json(InternalState {
+            engine,
+            requests: registry().list(),
+        })
+        .into_response(),
+        Err(e) => {
+            MistralRs::maybe_log_error(state, &*e);
+            JsonError::server_error(e.to_string())
+                .to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 struct ReIsqRequest {
     #[schema(example = "Q4K")]
@@ -218,7 +504,7 @@ struct ReIsqRequest {
 )]
 async fn re_isq(
     State(state): State<Arc<MistralRs>>,
-    Json(request): Json<ReIsqRequest>,
+    JsonBody(request): JsonBody<ReIsqRequest>,
 ) -> Result<String, String> {
     let repr = format!("Re ISQ: {:?}", request.ggml_type);
     MistralRs::maybe_log_request(state.clone(), repr.clone());
@@ -227,12 +513,19 @@ async fn re_isq(
     Ok(repr)
 }
 
-fn get_router(state: Arc<MistralRs>) -> Router {
+fn get_router(
+    state: Arc<MistralRs>,
+    auth: Option<Arc<dyn AuthVerifier>>,
+    cors_layer: CorsLayer,
+    metrics_handle: Option<PrometheusHandle>,
+    enable_internal_state: bool,
+    enable_compression: bool,
+) -> Router {
     #[derive(OpenApi)]
     #[openapi(
-        paths(models, health, chatcompletions),
+        paths(models, health, chatcompletions, chatcompletions_batch, completions, embeddings, tokenize, detokenize, load_adapter, cancel_request, load_model, unload_model),
         components(
-            schemas(ModelObjects, ModelObject, ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, StopTokens, Message)),
+            schemas(ModelObjects, ModelObject, ChatCompletionRequest, CompletionRequest, EmbeddingRequest, EncodingFormat, TokenizeRequest, TokenizeResponse, DryRunResponse, DetokenizeRequest, DetokenizeResponse, ImageGenerationRequest, AdapterLoadRequest, AdapterList, ModelLoadRequest, ModelUnloadRequest, StopTokens, Message)),
         tags(
             (name = "Mistral.rs", description = "Mistral.rs API")
         ),
@@ -247,22 +540,78 @@ fn get_router(state: Arc<MistralRs>) -> Router {
 
     let doc = { ApiDoc::openapi() };
 
-    let allow_origin = AllowOrigin::any();
-    let cors_layer = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
-        .allow_headers([http::header::CONTENT_TYPE, http::header::AUTHORIZATION])
-        .allow_origin(allow_origin);
+    let deduplication = middleware::from_fn_with_state(IdempotencyStore::from_env(), deduplicate);
+
+    // Token budgets are kept per API key, so they need authentication. They cover every route
+    // which generates. Replayed responses are not charged again.
+    let mut chat_completions_route = post(chatcompletions);
+    let mut chat_completions_batch_route = post(chatcompletions_batch);
+    let mut chat_completions_ws_route = get(chatcompletions_ws);
+    let mut completions_route = post(completions);
+    let has_api_keys = auth.as_ref().is_some_and(|auth| auth.identifies_clients());
+    match TokenBudgets::from_env() {
+        Some(budgets) if has_api_keys => {
+            info!("Token budgets per API key are enabled.");
+            let limit = middleware::from_fn_with_state(budgets, limit_tokens);
+            chat_completions_route = chat_completions_route.route_layer(limit.clone());
+            chat_completions_batch_route = chat_completions_batch_route.route_layer(limit.clone());
+            chat_completions_ws_route = chat_completions_ws_route.route_layer(limit.clone());
+            completions_route = completions_route.route_layer(limit);
+        }
+        Some(_) => warn!(
+            "`MISTRALRS_TOKENS_PER_MINUTE` is ignored, as token budgets are per API key and no API keys are configured."
+        ),
+        None => {}
+    }
 
-    Router::new()
-        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", doc))
-        .route("/v1/chat/completions", post(chatcompletions))
-        .route("/v1/completions", post(completions))
+    // Everything but the health checks and the docs requires authentication, if it is enabled.
+    let mut protected = Router::new()
+        .route(
+            "/v1/chat/completions",
+            chat_completions_route.route_layer(deduplication.clone()),
+        )
+        .route("/v1/chat/completions/batch", chat_completions_batch_route)
+        .route("/v1/chat/completions/ws", chat_completions_ws_route)
+        .route(
+            "/v1/completions",
+            completions_route.route_layer(deduplication),
+        )
+        .route("/v1/embeddings", post(embeddings))
+        .route("/v1/tokenize", post(tokenize))
+        .route("/v1/detokenize", post(detokenize))
         .route("/v1/models", get(models))
-        .route("/health", get(health))
-        .route("/", get(health))
         .route("/activate_adapters", post(activate_adapters))
+        .route("/v1/adapters", post(load_adapter))
+        .route("/v1/cancel/:request_id", post(cancel_request))
+        .route("/v1/internal/load", post(load_model))
+        .route("/v1/internal/unload", post(unload_model))
         .route("/re_isq", post(re_isq))
-        .route("/v1/images/generations", post(image_generation))
+        .route("/v1/images/generations", post(image_generation));
+    if let Some(handle) = metrics_handle {
+        protected = protected.route(
+            "/metrics",
+            get(move |state| metrics::metrics(handle, state)),
+        );
+    }
+    if enable_internal_state {
+        protected = protected.route("/v1/internal/state", get(internal_state));
+    }
+    protected = protected.route_layer(middleware::from_fn(reject_during_shutdown));
+    if let Some(auth) = auth {
+        protected = protected.route_layer(middleware::from_fn_with_state(auth, require_auth));
+    }
+
+    let mut router = Router::new()
+        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", doc))
+        .merge(protected)
+        .route("/health", get(health))
+        .route("/v1/health", get(health))
+        .route("/", get(health));
+    if enable_compression {
+        router = router.layer(compression_layer());
+    }
+    router
+        .layer(middleware::from_fn(propagate_request_id))
         .layer(cors_layer)
         .layer(DefaultBodyLimit::max(N_INPUT_SIZE * MB_TO_B))
         .with_state(state)
@@ -273,17 +622,93 @@ async fn main() -> Result<()> {
     let mut args = Args::parse();
     initialize_logging();
 
+    let response_buffer_size = util::resolve_response_buffer_size(args.response_buffer_size);
+    util::set_response_buffer_size(response_buffer_size);
+    util::set_keep_alive(util::resolve_keep_alive(
+        args.no_keep_alive,
+        args.keep_alive_interval_ms,
+        args.keep_alive_text.take(),
+    ));
+    util::set_heartbeat_interval(util::resolve_heartbeat_interval(args.heartbeat_interval_ms));
+    debug!("Buffering up to {response_buffer_size} responses per request.");
+    if let Some(prompt) = args.default_system_prompt.take() {
+        util::set_default_system_prompt(prompt);
+    }
+    // The loader takes `.json` files as they are, and anything else as the template source.
+    let loader_chat_template = match args.chat_template.take() {
+        Some(arg) => {
+            let template = util::read_chat_template(&arg)
+                .map_err(|e| anyhow::anyhow!("Invalid `--chat-template`: {e}"))?;
+            util::set_chat_template(template.clone());
+            Some(if arg.ends_with(".json") {
+                arg
+            } else {
+                template
+            })
+        }
+        None => None,
+    };
+
     #[cfg(not(feature = "flash-attn"))]
     let use_flash_attn = false;
     #[cfg(feature = "flash-attn")]
     let use_flash_attn = true;
 
-    let tgt_non_granular_index = get_tgt_non_granular_index(&args.model);
-    let dtype = get_model_dtype(&args.model)?;
+    let model = match (args.model.take(), args.gguf.take()) {
+        (Some(model), None) => model,
+        (None, Some(gguf)) => util::gguf_model_selected(&gguf, args.tokenizer.take())?,
+        (Some(_), Some(_)) => {
+            anyhow::bail!(
+                "`--gguf` selects the model, so it cannot be used with a model subcommand."
+            )
+        }
+        (None, None) => {
+            anyhow::bail!("Select a model with a subcommand, or a GGUF file with `--gguf`.")
+        }
+    };
+
+    let tgt_non_granular_index = get_tgt_non_granular_index(&model);
+    let dtype = get_model_dtype(&model)?;
 
     if tgt_non_granular_index.is_some() {
         args.max_seqs = 1;
     }
+    if args.kv_cache_dtype != KvCacheDtype::F16 && !args.no_paged_attn {
+        info!(
+            "Disabling PagedAttention to store the KV cache as `{}`.",
+            args.kv_cache_dtype
+        );
+        args.no_paged_attn = true;
+    }
+    if args.draft_model.is_some() || args.ngram_speculative {
+        if args.num_speculative_tokens == 0 {
+            anyhow::bail!("`num-speculative-tokens` must be a strictly positive integer, got 0.");
+        }
+        if args.ngram_speculative {
+            args.max_seqs = 1;
+        }
+        args.no_paged_attn = true;
+    }
+
+    let output_filter = if args.content_filters.is_empty() {
+        None
+    } else {
+        let filter = RegexOutputFilter::new(&args.content_filters, args.redact_content_filter)
+            .map_err(|e| anyhow::anyhow!("Invalid `--content-filter`: {e}"))?;
+        Some(filter)
+    };
+
+    let prefill_chunk_size = match args.prefill_chunk_size {
+        Some(0) => {
+            anyhow::bail!("`prefill-chunk-size` must be a strictly positive integer, got 0.")
+        }
+        Some(x) => Some(NonZeroUsize::new(x).unwrap()),
+        None => None,
+    };
+    if prefill_chunk_size.is_some() && !args.no_paged_attn {
+        info!("Disabling PagedAttention for chunked prefill.");
+        args.no_paged_attn = true;
+    }
 
     let prompt_batchsize = match args.prompt_batchsize {
         Some(0) => {
@@ -293,17 +718,65 @@ async fn main() -> Result<()> {
         None => None,
     };
 
-    let loader: Box<dyn Loader> = LoaderBuilder::new(args.model)
+    let loader: Box<dyn Loader> = LoaderBuilder::new(model)
+        .with_no_kv_cache(args.no_kv_cache)
+        .with_chat_template(loader_chat_template)
+        .with_use_flash_attn(use_flash_attn)
+        .with_prompt_batchsize(prompt_batchsize)
+        .build()?;
+    let loader: Box<dyn Loader> = if let Some(draft_model) = args.draft_model {
+        let draft = LoaderBuilder::new(ModelSelected::Plain {
+            model_id: draft_model,
+            tokenizer_json: None,
+            arch: None,
+            dtype,
+            topology: None,
+            organization: None,
+            write_uqff: None,
+            from_uqff: None,
+        })
         .with_no_kv_cache(args.no_kv_cache)
-        .with_chat_template(args.chat_template)
         .with_use_flash_attn(use_flash_attn)
         .with_prompt_batchsize(prompt_batchsize)
         .build()?;
+        Box::new(SpeculativeLoader {
+            target: loader,
+            draft,
+            config: SpeculativeConfig {
+                gamma: args.num_speculative_tokens,
+            },
+        })
+    } else if args.ngram_speculative {
+        Box::new(NGramSpeculativeLoader {
+            target: loader,
+            config: NGramSpeculativeConfig {
+                gamma: args.num_speculative_tokens,
+                max_ngram: NGramSpeculativeConfig::DEFAULT_MAX_NGRAM,
+            },
+        })
+    } else {
+        loader
+    };
 
-    #[cfg(feature = "metal")]
-    let device = Device::new_metal(0)?;
-    #[cfg(not(feature = "metal"))]
-    let device = Device::cuda_if_available(0)?;
+    let device_ids = devices::resolve_device_ids(std::mem::take(&mut args.device_ids))?;
+    if args.num_shards == 0 {
+        anyhow::bail!("`num-shards` must be a strictly positive integer, got 0.");
+    }
+    if args.num_shards > 1 && args.num_shards > device_ids.len() {
+        anyhow::bail!(
+            "`--num-shards {}` needs at least as many GPUs from `--device-ids`, got {}.",
+            args.num_shards,
+            device_ids.len()
+        );
+    }
+    let device = match devices::select_devices(&device_ids)?.into_iter().next() {
+        Some(device) => device,
+        #[cfg(feature = "metal")]
+        None => Device::new_metal(0)?,
+        #[cfg(not(feature = "metal"))]
+        None => Device::cuda_if_available(0)?,
+    };
+    info!("Loading the model on {:?}.", device.location());
 
     if let Some(seed) = args.seed {
         device.set_seed(seed)?;
@@ -326,7 +799,11 @@ async fn main() -> Result<()> {
     info!("Model kind is: {}", loader.get_kind().to_string());
 
     // Parse device mapper
-    let mapper = if let Some(device_layers) = args.num_device_layers {
+    let mapper = if args.num_shards > 1 {
+        let ordinals = device_ids[..args.num_shards].to_vec();
+        info!("Sharding the model's layers across GPUs {ordinals:?}.");
+        DeviceMapMetadata::from_even_split(ordinals)
+    } else if let Some(device_layers) = args.num_device_layers {
         if device_layers.len() == 1 && device_layers[0].parse::<usize>().is_ok() {
             let layers = device_layers[0].parse::<usize>().unwrap();
             DeviceMapMetadata::from_num_device_layers(vec![DeviceLayerMapMetadata {
@@ -421,7 +898,7 @@ async fn main() -> Result<()> {
 
     let pipeline = loader.load_model_from_hf(
         None,
-        args.token_source,
+        args.token_source.clone(),
         &dtype,
         &device,
         false,
@@ -450,10 +927,23 @@ async fn main() -> Result<()> {
     };
     // Throughput logging in the server
     let builder = MistralRsBuilder::new(pipeline, scheduler_config)
-        .with_opt_log(args.log)
+        .with_opt_log(args.log.clone())
         .with_truncate_sequence(args.truncate_sequence)
         .with_no_kv_cache(args.no_kv_cache)
-        .with_prefix_cache_n(args.prefix_cache_n);
+        .with_kv_cache_dtype(args.kv_cache_dtype)
+        .with_prefix_cache_n(args.prefix_cache_n)
+        .with_prefill_chunk_size(prefill_chunk_size)
+        .with_reasoning_delimiters(args.reasoning_delimiters.map(|delimiters| {
+            // Clap requires both values.
+            ReasoningDelimiters {
+                open: delimiters[0].clone(),
+                close: delimiters[1].clone(),
+            }
+        }));
+    let builder = match output_filter {
+        Some(filter) => builder.with_output_filter(Arc::new(filter)),
+        None => builder,
+    };
 
     if args.interactive_mode {
         interactive_mode(builder.build(), args.throughput_log).await;
@@ -467,9 +957,49 @@ async fn main() -> Result<()> {
     };
     let mistralrs = builder.build();
 
+    let model_loader = ModelLoader {
+        dtype,
+        device,
+        token_source: args.token_source.clone(),
+        in_situ_quant: args.in_situ_quant,
+        no_kv_cache: args.no_kv_cache,
+        use_flash_attn,
+        prompt_batchsize,
+        max_seqs: args.max_seqs,
+        log: args.log.clone(),
+        truncate_sequence: args.truncate_sequence,
+        kv_cache_dtype: args.kv_cache_dtype,
+        prefix_cache_n: args.prefix_cache_n,
+        drain_grace: Duration::from_secs(args.shutdown_grace_secs),
+    };
+    for model_id in args.serve_models {
+        mistralrs.add_model(model_loader.load(&model_id)?)?;
+    }
+    models::set_loader(model_loader);
+
+    if warmup::resolve_warmup(args.warmup) {
+        warmup::spawn(mistralrs.clone());
+    }
+
     let port = args.port.expect("Interactive mode was not specified, so expected port to be specified. Perhaps you forgot `-i` or `--port`?");
 
-    let app = get_router(mistralrs);
+    let auth = verifier_from_env(ApiKeys::new(args.api_keys))?;
+    if auth.is_some() {
+        info!("Authentication is enabled.");
+    }
+    let metrics_handle = if args.metrics {
+        Some(metrics::install()?)
+    } else {
+        None
+    };
+    let app = get_router(
+        mistralrs.clone(),
+        auth,
+        cors_layer(args.cors_origins)?,
+        metrics_handle,
+        args.enable_internal_state,
+        args.enable_compression,
+    );
 
     let ip = if let Some(ref ip) = args.serve_ip {
         ip.to_string()
@@ -478,7 +1008,12 @@ async fn main() -> Result<()> {
     };
     let listener = tokio::net::TcpListener::bind(format!("{ip}:{}", port)).await?;
     info!("Serving on http://{ip}:{}.", port);
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown::drain(
+            mistralrs,
+            Duration::from_secs(args.shutdown_grace_secs),
+        ))
+        .await?;
 
     Ok(())
 }
@@ -0,0 +1,130 @@
+//! Prometheus metrics. Nothing is recorded unless `--metrics` installed the recorder, in which case
+//! `/metrics` serves them in the Prometheus text format.
+
+use std::{sync::Arc, time::Duration};
+
+use axum::extract::State;
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use mistralrs_core::{MistralRs, SPECULATIVE_STATS};
+
+const REQUESTS_TOTAL: &str = "mistralrs_requests_total";
+const GENERATED_TOKENS_TOTAL: &str = "mistralrs_generated_tokens_total";
+const WAITING_SEQUENCES: &str = "mistralrs_waiting_sequences";
+const RUNNING_SEQUENCES: &str = "mistralrs_running_sequences";
+const TIME_TO_FIRST_TOKEN: &str = "mistralrs_time_to_first_token_seconds";
+const INTER_TOKEN_LATENCY: &str = "mistralrs_inter_token_latency_seconds";
+const REQUEST_LATENCY: &str = "mistralrs_request_latency_seconds";
+const SPECULATIVE_ACCEPTANCE_RATE: &str = "mistralrs_speculative_acceptance_rate";
+
+/// Buckets, in seconds, of the time to the first token and to the whole response.
+const REQUEST_BUCKETS: [f64; 12] = [
+    0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30., 60.,
+];
+/// Buckets, in seconds, of the time between two tokens.
+const TOKEN_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.];
+
+/// The latencies are histograms with buckets, rather than summaries, so that they can be
+/// aggregated across servers.
+fn builder() -> anyhow::Result<PrometheusBuilder> {
+    Ok(PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(TIME_TO_FIRST_TOKEN.to_string()),
+            &REQUEST_BUCKETS,
+        )?
+        .set_buckets_for_metric(Matcher::Full(REQUEST_LATENCY.to_string()), &REQUEST_BUCKETS)?
+        .set_buckets_for_metric(
+            Matcher::Full(INTER_TOKEN_LATENCY.to_string()),
+            &TOKEN_BUCKETS,
+        )?)
+}
+
+/// Install the global Prometheus recorder.
+pub fn install() -> anyhow::Result<PrometheusHandle> {
+    let handle = builder()?.install_recorder()?;
+    describe_counter!(REQUESTS_TOTAL, "Total number of chat completion requests.");
+    describe_counter!(GENERATED_TOKENS_TOTAL, "Total number of generated tokens.");
+    describe_gauge!(WAITING_SEQUENCES, "Sequences waiting to be scheduled.");
+    describe_gauge!(RUNNING_SEQUENCES, "Sequences currently being decoded.");
+    describe_histogram!(
+        TIME_TO_FIRST_TOKEN,
+        "Time from receiving a request to its first generated token."
+    );
+    describe_histogram!(
+        INTER_TOKEN_LATENCY,
+        "Time between two streamed chunks of a response."
+    );
+    describe_histogram!(
+        REQUEST_LATENCY,
+        "Time from receiving a request to its final response."
+    );
+    describe_gauge!(
+        SPECULATIVE_ACCEPTANCE_RATE,
+        "Fraction of draft tokens accepted by the target model with speculative decoding."
+    );
+    Ok(handle)
+}
+
+pub async fn metrics(handle: PrometheusHandle, State(state): State<Arc<MistralRs>>) -> String {
+    let stats = state.get_scheduler_stats();
+    gauge!(WAITING_SEQUENCES).set(stats.waiting() as f64);
+    gauge!(RUNNING_SEQUENCES).set(stats.running() as f64);
+    if let Some(rate) = SPECULATIVE_STATS.acceptance_rate() {
+        gauge!(SPECULATIVE_ACCEPTANCE_RATE).set(rate);
+    }
+    handle.render()
+}
+
+pub fn record_request() {
+    counter!(REQUESTS_TOTAL).increment(1);
+}
+
+pub fn record_generated_tokens(n: usize) {
+    counter!(GENERATED_TOKENS_TOTAL).increment(n as u64);
+}
+
+pub fn record_time_to_first_token(elapsed: Duration) {
+    histogram!(TIME_TO_FIRST_TOKEN).record(elapsed.as_secs_f64());
+}
+
+pub fn record_inter_token_latency(elapsed: Duration) {
+    histogram!(INTER_TOKEN_LATENCY).record(elapsed.as_secs_f64());
+}
+
+pub fn record_request_latency(elapsed: Duration) {
+    histogram!(REQUEST_LATENCY).record(elapsed.as_secs_f64());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        builder, record_inter_token_latency, record_request_latency, record_time_to_first_token,
+    };
+
+    #[test]
+    fn latencies_are_exported_with_buckets() {
+        let recorder = builder().unwrap().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            record_time_to_first_token(Duration::from_millis(80));
+            record_inter_token_latency(Duration::from_millis(20));
+            record_request_latency(Duration::from_secs(3));
+        });
+        let rendered = handle.render();
+        for bucket in [
+            r#"mistralrs_time_to_first_token_seconds_bucket{le="0.1"} 1"#,
+            r#"mistralrs_time_to_first_token_seconds_bucket{le="0.05"} 0"#,
+            r#"mistralrs_inter_token_latency_seconds_bucket{le="0.025"} 1"#,
+            r#"mistralrs_request_latency_seconds_bucket{le="5"} 1"#,
+            r#"mistralrs_request_latency_seconds_bucket{le="+Inf"} 1"#,
+        ] {
+            assert!(
+                rendered.contains(bucket),
+                "`{bucket}` is missing from:\n{rendered}"
+            );
+        }
+        assert!(!rendered.contains("quantile"));
+    }
+}
@@ -0,0 +1,345 @@
+use std::{num::NonZeroUsize, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Json, State},
+    http,
+    response::{IntoResponse, Response},
+};
+use candle_core::Device;
+use mistralrs_core::{
+    DefaultSchedulerMethod, DeviceMapMetadata, IsqType, KvCacheDtype, LoaderBuilder, MistralRs,
+    MistralRsBuilder, ModelDType, ModelSelected, SchedulerConfig, TokenSource,
+};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{
+    error::{JsonBody, JsonError},
+    openai::{ModelObject, ModelObjects},
+    shutdown::drain_engine,
+};
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/v1/models",
+    responses((status = 200, description = "Served model info", body = ModelObjects))
+)]
+pub async fn models(State(state): State<Arc<MistralRs>>) -> Json<ModelObjects> {
+    Json(model_objects(&state))
+}
+
+fn model_objects(state: &Arc<MistralRs>) -> ModelObjects {
+    ModelObjects {
+        object: "list",
+        data: state
+            .get_models()
+            .iter()
+            .map(|model| ModelObject {
+                id: model.get_id(),
+                object: "model",
+                created: model.get_creation_time(),
+                owned_by: "local",
+                adapters: model.get_adapter_names(),
+            })
+            .collect(),
+    }
+}
+
+/// How the models served alongside the selected one are loaded, both with `--serve-model` and
+/// at runtime: plain models with the dtype and ISQ of the selected model, without device mapping
+/// or PagedAttention.
+pub struct ModelLoader {
+    pub dtype: ModelDType,
+    pub device: Device,
+    pub token_source: TokenSource,
+    pub in_situ_quant: Option<IsqType>,
+    pub no_kv_cache: bool,
+    pub use_flash_attn: bool,
+    pub prompt_batchsize: Option<NonZeroUsize>,
+    pub max_seqs: usize,
+    pub log: Option<String>,
+    pub truncate_sequence: bool,
+    pub kv_cache_dtype: KvCacheDtype,
+    pub prefix_cache_n: usize,
+    /// How long an unloaded model may finish its running sequences before they are cut off.
+    pub drain_grace: Duration,
+}
+
+impl ModelLoader {
+    /// Load the plain model `model_id`, a HF hub repo or a local path, with its own engine.
+    pub fn load(&self, model_id: &str) -> anyhow::Result<Arc<MistralRs>> {
+        let loader = LoaderBuilder::new(ModelSelected::Plain {
+            model_id: model_id.to_string(),
+            tokenizer_json: None,
+            arch: None,
+            dtype: self.dtype,
+            topology: None,
+            organization: None,
+            write_uqff: None,
+            from_uqff: None,
+        })
+        .with_no_kv_cache(self.no_kv_cache)
+        .with_use_flash_attn(self.use_flash_attn)
+        .with_prompt_batchsize(self.prompt_batchsize)
+        .build()?;
+        let pipeline = loader.load_model_from_hf(
+            None,
+            self.token_source.clone(),
+            &self.dtype,
+            &self.device,
+            false,
+            DeviceMapMetadata::dummy(),
+            self.in_situ_quant,
+            None,
+        )?;
+        info!("Model `{model_id}` loaded.");
+        Ok(MistralRsBuilder::new(
+            pipeline,
+            SchedulerConfig::DefaultScheduler {
+                method: DefaultSchedulerMethod::Fixed(self.max_seqs.try_into()?),
+            },
+        )
+        .with_opt_log(self.log.clone())
+        .with_truncate_sequence(self.truncate_sequence)
+        .with_no_kv_cache(self.no_kv_cache)
+        .with_kv_cache_dtype(self.kv_cache_dtype)
+        .with_prefix_cache_n(self.prefix_cache_n)
+        .build())
+    }
+}
+
+static LOADER: OnceCell<ModelLoader> = OnceCell::new();
+
+/// Held while a model is loaded or unloaded, so that swaps happen one at a time.
+static SWAP: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+pub fn set_loader(loader: ModelLoader) {
+    let _ = LOADER.set(loader);
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ModelLoadRequest {
+    /// A HF hub repo or a local path of a plain model.
+    #[schema(example = "microsoft/Phi-3.5-mini-instruct")]
+    pub model_id: String,
+    /// The ID of a served model to unload once the new model is serving in its place. The model
+    /// loaded at startup cannot be replaced, as the server is built around it: restart the server
+    /// to change it.
+    #[schema(example = json!(Option::None::<String>))]
+    pub replace: Option<String>,
+    /// Unload the `replace` model before loading the new one, rather than after, so that both
+    /// need not fit in memory at once. Requests naming either model are rejected in the meantime,
+    /// and if the new model does not load, neither is served.
+    #[serde(default)]
+    #[schema(example = false)]
+    pub drain_first: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ModelUnloadRequest {
+    /// The ID of the served model to unload.
+    #[schema(example = "microsoft/Phi-3.5-mini-instruct")]
+    pub model: String,
+}
+
+fn invalid_request(message: String) -> Response {
+    JsonError::invalid_request(message).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/internal/load",
+    request_body = ModelLoadRequest,
+    responses((status = 200, description = "Load a model to serve alongside the others", body = ModelObjects))
+)]
+pub async fn load_model(
+    State(state): State<Arc<MistralRs>>,
+    JsonBody(request): JsonBody<ModelLoadRequest>,
+) -> Response {
+    let repr = format!("Model load: {}", request.model_id);
+    MistralRs::maybe_log_request(state.clone(), repr);
+    let Some(loader) = LOADER.get() else {
+        return invalid_request("This server cannot load models.".to_string());
+    };
+    swap_model(state, request, loader.drain_grace, |model_id| {
+        loader.load(model_id)
+    })
+    .await
+}
+
+/// Serve the model which `load` loads for `request`, alongside the others or in place of
+/// `request.replace`, whose running sequences may finish for `drain_grace`.
+async fn swap_model(
+    state: Arc<MistralRs>,
+    request: ModelLoadRequest,
+    drain_grace: Duration,
+    load: impl FnOnce(&str) -> anyhow::Result<Arc<MistralRs>> + Send + 'static,
+) -> Response {
+    let _swap = SWAP.lock().await;
+    let served = state
+        .get_models()
+        .iter()
+        .map(|model| model.get_id())
+        .collect::<Vec<_>>();
+    if served.contains(&request.model_id) && request.replace.as_ref() != Some(&request.model_id) {
+        return invalid_request(format!(
+            "A model with ID `{}` is already being served.",
+            request.model_id
+        ));
+    }
+    if let Some(replace) = &request.replace {
+        // The first model is the one loaded at startup.
+        if !served[1..].contains(replace) {
+            return invalid_request(format!(
+                "`replace` must be a model served alongside the one loaded at startup, got \
+                 `{replace}`. The model loaded at startup is only changed by restarting the server."
+            ));
+        }
+    }
+    let replace = match (request.replace.as_deref(), request.drain_first) {
+        (Some(replace), true) => {
+            match state.remove_model(replace) {
+                Ok(old) => unload(old, drain_grace).await,
+                Err(e) => return invalid_request(e.to_string()),
+            }
+            None
+        }
+        (None, true) => return invalid_request("`drain_first` requires `replace`.".to_string()),
+        (replace, false) => replace,
+    };
+
+    // Loading takes a while, during which the models keep serving.
+    let model_id = request.model_id.clone();
+    let model = match tokio::task::spawn_blocking(move || load(&model_id)).await {
+        Ok(Ok(model)) => model,
+        Ok(Err(e)) => {
+            MistralRs::maybe_log_error(state, &*e);
+            return invalid_request(format!("Loading `{}` failed: {e}", request.model_id));
+        }
+        Err(e) => {
+            MistralRs::maybe_log_error(state, &e);
+            return JsonError::server_error(e.to_string())
+                .to_response(http::StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let result = match replace {
+        Some(replace) => state.replace_model(replace, model).map(Some),
+        None => state.add_model(model).map(|()| None),
+    };
+    match result {
+        Ok(Some(old)) => unload(old, drain_grace).await,
+        Ok(None) => (),
+        Err(e) => return invalid_request(e.to_string()),
+    }
+    Json(model_objects(&state)).into_response()
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/internal/unload",
+    request_body = ModelUnloadRequest,
+    responses((status = 200, description = "Stop serving a model and free its memory", body = ModelObjects))
+)]
+pub async fn unload_model(
+    State(state): State<Arc<MistralRs>>,
+    JsonBody(request): JsonBody<ModelUnloadRequest>,
+) -> Response {
+    let repr = format!("Model unload: {}", request.model);
+    MistralRs::maybe_log_request(state.clone(), repr);
+    let Some(loader) = LOADER.get() else {
+        return invalid_request("This server cannot unload models.".to_string());
+    };
+    let _swap = SWAP.lock().await;
+    match state.remove_model(&request.model) {
+        Ok(model) => unload(model, loader.drain_grace).await,
+        Err(e) => return invalid_request(e.to_string()),
+    }
+    Json(model_objects(&state)).into_response()
+}
+
+/// Let `model`, which no longer receives requests, finish its running sequences, and drop it,
+/// which stops its engine and frees its weights and KV cache once the last request using it ends.
+async fn unload(model: Arc<MistralRs>, grace: Duration) {
+    let id = model.get_id();
+    info!("Unloading model `{id}` once its running sequences finish.");
+    drain_engine(&model, grace).await;
+    drop(model);
+    info!("Model `{id}` unloaded.");
+}
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_core::TestPipeline;
+    use serde_json::json;
+
+    use super::*;
+    use crate::completions::completions;
+
+    const GRACE: Duration = Duration::from_secs(1);
+
+    fn load(model_id: &str) -> anyhow::Result<Arc<MistralRs>> {
+        Ok(TestPipeline::new(model_id).build(false))
+    }
+
+    fn load_request(model_id: &str, replace: Option<&str>, drain_first: bool) -> ModelLoadRequest {
+        ModelLoadRequest {
+            model_id: model_id.to_string(),
+            replace: replace.map(str::to_string),
+            drain_first,
+        }
+    }
+
+    fn served(state: &Arc<MistralRs>) -> Vec<String> {
+        state
+            .get_models()
+            .iter()
+            .map(|model| model.get_id())
+            .collect()
+    }
+
+    /// The status of a completion request naming `model`.
+    async fn complete(state: &Arc<MistralRs>, model: &str) -> http::StatusCode {
+        let request = json!({"model": model, "prompt": "a b c", "max_tokens": 2});
+        let request = serde_json::from_value(request).unwrap();
+        completions(State(state.clone()), None, JsonBody(request))
+            .await
+            .into_response()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_replaced_models_are_no_longer_served() {
+        for drain_first in [false, true] {
+            let state = load("selected").unwrap();
+            let request = load_request("first", None, false);
+            let response = swap_model(state.clone(), request, GRACE, load).await;
+            assert_eq!(response.status(), http::StatusCode::OK);
+            assert_eq!(served(&state), ["selected", "first"]);
+            assert_eq!(complete(&state, "first").await, http::StatusCode::OK);
+
+            let request = load_request("second", Some("first"), drain_first);
+            let response = swap_model(state.clone(), request, GRACE, load).await;
+            assert_eq!(response.status(), http::StatusCode::OK);
+            assert_eq!(served(&state), ["selected", "second"]);
+            assert_eq!(complete(&state, "first").await, http::StatusCode::NOT_FOUND);
+            assert_eq!(complete(&state, "second").await, http::StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_the_selected_model_is_not_replaced() {
+        let state = load("selected").unwrap();
+        for drain_first in [false, true] {
+            let request = load_request("other", Some("selected"), drain_first);
+            let response = swap_model(state.clone(), request, GRACE, load).await;
+            assert_eq!(response.status(), http::StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(served(&state), ["selected"]);
+        }
+    }
+}
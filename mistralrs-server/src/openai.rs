@@ -1,5 +1,8 @@
 use either::Either;
-use mistralrs_core::{ImageGenerationResponseFormat, Tool, ToolChoice};
+use mistralrs_core::{
+    ImageGenerationResponseFormat, MirostatParams, StopTokens as InternalStopTokens, Tool,
+    ToolCallResponse, ToolChoice, Usage,
+};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, ops::Deref};
 use utoipa::ToSchema;
@@ -31,9 +34,25 @@ impl Deref for MessageContent {
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct Message {
-    pub content: MessageContent,
+    pub content: Option<MessageContent>,
     pub role: String,
     pub name: Option<String>,
+    /// For `tool` messages: the ID of the tool call this message is responding to.
+    pub tool_call_id: Option<String>,
+    /// For `assistant` messages: tool calls previously made by the model.
+    pub tool_calls: Option<Vec<ToolCallResponse>>,
+}
+
+impl Message {
+    pub fn system(content: String) -> Self {
+        Self {
+            content: Some(MessageContent(Either::Left(content))),
+            role: "system".to_string(),
+            name: None,
+            tool_call_id: None,
+            tool_calls: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -41,12 +60,28 @@ pub struct Message {
 pub enum StopTokens {
     Multi(Vec<String>),
     Single(String),
+    /// Token ids, which stop generation as soon as one of them is sampled.
+    Ids(Vec<u32>),
+}
+
+impl From<StopTokens> for InternalStopTokens {
+    fn from(stop: StopTokens) -> Self {
+        match stop {
+            StopTokens::Multi(m) => InternalStopTokens::Seqs(m),
+            StopTokens::Single(s) => InternalStopTokens::Seqs(vec![s]),
+            StopTokens::Ids(ids) => InternalStopTokens::Ids(ids),
+        }
+    }
 }
 
 fn default_false() -> bool {
     false
 }
 
+fn default_true() -> bool {
+    true
+}
+
 fn default_1usize() -> usize {
     1
 }
@@ -74,18 +109,57 @@ pub enum Grammar {
     Regex(String),
     #[serde(rename = "yacc")]
     Yacc(String),
+    /// A GBNF grammar, as used by llama.cpp.
+    #[serde(rename = "gbnf")]
+    Gbnf(String),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct JsonSchemaResponseFormat {
+    pub name: String,
+    pub schema: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type")]
+pub enum ResponseFormat {
+    /// Unconstrained text.
+    #[serde(rename = "text")]
+    Text,
+    /// Any valid JSON value.
+    #[serde(rename = "json_object")]
+    JsonObject,
+    /// JSON matching the given schema.
+    #[serde(rename = "json_schema")]
+    JsonSchema {
+        json_schema: JsonSchemaResponseFormat,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct StreamOptions {
+    /// Send an extra chunk with the token usage of the request before the stream ends.
+    #[serde(default = "default_false")]
+    pub include_usage: bool,
+    /// Attach the token usage so far to every chunk.
+    #[serde(default = "default_false")]
+    pub continuous_usage_stats: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 pub struct ChatCompletionRequest {
-    #[schema(example = json!(vec![Message{content:"Why did the crab cross the road?".to_string(), role:"user".to_string(), name: None}]))]
+    #[schema(example = json!(vec![Message{content:Some(MessageContent(Either::Left("Why did the crab cross the road?".to_string()))), role:"user".to_string(), name: None, tool_call_id: None, tool_calls: None}]))]
     #[serde(with = "either::serde_untagged")]
     pub messages: Either<Vec<Message>, String>,
     #[schema(example = "mistral")]
     #[serde(default = "default_model")]
     pub model: String,
-    #[schema(example = json!(Option::None::<HashMap<u32, f32>>))]
-    pub logit_bias: Option<HashMap<u32, f32>>,
+    /// Biases added to the logits of tokens, keyed by token id or by token string.
+    #[schema(example = json!(Option::None::<HashMap<String, f32>>))]
+    pub logit_bias: Option<HashMap<String, f32>>,
+    /// Biases added to the logits of every token whose text matches a regex, keyed by the regex.
+    #[schema(example = json!(Option::None::<HashMap<String, f32>>))]
+    pub logit_bias_patterns: Option<HashMap<String, f32>>,
     #[serde(default = "default_false")]
     #[schema(example = false)]
     pub logprobs: bool,
@@ -93,6 +167,9 @@ pub struct ChatCompletionRequest {
     pub top_logprobs: Option<usize>,
     #[schema(example = 256)]
     pub max_tokens: Option<usize>,
+    /// The most characters to generate, whichever of this and `max_tokens` is reached first.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub max_chars: Option<usize>,
     #[serde(rename = "n")]
     #[serde(default = "default_1usize")]
     #[schema(example = 1)]
@@ -101,6 +178,10 @@ pub struct ChatCompletionRequest {
     pub presence_penalty: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
     pub frequency_penalty: Option<f32>,
+    /// Whether `frequency_penalty` and `presence_penalty` count the prompt tokens. Defaults to
+    /// true; false only penalizes repeating generated tokens, so a long context is not penalized.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub penalize_prompt: Option<bool>,
     #[serde(rename = "stop")]
     #[schema(example = json!(Option::None::<StopTokens>))]
     pub stop_seqs: Option<StopTokens>,
@@ -110,20 +191,42 @@ pub struct ChatCompletionRequest {
     pub top_p: Option<f64>,
     #[schema(example = true)]
     pub stream: Option<bool>,
+    #[schema(example = json!(Option::None::<StreamOptions>))]
+    pub stream_options: Option<StreamOptions>,
+    /// When streaming, the number of tokens batched into each chunk. Defaults to 3.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub stream_granularity: Option<usize>,
     #[schema(example = json!(Option::None::<Vec<Tool>>))]
     pub tools: Option<Vec<Tool>>,
     #[schema(example = json!(Option::None::<ToolChoice>))]
     pub tool_choice: Option<ToolChoice>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
+    #[schema(example = json!(Option::None::<ResponseFormat>))]
+    pub response_format: Option<ResponseFormat>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
     pub top_k: Option<usize>,
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
+    /// Only generate exactly one of these strings.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub guided_choice: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<Vec<String>>))]
     pub adapters: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
+    /// Eta sampling, as in HF transformers. Only relevant if in `(0, 1)`.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub eta_cutoff: Option<f64>,
+    /// Epsilon sampling, as in HF transformers. Only relevant if in `(0, 1)`.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub epsilon_cutoff: Option<f64>,
+    /// The order to apply `temperature`, `top_k`, `top_p` and `min_p` in, such as
+    /// `["top_k", "top_p", "temperature"]`. Steps left out follow, in the default order.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub sampler_order: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<f32>))]
     pub dry_multiplier: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
@@ -132,6 +235,49 @@ pub struct ChatCompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub repetition_penalty: Option<f64>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub repetition_context_size: Option<usize>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<MirostatParams>))]
+    pub mirostat: Option<MirostatParams>,
+    /// Contrastive search: the weight of the degeneration penalty when choosing between the
+    /// `top_k` most likely tokens, in `[0, 1]`.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub penalty_alpha: Option<f64>,
+    /// Keep a matched stop string at the end of the output, instead of cutting it.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub include_stop_str_in_output: Option<bool>,
+    /// Attach the ids of the generated tokens to each choice as `raw_tokens`, or to each chunk when
+    /// streaming.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub return_raw_tokens: Option<bool>,
+    /// Times to generate again if the output does not match the `response_format` JSON schema,
+    /// before failing. Not supported when streaming.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub json_schema_retries: Option<usize>,
+    /// Seconds to wait for the model before giving up, overriding `MISTRALRS_REQUEST_TIMEOUT_SECS`.
+    #[schema(example = json!(Option::None::<u64>))]
+    pub timeout_secs: Option<u64>,
+    /// Attach a `timings` object to the response, or to the usage chunk when streaming.
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub return_timings: bool,
+    /// A Jinja chat template to render the messages with, instead of the model's.
+    #[schema(example = json!(Option::None::<String>))]
+    pub chat_template: Option<String>,
+    /// Identifier of the end user, recorded in the logs.
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
+    /// Requests with a higher priority are scheduled before waiting ones with a lower priority.
+    #[schema(example = json!(Option::None::<i32>))]
+    pub priority: Option<i32>,
+    /// Return the prompt rendered with the chat template and its number of tokens, without
+    /// generating.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub dry_run: Option<bool>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -140,6 +286,8 @@ pub struct ModelObject {
     pub object: &'static str,
     pub created: u64,
     pub owned_by: &'static str,
+    /// Names of the adapters loaded with this model.
+    pub adapters: Vec<String>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -154,10 +302,12 @@ pub struct CompletionRequest {
     #[serde(default = "default_model")]
     pub model: String,
     #[schema(example = "Say this is a test.")]
-    pub prompt: String,
-    #[serde(default = "default_1usize")]
-    #[schema(example = 1)]
-    pub best_of: usize,
+    #[serde(with = "either::serde_untagged")]
+    pub prompt: Either<Vec<String>, String>,
+    /// Candidates to generate for each prompt, of which the `n` with the highest mean token logprob
+    /// are returned. Defaults to `n`.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub best_of: Option<usize>,
     #[serde(rename = "echo")]
     #[serde(default = "default_false")]
     #[schema(example = false)]
@@ -166,12 +316,23 @@ pub struct CompletionRequest {
     pub presence_penalty: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
     pub frequency_penalty: Option<f32>,
-    #[schema(example = json!(Option::None::<HashMap<u32, f32>>))]
-    pub logit_bias: Option<HashMap<u32, f32>>,
+    /// Whether `frequency_penalty` and `presence_penalty` count the prompt tokens. Defaults to
+    /// true; false only penalizes repeating generated tokens, so a long context is not penalized.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub penalize_prompt: Option<bool>,
+    /// Biases added to the logits of tokens, keyed by token id or by token string.
+    #[schema(example = json!(Option::None::<HashMap<String, f32>>))]
+    pub logit_bias: Option<HashMap<String, f32>>,
+    /// Biases added to the logits of every token whose text matches a regex, keyed by the regex.
+    #[schema(example = json!(Option::None::<HashMap<String, f32>>))]
+    pub logit_bias_patterns: Option<HashMap<String, f32>>,
     #[schema(example = json!(Option::None::<usize>))]
     pub logprobs: Option<usize>,
     #[schema(example = 16)]
     pub max_tokens: Option<usize>,
+    /// The most characters to generate, whichever of this and `max_tokens` is reached first.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub max_chars: Option<usize>,
     #[serde(rename = "n")]
     #[serde(default = "default_1usize")]
     #[schema(example = 1)]
@@ -180,28 +341,53 @@ pub struct CompletionRequest {
     #[schema(example = json!(Option::None::<StopTokens>))]
     pub stop_seqs: Option<StopTokens>,
     pub stream: Option<bool>,
+    /// Only `continuous_usage_stats` applies, as the final chunk always has the usage.
+    #[schema(example = json!(Option::None::<StreamOptions>))]
+    pub stream_options: Option<StreamOptions>,
+    /// When streaming, the number of tokens batched into each chunk. Defaults to 3.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub stream_granularity: Option<usize>,
     #[schema(example = 0.7)]
     pub temperature: Option<f64>,
     #[schema(example = json!(Option::None::<f64>))]
     pub top_p: Option<f64>,
     #[schema(example = json!(Option::None::<String>))]
     pub suffix: Option<String>,
-    #[serde(rename = "user")]
-    pub _user: Option<String>,
+    /// Identifier of the end user, recorded in the logs.
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
+    /// Requests with a higher priority are scheduled before waiting ones with a lower priority.
+    #[schema(example = json!(Option::None::<i32>))]
+    pub priority: Option<i32>,
     #[schema(example = json!(Option::None::<Vec<Tool>>))]
     pub tools: Option<Vec<Tool>>,
     #[schema(example = json!(Option::None::<ToolChoice>))]
     pub tool_choice: Option<ToolChoice>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
 
     // mistral.rs additional
     #[schema(example = json!(Option::None::<usize>))]
     pub top_k: Option<usize>,
     #[schema(example = json!(Option::None::<Grammar>))]
     pub grammar: Option<Grammar>,
+    /// Only generate exactly one of these strings.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub guided_choice: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<Vec<String>>))]
     pub adapters: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<f64>))]
     pub min_p: Option<f64>,
+    /// Eta sampling, as in HF transformers. Only relevant if in `(0, 1)`.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub eta_cutoff: Option<f64>,
+    /// Epsilon sampling, as in HF transformers. Only relevant if in `(0, 1)`.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub epsilon_cutoff: Option<f64>,
+    /// The order to apply `temperature`, `top_k`, `top_p` and `min_p` in, such as
+    /// `["top_k", "top_p", "temperature"]`. Steps left out follow, in the default order.
+    #[schema(example = json!(Option::None::<Vec<String>>))]
+    pub sampler_order: Option<Vec<String>>,
     #[schema(example = json!(Option::None::<f32>))]
     pub dry_multiplier: Option<f32>,
     #[schema(example = json!(Option::None::<f32>))]
@@ -210,6 +396,41 @@ pub struct CompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub repetition_penalty: Option<f64>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub repetition_context_size: Option<usize>,
+    /// Contrastive search: the weight of the degeneration penalty when choosing between the
+    /// `top_k` most likely tokens, in `[0, 1]`.
+    #[schema(example = json!(Option::None::<f64>))]
+    pub penalty_alpha: Option<f64>,
+    /// Beam search: the hypotheses kept for each prompt, of which the `n` best are returned,
+    /// instead of sampling.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub num_beams: Option<usize>,
+    /// Beam search: hypotheses are scored by their total logprob divided by their length to this
+    /// power. Defaults to 1.
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+    /// Beam search: stop as soon as `n` hypotheses have finished, instead of once no running one
+    /// would score better. Defaults to false.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub early_stopping: Option<bool>,
+    /// Keep a matched stop string at the end of the output, instead of cutting it.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub include_stop_str_in_output: Option<bool>,
+    /// Return the logprob of each prompt token, with this many top logprobs at each position.
+    /// With `max_tokens: 0` the prompt is only scored.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub prompt_logprobs: Option<usize>,
+    /// Attach the ids of the generated tokens to each choice as `raw_tokens`, or to each chunk when
+    /// streaming.
+    #[schema(example = json!(Option::None::<bool>))]
+    pub return_raw_tokens: Option<bool>,
+    /// Attach a `timings` object to the response, or to the final chunk when streaming.
+    #[serde(default = "default_false")]
+    #[schema(example = false)]
+    pub return_timings: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -232,3 +453,154 @@ pub struct ImageGenerationRequest {
     #[schema(example = 1280)]
     pub width: usize,
 }
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum EncodingFormat {
+    #[default]
+    Float,
+    /// Little-endian `f32`s, base64 encoded.
+    Base64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct EmbeddingRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[schema(example = "The food was delicious.")]
+    #[serde(with = "either::serde_untagged")]
+    pub input: Either<Vec<String>, String>,
+    #[serde(default)]
+    #[schema(example = "float")]
+    pub encoding_format: EncodingFormat,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct TokenizeRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[schema(example = "Hello, world!")]
+    pub text: String,
+    /// Whether to include the special tokens the tokenizer adds, such as BOS.
+    #[serde(default = "default_true")]
+    pub add_special_tokens: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct DetokenizeRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[schema(example = json!([1, 22557, 28725, 1526, 28808]))]
+    pub tokens: Vec<u32>,
+    /// Whether to leave special tokens out of the text.
+    #[serde(default = "default_true")]
+    pub skip_special_tokens: bool,
+}
+
+/// How long the model spent on the prompt and on the completion, summed over all choices.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Timings {
+    pub prompt_tokens: usize,
+    pub prompt_eval_time_ms: f32,
+    pub prompt_tokens_per_sec: f32,
+    pub completion_tokens: usize,
+    pub completion_eval_time_ms: f32,
+    pub completion_tokens_per_sec: f32,
+    pub time_to_first_token_ms: f32,
+}
+
+impl From<&Usage> for Timings {
+    fn from(usage: &Usage) -> Self {
+        Self {
+            prompt_tokens: usage.prompt_tokens,
+            prompt_eval_time_ms: usage.total_prompt_time_sec * 1000.,
+            prompt_tokens_per_sec: usage.avg_prompt_tok_per_sec,
+            completion_tokens: usage.completion_tokens,
+            completion_eval_time_ms: usage.total_completion_time_sec * 1000.,
+            completion_tokens_per_sec: usage.avg_compl_tok_per_sec,
+            time_to_first_token_ms: usage.time_to_first_token_sec * 1000.,
+        }
+    }
+}
+
+/// A response body with the `timings` of the request, if it asked for them.
+#[derive(Debug, Serialize)]
+pub struct WithTimings<T> {
+    #[serde(flatten)]
+    pub response: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+}
+
+impl<T> WithTimings<T> {
+    pub fn new(response: T, usage: Option<&Usage>, return_timings: bool) -> Self {
+        Self {
+            response,
+            timings: usage.filter(|_| return_timings).map(Timings::from),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_core::Usage;
+    use serde_json::json;
+
+    use super::{InternalStopTokens, StopTokens, WithTimings};
+
+    #[test]
+    fn test_stop_token_ids() {
+        // Llama 3's `<|eot_id|>`.
+        let stop: StopTokens = serde_json::from_value(json!([128009])).unwrap();
+        assert!(matches!(
+            InternalStopTokens::from(stop),
+            InternalStopTokens::Ids(ids) if ids == [128009]
+        ));
+
+        let stop: StopTokens = serde_json::from_value(json!(["<|eot_id|>"])).unwrap();
+        assert!(matches!(
+            InternalStopTokens::from(stop),
+            InternalStopTokens::Seqs(seqs) if seqs == ["<|eot_id|>"]
+        ));
+    }
+
+    #[test]
+    fn test_timings_are_opt_in() {
+        let usage = Usage {
+            completion_tokens: 8,
+            prompt_tokens: 4,
+            total_tokens: 12,
+            avg_tok_per_sec: 6.,
+            avg_prompt_tok_per_sec: 8.,
+            avg_compl_tok_per_sec: 4.,
+            total_time_sec: 2.5,
+            total_prompt_time_sec: 0.5,
+            total_completion_time_sec: 2.,
+            time_to_first_token_sec: 0.25,
+        };
+        let body = json!({"id": "0"});
+
+        let without = WithTimings::new(body.clone(), Some(&usage), false);
+        assert_eq!(serde_json::to_value(without).unwrap(), body);
+
+        let with = WithTimings::new(body, Some(&usage), true);
+        assert_eq!(
+            serde_json::to_value(with).unwrap(),
+            json!({
+                "id": "0",
+                "timings": {
+                    "prompt_tokens": 4,
+                    "prompt_eval_time_ms": 500.0,
+                    "prompt_tokens_per_sec": 8.0,
+                    "completion_tokens": 8,
+                    "completion_eval_time_ms": 2000.0,
+                    "completion_tokens_per_sec": 4.0,
+                    "time_to_first_token_ms": 250.0,
+                }
+            })
+        );
+    }
+}
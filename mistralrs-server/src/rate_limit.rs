@@ -0,0 +1,258 @@
+//! Token budgets per API key, set by `MISTRALRS_TOKENS_PER_MINUTE`, like OpenAI's TPM limits. The
+//! bucket of each key holds up to a minute of tokens and refills continuously. A request is
+//! admitted while its bucket is not empty, and reserves an estimate of its prompt and completion
+//! tokens up front; once it has finished, or its stream has ended, the estimate is replaced by the
+//! tokens it used. Requests for an empty bucket are rejected with a 429 whose `Retry-After` says
+//! when it will have tokens again. Budgets only apply when API keys are configured, and cover
+//! every route which generates: chat completions, including batches and websockets, and
+//! completions.
+
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, header::RETRY_AFTER, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use mistralrs_core::Usage;
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::{error::JsonError, util::DEFAULT_MAX_TOKENS, MB_TO_B, N_INPUT_SIZE};
+
+/// Bytes of a request body counted as one prompt token by the estimate.
+const BYTES_PER_TOKEN: usize = 4;
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+#[derive(Clone)]
+pub struct TokenBudgets {
+    tokens_per_minute: f64,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl TokenBudgets {
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(tokens_per_minute: u64) -> Self {
+        Self {
+            tokens_per_minute: tokens_per_minute as f64,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The budgets under `MISTRALRS_TOKENS_PER_MINUTE`, or `None` if it is unset.
+    pub fn from_env() -> Option<Self> {
+        env::var("MISTRALRS_TOKENS_PER_MINUTE")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+            .filter(|tokens_per_minute| *tokens_per_minute > 0)
+            .map(Self::new)
+    }
+
+    /// Update the bucket of `key` with `f`, after refilling it for the time since it last was.
+    fn with_bucket<T>(&self, key: &str, f: impl FnOnce(&mut Bucket) -> T) -> T {
+        let mut buckets = self.buckets.lock().expect("`buckets` was poisoned");
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.tokens_per_minute,
+            refilled_at: Instant::now(),
+        });
+        let refill = bucket.refilled_at.elapsed().as_secs_f64() * self.tokens_per_minute / 60.;
+        bucket.tokens = (bucket.tokens + refill).min(self.tokens_per_minute);
+        bucket.refilled_at = Instant::now();
+        f(bucket)
+    }
+
+    /// Reserve `estimate` tokens from the budget of `key`, or the time until it has tokens again
+    /// if it is empty. The bucket may go into debt, which later requests wait out.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn try_reserve(&self, key: &str, estimate: usize) -> Result<TokenReservation, Duration> {
+        let tokens_per_minute = self.tokens_per_minute;
+        self.with_bucket(key, |bucket| {
+            if bucket.tokens <= 0. {
+                let missing = 1. - bucket.tokens;
+                return Err(Duration::from_secs_f64(missing * 60. / tokens_per_minute));
+            }
+            bucket.tokens -= estimate as f64;
+            Ok(())
+        })?;
+        Ok(TokenReservation(Arc::new(Reservation {
+            budgets: self.clone(),
+            key: key.to_string(),
+            estimate,
+            used: Mutex::new(None),
+        })))
+    }
+}
+
+struct Reservation {
+    budgets: TokenBudgets,
+    key: String,
+    estimate: usize,
+    used: Mutex<Option<usize>>,
+}
+
+impl Drop for Reservation {
+    #[allow(clippy::cast_precision_loss)]
+    fn drop(&mut self) {
+        // A request which never reported its usage, such as one whose client went away
+        // mid-stream, keeps its estimate.
+        let used = self.used.lock().expect("`used` was poisoned").take();
+        if let Some(used) = used {
+            let refund = self.estimate as f64 - used as f64;
+            self.budgets
+                .with_bucket(&self.key, |bucket| bucket.tokens += refund);
+        }
+    }
+}
+
+/// Tokens reserved for one request, settled against the tokens it used once the last clone of it
+/// is dropped.
+#[derive(Clone)]
+pub struct TokenReservation(Arc<Reservation>);
+
+impl TokenReservation {
+    /// Charge `usage` to the reservation. A request which generates more than once, such as a
+    /// batch or a retried `json_schema` output, is charged for every generation.
+    pub fn add_usage(&self, usage: &Usage) {
+        let mut used = self.0.used.lock().expect("`used` was poisoned");
+        *used = Some(used.unwrap_or(0) + usage.prompt_tokens + usage.completion_tokens);
+    }
+}
+
+/// An upper estimate of the tokens used by the completion request in `body`, or by each request
+/// of a batch: its bytes as the prompt, and `max_tokens` for each of its choices. A websocket
+/// upgrade has no body, and is estimated as one request with the default `max_tokens`.
+fn estimate_tokens(body: &[u8]) -> usize {
+    let request = serde_json::from_slice::<Value>(body).unwrap_or_default();
+    let completion_tokens = |request: &Value| {
+        let field = |name: &str| {
+            request
+                .get(name)
+                .and_then(Value::as_u64)
+                .and_then(|val| usize::try_from(val).ok())
+        };
+        let max_tokens = field("max_tokens").unwrap_or(DEFAULT_MAX_TOKENS);
+        let choices = field("best_of").or(field("n")).unwrap_or(1).max(1);
+        max_tokens.saturating_mul(choices)
+    };
+    let completion_tokens: usize = match &request {
+        Value::Array(requests) => requests.iter().map(completion_tokens).sum(),
+        request => completion_tokens(request),
+    };
+    body.len() / BYTES_PER_TOKEN + completion_tokens
+}
+
+/// The 429 for a request whose key has no tokens left, to be retried after `retry_after`.
+pub fn budget_exceeded_response(retry_after: Duration) -> Response {
+    let mut response = JsonError::server_error(
+        "Tokens per minute limit reached for this API key, please retry later.".to_string(),
+    )
+    .with_code("rate_limit_exceeded")
+    .to_response(StatusCode::TOO_MANY_REQUESTS);
+    let retry_after_secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after_secs.max(1)));
+    response
+}
+
+/// Charge requests to the token budget of their API key in `budgets`. The reservation is added to
+/// the request extensions, for the handler to report the usage into.
+pub async fn limit_tokens(
+    State(budgets): State<TokenBudgets>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|key| key.trim().to_string())
+    else {
+        return next.run(request).await;
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, N_INPUT_SIZE * MB_TO_B).await {
+        Ok(body) => body,
+        Err(e) => {
+            return JsonError::invalid_request(e.to_string())
+                .to_response(StatusCode::PAYLOAD_TOO_LARGE)
+        }
+    };
+    let reservation = match budgets.try_reserve(&key, estimate_tokens(&body)) {
+        Ok(reservation) => reservation,
+        Err(retry_after) => return budget_exceeded_response(retry_after),
+    };
+    let mut request = Request::from_parts(parts, Body::from(body));
+    request.extensions_mut().insert(reservation);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt_tokens: usize, completion_tokens: usize) -> Usage {
+        Usage {
+            completion_tokens,
+            prompt_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            avg_tok_per_sec: 0.,
+            avg_prompt_tok_per_sec: 0.,
+            avg_compl_tok_per_sec: 0.,
+            total_time_sec: 0.,
+            total_prompt_time_sec: 0.,
+            total_completion_time_sec: 0.,
+            time_to_first_token_sec: 0.,
+        }
+    }
+
+    #[test]
+    fn test_exhausting_the_budget_yields_429() {
+        let budgets = TokenBudgets::new(600);
+
+        // The estimate is settled against the usage, leaving 600 - 300 tokens.
+        let first = budgets.try_reserve("key", 500).unwrap();
+        first.add_usage(&usage(100, 200));
+        drop(first);
+        // Going over the budget is allowed once, leaving a debt of 100 tokens. Both generations
+        // of the request are charged.
+        let second = budgets.try_reserve("key", 400).unwrap();
+        second.add_usage(&usage(50, 150));
+        second.add_usage(&usage(50, 150));
+        drop(second);
+
+        // Each key has its own budget.
+        assert!(budgets.try_reserve("other", 100).is_ok());
+        let retry_after = match budgets.try_reserve("key", 100) {
+            Ok(_) => panic!("The budget was not exhausted."),
+            Err(retry_after) => retry_after,
+        };
+        // A token refills every 100ms, and the debt must be paid off first.
+        assert!(retry_after > Duration::from_secs(10));
+        assert!(retry_after <= Duration::from_millis(10_100));
+
+        let response = budget_exceeded_response(retry_after);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "11");
+    }
+
+    #[test]
+    fn test_batch_estimate_counts_each_request() {
+        let body = br#"[{"max_tokens": 10}, {"max_tokens": 20, "n": 2}]"#;
+        assert_eq!(estimate_tokens(body), body.len() / BYTES_PER_TOKEN + 50);
+        assert_eq!(estimate_tokens(b""), DEFAULT_MAX_TOKENS);
+    }
+}
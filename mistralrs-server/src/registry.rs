@@ -0,0 +1,195 @@
+//! The chat completion requests in flight, by model and request ID, for observability and
+//! cancellation. Request IDs are only unique within a model, so both identify a request. A request
+//! is registered once it has been sent to the engine, and deregisters when its
+//! registration is dropped: once its response has been produced, once its stream has ended, or
+//! when its client goes away and the handler or stream is dropped with it.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::time::Instant;
+
+static REGISTRY: Lazy<RequestRegistry> = Lazy::new(RequestRegistry::default);
+
+/// The registry of the server.
+pub fn registry() -> &'static RequestRegistry {
+    &REGISTRY
+}
+
+struct Entry {
+    streaming: bool,
+    started_at: Instant,
+    /// Stops the generation of the request.
+    cancel: Box<dyn Fn() + Send + Sync>,
+}
+
+/// A request in flight, as listed by [`RequestRegistry::list`].
+#[derive(Debug, Serialize)]
+pub struct InFlightRequest {
+    pub id: usize,
+    pub model: String,
+    pub streaming: bool,
+    pub elapsed_secs: f64,
+}
+
+#[derive(Clone, Default)]
+pub struct RequestRegistry {
+    entries: Arc<Mutex<HashMap<(String, usize), Entry>>>,
+}
+
+impl RequestRegistry {
+    /// Register the request `id` of `model` until the returned registration is dropped. `cancel` is
+    /// called if the request is canceled through the registry.
+    pub fn register(
+        &self,
+        model: String,
+        id: usize,
+        streaming: bool,
+        cancel: impl Fn() + Send + Sync + 'static,
+    ) -> Registration {
+        let key = (model, id);
+        self.entries.lock().expect("`entries` was poisoned").insert(
+            key.clone(),
+            Entry {
+                streaming,
+                started_at: Instant::now(),
+                cancel: Box::new(cancel),
+            },
+        );
+        Registration {
+            registry: self.clone(),
+            key,
+        }
+    }
+
+    /// The requests in flight, by model and ID.
+    pub fn list(&self) -> Vec<InFlightRequest> {
+        let entries = self.entries.lock().expect("`entries` was poisoned");
+        let mut requests = entries
+            .iter()
+            .map(|((model, id), entry)| InFlightRequest {
+                id: *id,
+                model: model.clone(),
+                streaming: entry.streaming,
+                elapsed_secs: entry.started_at.elapsed().as_secs_f64(),
+            })
+            .collect::<Vec<_>>();
+        requests.sort_by(|a, b| (&a.model, a.id).cmp(&(&b.model, b.id)));
+        requests
+    }
+
+    /// Cancel the request `id` of `model`, returning whether it was in flight. It stays registered
+    /// until its handler has ended the response.
+    pub fn cancel(&self, model: &str, id: usize) -> bool {
+        let entries = self.entries.lock().expect("`entries` was poisoned");
+        match entries.get(&(model.to_string(), id)) {
+            Some(entry) => {
+                (entry.cancel)();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Keeps a request in the registry until it is dropped.
+pub struct Registration {
+    registry: RequestRegistry,
+    key: (String, usize),
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry
+            .entries
+            .lock()
+            .expect("`entries` was poisoned")
+            .remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    fn ids(registry: &RequestRegistry) -> Vec<usize> {
+        registry.list().iter().map(|request| request.id).collect()
+    }
+
+    #[tokio::test]
+    async fn test_requests_deregister_when_finished_or_dropped() {
+        let registry = RequestRegistry::default();
+        let canceled = Arc::new(AtomicBool::new(false));
+
+        let finished = registry.register("default".to_string(), 1, false, || ());
+        let flag = canceled.clone();
+        let streaming = registry.register("default".to_string(), 2, true, move || {
+            flag.store(true, Ordering::SeqCst)
+        });
+        assert_eq!(ids(&registry), [1, 2]);
+        assert!(registry.list()[1].streaming);
+
+        assert!(registry.cancel("default", 2));
+        assert!(canceled.load(Ordering::SeqCst));
+        assert!(!registry.cancel("default", 3));
+
+        // A request whose response was produced.
+        drop(finished);
+        assert_eq!(ids(&registry), [2]);
+
+        // A stream whose client went away, which drops it mid-generation.
+        let stream = async move {
+            let _registration = streaming;
+            futures::future::pending::<()>().await
+        };
+        assert!(tokio::time::timeout(Duration::from_millis(10), stream)
+            .await
+            .is_err());
+        assert!(registry.list().is_empty());
+    }
+
+    #[test]
+    fn test_colliding_ids_of_different_models_are_kept_apart() {
+        let registry = RequestRegistry::default();
+        let canceled = Arc::new(Mutex::new(Vec::new()));
+        let register = |model: &str| {
+            let canceled = canceled.clone();
+            let name = model.to_string();
+            registry.register(model.to_string(), 0, false, move || {
+                canceled.lock().unwrap().push(name.clone())
+            })
+        };
+
+        // Each model counts its request IDs from 0.
+        let first = register("model-a");
+        let second = register("model-b");
+        let models = registry
+            .list()
+            .into_iter()
+            .map(|request| (request.model, request.id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            models,
+            [("model-a".to_string(), 0), ("model-b".to_string(), 0)]
+        );
+
+        assert!(registry.cancel("model-b", 0));
+        assert_eq!(*canceled.lock().unwrap(), ["model-b"]);
+        assert!(!registry.cancel("model-c", 0));
+
+        // Deregistering one request leaves the other with the same ID in place.
+        drop(first);
+        assert!(registry.cancel("model-b", 0));
+        drop(second);
+        assert!(registry.list().is_empty());
+    }
+}
@@ -0,0 +1,93 @@
+//! The `X-Request-Id` of each request, which identifies it across the services it passes through.
+//! A client-supplied ID is kept, otherwise a UUID is generated, and the response carries it back.
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The longest client-supplied ID which is kept. Longer ones are replaced by a generated one.
+const MAX_REQUEST_ID_LEN: usize = 256;
+
+/// The `X-Request-Id` of a request, in its extensions for the handlers.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Give each request an `X-Request-Id` and set it on the response, streamed or not.
+pub async fn propagate_request_id(mut request: Request, next: Next) -> Response {
+    let supplied = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string);
+    let id = supplied.unwrap_or_else(|| Uuid::new_v4().to_string());
+    let value = HeaderValue::from_str(&id).expect("request IDs are visible ASCII");
+    request.extensions_mut().insert(RequestId(id));
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{self, StatusCode},
+        middleware,
+        routing::post,
+        Extension, Router,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn router() -> Router {
+        Router::new()
+            .route(
+                "/v1/chat/completions",
+                post(|Extension(RequestId(id)): Extension<RequestId>| async move { id }),
+            )
+            .layer(middleware::from_fn(propagate_request_id))
+    }
+
+    async fn send(request_id: Option<&str>) -> (String, String) {
+        let mut request = http::Request::post("/v1/chat/completions");
+        if let Some(request_id) = request_id {
+            request = request.header("x-request-id", request_id);
+        }
+        let response = router()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let header = response.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (header, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_request_id_is_echoed_or_generated() {
+        // The handler sees the same ID as the client.
+        let (header, seen) = send(Some("trace-1234")).await;
+        assert_eq!(header, "trace-1234");
+        assert_eq!(seen, "trace-1234");
+
+        let (header, seen) = send(None).await;
+        assert!(Uuid::parse_str(&header).is_ok());
+        assert_eq!(seen, header);
+        let (other, _) = send(None).await;
+        assert_ne!(other, header);
+    }
+}
@@ -0,0 +1,91 @@
+//! Graceful shutdown. On `SIGTERM` or Ctrl-C the server stops accepting generation requests, lets the
+//! engine finish the sequences it already has for up to a grace period, and only then stops serving.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{extract::Request, http::StatusCode, middleware::Next, response::Response};
+use mistralrs_core::{MistralRs, Request as EngineRequest};
+use tokio::time::{sleep, Instant};
+use tracing::{info, warn};
+
+use crate::error::JsonError;
+
+/// How often the engine is checked for remaining sequences while draining.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Whether a shutdown signal has been received.
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/// Middleware rejecting new requests with a 503 once shutdown has begun.
+pub async fn reject_during_shutdown(request: Request, next: Next) -> Response {
+    if !is_shutting_down() {
+        return next.run(request).await;
+    }
+    JsonError::server_error("The server is shutting down.".to_string())
+        .with_code("service_unavailable")
+        .to_response(StatusCode::SERVICE_UNAVAILABLE)
+}
+
+async fn wait_for_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install the Ctrl-C handler.");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install the SIGTERM handler.")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Resolves once a shutdown signal was received and the engine has drained, or `grace` has passed.
+/// In the latter case the engine is terminated, which ends the remaining streams.
+pub async fn drain(state: Arc<MistralRs>, grace: Duration) {
+    wait_for_signal().await;
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+    info!(
+        "Shutting down, waiting up to {}s for running sequences to finish.",
+        grace.as_secs()
+    );
+
+    if drain_engine(&state, grace).await {
+        info!("All sequences finished.");
+    }
+}
+
+/// Wait for the engine of `state` to finish its sequences, for up to `grace`, after which it is
+/// terminated, which ends the remaining streams. Returns whether it finished in time.
+pub async fn drain_engine(state: &MistralRs, grace: Duration) -> bool {
+    let deadline = Instant::now() + grace;
+    while !state.is_idle() {
+        if Instant::now() >= deadline {
+            warn!("Grace period elapsed, terminating the remaining sequences.");
+            if let Ok(sender) = state.get_sender() {
+                let _ = sender.send(EngineRequest::Terminate).await;
+            }
+            return false;
+        }
+        sleep(DRAIN_POLL_INTERVAL).await;
+    }
+    true
+}
@@ -0,0 +1,69 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Json, State},
+    http,
+    response::{IntoResponse, Response},
+};
+use mistralrs_core::MistralRs;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    error::{JsonBody, JsonError},
+    openai::{DetokenizeRequest, TokenizeRequest},
+};
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TokenizeResponse {
+    pub tokens: Vec<u32>,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DetokenizeResponse {
+    pub text: String,
+}
+
+fn tokenizer_error(state: Arc<MistralRs>, e: anyhow::Error) -> Response {
+    MistralRs::maybe_log_error(state, &*e);
+    JsonError::invalid_request(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/tokenize",
+    request_body = TokenizeRequest,
+    responses((status = 200, description = "The token IDs of the text", body = TokenizeResponse))
+)]
+pub async fn tokenize(
+    State(state): State<Arc<MistralRs>>,
+    JsonBody(request): JsonBody<TokenizeRequest>,
+) -> Response {
+    match state.tokenize(&request.text, request.add_special_tokens) {
+        Ok(tokens) => Json(TokenizeResponse {
+            count: tokens.len(),
+            tokens,
+        })
+        .into_response(),
+        Err(e) => tokenizer_error(state, e),
+    }
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/detokenize",
+    request_body = DetokenizeRequest,
+    responses((status = 200, description = "The text of the token IDs", body = DetokenizeResponse))
+)]
+pub async fn detokenize(
+    State(state): State<Arc<MistralRs>>,
+    JsonBody(request): JsonBody<DetokenizeRequest>,
+) -> Response {
+    match state.detokenize(&request.tokens, request.skip_special_tokens) {
+        Ok(text) => Json(DetokenizeResponse { text }).into_response(),
+        Err(e) => tokenizer_error(state, e),
+    }
+}
@@ -1,8 +1,241 @@
+use std::{
+    collections::HashMap,
+    env,
+    ops::RangeInclusive,
+    path::Path,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use axum::{
+    response::sse::{Event, KeepAlive, Sse},
+    BoxError,
+};
+use futures::TryStream;
 use image::DynamicImage;
+use mistralrs_core::{validate_chat_template, MistralRs, ModelSelected, Response, SamplingParams};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
 use tokio::{
     fs::{self, File},
     io::AsyncReadExt,
+    sync::mpsc::{channel, Receiver, Sender},
 };
+use tracing::info;
+
+use crate::openai::Message;
+
+/// The largest encoded image accepted in a chat message.
+pub const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+/// The number of tokens generated for a request which does not set `max_tokens`, if below the cap.
+pub const DEFAULT_MAX_TOKENS: usize = 4096;
+
+/// The number of responses the engine may queue for a request before it waits for the client.
+pub const DEFAULT_RESPONSE_BUFFER_SIZE: usize = 256;
+
+static RESPONSE_BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_RESPONSE_BUFFER_SIZE);
+
+/// The buffer size from `--response-buffer-size`, or else from `MISTRALRS_RESPONSE_BUFFER_SIZE`,
+/// or else [`DEFAULT_RESPONSE_BUFFER_SIZE`].
+pub fn resolve_response_buffer_size(arg: Option<usize>) -> usize {
+    arg.or_else(|| {
+        env::var("MISTRALRS_RESPONSE_BUFFER_SIZE")
+            .ok()
+            .and_then(|val| val.parse::<usize>().ok())
+    })
+    .unwrap_or(DEFAULT_RESPONSE_BUFFER_SIZE)
+    .max(1)
+}
+
+pub fn set_response_buffer_size(size: usize) {
+    RESPONSE_BUFFER_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// The channel the engine sends the responses of one request through.
+pub fn response_channel() -> (Sender<Response>, Receiver<Response>) {
+    channel(RESPONSE_BUFFER_SIZE.load(Ordering::Relaxed))
+}
+
+const DEFAULT_KEEP_ALIVE_INTERVAL_MS: u64 = 1000;
+const DEFAULT_KEEP_ALIVE_TEXT: &str = "keep-alive-text";
+
+/// The comment an SSE stream sends whenever no chunk was sent for `interval`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeepAliveConfig {
+    interval: Duration,
+    text: String,
+}
+
+static KEEP_ALIVE: OnceCell<Option<KeepAliveConfig>> = OnceCell::new();
+
+/// The keep-alive from `--keep-alive-interval-ms` and `--keep-alive-text`, or else from the
+/// `KEEP_ALIVE_INTERVAL` and `KEEP_ALIVE_TEXT` environment variables, or else a `keep-alive-text`
+/// comment every second. `--no-keep-alive`, or an interval of 0, disables it.
+pub fn resolve_keep_alive(
+    disable: bool,
+    interval_ms: Option<u64>,
+    text: Option<String>,
+) -> Option<KeepAliveConfig> {
+    let interval_ms = interval_ms
+        .or_else(|| {
+            env::var("KEEP_ALIVE_INTERVAL")
+                .ok()
+                .map(|val| val.parse::<u64>().unwrap_or(DEFAULT_KEEP_ALIVE_INTERVAL_MS))
+        })
+        .unwrap_or(DEFAULT_KEEP_ALIVE_INTERVAL_MS);
+    if disable || interval_ms == 0 {
+        return None;
+    }
+    let text = text
+        .or_else(|| env::var("KEEP_ALIVE_TEXT").ok())
+        .unwrap_or_else(|| DEFAULT_KEEP_ALIVE_TEXT.to_string());
+    Some(KeepAliveConfig {
+        interval: Duration::from_millis(interval_ms),
+        text,
+    })
+}
+
+pub fn set_keep_alive(keep_alive: Option<KeepAliveConfig>) {
+    let _ = KEEP_ALIVE.set(keep_alive);
+}
+
+/// The SSE response streaming `stream`, with the keep-alive comments configured for the server.
+pub fn sse<S>(stream: S) -> Sse<S>
+where
+    S: TryStream<Ok = Event> + Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    let keep_alive = KEEP_ALIVE.get_or_init(|| resolve_keep_alive(false, None, None));
+    with_keep_alive(Sse::new(stream), keep_alive.as_ref())
+}
+
+fn with_keep_alive<S>(sse: Sse<S>, keep_alive: Option<&KeepAliveConfig>) -> Sse<S> {
+    match keep_alive {
+        Some(keep_alive) => sse.keep_alive(
+            KeepAlive::new()
+                .interval(keep_alive.interval)
+                .text(&keep_alive.text),
+        ),
+        None => sse,
+    }
+}
+
+/// Milliseconds between heartbeat chunks, 0 if they are disabled.
+static HEARTBEAT_INTERVAL_MS: AtomicU64 = AtomicU64::new(0);
+
+/// The interval from `--heartbeat-interval-ms`, or else from `MISTRALRS_HEARTBEAT_INTERVAL_MS`.
+/// Heartbeats are off by default, and an interval of 0 disables them.
+pub fn resolve_heartbeat_interval(arg: Option<u64>) -> Option<Duration> {
+    arg.or_else(|| {
+        env::var("MISTRALRS_HEARTBEAT_INTERVAL_MS")
+            .ok()
+            .and_then(|val| val.parse::<u64>().ok())
+    })
+    .filter(|ms| *ms > 0)
+    .map(Duration::from_millis)
+}
+
+pub fn set_heartbeat_interval(interval: Option<Duration>) {
+    let ms = interval.map_or(0, |interval| {
+        u64::try_from(interval.as_millis()).unwrap_or(u64::MAX)
+    });
+    HEARTBEAT_INTERVAL_MS.store(ms, Ordering::Relaxed);
+}
+
+/// How often a chat stream sends an empty chunk while waiting for its first token, if at all.
+pub fn heartbeat_interval() -> Option<Duration> {
+    match HEARTBEAT_INTERVAL_MS.load(Ordering::Relaxed) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+static DEFAULT_SYSTEM_PROMPT: OnceCell<String> = OnceCell::new();
+
+pub fn set_default_system_prompt(prompt: String) {
+    let _ = DEFAULT_SYSTEM_PROMPT.set(prompt);
+}
+
+/// Put the `--default-system-prompt` first in `messages`, unless they already have a system message.
+pub fn apply_default_system_prompt(messages: &mut Vec<Message>) {
+    if let Some(prompt) = DEFAULT_SYSTEM_PROMPT.get() {
+        insert_system_prompt(messages, prompt);
+    }
+}
+
+fn insert_system_prompt(messages: &mut Vec<Message>, prompt: &str) {
+    if !messages.iter().any(|message| message.role == "system") {
+        messages.insert(0, Message::system(prompt.to_string()));
+    }
+}
+
+static CHAT_TEMPLATE: OnceCell<String> = OnceCell::new();
+
+/// The Jinja source of `--chat-template`: the `chat_template` key of a `.json` file, the contents
+/// of any other file, or else the argument itself. It must compile.
+pub fn read_chat_template(arg: &str) -> anyhow::Result<String> {
+    #[derive(Deserialize)]
+    struct TemplateFile {
+        chat_template: String,
+    }
+
+    let template = if arg.ends_with(".json") {
+        serde_json::from_str::<TemplateFile>(&std::fs::read_to_string(arg)?)?.chat_template
+    } else if Path::new(arg).is_file() {
+        std::fs::read_to_string(arg)?
+    } else {
+        arg.to_string()
+    };
+    validate_chat_template(&template)?;
+    Ok(template)
+}
+
+pub fn set_chat_template(template: String) {
+    let _ = CHAT_TEMPLATE.set(template);
+}
+
+/// The template of a chat request: its own `chat_template`, or else `--chat-template`, or else
+/// `None` for the model's.
+pub fn chat_template_for(requested: Option<String>) -> Option<String> {
+    requested.or_else(|| CHAT_TEMPLATE.get().cloned())
+}
+
+/// The model selected by `--gguf <path>`: the GGUF file at `path`, with the tokenizer and chat
+/// template from its metadata unless `tokenizer` names a model ID to take them from.
+pub fn gguf_model_selected(path: &str, tokenizer: Option<String>) -> anyhow::Result<ModelSelected> {
+    let path = Path::new(path);
+    let Some(filename) = path.file_name().and_then(|name| name.to_str()) else {
+        anyhow::bail!(
+            "`--gguf` must be the path of a GGUF file, got `{}`.",
+            path.display()
+        );
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy().into_owned(),
+        _ => ".".to_string(),
+    };
+    Ok(ModelSelected::GGUF {
+        tok_model_id: tokenizer,
+        quantized_model_id: dir,
+        quantized_filename: filename.to_string(),
+        topology: None,
+    })
+}
+
+/// `guided_choice` must offer at least one choice, and replaces any other constraint.
+pub fn validate_guided_choice(
+    guided_choice: Option<&[String]>,
+    has_other_constraint: bool,
+) -> anyhow::Result<()> {
+    match guided_choice {
+        Some([]) => anyhow::bail!("`guided_choice` must contain at least one choice."),
+        Some(_) if has_other_constraint => anyhow::bail!(
+            "`guided_choice` cannot be set together with `grammar` or `response_format`."
+        ),
+        _ => Ok(()),
+    }
+}
 
 pub async fn parse_image_url(url_unparsed: &str) -> Result<DynamicImage, anyhow::Error> {
     let url = if let Ok(url) = url::Url::parse(url_unparsed) {
@@ -18,7 +251,12 @@ pub async fn parse_image_url(url_unparsed: &str) -> Result<DynamicImage, anyhow:
     let bytes = if url.scheme() == "http" || url.scheme() == "https" {
         // Read from http
         match reqwest::get(url.clone()).await {
-            Ok(http_resp) => http_resp.bytes().await?.to_vec(),
+            Ok(http_resp) => {
+                if let Some(len) = http_resp.content_length() {
+                    check_image_size(len as usize)?;
+                }
+                http_resp.bytes().await?.to_vec()
+            }
             Err(e) => anyhow::bail!(e),
         }
     } else if url.scheme() == "file" {
@@ -29,6 +267,7 @@ pub async fn parse_image_url(url_unparsed: &str) -> Result<DynamicImage, anyhow:
         if let Ok(mut f) = File::open(&path).await {
             // Read from local file
             let metadata = fs::metadata(&path).await?;
+            check_image_size(metadata.len() as usize)?;
             let mut buffer = vec![0; metadata.len() as usize];
             f.read_exact(&mut buffer).await?;
             buffer
@@ -43,15 +282,432 @@ pub async fn parse_image_url(url_unparsed: &str) -> Result<DynamicImage, anyhow:
         anyhow::bail!("Unsupported URL scheme: {}", url.scheme());
     };
 
+    check_image_size(bytes.len())?;
+
     Ok(image::load_from_memory(&bytes)?)
 }
 
+fn check_image_size(n_bytes: usize) -> anyhow::Result<()> {
+    if n_bytes > MAX_IMAGE_BYTES {
+        anyhow::bail!(
+            "Image is {n_bytes} bytes, larger than the maximum of {MAX_IMAGE_BYTES} bytes."
+        );
+    }
+    Ok(())
+}
+
+/// The range OpenAI allows for `frequency_penalty` and `presence_penalty`.
+const PENALTY_RANGE: RangeInclusive<f32> = -2.0..=2.0;
+
+/// Check the sampling parameters against the ranges OpenAI allows, naming the first one outside its
+/// range. NaN is outside every range. `top_k` cannot be negative, which deserialization enforces.
+pub fn validate_sampling_params(
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+) -> anyhow::Result<()> {
+    if let Some(temperature) = temperature.filter(|t| !(0.0..=f64::MAX).contains(t)) {
+        anyhow::bail!("`temperature` must be at least 0, got {temperature}.");
+    }
+    if let Some(top_p) = top_p.filter(|p| !(0.0..=1.0).contains(p)) {
+        anyhow::bail!("`top_p` must be between 0 and 1, got {top_p}.");
+    }
+    for (name, penalty) in [
+        ("frequency_penalty", frequency_penalty),
+        ("presence_penalty", presence_penalty),
+    ] {
+        if let Some(penalty) = penalty.filter(|p| !PENALTY_RANGE.contains(p)) {
+            anyhow::bail!("`{name}` must be between -2 and 2, got {penalty}.");
+        }
+    }
+    Ok(())
+}
+
+/// OpenAI's `temperature` and `top_p`, used when a request omits them.
+const DEFAULT_TEMPERATURE: f64 = 1.0;
+const DEFAULT_TOP_P: f64 = 1.0;
+
+/// Fill in OpenAI's defaults for the sampling parameters a request omits, so that it samples as it
+/// would there: a `temperature` and `top_p` of 1, and no frequency or presence penalty. Mirostat
+/// replaces top-p sampling, so a request using it gets no `top_p`.
+pub fn with_sampling_defaults(params: SamplingParams) -> SamplingParams {
+    SamplingParams {
+        temperature: params.temperature.or(Some(DEFAULT_TEMPERATURE)),
+        top_p: params
+            .top_p
+            .or(params.mirostat.is_none().then_some(DEFAULT_TOP_P)),
+        frequency_penalty: params.frequency_penalty.or(Some(0.0)),
+        presence_penalty: params.presence_penalty.or(Some(0.0)),
+        ..params
+    }
+}
+
+/// Check that every requested adapter was loaded, naming the unknown and the available ones if not.
+pub fn validate_adapters(requested: Option<&[String]>, available: &[String]) -> anyhow::Result<()> {
+    let unknown = requested
+        .unwrap_or_default()
+        .iter()
+        .filter(|name| !available.contains(name))
+        .map(|name| format!("`{name}`"))
+        .collect::<Vec<_>>();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    if available.is_empty() {
+        anyhow::bail!(
+            "Unknown adapters {}: this model has no adapters loaded.",
+            unknown.join(", ")
+        );
+    }
+    anyhow::bail!(
+        "Unknown adapters {}. Available adapters: {}.",
+        unknown.join(", "),
+        available
+            .iter()
+            .map(|name| format!("`{name}`"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// The generation length for a request: its `max_tokens` clamped to the server-wide
+/// `MISTRALRS_MAX_TOKENS`, or [`DEFAULT_MAX_TOKENS`] (also clamped) if it was omitted.
+pub fn resolve_max_tokens(requested: Option<usize>) -> usize {
+    let cap = env::var("MISTRALRS_MAX_TOKENS")
+        .ok()
+        .and_then(|val| val.parse::<usize>().ok());
+    clamp_max_tokens(requested, cap)
+}
+
+fn clamp_max_tokens(requested: Option<usize>, cap: Option<usize>) -> usize {
+    let max_tokens = requested.unwrap_or(DEFAULT_MAX_TOKENS);
+    match cap {
+        Some(cap) if max_tokens > cap => {
+            if requested.is_some() {
+                info!("Clamping `max_tokens` of {max_tokens} to `MISTRALRS_MAX_TOKENS` of {cap}.");
+            }
+            cap
+        }
+        _ => max_tokens,
+    }
+}
+
+/// A prompt which does not fit in the model's context alongside the requested `max_tokens`.
+#[derive(Debug)]
+pub struct PromptTooLong {
+    pub prompt_tokens: usize,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for PromptTooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The prompt is {} tokens, {} over the limit of {} tokens for this model and `max_tokens`. Shorten the prompt or lower `max_tokens`.",
+            self.prompt_tokens,
+            self.prompt_tokens - self.limit,
+            self.limit
+        )
+    }
+}
+
+impl std::error::Error for PromptTooLong {}
+
+/// The most tokens a prompt may have: the model's context less the requested `max_tokens`, capped
+/// by `MISTRALRS_MAX_PROMPT_TOKENS` if set. An omitted `max_tokens` reserves nothing, as the engine
+/// then stops generating at the end of the context.
+fn prompt_token_limit(max_seq_len: usize, max_tokens: Option<usize>, cap: Option<usize>) -> usize {
+    let limit = max_seq_len.saturating_sub(max_tokens.map_or(0, |n| resolve_max_tokens(Some(n))));
+    cap.map_or(limit, |cap| limit.min(cap))
+}
+
+/// Tokenize `prompt` up front and check it against [`prompt_token_limit`], so that a prompt which
+/// is too long is rejected before it is queued. Models which truncate prompts, and models without a
+/// tokenizer, are not checked.
+pub fn validate_prompt_length(
+    state: &MistralRs,
+    prompt: &str,
+    max_tokens: Option<usize>,
+) -> Result<(), PromptTooLong> {
+    if state.truncates_sequences() {
+        return Ok(());
+    }
+    let Ok(tokens) = state.tokenize(prompt, true) else {
+        return Ok(());
+    };
+    let cap = env::var("MISTRALRS_MAX_PROMPT_TOKENS")
+        .ok()
+        .and_then(|val| val.parse::<usize>().ok());
+    check_prompt_length(
+        tokens.len(),
+        prompt_token_limit(state.max_seq_len(), max_tokens, cap),
+    )
+}
+
+fn check_prompt_length(prompt_tokens: usize, limit: usize) -> Result<(), PromptTooLong> {
+    if prompt_tokens > limit {
+        return Err(PromptTooLong {
+            prompt_tokens,
+            limit,
+        });
+    }
+    Ok(())
+}
+
+/// Split the keys of a `logit_bias` map into token ids and token strings. Keys which parse as an
+/// integer are token ids; the model's tokenizer resolves the others when the request is added.
+pub fn split_logit_bias(
+    logit_bias: Option<HashMap<String, f32>>,
+) -> (Option<HashMap<u32, f32>>, Option<HashMap<String, f32>>) {
+    let Some(logit_bias) = logit_bias else {
+        return (None, None);
+    };
+    let mut ids = HashMap::new();
+    let mut strs = HashMap::new();
+    for (key, bias) in logit_bias {
+        match key.trim().parse::<u32>() {
+            Ok(id) => {
+                ids.insert(id, bias);
+            }
+            Err(_) => {
+                strs.insert(key, bias);
+            }
+        }
+    }
+    (
+        (!ids.is_empty()).then_some(ids),
+        (!strs.is_empty()).then_some(strs),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use image::GenericImageView;
 
     use super::*;
 
+    #[test]
+    fn test_gguf_model_selected() {
+        let ModelSelected::GGUF {
+            tok_model_id,
+            quantized_model_id,
+            quantized_filename,
+            topology,
+        } = gguf_model_selected(
+            "models/tinyllama.Q4_K_M.gguf",
+            Some("TinyLlama/TinyLlama-1.1B-Chat-v1.0".to_string()),
+        )
+        .unwrap()
+        else {
+            panic!("Expected a GGUF model.");
+        };
+        assert_eq!(
+            tok_model_id.as_deref(),
+            Some("TinyLlama/TinyLlama-1.1B-Chat-v1.0")
+        );
+        assert_eq!(quantized_model_id, "models");
+        assert_eq!(quantized_filename, "tinyllama.Q4_K_M.gguf");
+        assert_eq!(topology, None);
+
+        let ModelSelected::GGUF {
+            quantized_model_id, ..
+        } = gguf_model_selected("tinyllama.Q4_K_M.gguf", None).unwrap()
+        else {
+            panic!("Expected a GGUF model.");
+        };
+        assert_eq!(quantized_model_id, ".");
+
+        assert!(gguf_model_selected("..", None).is_err());
+    }
+
+    #[test]
+    fn test_insert_system_prompt() {
+        let user = Message {
+            role: "user".to_string(),
+            ..Message::system("Hi!".to_string())
+        };
+
+        let mut messages = vec![user.clone()];
+        insert_system_prompt(&mut messages, "You are a pirate.");
+        let roles = messages.iter().map(|m| m.role.as_str()).collect::<Vec<_>>();
+        assert_eq!(roles, ["system", "user"]);
+        assert_eq!(
+            messages[0]
+                .content
+                .as_deref()
+                .and_then(|c| c.as_ref().left()),
+            Some(&"You are a pirate.".to_string())
+        );
+
+        // The client's own system message wins, wherever it is.
+        let mut messages = vec![user, Message::system("You are a robot.".to_string())];
+        insert_system_prompt(&mut messages, "You are a pirate.");
+        let roles = messages.iter().map(|m| m.role.as_str()).collect::<Vec<_>>();
+        assert_eq!(roles, ["user", "system"]);
+    }
+
+    #[test]
+    fn test_validate_guided_choice() {
+        let choices = ["positive", "negative", "neutral"].map(String::from);
+        assert!(validate_guided_choice(None, true).is_ok());
+        assert!(validate_guided_choice(Some(&choices), false).is_ok());
+        assert!(validate_guided_choice(Some(&[]), false).is_err());
+        assert!(validate_guided_choice(Some(&choices), true).is_err());
+    }
+
+    #[test]
+    fn test_validate_adapters() {
+        let available = ["adapter_1".to_string(), "adapter_2".to_string()];
+        assert!(validate_adapters(None, &available).is_ok());
+        assert!(validate_adapters(Some(&["adapter_2".to_string()]), &available).is_ok());
+
+        let err = validate_adapters(
+            Some(&["adapter_2".to_string(), "bogus".to_string()]),
+            &available,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown adapters `bogus`. Available adapters: `adapter_1`, `adapter_2`."
+        );
+
+        let err = validate_adapters(Some(&["bogus".to_string()]), &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown adapters `bogus`: this model has no adapters loaded."
+        );
+    }
+
+    #[test]
+    fn test_validate_sampling_params() {
+        assert!(validate_sampling_params(None, None, None, None).is_ok());
+        assert!(validate_sampling_params(Some(0.), Some(0.), Some(-2.), Some(-2.)).is_ok());
+        assert!(validate_sampling_params(Some(5.), Some(1.), Some(2.), Some(2.)).is_ok());
+
+        for (params, field) in [
+            ((Some(-0.1), None, None, None), "temperature"),
+            ((Some(f64::NAN), None, None, None), "temperature"),
+            ((None, Some(-0.1), None, None), "top_p"),
+            ((None, Some(1.1), None, None), "top_p"),
+            ((None, None, Some(-2.1), None), "frequency_penalty"),
+            ((None, None, Some(2.1), None), "frequency_penalty"),
+            ((None, None, None, Some(-2.1)), "presence_penalty"),
+            ((None, None, None, Some(f32::NAN)), "presence_penalty"),
+        ] {
+            let (temperature, top_p, frequency_penalty, presence_penalty) = params;
+            let err =
+                validate_sampling_params(temperature, top_p, frequency_penalty, presence_penalty)
+                    .unwrap_err();
+            assert!(err.to_string().starts_with(&format!("`{field}`")), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_clamp_max_tokens() {
+        assert_eq!(clamp_max_tokens(None, None), DEFAULT_MAX_TOKENS);
+        assert_eq!(clamp_max_tokens(Some(100_000), None), 100_000);
+        assert_eq!(clamp_max_tokens(Some(100), Some(512)), 100);
+        assert_eq!(clamp_max_tokens(Some(1000), Some(512)), 512);
+        assert_eq!(clamp_max_tokens(None, Some(512)), 512);
+        assert_eq!(clamp_max_tokens(None, Some(100_000)), DEFAULT_MAX_TOKENS);
+    }
+
+    #[test]
+    fn test_overlong_prompt_is_rejected() {
+        assert_eq!(prompt_token_limit(4096, None, None), 4096);
+        assert_eq!(prompt_token_limit(4096, Some(96), None), 4000);
+        assert_eq!(prompt_token_limit(4096, Some(96), Some(1000)), 1000);
+        assert_eq!(prompt_token_limit(4096, Some(8192), None), 0);
+
+        assert!(check_prompt_length(4000, 4000).is_ok());
+        let e = check_prompt_length(5000, 4000).unwrap_err();
+        assert_eq!(e.prompt_tokens, 5000);
+        assert!(e
+            .to_string()
+            .contains("5000 tokens, 1000 over the limit of 4000"));
+    }
+
+    #[test]
+    fn test_resolve_response_buffer_size() {
+        assert_eq!(resolve_response_buffer_size(Some(64)), 64);
+        // A channel needs room for at least one response.
+        assert_eq!(resolve_response_buffer_size(Some(0)), 1);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_keep_alive_sends_no_comments() {
+        use axum::{body::to_bytes, response::IntoResponse};
+        use futures::StreamExt;
+
+        let body = |keep_alive: Option<KeepAliveConfig>| async move {
+            // Chunks slower than the keep-alive interval.
+            let stream = futures::stream::iter(["Hello", "world"]).then(|data| async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok::<_, std::convert::Infallible>(Event::default().data(data))
+            });
+            let response = with_keep_alive(Sse::new(stream), keep_alive.as_ref()).into_response();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        let keep_alive = KeepAliveConfig {
+            interval: Duration::from_millis(10),
+            text: "ping".to_string(),
+        };
+        assert!(body(Some(keep_alive)).await.contains(":ping\n\n"));
+        assert_eq!(body(None).await, "data: Hello\n\ndata: world\n\n");
+
+        assert_eq!(resolve_keep_alive(true, Some(500), None), None);
+        assert_eq!(resolve_keep_alive(false, Some(0), None), None);
+    }
+
+    #[test]
+    fn test_omitted_sampling_params_default_to_openai() {
+        use mistralrs_core::MirostatParams;
+
+        // An omitted temperature samples at 1, as with OpenAI, rather than greedily.
+        let omitted = with_sampling_defaults(SamplingParams::deterministic());
+        assert_eq!(omitted.temperature, Some(1.0));
+        assert_eq!(omitted.top_p, Some(1.0));
+        assert_eq!(omitted.frequency_penalty, Some(0.0));
+        assert_eq!(omitted.presence_penalty, Some(0.0));
+
+        let explicit = with_sampling_defaults(SamplingParams {
+            temperature: Some(0.0),
+            top_p: Some(0.9),
+            presence_penalty: Some(0.5),
+            ..SamplingParams::deterministic()
+        });
+        assert_eq!(explicit.temperature, Some(0.0));
+        assert_eq!(explicit.top_p, Some(0.9));
+        assert_eq!(explicit.presence_penalty, Some(0.5));
+
+        let mirostat = with_sampling_defaults(SamplingParams {
+            mirostat: Some(MirostatParams { tau: 5.0, eta: 0.1 }),
+            ..SamplingParams::deterministic()
+        });
+        assert_eq!(mirostat.top_p, None);
+    }
+
+    #[test]
+    fn test_split_logit_bias() {
+        assert_eq!(split_logit_bias(None), (None, None));
+
+        let (ids, strs) = split_logit_bias(Some(HashMap::from([
+            ("50256".to_string(), -100.),
+            ("hello".to_string(), 5.),
+        ])));
+        assert_eq!(ids, Some(HashMap::from([(50256, -100.)])));
+        assert_eq!(strs, Some(HashMap::from([("hello".to_string(), 5.)])));
+    }
+
+    #[test]
+    fn test_check_image_size() {
+        assert!(check_image_size(MAX_IMAGE_BYTES).is_ok());
+        assert!(check_image_size(MAX_IMAGE_BYTES + 1).is_err());
+    }
+
     #[tokio::test]
     async fn test_parse_image_url() {
         // from URL
@@ -0,0 +1,162 @@
+//! Warmup at startup. Before the server reports ready, every served model runs a short generation,
+//! so that the first real request does not pay for compiling kernels and allocating caches.
+
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use mistralrs_core::{
+    Constraint, MistralRs, ModelCategory, NormalRequest, Request, RequestMessage, SamplingParams,
+};
+use tokio::{sync::mpsc::Sender, time::Instant};
+use tracing::{info, warn};
+
+use crate::util::response_channel;
+
+/// The prompt of the warmup generation.
+const WARMUP_PROMPT: &str = "Hello";
+
+/// The length of the warmup generation, enough to run both a prompt step and completion steps.
+const WARMUP_MAX_TOKENS: usize = 8;
+
+static WARMING_UP: AtomicBool = AtomicBool::new(false);
+
+/// Whether the models are still being warmed up, during which the server is not ready.
+pub fn is_warming_up() -> bool {
+    WARMING_UP.load(Ordering::Relaxed)
+}
+
+/// Whether to warm up, from `--warmup`, or else from the `MISTRALRS_WARMUP` environment variable
+/// being `1` or `true`.
+pub fn resolve_warmup(arg: bool) -> bool {
+    arg || env::var("MISTRALRS_WARMUP")
+        .is_ok_and(|val| val == "1" || val.eq_ignore_ascii_case("true"))
+}
+
+/// Warm up every model served by `mistralrs` in the background. Until this is done,
+/// [`is_warming_up`] is true. A model failing its warmup is logged, and does not keep the server
+/// from reporting ready.
+pub fn spawn(mistralrs: Arc<MistralRs>) {
+    WARMING_UP.store(true, Ordering::Relaxed);
+    tokio::spawn(async move {
+        let start = Instant::now();
+        for model in mistralrs.get_models() {
+            let id = model.get_id();
+            // Image generation has no text prompt to warm up with.
+            if matches!(model.get_model_category(), ModelCategory::Diffusion) {
+                continue;
+            }
+            let model_start = Instant::now();
+            let result = match model.get_sender() {
+                Ok(sender) => warmup_engine(&sender, model.next_request_id()).await,
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(()) => info!(
+                    "Warmed up model `{id}` in {:.2}s.",
+                    model_start.elapsed().as_secs_f64()
+                ),
+                Err(e) => warn!("Warmup of model `{id}` failed: {e}"),
+            }
+        }
+        info!("Warmup finished in {:.2}s.", start.elapsed().as_secs_f64());
+        WARMING_UP.store(false, Ordering::Relaxed);
+    });
+}
+
+/// Run the warmup generation through the engine receiving from `sender`.
+async fn warmup_engine(sender: &Sender<Request>, id: usize) -> anyhow::Result<()> {
+    let (tx, mut rx) = response_channel();
+    let request = Request::Normal(NormalRequest {
+        id,
+        messages: RequestMessage::Completion {
+            text: vec![WARMUP_PROMPT.to_string()],
+            echo_prompt: false,
+            best_of: 1,
+            beam_search: None,
+        },
+        sampling_params: SamplingParams {
+            max_len: Some(WARMUP_MAX_TOKENS),
+            ..SamplingParams::deterministic()
+        },
+        response: tx,
+        return_logprobs: false,
+        is_streaming: false,
+        suffix: None,
+        constraint: Constraint::None,
+        adapters: None,
+        tool_choice: None,
+        tools: None,
+        logits_processors: None,
+        chat_template: None,
+        user: None,
+        priority: None,
+    });
+    if sender.send(request).await.is_err() {
+        anyhow::bail!("The engine is not running.");
+    }
+    match rx.recv().await {
+        Some(response) => match response.as_result() {
+            Ok(_) => Ok(()),
+            Err(e) => anyhow::bail!("{e}"),
+        },
+        None => anyhow::bail!("The engine dropped the warmup request."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mistralrs_core::{CompletionResponse, Response, Usage};
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+
+    fn completion_done() -> Response {
+        Response::CompletionDone(CompletionResponse {
+            id: "0".to_string(),
+            choices: Vec::new(),
+            created: 0,
+            model: "default".to_string(),
+            system_fingerprint: "local".to_string(),
+            object: "text_completion".to_string(),
+            usage: Usage {
+                completion_tokens: WARMUP_MAX_TOKENS,
+                prompt_tokens: 1,
+                total_tokens: WARMUP_MAX_TOKENS + 1,
+                avg_tok_per_sec: 0.,
+                avg_prompt_tok_per_sec: 0.,
+                avg_compl_tok_per_sec: 0.,
+                total_time_sec: 0.,
+                total_prompt_time_sec: 0.,
+                total_completion_time_sec: 0.,
+                time_to_first_token_sec: 0.,
+            },
+            seed: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_warmup_runs_a_short_generation() {
+        let (sender, mut engine) = channel(1);
+        let served = tokio::spawn(async move {
+            let Some(Request::Normal(request)) = engine.recv().await else {
+                panic!("Expected a normal request.");
+            };
+            assert_eq!(request.sampling_params.max_len, Some(WARMUP_MAX_TOKENS));
+            request.response.send(completion_done()).await.unwrap();
+        });
+        warmup_engine(&sender, 0).await.unwrap();
+        served.await.unwrap();
+
+        // An engine which goes away fails the warmup rather than hanging it.
+        let (sender, mut engine) = channel(1);
+        tokio::spawn(async move {
+            let _ = engine.recv().await;
+        });
+        assert!(warmup_engine(&sender, 1).await.is_err());
+    }
+}
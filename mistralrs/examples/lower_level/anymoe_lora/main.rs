@@ -101,6 +101,9 @@ fn main() -> anyhow::Result<()> {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        chat_template: None,
+        user: None,
+        priority: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -85,6 +85,9 @@ async fn bench_mistralrs(n_requests: usize) -> anyhow::Result<()> {
             tools: None,
             tool_choice: None,
             logits_processors: None,
+            chat_template: None,
+            user: None,
+            priority: None,
         });
         mistralrs.get_sender()?.send(request).await?;
         handles.push(rx);
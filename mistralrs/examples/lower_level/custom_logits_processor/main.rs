@@ -99,6 +99,9 @@ fn main() -> anyhow::Result<()> {
             Arc::new(move |logits: &Tensor, _context: &[u32]| logits * random_value),
             Arc::new(ThresholdLogitsProcessor { threshold }),
         ]),
+        chat_template: None,
+        user: None,
+        priority: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -78,6 +78,9 @@ fn main() -> anyhow::Result<()> {
         tool_choice: None,
         tools: None,
         logits_processors: None,
+        chat_template: None,
+        user: None,
+        priority: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
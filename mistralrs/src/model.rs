@@ -76,6 +76,9 @@ impl Model {
             tools,
             tool_choice,
             logits_processors: request.take_logits_processors(),
+            chat_template: None,
+            user: None,
+            priority: None,
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -117,6 +120,9 @@ impl Model {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            chat_template: None,
+            user: None,
+            priority: None,
         });
 
         self.runner.get_sender()?.send(request).await?;